@@ -1,16 +1,22 @@
+use std::sync::Arc;
+
 use anyhow::anyhow;
 use indexmap::IndexMap;
 use rs_merkle::{Hasher, MerkleTree};
 use sha2::{Digest, Sha256};
 
 use crate::{
-    errors::CrateResult,
+    errors::{CrateError, CrateResult},
+    rollup::traits::RollupStateTrait,
     types::{
+        balance::BalanceProof,
         common::{generate_salt, TransferBlock, TransferBlockSignature, U8_32},
+        policy::{DefaultPolicy, TransactionPolicy},
         public_key::BlsPublicKeyWrapper,
         signatures::{BlsPublicKey, BlsSignature},
-        transaction::{TransactionBatch, TransactionProof},
+        transaction::{NeighbourLeaf, NonInclusionProof, TransactionBatch, TransactionProof},
     },
+    wallet::utils::{calculate_balances_and_validate_balance_proof, unproven_sends},
 };
 
 #[derive(Clone)]
@@ -41,6 +47,18 @@ pub enum AggregatorState {
     Finalised(TransferBlock),
 }
 
+// What finalise_with_signed_only did with the round
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignedOnlyFinalisation {
+    // Every batch was signed, so the block is the one finalise would produce, along with the
+    // signers' proofs
+    Finalised(Box<TransferBlock>, Vec<TransactionProof>),
+    // The unsigned batches were dropped and the tree rebuilt. The signatures collected so far are
+    // over the old root, so the signers have to sign these proofs (see Wallet::resign_proof)
+    // before the round can be finalised
+    Resign(Vec<TransactionProof>),
+}
+
 pub struct Aggregator {
     pub tx_hash_to_metadata: IndexMap<BlsPublicKeyWrapper, TxMetadata>,
     pub merkle_tree: MerkleTree<Sha256Algorithm>,
@@ -48,18 +66,67 @@ pub struct Aggregator {
     pub state: AggregatorState,
     pub salt: U8_32,
     // rollup_state: impl RollupStateTrait + Send,
+    transaction_policy: Arc<dyn TransactionPolicy>,
+    // Recorded on the blocks this aggregator produces, so batch fees are credited to it
+    fee_recipient: Option<BlsPublicKey>,
+    // Proofs and the aggregated signature grow with the number of batches, so a deployment caps it
+    max_batches: usize,
 }
 
 impl Aggregator {
     pub fn new() -> Aggregator {
+        Aggregator::new_with_policy(Arc::new(DefaultPolicy))
+    }
+
+    pub fn new_with_policy(transaction_policy: Arc<dyn TransactionPolicy>) -> Aggregator {
+        Aggregator::new_with_config(transaction_policy, usize::MAX)
+    }
+
+    pub fn new_with_config(
+        transaction_policy: Arc<dyn TransactionPolicy>,
+        max_batches: usize,
+    ) -> Aggregator {
         Aggregator {
             tx_hash_to_metadata: IndexMap::new(),
             merkle_tree: MerkleTree::new(),
             state: AggregatorState::Open,
             salt: generate_salt(),
+            transaction_policy,
+            fee_recipient: None,
+            max_batches,
         }
     }
 
+    pub fn max_batches(&self) -> usize {
+        self.max_batches
+    }
+
+    // Only applies to batches added from then on
+    pub fn set_max_batches(&mut self, max_batches: usize) {
+        self.max_batches = max_batches;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tx_hash_to_metadata.len() >= self.max_batches
+    }
+
+    pub fn fee_recipient(&self) -> Option<BlsPublicKey> {
+        self.fee_recipient
+    }
+
+    pub fn set_fee_recipient(&mut self, fee_recipient: Option<BlsPublicKey>) {
+        self.fee_recipient = fee_recipient;
+    }
+
+    pub fn transaction_policy(&self) -> Arc<dyn TransactionPolicy> {
+        self.transaction_policy.clone()
+    }
+
+    // Only applies to batches added from then on
+    pub fn set_transaction_policy(&mut self, transaction_policy: Arc<dyn TransactionPolicy>) {
+        self.transaction_policy = transaction_policy;
+    }
+
     pub fn start_collecting_signatures(&mut self) -> CrateResult<()> {
         if self.tx_hash_to_metadata.is_empty() {
             return Err(anyhow!(
@@ -69,6 +136,9 @@ impl Aggregator {
 
         self.check_aggregator_state(AggregatorState::Open)?;
 
+        // Built once the batches are settled rather than on every add, sorting and hashing them all
+        // each time made filling a round quadratic
+        self.build_merkle_tree();
         self.state = AggregatorState::CollectSignatures;
 
         Ok(())
@@ -76,35 +146,149 @@ impl Aggregator {
 
     pub fn add_batch(&mut self, batch: &TransactionBatch) -> CrateResult<()> {
         self.check_aggregator_state(AggregatorState::Open)?;
+        batch.verify_signature()?;
+        // Checked regardless of the policy, which deployments can swap out
+        batch.validate(true)?;
+        self.transaction_policy.validate_batch(batch)?;
 
         let public_key_wrapper: BlsPublicKeyWrapper = batch.from.into();
         if self.tx_hash_to_metadata.contains_key(&public_key_wrapper) {
             return Err(anyhow!("Transaction already exists"));
         }
 
-        let index = self.merkle_tree.leaves_len();
+        if self.is_full() {
+            return Err(CrateError::AggregatorFull.into());
+        }
 
         self.tx_hash_to_metadata.insert(
             public_key_wrapper,
             TxMetadata {
-                index,
+                index: 0,
                 batch: batch.clone(),
                 signature: None,
             },
         );
-        self.merkle_tree.insert(batch.tx_hash()).commit();
 
         Ok(())
     }
 
+    // Same as add_batch, but the batch can't send more than the sender has. The rollup only knows
+    // deposits and withdraws, so the sender's balance proof is needed to count what they've sent
+    // and received in earlier blocks. It has to cover every block the sender signed, otherwise a
+    // deposit could be spent again by leaving the earlier sends out
+    pub async fn add_batch_checked(
+        &mut self,
+        batch: &TransactionBatch,
+        balance_proof: &BalanceProof,
+        rollup_state: &(impl RollupStateTrait + Sync),
+    ) -> CrateResult<()> {
+        let unproven = unproven_sends(rollup_state, &batch.from, balance_proof).await?;
+        if !unproven.is_empty() {
+            return Err(CrateError::UnprovenSends(unproven.len()).into());
+        }
+
+        let balances =
+            calculate_balances_and_validate_balance_proof(rollup_state, balance_proof).await?;
+        let available = match balances.get(&batch.from.into()) {
+            Some(balance) => *balance,
+            None => rollup_state.get_account_balance(&batch.from).await?,
+        };
+
+        let amount = batch.total_amount();
+        if amount > available {
+            return Err(CrateError::UnfundedBatch { amount, available }.into());
+        }
+
+        self.add_batch(batch)
+    }
+
+    // Lets a sender correct their batch while the round is still open
+    pub fn replace_batch(&mut self, batch: &TransactionBatch) -> CrateResult<()> {
+        self.check_aggregator_state(AggregatorState::Open)?;
+        batch.verify_signature()?;
+        batch.validate(true)?;
+        self.transaction_policy.validate_batch(batch)?;
+
+        let tx_metadata = self
+            .tx_hash_to_metadata
+            .get_mut(&BlsPublicKeyWrapper::from(batch.from))
+            .ok_or(anyhow!("No batch for public key"))?;
+        tx_metadata.batch = batch.clone();
+
+        Ok(())
+    }
+
+    // Drops a batch before the round starts collecting signatures, e.g. because it turned out to
+    // be unfunded. The tree isn't built until then, so the removed batch doesn't end up in any proof
+    pub fn remove_batch(&mut self, public_key: &BlsPublicKeyWrapper) -> CrateResult<()> {
+        self.check_aggregator_state(AggregatorState::Open)?;
+
+        self.tx_hash_to_metadata
+            .shift_remove(public_key)
+            .ok_or(anyhow!("No batch for public key"))?;
+
+        Ok(())
+    }
+
+    pub fn batches(&self) -> impl Iterator<Item = &TransactionBatch> {
+        self.tx_hash_to_metadata
+            .values()
+            .map(|tx_metadata| &tx_metadata.batch)
+    }
+
+    // Leaves are sorted by batch hash rather than insertion order, so the root only depends on
+    // the set of batches and two aggregators with the same batches produce the same block
+    fn build_merkle_tree(&mut self) {
+        self.tx_hash_to_metadata
+            .sort_by_cached_key(|_, tx_metadata| tx_metadata.batch.tx_hash());
+
+        let mut leaves = Vec::with_capacity(self.tx_hash_to_metadata.len());
+        for (index, tx_metadata) in self.tx_hash_to_metadata.values_mut().enumerate() {
+            tx_metadata.index = index;
+            leaves.push(tx_metadata.batch.tx_hash());
+        }
+
+        self.merkle_tree = MerkleTree::from_leaves(&leaves);
+    }
+
     pub fn root(&self) -> CrateResult<U8_32> {
         self.merkle_tree.root().ok_or(anyhow!("No transactions"))
     }
 
+    // The committed leaves (batch hashes) in index order, useful for diagnosing proofs that fail
+    // to verify
+    pub fn leaves(&self) -> Vec<U8_32> {
+        self.merkle_tree.leaves().unwrap_or_default()
+    }
+
+    // Upper bound on the number of hashes in a proof for the current tree, which is the depth of
+    // the tree. Some leaves in an unbalanced tree need fewer
+    pub fn estimated_proof_size(&self) -> usize {
+        let total_leaves = self.tx_hash_to_metadata.len();
+
+        if total_leaves <= 1 {
+            return 0;
+        }
+
+        (total_leaves as f64).log2().ceil() as usize
+    }
+
+    pub fn leaf_for_pubkey(&self, public_key: &BlsPublicKey) -> Option<U8_32> {
+        let public_key: BlsPublicKeyWrapper = public_key.into();
+        let TxMetadata { index, .. } = self.tx_hash_to_metadata.get(&public_key)?;
+
+        self.leaves().get(*index).copied()
+    }
+
     pub fn generate_proof_for_pubkey(
         &self,
         public_key: &BlsPublicKey,
     ) -> CrateResult<TransactionProof> {
+        // The tree isn't settled until the round stops accepting batches
+        if self.state == AggregatorState::Open {
+            return Err(CrateError::RoundNotReadyForProofs.into());
+        }
+
         self.check_aggregator_state(AggregatorState::CollectSignatures)?;
 
         let public_key: BlsPublicKeyWrapper = public_key.into();
@@ -126,6 +310,34 @@ impl Aggregator {
         Ok(merkle_proof)
     }
 
+    // Relies on the leaves being sorted, see build_merkle_tree
+    pub fn generate_non_inclusion_proof(&self, tx_hash: &U8_32) -> CrateResult<NonInclusionProof> {
+        if self.state == AggregatorState::Open {
+            return Err(CrateError::RoundNotReadyForProofs.into());
+        }
+
+        let leaves = self.leaves();
+
+        // The position the hash would be inserted at, the neighbours are either side of it
+        let position = match leaves.binary_search(tx_hash) {
+            Ok(_) => return Err(anyhow!("Batch is included in the transfer block")),
+            Err(position) => position,
+        };
+
+        let neighbour = |index: usize| NeighbourLeaf {
+            leaf: leaves[index],
+            index,
+            proof_hashes: self.merkle_tree.proof(&[index]).proof_hashes().to_vec(),
+        };
+
+        Ok(NonInclusionProof {
+            tx_hash: *tx_hash,
+            total_leaves: leaves.len(),
+            left: position.checked_sub(1).map(neighbour),
+            right: (position < leaves.len()).then(|| neighbour(position)),
+        })
+    }
+
     pub fn add_signature(
         &mut self,
         public_key: &BlsPublicKey,
@@ -148,6 +360,50 @@ impl Aggregator {
         Ok(())
     }
 
+    // None if the key has no batch in this round, otherwise whether it has signed
+    pub fn has_signed(&self, public_key: &BlsPublicKeyWrapper) -> Option<bool> {
+        self.tx_hash_to_metadata
+            .get(public_key)
+            .map(|tx_metadata| tx_metadata.signature.is_some())
+    }
+
+    // Every key with a batch in this round and whether it has signed
+    pub fn participants(&self) -> impl Iterator<Item = (&BlsPublicKeyWrapper, bool)> {
+        self.tx_hash_to_metadata
+            .iter()
+            .map(|(public_key, tx_metadata)| (public_key, tx_metadata.signature.is_some()))
+    }
+
+    // Senders that haven't signed yet, these are left out of the block if it's finalised now. Empty
+    // unless signatures are being collected
+    pub fn outstanding_signers(&self) -> Vec<BlsPublicKey> {
+        if self.state != AggregatorState::CollectSignatures {
+            return vec![];
+        }
+
+        self.tx_hash_to_metadata
+            .values()
+            .filter(|tx_metadata| tx_metadata.signature.is_none())
+            .map(|tx_metadata| tx_metadata.batch.from)
+            .collect()
+    }
+
+    pub fn has_signatures(&self) -> bool {
+        self.tx_hash_to_metadata
+            .values()
+            .any(|tx_metadata| tx_metadata.signature.is_some())
+    }
+
+    // Proofs for the batches that were signed, which are the only ones a block from this round
+    // counts. Only available while collecting signatures
+    pub fn signed_proofs(&self) -> CrateResult<Vec<TransactionProof>> {
+        self.tx_hash_to_metadata
+            .values()
+            .filter(|tx_metadata| tx_metadata.signature.is_some())
+            .map(|tx_metadata| self.generate_proof_for_pubkey(&tx_metadata.batch.from))
+            .collect()
+    }
+
     pub fn finalise(&mut self) -> CrateResult<TransferBlock> {
         self.check_aggregator_state(AggregatorState::CollectSignatures)?;
 
@@ -159,8 +415,12 @@ impl Aggregator {
             }
         }
 
+        // Every batch was dropped before it was signed, there's no block to produce so go back to
+        // accepting batches rather than being stuck collecting signatures
         if signatures_and_public_keys.is_empty() {
-            return Err(anyhow!("No signatures"));
+            self.reset();
+
+            return Err(CrateError::EmptyRound.into());
         }
 
         let signature = TransferBlockSignature::new(signatures_and_public_keys)?;
@@ -168,6 +428,10 @@ impl Aggregator {
         let transfer_block = TransferBlock {
             signature,
             merkle_root: self.root()?,
+            total_leaves: Some(self.merkle_tree.leaves_len()),
+            fee_recipient: self.fee_recipient.map(Into::into),
+            height: 0,
+            timestamp: 0,
         };
 
         self.state = AggregatorState::Finalised(transfer_block.clone());
@@ -175,6 +439,49 @@ impl Aggregator {
         Ok(transfer_block)
     }
 
+    // finalise leaves unsigned batches in the root, so a signer's proof verifies against a root
+    // committing to batches nobody signed for. This drops them first, the catch is that a new root
+    // means new signatures, so a round with unsigned batches takes another pass, see
+    // SignedOnlyFinalisation. Proofs from generate_proof_for_pubkey are for the tree at the time,
+    // anything handed out before the rebuild is for the old root and won't match the block
+    pub fn finalise_with_signed_only(&mut self) -> CrateResult<SignedOnlyFinalisation> {
+        self.check_aggregator_state(AggregatorState::CollectSignatures)?;
+
+        // Nothing to drop, or nothing left if everything was dropped which finalise reports
+        if self.outstanding_signers().is_empty() || !self.has_signatures() {
+            let signed_proofs = self.signed_proofs()?;
+
+            return Ok(SignedOnlyFinalisation::Finalised(
+                Box::new(self.finalise()?),
+                signed_proofs,
+            ));
+        }
+
+        self.tx_hash_to_metadata
+            .retain(|_, tx_metadata| tx_metadata.signature.is_some());
+        for tx_metadata in self.tx_hash_to_metadata.values_mut() {
+            tx_metadata.signature = None;
+        }
+        self.build_merkle_tree();
+
+        let proofs = self
+            .tx_hash_to_metadata
+            .values()
+            .map(|tx_metadata| self.generate_proof_for_pubkey(&tx_metadata.batch.from))
+            .collect::<CrateResult<Vec<TransactionProof>>>()?;
+
+        Ok(SignedOnlyFinalisation::Resign(proofs))
+    }
+
+    // Back to an empty open round for reuse after finalise, the policy, fee recipient and batch cap
+    // are kept
+    pub fn reset(&mut self) {
+        self.tx_hash_to_metadata.clear();
+        self.merkle_tree = MerkleTree::new();
+        self.state = AggregatorState::Open;
+        self.salt = generate_salt();
+    }
+
     fn check_aggregator_state(&self, expected_state: AggregatorState) -> CrateResult<()> {
         if self.state != expected_state {
             return Err(anyhow!(
@@ -190,11 +497,22 @@ impl Aggregator {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use crate::{
-        aggregator::{Aggregator, AggregatorState},
-        errors::CrateResult,
-        rollup::{mock_rollup_memory::MockRollupMemory, traits::MockRollupStateTrait},
-        types::transaction::TransactionBatch,
+        aggregator::{Aggregator, AggregatorState, SignedOnlyFinalisation},
+        errors::{CrateError, CrateResult},
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        types::{
+            balance::{BalanceProof, BalanceProofKey},
+            common::generate_salt,
+            policy::TransactionPolicy,
+            signatures::BlsSecretKey,
+            transaction::{SimpleTransaction, TransactionBatch},
+        },
         wallet::wallet::Wallet,
     };
 
@@ -238,14 +556,385 @@ mod tests {
                 .generate_proof_for_pubkey(&transaction.from)
                 .unwrap();
 
-            let verify_result = merkle_tree_proof.verify();
+            merkle_tree_proof.verify()?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outstanding_signers_are_the_ones_yet_to_sign() -> CrateResult<()> {
+        let (mut aggregator, mut accounts, _) =
+            setup_with_unique_accounts_and_transactions(2).await?;
 
-            assert_eq!(verify_result, true);
+        assert!(aggregator.outstanding_signers().is_empty());
+
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&accounts[0].public_key)?;
+        let signature = accounts[0].validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&accounts[0].public_key, &signature)?;
+
+        assert_eq!(
+            aggregator.outstanding_signers(),
+            vec![accounts[1].public_key]
+        );
+
+        aggregator.finalise()?;
+        assert!(aggregator.outstanding_signers().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalise_with_signed_only_drops_unsigned_batches_from_the_root() -> CrateResult<()>
+    {
+        let (mut aggregator, mut accounts, batches) =
+            setup_with_unique_accounts_and_transactions(3).await?;
+
+        aggregator.start_collecting_signatures()?;
+        let original_root = aggregator.root()?;
+        for account in accounts.iter_mut().take(2) {
+            let proof = aggregator.generate_proof_for_pubkey(&account.public_key)?;
+            let signature = account.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&account.public_key, &signature)?;
+        }
+
+        let SignedOnlyFinalisation::Resign(proofs) = aggregator.finalise_with_signed_only()? else {
+            panic!("Round with an unsigned batch should need resigning");
+        };
+        assert_eq!(proofs.len(), 2);
+        assert!(!aggregator.leaves().contains(&batches[2].tx_hash()));
+        assert_eq!(
+            aggregator.outstanding_signers().len(),
+            2,
+            "Old signatures are over the old root"
+        );
+
+        for account in accounts.iter_mut().take(2) {
+            let proof = proofs
+                .iter()
+                .find(|proof| proof.batch.from == account.public_key)
+                .unwrap();
+            let signature = account.resign_proof(&original_root, proof)?;
+            aggregator.add_signature(&account.public_key, &signature)?;
+        }
+
+        let SignedOnlyFinalisation::Finalised(transfer_block, signed_proofs) =
+            aggregator.finalise_with_signed_only()?
+        else {
+            panic!("Every remaining batch is signed");
+        };
+        assert_ne!(transfer_block.merkle_root, original_root);
+        assert_eq!(transfer_block.signature.public_keys().len(), 2);
+        transfer_block.verify()?;
+        for proof in signed_proofs {
+            assert_eq!(proof.root, transfer_block.merkle_root);
+            proof.verify()?;
         }
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_replaced_batch_is_the_one_proven() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut aggregator = Aggregator::new();
+        let mut sender = Wallet::new(None);
+        let receiver = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+
+        sender.append_transaction_to_batch(receiver.public_key, 30)?;
+        let batch = sender.produce_batch()?;
+        assert!(aggregator.replace_batch(&batch).is_err());
+        aggregator.add_batch(&batch)?;
+
+        sender.cancel_pending_batch()?;
+        sender.append_transaction_to_batch(receiver.public_key, 50)?;
+        let replacement = sender.produce_batch()?;
+        aggregator.replace_batch(&replacement)?;
+
+        aggregator.start_collecting_signatures()?;
+        assert_eq!(aggregator.leaves(), vec![replacement.tx_hash()]);
+        assert!(aggregator.replace_batch(&batch).is_err());
+
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        proof.verify()?;
+        assert_eq!(proof.batch, replacement);
+        assert_eq!(proof.batch.transactions[0].amount, 50);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_signature_isnt_accepted_as_a_root_signature() -> CrateResult<()> {
+        let (mut aggregator, accounts, batches) =
+            setup_with_unique_accounts_and_transactions(1).await?;
+
+        aggregator.start_collecting_signatures()?;
+        let batch_signature = batches[0].signature.unwrap();
+
+        assert!(aggregator
+            .add_signature(&accounts[0].public_key, &batch_signature)
+            .is_err());
+        assert_eq!(
+            aggregator.has_signed(&accounts[0].public_key.into()),
+            Some(false)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aggregator_can_be_reused_after_reset() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut aggregator = Aggregator::new();
+        let mut sender = Wallet::new(None);
+        let receiver = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+
+        let mut roots = vec![];
+        for _ in 0..2 {
+            sender.append_transaction_to_batch(receiver.public_key, 10)?;
+            aggregator.add_batch(&sender.produce_batch()?)?;
+            aggregator.start_collecting_signatures()?;
+            let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&sender.public_key, &signature)?;
+            let transfer_block = aggregator.finalise()?;
+            roots.push(transfer_block.merkle_root);
+            rollup_state.add_transfer_block(transfer_block).await?;
+
+            let salt = aggregator.salt;
+            aggregator.reset();
+            assert_eq!(aggregator.state, AggregatorState::Open);
+            assert!(aggregator.leaves().is_empty());
+            assert_ne!(aggregator.salt, salt);
+        }
+
+        assert_ne!(roots[0], roots[1]);
+        sender.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(sender.balance, 80);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checked_batch_cant_spend_more_than_the_deposit() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut aggregator = Aggregator::new();
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+        rollup_state.add_deposit(&public_key, 100).await?;
+
+        let batch_for = |amount| -> CrateResult<TransactionBatch> {
+            let mut batch = TransactionBatch::new(public_key);
+            batch.transactions.push(SimpleTransaction {
+                to: BlsSecretKey::new().public_key(),
+                from: public_key,
+                amount,
+                salt: Some(generate_salt()),
+                nonce: None,
+                reference: None,
+            });
+            batch.sign(&secret_key)?;
+
+            Ok(batch)
+        };
+
+        let err = aggregator
+            .add_batch_checked(&batch_for(150)?, &BalanceProof::new(), &rollup_state)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::UnfundedBatch {
+                amount: 150,
+                available: 100
+            })
+        );
+        assert!(aggregator.batches().next().is_none());
+
+        aggregator
+            .add_batch_checked(&batch_for(100)?, &BalanceProof::new(), &rollup_state)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checked_batch_cant_spend_a_deposit_twice() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+        rollup_state.add_deposit(&public_key, 100).await?;
+
+        let batch_for = |secret_key: &BlsSecretKey, amount| -> CrateResult<TransactionBatch> {
+            let mut batch = TransactionBatch::new(secret_key.public_key());
+            batch.transactions.push(SimpleTransaction {
+                to: BlsSecretKey::new().public_key(),
+                from: secret_key.public_key(),
+                amount,
+                salt: Some(generate_salt()),
+                nonce: None,
+                reference: None,
+            });
+            batch.sign(secret_key)?;
+
+            Ok(batch)
+        };
+
+        // The whole deposit is spent in the first round
+        let receiver_key = BlsSecretKey::new();
+        let mut first_batch = TransactionBatch::new(public_key);
+        first_batch.transactions.push(SimpleTransaction {
+            to: receiver_key.public_key(),
+            from: public_key,
+            amount: 100,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        first_batch.sign(&secret_key)?;
+        let mut aggregator = Aggregator::new();
+        aggregator
+            .add_batch_checked(&first_batch, &BalanceProof::new(), &rollup_state)
+            .await?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&public_key)?;
+        let signature = secret_key.sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &aggregator.root()?,
+        )?;
+        aggregator.add_signature(&public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        // Leaving the first send out of the proof doesn't make the deposit spendable again
+        let mut aggregator = Aggregator::new();
+        let err = aggregator
+            .add_batch_checked(
+                &batch_for(&secret_key, 100)?,
+                &BalanceProof::new(),
+                &rollup_state,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::UnprovenSends(1))
+        );
+
+        let balance_proof = BalanceProof::from([(
+            BalanceProofKey {
+                root: proof.root,
+                public_key: public_key.into(),
+            },
+            proof,
+        )]);
+        let err = aggregator
+            .add_batch_checked(&batch_for(&secret_key, 100)?, &balance_proof, &rollup_state)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::UnfundedBatch {
+                amount: 100,
+                available: 0
+            })
+        );
+        assert!(aggregator.batches().next().is_none());
+
+        // The receiver never deposited, the transfer alone funds them
+        aggregator
+            .add_batch_checked(
+                &batch_for(&receiver_key, 100)?,
+                &balance_proof,
+                &rollup_state,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_full_aggregator_rejects_batches() -> CrateResult<()> {
+        let (full_aggregator, _, batches) = setup_with_unique_accounts_and_transactions(3).await?;
+        let mut aggregator = Aggregator::new_with_config(full_aggregator.transaction_policy(), 2);
+
+        aggregator.add_batch(&batches[0])?;
+        aggregator.add_batch(&batches[1])?;
+        assert!(aggregator.is_full());
+
+        let err = aggregator.add_batch(&batches[2]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::AggregatorFull)
+        );
+
+        aggregator.start_collecting_signatures()?;
+        assert_eq!(aggregator.leaves().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalise_with_no_signatures_reopens_the_round() -> CrateResult<()> {
+        let (mut aggregator, _, _) = setup_with_unique_accounts_and_transactions(3).await?;
+
+        aggregator.start_collecting_signatures()?;
+
+        // Every participant dropped out before signing
+        let err = aggregator.finalise().unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::EmptyRound)
+        );
+        assert_eq!(aggregator.state, AggregatorState::Open);
+        assert!(aggregator.tx_hash_to_metadata.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_inclusion_proof() -> CrateResult<()> {
+        let (mut aggregator, _, batches) = setup_with_unique_accounts_and_transactions(5).await?;
+
+        aggregator.start_collecting_signatures()?;
+        let root = aggregator.root()?;
+        let leaves = aggregator.leaves();
+
+        // Absent hashes between, before and after the committed leaves
+        let mut between = leaves[2];
+        between[31] = between[31].wrapping_add(1);
+        for absent in [between, [0; 32], [255; 32]] {
+            assert!(!leaves.contains(&absent));
+
+            let proof = aggregator.generate_non_inclusion_proof(&absent)?;
+            assert!(proof.verify(&root));
+        }
+
+        // A batch in the block can't be proven absent
+        let included = batches[0].tx_hash();
+        assert!(aggregator.generate_non_inclusion_proof(&included).is_err());
+
+        // Nor can a proof for another hash be reused for it
+        let mut proof = aggregator.generate_non_inclusion_proof(&[0; 32])?;
+        proof.tx_hash = included;
+        assert!(!proof.verify(&root));
+
+        // Skipping over a leaf breaks the adjacency check
+        let mut proof = aggregator.generate_non_inclusion_proof(&between)?;
+        let skipped = aggregator.generate_non_inclusion_proof(&[255; 32])?;
+        proof.right = skipped.left;
+        assert!(!proof.verify(&root));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_finalise() -> CrateResult<()> {
         let (mut aggregator, mut accounts, batches) =
@@ -284,4 +973,159 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_leaves_match_batch_hashes() -> CrateResult<()> {
+        let (mut aggregator, _, batches) = setup_with_unique_accounts_and_transactions(3).await?;
+        // The tree is only built once the round closes
+        assert!(aggregator.leaves().is_empty());
+        aggregator.start_collecting_signatures()?;
+
+        let mut batch_hashes = batches
+            .iter()
+            .map(|batch| batch.tx_hash())
+            .collect::<Vec<_>>();
+        batch_hashes.sort();
+
+        assert_eq!(aggregator.leaves(), batch_hashes);
+
+        for batch in batches.iter() {
+            assert_eq!(
+                aggregator.leaf_for_pubkey(&batch.from),
+                Some(batch.tx_hash())
+            );
+        }
+
+        assert_eq!(
+            aggregator.leaf_for_pubkey(&Wallet::new(None).public_key),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_root_is_independent_of_insertion_order() -> CrateResult<()> {
+        let (mut aggregator, _, batches) = setup_with_unique_accounts_and_transactions(5).await?;
+
+        let mut reversed_aggregator = Aggregator::new();
+        for batch in batches.iter().rev() {
+            reversed_aggregator.add_batch(batch)?;
+        }
+
+        aggregator.start_collecting_signatures()?;
+        reversed_aggregator.start_collecting_signatures()?;
+
+        assert_eq!(aggregator.root()?, reversed_aggregator.root()?);
+
+        for batch in batches.iter() {
+            let proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+
+            proof.verify()?;
+            assert_eq!(
+                proof,
+                reversed_aggregator.generate_proof_for_pubkey(&batch.from)?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_estimated_proof_size_bounds_generated_proofs() -> CrateResult<()> {
+        assert_eq!(Aggregator::new().estimated_proof_size(), 0);
+
+        for (num_accounts, expected_size) in [(1, 0), (2, 1), (5, 3), (8, 3)] {
+            let (mut aggregator, _, batches) =
+                setup_with_unique_accounts_and_transactions(num_accounts).await?;
+
+            assert_eq!(aggregator.estimated_proof_size(), expected_size);
+
+            aggregator.start_collecting_signatures()?;
+            for batch in batches.iter() {
+                let proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+
+                assert!(proof.proof_hashes.len() <= expected_size);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct AllowAllPolicy;
+
+    impl TransactionPolicy for AllowAllPolicy {
+        fn validate(&self, _: &SimpleTransaction) -> CrateResult<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_amount_transaction_is_rejected_regardless_of_policy() -> CrateResult<()> {
+        let sender = BlsSecretKey::new();
+        let receiver = BlsSecretKey::new().public_key();
+        let mut batch = TransactionBatch::new(sender.public_key());
+        batch.transactions.push(SimpleTransaction {
+            to: receiver,
+            from: sender.public_key(),
+            amount: 0,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        batch.sign(&sender)?;
+
+        // As it would arrive from a peer, the wallet never gets a say
+        let batch: TransactionBatch = serde_json::from_str(&serde_json::to_string(&batch)?)?;
+
+        let mut aggregator = Aggregator::new_with_policy(Arc::new(AllowAllPolicy));
+        let err = aggregator.add_batch(&batch).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::ZeroAmountTransaction(receiver.to_string()))
+        );
+        assert!(aggregator.tx_hash_to_metadata.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_needs_exactly_one_of_salt_and_nonce() -> CrateResult<()> {
+        let sender = BlsSecretKey::new();
+        let receiver = BlsSecretKey::new().public_key();
+
+        for (salt, nonce, expected) in [
+            (
+                None,
+                None,
+                CrateError::MissingTransactionSaltOrNonce(receiver.to_string()),
+            ),
+            (
+                Some(generate_salt()),
+                Some(0),
+                CrateError::TransactionHasSaltAndNonce(receiver.to_string()),
+            ),
+        ] {
+            let mut batch = TransactionBatch::new(sender.public_key());
+            batch.transactions.push(SimpleTransaction {
+                to: receiver,
+                from: sender.public_key(),
+                amount: 10,
+                salt,
+                nonce,
+                reference: None,
+            });
+            batch.sign(&sender)?;
+
+            let mut aggregator = Aggregator::new_with_policy(Arc::new(AllowAllPolicy));
+            let err = aggregator.add_batch(&batch).unwrap_err();
+
+            assert_eq!(err.downcast_ref::<CrateError>(), Some(&expected));
+            assert!(aggregator.tx_hash_to_metadata.is_empty());
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,106 @@
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use stateless_bitcoin_l2::{errors::CrateResult, types::signatures::BlsPublicKey};
+
+// Tells checksummed keys apart from raw hex keys, which are still accepted
+pub const ADDRESS_PREFIX: &str = "pk_";
+
+// Hex characters of the checksum, 4 bytes
+const CHECKSUM_LENGTH: usize = 8;
+
+// Public keys as the CLI shows them: the hex key followed by the first 4 bytes of its SHA-256, so
+// a mistyped or truncated key is rejected rather than paying a key nobody holds
+pub fn encode_address(public_key: &BlsPublicKey) -> String {
+    let key_hex = public_key_hex(public_key);
+    let checksum = checksum(&key_hex);
+
+    format!("{}{}{}", ADDRESS_PREFIX, key_hex, checksum)
+}
+
+pub fn decode_address(value: &str) -> CrateResult<BlsPublicKey> {
+    let address = value
+        .strip_prefix(ADDRESS_PREFIX)
+        .ok_or(anyhow!("Address must start with {}", ADDRESS_PREFIX))?
+        .to_ascii_lowercase();
+
+    if address.len() <= CHECKSUM_LENGTH || !address.is_ascii() {
+        return Err(anyhow!("Address is too short"));
+    }
+
+    let (key_hex, address_checksum) = address.split_at(address.len() - CHECKSUM_LENGTH);
+    if checksum(key_hex) != address_checksum {
+        return Err(anyhow!(
+            "Address checksum doesn't match, check it for typos"
+        ));
+    }
+
+    parse_public_key_hex(key_hex)
+}
+
+pub fn parse_public_key_hex(value: &str) -> CrateResult<BlsPublicKey> {
+    // Not sure why we need to do this, but validation fails otherwise
+    let formatted_string = format!("\"{}\"", value);
+
+    serde_json::from_str(&formatted_string)
+        .map_err(|_| anyhow!("Invalid public key, expected a hex public key or @alias"))
+}
+
+fn public_key_hex(public_key: &BlsPublicKey) -> String {
+    serde_json::to_string(public_key)
+        .expect("Public keys always serialize")
+        .trim_matches('"')
+        .to_ascii_lowercase()
+}
+
+fn checksum(key_hex: &str) -> String {
+    Sha256::digest(key_hex.as_bytes())[..CHECKSUM_LENGTH / 2]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use stateless_bitcoin_l2::{errors::CrateResult, types::signatures::BlsSecretKey};
+
+    use super::*;
+
+    #[test]
+    fn test_address_round_trips() -> CrateResult<()> {
+        let public_key = BlsSecretKey::new().public_key();
+        let address = encode_address(&public_key);
+
+        assert!(address.starts_with(ADDRESS_PREFIX));
+        assert_eq!(decode_address(&address)?, public_key);
+        assert_eq!(
+            decode_address(
+                &address
+                    .to_ascii_uppercase()
+                    .replacen("PK_", ADDRESS_PREFIX, 1)
+            )?,
+            public_key
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_character_corruption_is_rejected() {
+        let address = encode_address(&BlsSecretKey::new().public_key());
+
+        for index in ADDRESS_PREFIX.len()..address.len() {
+            let mut corrupted = address.clone().into_bytes();
+            corrupted[index] = if corrupted[index] == b'0' { b'1' } else { b'0' };
+            let corrupted = String::from_utf8(corrupted).unwrap();
+
+            assert!(
+                decode_address(&corrupted).is_err(),
+                "Corrupting character {} wasn't caught",
+                index
+            );
+        }
+
+        // Dropping a character too
+        assert!(decode_address(&address[..address.len() - 1]).is_err());
+    }
+}
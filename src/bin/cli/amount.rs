@@ -0,0 +1,169 @@
+use std::fmt::Display;
+
+use anyhow::anyhow;
+use stateless_bitcoin_l2::errors::CrateResult;
+
+// Decimal places shown and accepted by the CLI, 1 unit is 10^8 base units like BTC and sats. The
+// wallet and the wire only ever deal in base units
+pub const AMOUNT_DECIMALS: u32 = 8;
+
+// A u64 amount of base units with a fixed number of decimal places, only used for parsing and
+// displaying so there are no floats involved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Amount {
+    base_units: u64,
+    decimals: u32,
+}
+
+impl Amount {
+    pub fn new(base_units: u64, decimals: u32) -> Self {
+        Self {
+            base_units,
+            decimals,
+        }
+    }
+
+    pub fn base_units(&self) -> u64 {
+        self.base_units
+    }
+
+    // Parses a decimal string like "1.5" into base units
+    pub fn parse(value: &str, decimals: u32) -> CrateResult<Self> {
+        let (whole, fraction) = match value.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (value, ""),
+        };
+
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty()
+            || !is_digits(whole)
+            || !is_digits(fraction)
+            || (value.contains('.') && fraction.is_empty())
+        {
+            return Err(anyhow!("Amount must be a number, got \"{}\"", value));
+        }
+
+        if fraction.len() > decimals as usize {
+            return Err(anyhow!(
+                "Amount can have at most {} decimal places, got \"{}\"",
+                decimals,
+                value
+            ));
+        }
+
+        let too_large = || {
+            anyhow!(
+                "Amount is too large, the maximum is {}",
+                Amount::new(u64::MAX, decimals)
+            )
+        };
+
+        // Right pad the fraction so it's in base units, e.g. "5" is 50000000 with 8 decimals
+        let fraction = format!("{:0<width$}", fraction, width = decimals as usize);
+        let fraction = if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<u64>()?
+        };
+
+        let base_units = whole
+            .parse::<u64>()
+            .map_err(|_| too_large())?
+            .checked_mul(10u64.pow(decimals))
+            .and_then(|whole| whole.checked_add(fraction))
+            .ok_or_else(too_large)?;
+
+        Ok(Self::new(base_units, decimals))
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unit = 10u64.pow(self.decimals);
+        let whole = self.base_units / unit;
+        let fraction = self.base_units % unit;
+
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+
+        let fraction = format!("{:0>width$}", fraction, width = self.decimals as usize);
+
+        write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stateless_bitcoin_l2::errors::CrateResult;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_converts_to_base_units() -> CrateResult<()> {
+        for (value, base_units) in [
+            ("1", 100_000_000),
+            ("1.5", 150_000_000),
+            ("0.00000001", 1),
+            ("21.12345678", 2_112_345_678),
+            ("0", 0),
+        ] {
+            assert_eq!(
+                Amount::parse(value, AMOUNT_DECIMALS)?.base_units(),
+                base_units
+            );
+        }
+
+        assert_eq!(Amount::parse("42", 0)?.base_units(), 42);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_round_trips() -> CrateResult<()> {
+        for value in [
+            "1",
+            "1.5",
+            "0.00000001",
+            "21.12345678",
+            "0",
+            "184467440737.09551615",
+        ] {
+            let amount = Amount::parse(value, AMOUNT_DECIMALS)?;
+
+            assert_eq!(amount.to_string(), value);
+            assert_eq!(Amount::parse(&amount.to_string(), AMOUNT_DECIMALS)?, amount);
+        }
+
+        assert_eq!(Amount::new(150_000_000, AMOUNT_DECIMALS).to_string(), "1.5");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_amounts() {
+        for value in ["ten", "-5", "1.", ".5", "1.2.3", "+1", ""] {
+            assert_eq!(
+                Amount::parse(value, AMOUNT_DECIMALS)
+                    .unwrap_err()
+                    .to_string(),
+                format!("Amount must be a number, got \"{}\"", value)
+            );
+        }
+
+        assert_eq!(
+            Amount::parse("1.123456789", AMOUNT_DECIMALS)
+                .unwrap_err()
+                .to_string(),
+            "Amount can have at most 8 decimal places, got \"1.123456789\""
+        );
+
+        assert_eq!(
+            Amount::parse("184467440737.09551616", AMOUNT_DECIMALS)
+                .unwrap_err()
+                .to_string(),
+            "Amount is too large, the maximum is 184467440737.09551615"
+        );
+        assert!(Amount::parse("1.5", 0).is_err());
+    }
+}
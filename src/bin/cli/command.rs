@@ -1,11 +1,45 @@
 use anyhow::anyhow;
-use stateless_bitcoin_l2::types::signatures::BlsPublicKey;
+use stateless_bitcoin_l2::{
+    errors::CrateResult, types::signatures::BlsPublicKey, wallet::wallet::Wallet,
+};
+
+use super::{
+    address::{decode_address, parse_public_key_hex, ADDRESS_PREFIX},
+    amount::{Amount, AMOUNT_DECIMALS},
+};
+
+// Either a public key or @alias for one of the wallet's contacts
+#[derive(Debug, PartialEq)]
+pub enum Recipient {
+    PublicKey(BlsPublicKey),
+    Alias(String),
+}
+
+impl Recipient {
+    fn parse(value: &str) -> CrateResult<Self> {
+        match value.strip_prefix('@') {
+            Some(alias) => Ok(Recipient::Alias(alias.to_string())),
+            None => Ok(Recipient::PublicKey(parse_public_key(value)?)),
+        }
+    }
+
+    pub fn resolve(&self, wallet: &Wallet) -> CrateResult<BlsPublicKey> {
+        match self {
+            Recipient::PublicKey(public_key) => Ok(*public_key),
+            Recipient::Alias(alias) => wallet.resolve_contact(alias),
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
-    AppendTransactionToBatch(BlsPublicKey, u64),
+    AppendTransactionToBatch(Recipient, u64),
+    AddContact(String, BlsPublicKey),
+    RemoveContact(String),
     SendBatchToServer,
     PrintBalance,
+    PrintPublicKey,
+    PrintProofInfo,
     Deposit(u64),
     Exit,
 }
@@ -29,14 +63,38 @@ impl TryFrom<&str> for Command {
                     )));
                 }
 
-                // Not sure why we need to do this, but validation fails otherwise
-                let formatted_string = format!("\"{}\"", parts[1]);
+                let recipient = Recipient::parse(parts[1])?;
+
+                let amount = parse_amount(parts[2])?;
+
+                Ok(Command::AppendTransactionToBatch(recipient, amount))
+            }
+            "add_contact" => {
+                if parts.len() != 3 {
+                    return Err(anyhow!(format!(
+                        "Invalid number of arguments for add_contact, expected 3 got {}",
+                        parts.len()
+                    )));
+                }
+
+                let alias = parts[1].strip_prefix('@').unwrap_or(parts[1]);
 
-                let public_key: BlsPublicKey = serde_json::from_str(&formatted_string).unwrap();
+                Ok(Command::AddContact(
+                    alias.to_string(),
+                    parse_public_key(parts[2])?,
+                ))
+            }
+            "remove_contact" => {
+                if parts.len() != 2 {
+                    return Err(anyhow!(format!(
+                        "Invalid number of arguments for remove_contact, expected 2 got {}",
+                        parts.len()
+                    )));
+                }
 
-                let amount = parts[2].parse::<u64>()?;
+                let alias = parts[1].strip_prefix('@').unwrap_or(parts[1]);
 
-                Ok(Command::AppendTransactionToBatch(public_key, amount))
+                Ok(Command::RemoveContact(alias.to_string()))
             }
             "deposit" => {
                 if parts.len() != 2 {
@@ -46,37 +104,166 @@ impl TryFrom<&str> for Command {
                     )));
                 }
 
-                let amount = parts[1].parse::<u64>()?;
+                let amount = parse_amount(parts[1])?;
 
                 Ok(Command::Deposit(amount))
             }
             "send_batch" => Ok(Command::SendBatchToServer),
             "balance" => Ok(Command::PrintBalance),
+            "pubkey" => Ok(Command::PrintPublicKey),
+            "proof_info" => Ok(Command::PrintProofInfo),
             "exit" => Ok(Command::Exit),
             _ => Err(anyhow!("Invalid command")),
         }
     }
 }
 
+// Checksummed addresses are preferred, raw hex keys are still accepted
+fn parse_public_key(value: &str) -> CrateResult<BlsPublicKey> {
+    if value.starts_with(ADDRESS_PREFIX) {
+        decode_address(value)
+    } else {
+        parse_public_key_hex(value)
+    }
+}
+
+// Amounts are validated here so bad input is rejected before it reaches the wallet, they're
+// entered in whole units and converted to base units
+fn parse_amount(value: &str) -> CrateResult<u64> {
+    let amount = Amount::parse(value, AMOUNT_DECIMALS)?.base_units();
+
+    if amount == 0 {
+        return Err(anyhow!("Amount must be greater than 0"));
+    }
+
+    Ok(amount)
+}
+
 #[cfg(test)]
 mod tests {
-    use stateless_bitcoin_l2::errors::CrateResult;
+    use stateless_bitcoin_l2::{errors::CrateResult, types::signatures::BlsSecretKey};
 
     use super::*;
+    use crate::cli::address::encode_address;
 
     #[test]
     fn test_append_tx_to_batch() -> CrateResult<()> {
         let pubkey_string = "808868b2d0b654328c66f5b005758db14415ed1e2a6db7eb9177721cd4d55a332b0b2805b531c4b71308af26827526ed19ba9745dccfba815b7411ef93f26111e7ed041466aa724f5ce1c4b074cf957ea874ac72b5ae29878cbbfed10095f45d";
-        let command_string = format!("append_tx {} 100", pubkey_string);
+        let command_string = format!("append_tx {} 1.5", pubkey_string);
         let command = Command::try_from(command_string.as_str())?;
 
         match command {
             Command::AppendTransactionToBatch(_, amount) => {
-                assert_eq!(amount, 100);
+                assert_eq!(amount, 150_000_000);
             }
             _ => assert!(false, "Append transaction to batch not parsed correctly"),
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_append_tx_resolves_aliases() -> CrateResult<()> {
+        let alice = BlsSecretKey::new().public_key();
+        let mut wallet = Wallet::new(None);
+
+        let command = Command::try_from(
+            format!(
+                "add_contact @alice {}",
+                serde_json::to_string(&alice)?.trim_matches('"')
+            )
+            .as_str(),
+        )?;
+        let Command::AddContact(alias, public_key) = command else {
+            panic!("Add contact not parsed correctly");
+        };
+        assert_eq!(alias, "alice");
+        wallet.add_contact(&alias, public_key)?;
+
+        let command = Command::try_from("append_tx @alice 100")?;
+        let Command::AppendTransactionToBatch(recipient, amount) = command else {
+            panic!("Append transaction to batch not parsed correctly");
+        };
+        assert_eq!(recipient, Recipient::Alias("alice".to_string()));
+        assert_eq!(amount, 10_000_000_000);
+        assert_eq!(recipient.resolve(&wallet)?, alice);
+
+        let Command::AppendTransactionToBatch(recipient, _) =
+            Command::try_from("append_tx @bob 100")?
+        else {
+            panic!("Append transaction to batch not parsed correctly");
+        };
+        assert_eq!(
+            recipient.resolve(&wallet).unwrap_err().to_string(),
+            "Unknown contact @bob, add it with add_contact first"
+        );
+
+        // The alias is taken
+        let error = wallet
+            .add_contact("alice", BlsSecretKey::new().public_key())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Alias @alice is already used by another contact"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_tx_accepts_checksummed_addresses() -> CrateResult<()> {
+        let public_key = BlsSecretKey::new().public_key();
+        let address = encode_address(&public_key);
+
+        let command = Command::try_from(format!("append_tx {} 1", address).as_str())?;
+        assert_eq!(
+            command,
+            Command::AppendTransactionToBatch(Recipient::PublicKey(public_key), 100_000_000)
+        );
+
+        // A typo in the key
+        let replacement = if &address[10..11] == "a" { "b" } else { "a" };
+        let typo = format!("{}{}{}", &address[..10], replacement, &address[11..]);
+        assert!(Command::try_from(format!("append_tx {} 1", typo).as_str()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_invalid_recipients() {
+        let error = Command::try_from("append_tx alice 100").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid public key, expected a hex public key or @alias"
+        );
+
+        let mut wallet = Wallet::new(None);
+        assert!(wallet
+            .add_contact("not an alias!", BlsSecretKey::new().public_key())
+            .is_err());
+    }
+
+    #[test]
+    fn test_rejects_zero_amount() {
+        let error = Command::try_from("deposit 0").unwrap_err();
+        assert_eq!(error.to_string(), "Amount must be greater than 0");
+
+        let error = Command::try_from("deposit 0.00000000").unwrap_err();
+        assert_eq!(error.to_string(), "Amount must be greater than 0");
+    }
+
+    #[test]
+    fn test_rejects_invalid_amounts() {
+        let error = Command::try_from("deposit ten").unwrap_err();
+        assert_eq!(error.to_string(), "Amount must be a number, got \"ten\"");
+
+        let error = Command::try_from("deposit -5").unwrap_err();
+        assert_eq!(error.to_string(), "Amount must be a number, got \"-5\"");
+
+        let error = Command::try_from("deposit 184467440738").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Amount is too large, the maximum is 184467440737.09551615"
+        );
+    }
 }
@@ -1,2 +1,4 @@
+pub mod address;
+pub mod amount;
 pub mod command;
 pub mod user_input;
@@ -13,7 +13,11 @@ use tokio::{
     task::JoinHandle,
 };
 
-use crate::cli::command::Command;
+use crate::cli::{
+    address::encode_address,
+    amount::{Amount, AMOUNT_DECIMALS},
+    command::Command,
+};
 
 // This function handles user input and sends it to the server
 //
@@ -22,7 +26,7 @@ use crate::cli::command::Command;
 // inputs
 pub fn spawn_user_input_handler(
     client: Arc<Mutex<Client>>,
-    rollup_state: impl RollupStateTrait + MockRollupStateTrait + Sync + Send + Copy + 'static,
+    rollup_state: impl RollupStateTrait + MockRollupStateTrait + Sync + Send + Clone + 'static,
 ) -> JoinHandle<CrateResult<()>> {
     tokio::spawn(async move {
         let stdin = io::stdin();
@@ -34,7 +38,7 @@ pub fn spawn_user_input_handler(
         stdout.flush().await?;
 
         while let Ok(Some(line)) = reader.next_line().await {
-            match handle_new_line(client.clone(), &line, rollup_state).await {
+            match handle_new_line(client.clone(), &line, rollup_state.clone()).await {
                 Ok(Command::Exit) => {
                     info!("Exiting CLI");
                     break;
@@ -58,23 +62,62 @@ pub fn spawn_user_input_handler(
 async fn handle_new_line(
     client: Arc<Mutex<Client>>,
     line: &str,
-    mut rollup_state: impl RollupStateTrait + MockRollupStateTrait + Sync + Send + Copy + 'static,
+    mut rollup_state: impl RollupStateTrait + MockRollupStateTrait + Sync + Send + Clone + 'static,
 ) -> CrateResult<Command> {
     let command: Command = line.trim().try_into()?;
 
     match command {
-        Command::AppendTransactionToBatch(to, amount) => {
-            client
+        Command::AppendTransactionToBatch(ref recipient, amount) => {
+            let wallet = &mut client.lock().await.wallet;
+            let to = recipient.resolve(wallet)?;
+            wallet.append_transaction_to_batch(to, amount)?;
+        }
+        Command::AddContact(ref alias, public_key) => {
+            client.lock().await.wallet.add_contact(alias, public_key)?;
+            println!("Added contact @{}", alias);
+        }
+        Command::RemoveContact(ref alias) => {
+            client.lock().await.wallet.remove_contact(alias)?;
+            println!("Removed contact @{}", alias);
+        }
+        Command::SendBatchToServer => client.lock().await.send_transaction_batch().await?,
+        Command::PrintBalance => {
+            let breakdown = client.lock().await.wallet.balance_breakdown();
+            println!(
+                "Balance: {}",
+                Amount::new(breakdown.confirmed, AMOUNT_DECIMALS)
+            );
+            println!(
+                "Pending outgoing: {}",
+                Amount::new(breakdown.pending_outgoing, AMOUNT_DECIMALS)
+            );
+            println!(
+                "Spendable: {}",
+                Amount::new(breakdown.spendable, AMOUNT_DECIMALS)
+            );
+        }
+        Command::PrintPublicKey => {
+            let public_key = client.lock().await.wallet.public_key;
+            println!("Public key: {}", encode_address(&public_key));
+        }
+        Command::PrintProofInfo => {
+            let stats = client
                 .lock()
                 .await
                 .wallet
-                .append_transaction_to_batch(to, amount)?;
+                .balance_proof_stats(&rollup_state)
+                .await?;
 
-            ()
-        }
-        Command::SendBatchToServer => client.lock().await.send_transaction_batch().await?,
-        Command::PrintBalance => {
-            println!("Balance: {}", client.lock().await.wallet.balance);
+            println!(
+                "Balance proof: {} entries across {} roots, {} bytes serialized",
+                stats.entries, stats.distinct_roots, stats.serialized_bytes
+            );
+            if stats.prunable_entries > 0 {
+                println!(
+                    "{} entries aren't in any transfer block and can be pruned",
+                    stats.prunable_entries
+                );
+            }
         }
         Command::Deposit(amount) => {
             let public_key = client.lock().await.wallet.public_key.clone();
@@ -89,7 +132,9 @@ async fn handle_new_line(
 
             println!(
                 "Deposited {} into account, balance went from {} to {}",
-                amount, prev_balance, new_balance
+                Amount::new(amount, AMOUNT_DECIMALS),
+                Amount::new(prev_balance, AMOUNT_DECIMALS),
+                Amount::new(new_balance, AMOUNT_DECIMALS)
             );
         }
         _ => {
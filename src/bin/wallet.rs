@@ -1,9 +1,21 @@
-use std::env;
+use std::{
+    env,
+    io::{self, BufRead, Write},
+};
 
-use cli::user_input::spawn_user_input_handler;
+use cli::{address::encode_address, user_input::spawn_user_input_handler};
 use stateless_bitcoin_l2::{
-    constants::WEBSOCKET_PORT, errors::CrateResult, rollup::mock_rollup_fs::MockRollupFS,
-    wallet::wallet::Wallet, websocket::client::client::Client,
+    constants::{ROLLUP_SQLITE_PATH, WALLET_STORAGE_DIR, WEBSOCKET_PORT},
+    errors::{CrateError, CrateResult},
+    rollup::{
+        mock_rollup_fs::MockRollupFS, sqlite_rollup::SqliteRollupState,
+        traits::MockRollupStateTrait,
+    },
+    wallet::wallet::Wallet,
+    websocket::{
+        client::{client::Client, constants::DEFAULT_MAX_RECONNECT_ATTEMPTS},
+        server::server::RollupBackend,
+    },
 };
 
 mod cli;
@@ -21,12 +33,56 @@ async fn main() -> CrateResult<()> {
         None
     };
 
-    let rollup_state = MockRollupFS::new()?;
+    // Has to match the backend the server was started with, e.g. `wallet alice sqlite`
+    let rollup_backend = match args.get(2) {
+        Some(arg) => arg.parse()?,
+        None => RollupBackend::default(),
+    };
+
+    match rollup_backend {
+        RollupBackend::Fs => run_wallet(wallet_name, MockRollupFS::new()?).await,
+        RollupBackend::Sqlite => {
+            run_wallet(wallet_name, SqliteRollupState::open(ROLLUP_SQLITE_PATH)?).await
+        }
+    }
+}
+
+// An encrypted wallet file asks for its passphrase until the right one is entered, rather than
+// failing to start
+fn load_wallet(wallet_name: Option<String>) -> CrateResult<Wallet> {
+    let Some(wallet_name) = wallet_name else {
+        return Ok(Wallet::new(None));
+    };
+
+    match Wallet::with_storage_dir(&wallet_name, WALLET_STORAGE_DIR) {
+        Err(e) if e.downcast_ref::<CrateError>() == Some(&CrateError::PassphraseRequired) => {}
+        result => return result,
+    }
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("Passphrase for {}: ", wallet_name);
+        io::stdout().flush()?;
+
+        let passphrase = lines.next().ok_or(CrateError::PassphraseRequired)??;
+        match Wallet::with_encryption(&wallet_name, WALLET_STORAGE_DIR, passphrase.trim_end()) {
+            Err(e) if e.downcast_ref::<CrateError>() == Some(&CrateError::WrongPassphrase) => {
+                println!("{}", e);
+            }
+            result => return result,
+        }
+    }
+}
 
+async fn run_wallet(
+    wallet_name: Option<String>,
+    rollup_state: impl MockRollupStateTrait + Sync + Send + Clone + 'static,
+) -> CrateResult<()> {
     let (client, automatic_sync_handler, ws_receiver_handler) = Client::new(
-        Wallet::new(wallet_name),
+        load_wallet(wallet_name)?,
         rollup_state.clone(),
         WEBSOCKET_PORT,
+        DEFAULT_MAX_RECONNECT_ATTEMPTS,
     )
     .await?;
 
@@ -35,10 +91,7 @@ async fn main() -> CrateResult<()> {
 
         println!("Welcome to the L2 wallet CLI");
 
-        println!(
-            "Your public key is: {}",
-            serde_json::to_string(&public_key)?,
-        );
+        println!("Your public key is: {}", encode_address(&public_key));
     }
 
     let (user_input_result, ws_handler_result, automatic_sync_handler_result) = tokio::try_join!(
@@ -0,0 +1,124 @@
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+// Source of time for anything time based (block production, rate limits, signing deadlines), so
+// tests can control time with MockClock instead of sleeping
+#[async_trait]
+pub trait Clock: Debug + Send + Sync {
+    // Monotonic time, for measuring durations
+    fn now(&self) -> Instant;
+
+    // Wall clock time, for timestamps shared with other machines
+    fn system_time(&self) -> SystemTime;
+
+    async fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+// Only moves when advanced, sleeps wake once the clock has been advanced past their deadline.
+// Clones share the same time
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    started_at: Instant,
+    started_at_system_time: SystemTime,
+    // How far the clock has been advanced since it was created
+    elapsed: Arc<watch::Sender<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            started_at_system_time: SystemTime::now(),
+            elapsed: Arc::new(watch::Sender::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    // Number of sleeps waiting on the clock, lets tests wait for a task to go to sleep before
+    // advancing past it
+    pub fn pending_sleeps(&self) -> usize {
+        self.elapsed.receiver_count()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.started_at + *self.elapsed.borrow()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.started_at_system_time + *self.elapsed.borrow()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let wake_at = *self.elapsed.borrow() + duration;
+
+        // The sender lives as long as self, so this can't fail
+        let _ = self
+            .elapsed
+            .subscribe()
+            .wait_for(|elapsed| *elapsed >= wake_at)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+
+    use super::{Clock, MockClock};
+
+    #[tokio::test]
+    async fn test_mock_clock_sleeps_until_advanced() {
+        let clock = MockClock::new();
+        let started_at = clock.now();
+
+        let sleep = clock.sleep(Duration::from_secs(10));
+        tokio::pin!(sleep);
+
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(9));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert!((&mut sleep).now_or_never().is_some());
+        assert_eq!(clock.now() - started_at, Duration::from_secs(10));
+    }
+}
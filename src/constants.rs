@@ -1 +1,36 @@
 pub const WEBSOCKET_PORT: u16 = 3030;
+// Database used by the aggregator server when it's started with the sqlite rollup backend
+pub const ROLLUP_SQLITE_PATH: &str = "rollup_state.sqlite";
+// How long the block producer waits for clients to sign before finalising a round
+pub const SIGNATURE_WINDOW_SECONDS: u64 = 10;
+
+// Both ends of a websocket connection ping the other this often, a connection that misses this
+// many pongs in a row is treated as dead
+pub const KEEPALIVE_INTERVAL_SECONDS: u64 = 15;
+pub const KEEPALIVE_MAX_MISSED_PONGS: u32 = 3;
+
+// How long a new connection has to answer the server's auth challenge
+pub const AUTH_RESPONSE_TIMEOUT_SECONDS: u64 = 10;
+
+// Maximum number of batches a single public key can submit within the rate limit window
+pub const BATCH_RATE_LIMIT: usize = 10;
+pub const BATCH_RATE_LIMIT_WINDOW_SECONDS: u64 = 60;
+
+// How long a proof request for a batch in the open round is held while waiting for the round to
+// start collecting signatures, and how often it's retried in that time
+pub const PROOF_REQUEST_TIMEOUT_SECONDS: u64 = 15;
+pub const PROOF_REQUEST_RETRY_MILLISECONDS: u64 = 100;
+
+// Finalised blocks are posted to the webhook on a separate task, each attempt times out after
+// this long and failed attempts are retried with a fixed delay
+pub const WEBHOOK_TIMEOUT_SECONDS: u64 = 5;
+pub const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+pub const WEBHOOK_RETRY_MILLISECONDS: u64 = 500;
+
+// Finalised proofs kept for clients to re-fetch, when the server stores them. The oldest are
+// dropped first once there are too many
+pub const PROOF_STORE_MAX_PROOFS: usize = 10_000;
+pub const PROOF_STORE_RETENTION_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// Where named wallets are persisted unless they're given another directory
+pub const WALLET_STORAGE_DIR: &str = "wallet_data";
@@ -1,4 +1,4 @@
-use crate::types::transaction::TransactionBatch;
+use crate::types::{common::U8_32, transaction::TransactionBatch};
 use thiserror::Error;
 
 pub type CrateResult<T> = anyhow::Result<T>;
@@ -6,5 +6,132 @@ pub type CrateResult<T> = anyhow::Result<T>;
 #[derive(Debug, Error, PartialEq)]
 pub enum CrateError {
     #[error("TransactionBatch not in a transfer block, batch: {0:?}")]
-    BatchNotInATransferBlock(TransactionBatch),
+    BatchNotInATransferBlock(Box<TransactionBatch>),
+
+    #[error(
+        "Balance proof is missing the batch {public_key} sent in the block with root {root:?}"
+    )]
+    MissingBalanceProofLink { public_key: String, root: U8_32 },
+
+    #[error(
+        "{0} transfer blocks signed by this wallet have no proof, so its sends can't be counted"
+    )]
+    UnprovenSends(usize),
+
+    #[error(
+        "{0} sent more than the balance proof shows they had, a proof funding them is missing"
+    )]
+    UnfundedBalanceProofSender(String),
+
+    #[error("Malformed transaction proof: {0}")]
+    MalformedTransactionProof(String),
+
+    #[error("Transaction proof failed merkle verification")]
+    InvalidTransactionProof,
+
+    #[error("Transaction batch is missing the sender's signature")]
+    MissingBatchSignature,
+
+    #[error("Transaction batch signature is not valid for the sender")]
+    InvalidBatchSignature,
+
+    #[error("Receipt signature is not valid for the receiver and payment")]
+    InvalidReceiptSignature,
+
+    #[error("Auth challenge signature is not valid for the connection's public key")]
+    InvalidAuthSignature,
+
+    #[error("Transaction batch has more than one transaction to {0}")]
+    DuplicateBatchRecipient(String),
+
+    #[error("Transaction batch has a zero amount transaction to {0}")]
+    ZeroAmountTransaction(String),
+
+    #[error("Transaction batch has no transactions")]
+    EmptyBatch,
+
+    #[error("Transaction batch from {batch_from} has a transaction from {transaction_from}")]
+    ForeignBatchTransaction {
+        batch_from: String,
+        transaction_from: String,
+    },
+
+    #[error("Transaction to {0} has neither a salt nor a nonce")]
+    MissingTransactionSaltOrNonce(String),
+
+    #[error("Transaction to {0} has both a salt and a nonce")]
+    TransactionHasSaltAndNonce(String),
+
+    #[error("Round is still open, proofs are available once it starts collecting signatures")]
+    RoundNotReadyForProofs,
+
+    #[error("Round has no signatures, nothing to finalise")]
+    EmptyRound,
+
+    #[error("Round is full, batches go in the next one")]
+    AggregatorFull,
+
+    #[error("Unknown rollup backend {0}, expected fs or sqlite")]
+    UnknownRollupBackend(String),
+
+    #[error("Transfer block signers have {weight} stake, below the threshold of {threshold}")]
+    InsufficientStake { weight: u64, threshold: u64 },
+
+    #[error("Insufficient balance to withdraw {amount}, {available} is available")]
+    InsufficientWithdrawalBalance { amount: u64, available: u64 },
+
+    #[error(
+        "Rollup has withdraws of {withdraws} exceeding deposits of {deposits} for {public_key}"
+    )]
+    WithdrawsExceedDeposits {
+        public_key: String,
+        deposits: u64,
+        withdraws: u64,
+    },
+
+    // The rollup doesn't record when deposits and withdraws happened relative to transfer blocks
+    #[error(
+        "Balance at height {0} is unknown, the account's deposits or withdraws can't be placed \
+         before or after it"
+    )]
+    BalanceAtHeightUnknown(usize),
+
+    #[error("Batch sends {amount} but the sender only has {available}")]
+    UnfundedBatch { amount: u64, available: u64 },
+
+    #[error("Transfer block signer weights don't match their stake")]
+    MismatchedSignerWeights,
+
+    #[error("Transfer block lists signer {0} more than once")]
+    DuplicateSigner(String),
+
+    #[error("Too many batches submitted, try again later")]
+    RateLimited,
+
+    #[error("Client and server have no codec in common")]
+    NoCommonCodec,
+
+    #[error("Couldn't reconnect to the server after {0} attempts")]
+    ReconnectFailed(u32),
+
+    #[error("Connection missed {0} pongs in a row")]
+    MissedPongs(u32),
+
+    #[error("Wrong passphrase, the wallet's secret key can't be decrypted")]
+    WrongPassphrase,
+
+    #[error("Wallet is encrypted, a passphrase is required")]
+    PassphraseRequired,
+
+    // Transient, whatever failed is worth retrying once the rollup is back
+    #[error("Rollup state is unavailable: {0}")]
+    RollupUnavailable(String),
+}
+
+// Anywhere in the error's context chain, so callers can wrap it freely
+pub fn is_rollup_unavailable(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<CrateError>(),
+        Some(CrateError::RollupUnavailable(_))
+    )
 }
@@ -1,4 +1,5 @@
 pub mod aggregator;
+pub mod clock;
 pub mod constants;
 pub mod errors;
 pub mod rollup;
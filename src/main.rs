@@ -1,10 +1,11 @@
 #![allow(dead_code)]
 
 use errors::CrateResult;
-use tokio;
-use websocket::server::server::run_aggregator_server;
+use log::info;
+use websocket::server::server::{run_aggregator_server, RollupBackend};
 
 mod aggregator;
+mod clock;
 mod constants;
 mod errors;
 mod rollup;
@@ -16,24 +17,18 @@ mod websocket;
 async fn main() -> CrateResult<()> {
     env_logger::init();
 
-    let task = run_aggregator_server().await;
-
-    // loop {
-    //     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    //
-    //     let block_producer_thread_finished = task.is_finished();
-    //
-    //     if block_producer_thread_finished {
-    //         task.abort();
-    //
-    //         let result = task.await;
-    //
-    //         if let Err(e) = result {
-    //             eprintln!("Error in block producer thread: {:?}", e);
-    //         }
-    //         break;
-    //     }
-    // }
+    // e.g. `cargo run -- sqlite`, the json file backend is used by default
+    let rollup_backend = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => RollupBackend::default(),
+    };
+
+    let server = run_aggregator_server(rollup_backend).await?;
+
+    tokio::signal::ctrl_c().await?;
+    info!("Received shutdown signal");
+
+    server.shutdown().await?;
 
     Ok(())
 }
@@ -0,0 +1,161 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::CrateResult,
+    types::{
+        common::{TransferBlock, U8_32},
+        public_key::BlsPublicKeyWrapper,
+        signatures::BlsPublicKey,
+    },
+};
+
+// The first entry links to this
+pub const GENESIS_BLOCK_LOG_HASH: U8_32 = [0; 32];
+
+// One transfer block in an exported log, each entry commits to the one before it so the log can
+// be checked without access to the rollup, see verify_block_log
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLogEntry {
+    pub index: u64,
+    pub merkle_root: U8_32,
+    pub signers: Vec<BlsPublicKeyWrapper>,
+    pub prev_hash: U8_32,
+    pub hash: U8_32,
+}
+
+impl BlockLogEntry {
+    pub fn compute_hash(
+        prev_hash: &U8_32,
+        merkle_root: &U8_32,
+        signers: &[BlsPublicKeyWrapper],
+    ) -> U8_32 {
+        let mut hasher = Sha256::new();
+        hasher.update(prev_hash);
+        hasher.update(merkle_root);
+        for signer in signers {
+            hasher.update(Into::<BlsPublicKey>::into(*signer).to_string().as_bytes());
+        }
+
+        hasher.finalize().into()
+    }
+}
+
+pub fn block_log_from_transfer_blocks(transfer_blocks: &[TransferBlock]) -> Vec<BlockLogEntry> {
+    let mut prev_hash = GENESIS_BLOCK_LOG_HASH;
+
+    transfer_blocks
+        .iter()
+        .enumerate()
+        .map(|(index, transfer_block)| {
+            let signers = transfer_block.signature.public_keys();
+            let hash =
+                BlockLogEntry::compute_hash(&prev_hash, &transfer_block.merkle_root, &signers);
+            let entry = BlockLogEntry {
+                index: index as u64,
+                merkle_root: transfer_block.merkle_root,
+                signers,
+                prev_hash,
+                hash,
+            };
+            prev_hash = hash;
+
+            entry
+        })
+        .collect()
+}
+
+// Checks the indexes run from 0, every entry links to the previous one and every hash matches
+// its contents. Editing any entry breaks the chain from there on
+pub fn verify_block_log(entries: &[BlockLogEntry]) -> CrateResult<()> {
+    let mut prev_hash = GENESIS_BLOCK_LOG_HASH;
+
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.index != index as u64 {
+            return Err(anyhow!(
+                "Block log entry {} has index {}",
+                index,
+                entry.index
+            ));
+        }
+
+        if entry.prev_hash != prev_hash {
+            return Err(anyhow!(
+                "Block log entry {} doesn't link to the previous entry",
+                index
+            ));
+        }
+
+        if BlockLogEntry::compute_hash(&entry.prev_hash, &entry.merkle_root, &entry.signers)
+            != entry.hash
+        {
+            return Err(anyhow!("Block log entry {} hash doesn't match", index));
+        }
+
+        prev_hash = entry.hash;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        wallet::wallet::Wallet,
+    };
+
+    use super::{verify_block_log, BlockLogEntry};
+
+    #[tokio::test]
+    async fn test_exported_block_log_is_a_verifiable_chain() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut sender = Wallet::new(None);
+        let receiver = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+
+        for _ in 0..3 {
+            sender.sync_rollup_state(&rollup_state).await?;
+            sender.append_transaction_to_batch(receiver.public_key, 10)?;
+
+            let mut aggregator = Aggregator::new();
+            aggregator.add_batch(&sender.produce_batch()?)?;
+            aggregator.start_collecting_signatures()?;
+            let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&sender.public_key, &signature)?;
+            rollup_state
+                .add_transfer_block(aggregator.finalise()?)
+                .await?;
+        }
+
+        let log = rollup_state.export_block_log().await?;
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[0].signers, vec![sender.public_key.into()]);
+        for pair in log.windows(2) {
+            assert_eq!(pair[1].prev_hash, pair[0].hash);
+        }
+        verify_block_log(&log)?;
+
+        // Swapping in another root for the middle block breaks the chain
+        let mut tampered = log.clone();
+        tampered[1].merkle_root = [1; 32];
+        assert!(verify_block_log(&tampered).is_err());
+
+        // Recomputing its hash doesn't help, the next entry still links to the original
+        tampered[1].hash = BlockLogEntry::compute_hash(
+            &tampered[1].prev_hash,
+            &tampered[1].merkle_root,
+            &tampered[1].signers,
+        );
+        assert!(verify_block_log(&tampered).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+
+use crate::{
+    errors::CrateResult,
+    types::{
+        common::TransferBlock,
+        public_key::{AccountTotals, BlsPublicKeyWrapper},
+        signatures::BlsPublicKey,
+    },
+};
+
+use super::traits::RollupStateTrait;
+
+// An account whose total differs between the two states, a missing account counts as 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountTotalDiff {
+    pub public_key: BlsPublicKeyWrapper,
+    pub a: u64,
+    pub b: u64,
+}
+
+// Where two rollup states disagree, e.g. a provider and a trusted mirror. Transfer blocks are
+// compared as sets, so the same blocks in a different order aren't reported
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RollupDiff {
+    pub deposit_totals: Vec<AccountTotalDiff>,
+    pub withdraw_totals: Vec<AccountTotalDiff>,
+    pub transfer_blocks_only_in_a: Vec<TransferBlock>,
+    pub transfer_blocks_only_in_b: Vec<TransferBlock>,
+}
+
+impl RollupDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == RollupDiff::default()
+    }
+}
+
+pub async fn diff_rollup_states(
+    a: &(impl RollupStateTrait + Sync),
+    b: &(impl RollupStateTrait + Sync),
+) -> CrateResult<RollupDiff> {
+    let transfer_blocks_a = a.get_transfer_blocks().await?;
+    let transfer_blocks_b = b.get_transfer_blocks().await?;
+
+    Ok(RollupDiff {
+        deposit_totals: diff_account_totals(
+            &a.get_deposit_totals().await?,
+            &b.get_deposit_totals().await?,
+        ),
+        withdraw_totals: diff_account_totals(
+            &a.get_withdraw_totals().await?,
+            &b.get_withdraw_totals().await?,
+        ),
+        transfer_blocks_only_in_a: blocks_missing_from(&transfer_blocks_a, &transfer_blocks_b),
+        transfer_blocks_only_in_b: blocks_missing_from(&transfer_blocks_b, &transfer_blocks_a),
+    })
+}
+
+fn diff_account_totals(a: &AccountTotals, b: &AccountTotals) -> Vec<AccountTotalDiff> {
+    let public_keys = a.keys().chain(b.keys()).collect::<HashSet<_>>();
+
+    let mut diffs = public_keys
+        .into_iter()
+        .map(|public_key| AccountTotalDiff {
+            public_key: *public_key,
+            a: a.get(public_key).copied().unwrap_or(0),
+            b: b.get(public_key).copied().unwrap_or(0),
+        })
+        .filter(|diff| diff.a != diff.b)
+        .collect::<Vec<_>>();
+    // HashMap order isn't stable, sorted so the same divergence always gives the same diff
+    diffs.sort_by_key(|diff| Into::<BlsPublicKey>::into(diff.public_key).to_string());
+
+    diffs
+}
+
+fn blocks_missing_from(blocks: &[TransferBlock], other: &[TransferBlock]) -> Vec<TransferBlock> {
+    blocks
+        .iter()
+        .filter(|block| !other.contains(block))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        wallet::wallet::Wallet,
+    };
+
+    use super::{diff_rollup_states, AccountTotalDiff};
+
+    #[tokio::test]
+    async fn test_diff_rollup_states() -> CrateResult<()> {
+        let mut a = MockRollupMemory::new();
+        let mut sender = Wallet::new(None);
+        let other = Wallet::new(None);
+        a.add_deposit(&sender.public_key, 100).await?;
+        a.add_deposit(&other.public_key, 20).await?;
+        sender.sync_rollup_state(&a).await?;
+
+        sender.append_transaction_to_batch(other.public_key, 10)?;
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        a.add_transfer_block(aggregator.finalise()?).await?;
+
+        let mut b = a.clone();
+        assert!(diff_rollup_states(&a, &b).await?.is_empty());
+
+        // b misses a deposit and a transfer block, and has a withdraw a doesn't
+        b.deposit_totals.remove(&other.public_key.into());
+        let missing_block = b.transfer_blocks.pop().unwrap();
+        b.add_withdraw(&sender.public_key, 30).await?;
+
+        let diff = diff_rollup_states(&a, &b).await?;
+
+        assert_eq!(
+            diff.deposit_totals,
+            vec![AccountTotalDiff {
+                public_key: other.public_key.into(),
+                a: 20,
+                b: 0,
+            }]
+        );
+        assert_eq!(
+            diff.withdraw_totals,
+            vec![AccountTotalDiff {
+                public_key: sender.public_key.into(),
+                a: 0,
+                b: 30,
+            }]
+        );
+        assert_eq!(diff.transfer_blocks_only_in_a, vec![missing_block]);
+        assert!(diff.transfer_blocks_only_in_b.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+use crate::{
+    errors::{CrateError, CrateResult},
+    types::{
+        common::{TransferBlock, U8_32},
+        public_key::AccountTotals,
+        signatures::BlsPublicKey,
+    },
+};
+
+use super::traits::{MockRollupStateTrait, RollupStateTrait};
+
+// Wraps another rollup and fails every call with CrateError::RollupUnavailable while faults are
+// switched on, for testing how the wallet and client cope with the rollup going away for a bit.
+// Deposits and withdraws are left alone so tests can still change the state underneath. Clones
+// share the switch
+#[derive(Debug, Clone)]
+pub struct FaultyRollup<R> {
+    inner: R,
+    failing: Arc<AtomicBool>,
+}
+
+impl<R> FaultyRollup<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            failing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    fn check(&self) -> CrateResult<()> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(anyhow!(CrateError::RollupUnavailable(
+                "Injected fault".to_string()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R: RollupStateTrait + Send + Sync> RollupStateTrait for FaultyRollup<R> {
+    async fn add_transfer_block(&mut self, transfer_block: TransferBlock) -> CrateResult<()> {
+        self.check()?;
+        self.inner.add_transfer_block(transfer_block).await
+    }
+
+    async fn get_withdraw_totals(&self) -> CrateResult<AccountTotals> {
+        self.check()?;
+        self.inner.get_withdraw_totals().await
+    }
+
+    async fn get_deposit_totals(&self) -> CrateResult<AccountTotals> {
+        self.check()?;
+        self.inner.get_deposit_totals().await
+    }
+
+    async fn get_transfer_blocks(&self) -> CrateResult<Vec<TransferBlock>> {
+        self.check()?;
+        self.inner.get_transfer_blocks().await
+    }
+}
+
+#[async_trait]
+impl<R: MockRollupStateTrait + Send + Sync> MockRollupStateTrait for FaultyRollup<R> {
+    async fn add_deposit(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
+        self.inner.add_deposit(pubkey, amount).await
+    }
+
+    async fn add_deposits(&mut self, entries: &[(BlsPublicKey, u64)]) -> CrateResult<()> {
+        self.inner.add_deposits(entries).await
+    }
+
+    async fn add_deposit_with_id(
+        &mut self,
+        pubkey: &BlsPublicKey,
+        amount: u64,
+        deposit_id: U8_32,
+    ) -> CrateResult<bool> {
+        self.inner
+            .add_deposit_with_id(pubkey, amount, deposit_id)
+            .await
+    }
+
+    async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
+        self.inner.add_withdraw(pubkey, amount).await
+    }
+}
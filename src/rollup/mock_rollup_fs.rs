@@ -2,22 +2,36 @@ use anyhow::anyhow;
 use async_trait::async_trait;
 use fs2::FileExt;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_reader, to_writer};
-use std::fs::OpenOptions;
+use serde_json::{from_str, to_writer};
+use std::{
+    collections::HashSet,
+    fs::{rename, OpenOptions},
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    errors::CrateResult,
-    types::{common::TransferBlock, public_key::AccountTotals, signatures::BlsPublicKey},
+    errors::{CrateError, CrateResult},
+    types::{
+        common::{TransferBlock, U8_32},
+        public_key::AccountTotals,
+        signatures::BlsPublicKey,
+    },
 };
 
 use super::traits::{MockRollupStateTrait, RollupStateTrait};
 
+const ROLLUP_STATE_PATH: &str = "rollup_state.json";
+
 // This simply is just the struct that we will be writing to the file system
 #[derive(Debug, Serialize, Deserialize)]
 struct RollupState {
     withdraw_totals: AccountTotals,
     deposit_totals: AccountTotals,
     transfer_blocks: Vec<TransferBlock>,
+    // Defaulted so state files written before deposit ids existed still load
+    #[serde(default)]
+    applied_deposit_ids: HashSet<U8_32>,
 }
 
 impl RollupState {
@@ -26,56 +40,114 @@ impl RollupState {
             withdraw_totals: AccountTotals::new(),
             deposit_totals: AccountTotals::new(),
             transfer_blocks: vec![],
+            applied_deposit_ids: HashSet::new(),
         })
     }
+
+    fn add_deposits(&mut self, entries: &[(BlsPublicKey, u64)]) {
+        for (pubkey, amount) in entries {
+            self.deposit_totals
+                .entry(pubkey.into())
+                .and_modify(|e| *e += amount)
+                .or_insert(*amount);
+        }
+    }
 }
 
 // This is used for local demo's, so that we can persist the state
 //
-// The state inside this class is intentionally empty, this prevents any misuse where we modify the
-// memory
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub struct MockRollupFS {}
+// Only the path to the state is kept, this prevents any misuse where we modify the memory.
+// Instances at the same path share the state, separate servers or tests need their own path
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MockRollupFS {
+    path: PathBuf,
+}
 
 impl MockRollupFS {
     pub fn new() -> CrateResult<MockRollupFS> {
-        Ok(MockRollupFS {})
+        MockRollupFS::new_at(ROLLUP_STATE_PATH)
     }
 
-    fn read_state_from_fs() -> CrateResult<RollupState> {
-        let file = OpenOptions::new()
+    pub fn new_at(path: impl Into<PathBuf>) -> CrateResult<MockRollupFS> {
+        Ok(MockRollupFS { path: path.into() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read_state_from_fs(&self) -> CrateResult<RollupState> {
+        MockRollupFS::read_state_from_path(&self.path)
+    }
+
+    fn write_state_to_fs(&self, state: RollupState) -> CrateResult<()> {
+        MockRollupFS::write_state_to_path(&self.path, state)
+    }
+
+    // Failing to read the file is treated as the rollup being unavailable, e.g. another process
+    // holding it or a flaky disk, unlike a file that reads fine but doesn't parse
+    fn read_state_from_path(path: &Path) -> CrateResult<RollupState> {
+        let unavailable =
+            |e: std::io::Error| CrateError::RollupUnavailable(format!("{}: {}", path.display(), e));
+
+        let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("rollup_state.json")?;
+            .open(path)
+            .map_err(unavailable)?;
 
-        file.lock_exclusive()?;
+        file.lock_exclusive().map_err(unavailable)?;
 
-        let state: RollupState = match from_reader(&file) {
-            Ok(state) => state,
-            Err(e) => {
-                dbg!(e);
-                RollupState::new()?
-            }
-        };
+        let mut contents = String::new();
+        let read_result = file.read_to_string(&mut contents);
 
         file.unlock().expect("Unable to unlock file");
 
-        Ok(state)
+        read_result.map_err(unavailable)?;
+
+        // Only a missing or empty file is a fresh rollup, anything else that doesn't parse is
+        // corrupt and falling back to an empty state would wipe every deposit and transfer block
+        if contents.trim().is_empty() {
+            return RollupState::new();
+        }
+
+        from_str(&contents)
+            .map_err(|e| anyhow!("Rollup state file {} is corrupt: {}", path.display(), e))
     }
 
-    fn write_state_to_fs(state: RollupState) -> CrateResult<()> {
+    // Written to a temporary file which is then renamed over the state file, so being killed
+    // mid-write leaves the previous state intact rather than a truncated file
+    fn write_state_to_path(path: &Path, state: RollupState) -> CrateResult<()> {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
-            .truncate(true)
-            .open("rollup_state.json")?;
+            .truncate(false)
+            .open(path)?;
 
         file.lock_exclusive()?;
 
-        to_writer(&file, &state)?;
+        let result = MockRollupFS::write_temp_and_rename(path, &state);
 
         file.unlock()?;
+
+        result
+    }
+
+    fn write_temp_and_rename(path: &Path, state: &RollupState) -> CrateResult<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        to_writer(&temp_file, state)?;
+        temp_file.sync_all()?;
+
+        rename(&temp_path, path)?;
+
         Ok(())
     }
 }
@@ -83,34 +155,61 @@ impl MockRollupFS {
 #[async_trait]
 impl MockRollupStateTrait for MockRollupFS {
     async fn add_deposit(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
-        let mut state = MockRollupFS::read_state_from_fs()?;
+        let mut state = self.read_state_from_fs()?;
 
         state
             .deposit_totals
             .entry(pubkey.into())
             .and_modify(|e| *e += amount)
             .or_insert(amount);
-        MockRollupFS::write_state_to_fs(state)?;
+        self.write_state_to_fs(state)?;
 
         Ok(())
     }
 
-    async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
-        let deposit_amount = self.get_account_deposit_amount(&pubkey).await?;
-        let withdraw_amount = self.get_account_withdraw_amount(&pubkey).await?;
+    async fn add_deposits(&mut self, entries: &[(BlsPublicKey, u64)]) -> CrateResult<()> {
+        let mut state = self.read_state_from_fs()?;
+        state.add_deposits(entries);
+        self.write_state_to_fs(state)?;
+
+        Ok(())
+    }
+
+    async fn add_deposit_with_id(
+        &mut self,
+        pubkey: &BlsPublicKey,
+        amount: u64,
+        deposit_id: U8_32,
+    ) -> CrateResult<bool> {
+        let mut state = self.read_state_from_fs()?;
+
+        if !state.applied_deposit_ids.insert(deposit_id) {
+            return Ok(false);
+        }
+
+        state
+            .deposit_totals
+            .entry(pubkey.into())
+            .and_modify(|e| *e += amount)
+            .or_insert(amount);
+        self.write_state_to_fs(state)?;
 
-        if deposit_amount < withdraw_amount + amount {
+        Ok(true)
+    }
+
+    async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
+        if amount > self.get_account_balance(pubkey).await? {
             return Err(anyhow!("Insufficient funds"));
         }
 
-        let mut state = MockRollupFS::read_state_from_fs()?;
+        let mut state = self.read_state_from_fs()?;
         state
             .withdraw_totals
             .entry(pubkey.into())
             .and_modify(|e| *e += amount)
             .or_insert(amount);
 
-        MockRollupFS::write_state_to_fs(state)?;
+        self.write_state_to_fs(state)?;
 
         Ok(())
     }
@@ -118,28 +217,148 @@ impl MockRollupStateTrait for MockRollupFS {
 
 #[async_trait]
 impl RollupStateTrait for MockRollupFS {
-    async fn add_transfer_block(&mut self, transfer_block: TransferBlock) -> CrateResult<()> {
+    async fn add_transfer_block(&mut self, mut transfer_block: TransferBlock) -> CrateResult<()> {
         // Sync to FS
-        let mut state = MockRollupFS::read_state_from_fs()?;
+        let mut state = self.read_state_from_fs()?;
+        transfer_block.stamp(state.transfer_blocks.len() as u64);
         state.transfer_blocks.push(transfer_block);
-        MockRollupFS::write_state_to_fs(state)?;
+        self.write_state_to_fs(state)?;
 
         Ok(())
     }
 
     async fn get_withdraw_totals(&self) -> CrateResult<AccountTotals> {
         // Reload from FS
-        let state = MockRollupFS::read_state_from_fs()?;
+        let state = self.read_state_from_fs()?;
         Ok(state.withdraw_totals)
     }
 
     async fn get_deposit_totals(&self) -> CrateResult<AccountTotals> {
-        let state = MockRollupFS::read_state_from_fs()?;
+        let state = self.read_state_from_fs()?;
         Ok(state.deposit_totals)
     }
 
     async fn get_transfer_blocks(&self) -> CrateResult<Vec<TransferBlock>> {
-        let state = MockRollupFS::read_state_from_fs()?;
+        let state = self.read_state_from_fs()?;
         Ok(state.transfer_blocks)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{remove_file, write},
+        path::PathBuf,
+    };
+
+    use crate::{
+        errors::CrateResult,
+        rollup::traits::{MockRollupStateTrait, RollupStateTrait},
+        types::signatures::BlsSecretKey,
+    };
+
+    use super::{MockRollupFS, RollupState};
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("rollup_state_{}.json", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_state_round_trips_without_leaving_temp_file() -> CrateResult<()> {
+        let path = temp_path();
+        let mut state = RollupState::new()?;
+        state
+            .deposit_totals
+            .insert(BlsSecretKey::new().public_key().into(), 100);
+
+        MockRollupFS::write_state_to_path(&path, state)?;
+
+        let loaded_state = MockRollupFS::read_state_from_path(&path)?;
+        assert_eq!(loaded_state.deposit_totals.values().sum::<u64>(), 100);
+        assert!(!PathBuf::from(format!("{}.tmp", path.display())).exists());
+
+        remove_file(path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_deposits_are_written_together() -> CrateResult<()> {
+        let path = temp_path();
+        let public_keys = (0..50)
+            .map(|_| BlsSecretKey::new().public_key())
+            .collect::<Vec<_>>();
+        let entries = public_keys
+            .iter()
+            .enumerate()
+            .map(|(index, public_key)| (*public_key, index as u64 + 1))
+            .collect::<Vec<_>>();
+
+        let mut state = MockRollupFS::read_state_from_path(&path)?;
+        state.add_deposits(&entries);
+        state.add_deposits(&entries[..1]);
+        MockRollupFS::write_state_to_path(&path, state)?;
+
+        let loaded_state = MockRollupFS::read_state_from_path(&path)?;
+        assert_eq!(loaded_state.deposit_totals.len(), 50);
+        assert_eq!(
+            loaded_state.deposit_totals.get(&public_keys[0].into()),
+            Some(&2)
+        );
+        for (public_key, amount) in entries.iter().skip(1) {
+            assert_eq!(
+                loaded_state.deposit_totals.get(&public_key.into()),
+                Some(amount)
+            );
+        }
+
+        remove_file(path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_instances_at_different_paths_dont_share_state() -> CrateResult<()> {
+        let (first_path, second_path) = (temp_path(), temp_path());
+        let mut first = MockRollupFS::new_at(&first_path)?;
+        let mut second = MockRollupFS::new_at(&second_path)?;
+        let public_key = BlsSecretKey::new().public_key();
+
+        first.add_deposit(&public_key, 100).await?;
+        second.add_deposit(&public_key, 30).await?;
+        first.add_deposit(&public_key, 5).await?;
+
+        assert_eq!(first.get_account_deposit_amount(&public_key).await?, 105);
+        assert_eq!(second.get_account_deposit_amount(&public_key).await?, 30);
+
+        // Another instance at the same path sees the same state
+        let reopened = MockRollupFS::new_at(first.path())?;
+        assert_eq!(reopened.get_account_deposit_amount(&public_key).await?, 105);
+
+        remove_file(first_path)?;
+        remove_file(second_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_state_file_errors_instead_of_resetting() -> CrateResult<()> {
+        let path = temp_path();
+
+        // A write that was cut off part way through
+        write(&path, "{\"withdraw_totals\": {}, \"deposit_to")?;
+
+        let err = MockRollupFS::read_state_from_path(&path).unwrap_err();
+        assert!(err.to_string().contains("corrupt"));
+
+        // An empty file is still treated as a fresh rollup
+        write(&path, "")?;
+        assert!(MockRollupFS::read_state_from_path(&path)?
+            .transfer_blocks
+            .is_empty());
+
+        remove_file(path)?;
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -6,7 +6,11 @@ use tokio::sync::Mutex;
 
 use crate::{
     errors::CrateResult,
-    types::{common::TransferBlock, public_key::AccountTotals, signatures::BlsPublicKey},
+    types::{
+        common::{TransferBlock, U8_32},
+        public_key::AccountTotals,
+        signatures::BlsPublicKey,
+    },
 };
 
 use super::traits::{MockRollupStateTrait, RollupStateTrait};
@@ -17,6 +21,7 @@ pub struct MockRollupMemory {
     pub withdraw_totals: AccountTotals,
     pub deposit_totals: AccountTotals,
     pub transfer_blocks: Vec<TransferBlock>,
+    pub applied_deposit_ids: HashSet<U8_32>,
 }
 
 impl MockRollupMemory {
@@ -25,6 +30,7 @@ impl MockRollupMemory {
             withdraw_totals: AccountTotals::new(),
             deposit_totals: AccountTotals::new(),
             transfer_blocks: vec![],
+            applied_deposit_ids: HashSet::new(),
         }
     }
 }
@@ -40,12 +46,24 @@ impl MockRollupStateTrait for MockRollupMemory {
         Ok(())
     }
 
+    async fn add_deposit_with_id(
+        &mut self,
+        pubkey: &BlsPublicKey,
+        amount: u64,
+        deposit_id: U8_32,
+    ) -> CrateResult<bool> {
+        if !self.applied_deposit_ids.insert(deposit_id) {
+            return Ok(false);
+        }
+
+        self.add_deposit(pubkey, amount).await?;
+
+        Ok(true)
+    }
+
     // TODO: This also needs the balance proof of the user
     async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
-        let deposit_amount = self.get_account_deposit_amount(&pubkey).await?;
-        let withdraw_amount = self.get_account_withdraw_amount(&pubkey).await?;
-
-        if deposit_amount < withdraw_amount + amount {
+        if amount > self.get_account_balance(pubkey).await? {
             return Err(anyhow!("Insufficient funds"));
         }
 
@@ -64,6 +82,22 @@ impl MockRollupStateTrait for Arc<Mutex<MockRollupMemory>> {
         self.lock().await.add_deposit(pubkey, amount).await
     }
 
+    async fn add_deposits(&mut self, entries: &[(BlsPublicKey, u64)]) -> CrateResult<()> {
+        self.lock().await.add_deposits(entries).await
+    }
+
+    async fn add_deposit_with_id(
+        &mut self,
+        pubkey: &BlsPublicKey,
+        amount: u64,
+        deposit_id: U8_32,
+    ) -> CrateResult<bool> {
+        self.lock()
+            .await
+            .add_deposit_with_id(pubkey, amount, deposit_id)
+            .await
+    }
+
     async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
         self.lock().await.add_withdraw(pubkey, amount).await
     }
@@ -71,7 +105,8 @@ impl MockRollupStateTrait for Arc<Mutex<MockRollupMemory>> {
 
 #[async_trait]
 impl RollupStateTrait for MockRollupMemory {
-    async fn add_transfer_block(&mut self, transfer_block: TransferBlock) -> CrateResult<()> {
+    async fn add_transfer_block(&mut self, mut transfer_block: TransferBlock) -> CrateResult<()> {
+        transfer_block.stamp(self.transfer_blocks.len() as u64);
         self.transfer_blocks.push(transfer_block);
 
         Ok(())
@@ -106,3 +141,116 @@ impl RollupStateTrait for Arc<Mutex<MockRollupMemory>> {
         self.lock().await.get_transfer_blocks().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::traits::{MockRollupStateTrait, RollupStateTrait},
+        types::signatures::BlsSecretKey,
+        wallet::wallet::Wallet,
+    };
+
+    use super::MockRollupMemory;
+
+    #[tokio::test]
+    async fn test_deposit_with_the_same_id_is_only_counted_once() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let public_key = BlsSecretKey::new().public_key();
+
+        assert!(
+            rollup_state
+                .add_deposit_with_id(&public_key, 100, [1; 32])
+                .await?
+        );
+        // e.g. the deposit scanner processing the same L1 block again
+        assert!(
+            !rollup_state
+                .add_deposit_with_id(&public_key, 100, [1; 32])
+                .await?
+        );
+        assert_eq!(
+            rollup_state.get_account_deposit_amount(&public_key).await?,
+            100
+        );
+
+        rollup_state
+            .add_deposit_with_id(&public_key, 50, [2; 32])
+            .await?;
+        assert_eq!(
+            rollup_state.get_account_deposit_amount(&public_key).await?,
+            150
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deposits_to_many_accounts_in_one_call() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let entries = (0..50)
+            .map(|index| (BlsSecretKey::new().public_key(), index * 10 + 1))
+            .collect::<Vec<_>>();
+
+        rollup_state.add_deposits(&entries).await?;
+
+        assert_eq!(rollup_state.get_deposit_totals().await?.len(), 50);
+        for (public_key, amount) in entries.iter() {
+            assert_eq!(
+                rollup_state.get_account_deposit_amount(public_key).await?,
+                *amount
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_blocks_get_increasing_heights_and_are_found_by_root() -> CrateResult<()>
+    {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut sender = Wallet::new(None);
+        let receiver = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+
+        for _ in 0..3 {
+            sender.append_transaction_to_batch(receiver.public_key, 10)?;
+            let mut aggregator = Aggregator::new();
+            aggregator.add_batch(&sender.produce_batch()?)?;
+            aggregator.start_collecting_signatures()?;
+            let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&sender.public_key, &signature)?;
+            rollup_state
+                .add_transfer_block(aggregator.finalise()?)
+                .await?;
+        }
+
+        let transfer_blocks = rollup_state.get_transfer_blocks().await?;
+        assert_eq!(
+            transfer_blocks
+                .iter()
+                .map(|transfer_block| transfer_block.height)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            rollup_state
+                .get_transfer_block_by_root(&transfer_blocks[1].merkle_root)
+                .await?,
+            Some(transfer_blocks[1].clone())
+        );
+        assert_eq!(
+            rollup_state.get_transfer_block_by_root(&[0; 32]).await?,
+            None
+        );
+        for pair in transfer_blocks.windows(2) {
+            assert!(pair[0].timestamp > 0);
+            assert!(pair[1].timestamp >= pair[0].timestamp);
+        }
+
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+pub mod block_log;
+pub mod diff;
+pub mod faulty_rollup;
 pub mod mock_rollup_fs;
 pub mod mock_rollup_memory;
+pub mod sqlite_rollup;
 pub mod traits;
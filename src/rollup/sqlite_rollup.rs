@@ -0,0 +1,485 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::{
+    errors::{CrateError, CrateResult},
+    types::{
+        common::{TransferBlock, U8_32},
+        public_key::{AccountTotals, BlsPublicKeyWrapper},
+        signatures::BlsPublicKey,
+    },
+};
+
+use super::traits::{MockRollupStateTrait, RollupStateTrait};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS deposit_totals (
+        public_key TEXT PRIMARY KEY,
+        amount INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS withdraw_totals (
+        public_key TEXT PRIMARY KEY,
+        amount INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS applied_deposit_ids (
+        deposit_id BLOB PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS transfer_blocks (
+        height INTEGER PRIMARY KEY,
+        merkle_root BLOB NOT NULL,
+        block TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transfer_blocks_merkle_root ON transfer_blocks (merkle_root);
+    CREATE TABLE IF NOT EXISTS transfer_block_signers (
+        height INTEGER NOT NULL REFERENCES transfer_blocks (height),
+        public_key TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transfer_block_signers_public_key
+        ON transfer_block_signers (public_key);
+";
+
+// Same as MockRollupFS but each change is a single insert or update rather than rewriting the
+// whole state, and SQLite handles concurrent access. Clones share the connection
+#[derive(Clone)]
+pub struct SqliteRollupState {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteRollupState {
+    pub fn open(path: &str) -> CrateResult<SqliteRollupState> {
+        SqliteRollupState::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> CrateResult<SqliteRollupState> {
+        SqliteRollupState::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> CrateResult<SqliteRollupState> {
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteRollupState {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn connection(&self) -> CrateResult<std::sync::MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| anyhow!("Rollup database connection poisoned"))
+    }
+
+    fn account_totals(&self, table: &str) -> CrateResult<AccountTotals> {
+        let connection = self.connection()?;
+        let mut statement =
+            connection.prepare(&format!("SELECT public_key, amount FROM {}", table))?;
+
+        let mut totals = AccountTotals::new();
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let public_key: String = row.get(0)?;
+            let amount: i64 = row.get(1)?;
+            totals.insert(key_from_column(&public_key)?, amount.try_into()?);
+        }
+
+        Ok(totals)
+    }
+
+    fn account_total(&self, table: &str, pubkey: &BlsPublicKey) -> CrateResult<u64> {
+        account_total(&*self.connection()?, table, pubkey)
+    }
+
+    fn transfer_blocks_where(
+        &self,
+        condition: &str,
+        param: impl rusqlite::ToSql,
+    ) -> CrateResult<Vec<TransferBlock>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT block FROM transfer_blocks WHERE {} ORDER BY height",
+            condition
+        ))?;
+
+        let mut transfer_blocks = vec![];
+        let mut rows = statement.query(params![param])?;
+        while let Some(row) = rows.next()? {
+            let block: String = row.get(0)?;
+            transfer_blocks.push(serde_json::from_str(&block)?);
+        }
+
+        Ok(transfer_blocks)
+    }
+}
+
+// Keys are stored the same way they're serialized elsewhere
+fn key_column(pubkey: &BlsPublicKey) -> CrateResult<String> {
+    Ok(serde_json::to_string(&BlsPublicKeyWrapper::from(pubkey))?)
+}
+
+fn key_from_column(column: &str) -> CrateResult<BlsPublicKeyWrapper> {
+    Ok(serde_json::from_str(column)?)
+}
+
+fn account_total(connection: &Connection, table: &str, pubkey: &BlsPublicKey) -> CrateResult<u64> {
+    let amount: Option<i64> = connection
+        .query_row(
+            &format!("SELECT amount FROM {} WHERE public_key = ?1", table),
+            params![key_column(pubkey)?],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(amount.unwrap_or(0).try_into()?)
+}
+
+fn add_to_total(
+    connection: &Connection,
+    table: &str,
+    pubkey: &BlsPublicKey,
+    amount: u64,
+) -> CrateResult<()> {
+    let amount: i64 = amount.try_into()?;
+
+    connection.execute(
+        &format!(
+            "INSERT INTO {} (public_key, amount) VALUES (?1, ?2)
+             ON CONFLICT (public_key) DO UPDATE SET amount = amount + excluded.amount",
+            table
+        ),
+        params![key_column(pubkey)?, amount],
+    )?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl RollupStateTrait for SqliteRollupState {
+    async fn add_transfer_block(&mut self, mut transfer_block: TransferBlock) -> CrateResult<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        let height: i64 =
+            transaction.query_row("SELECT COUNT(*) FROM transfer_blocks", [], |row| row.get(0))?;
+        transfer_block.stamp(height.try_into()?);
+
+        transaction.execute(
+            "INSERT INTO transfer_blocks (height, merkle_root, block) VALUES (?1, ?2, ?3)",
+            params![
+                height,
+                transfer_block.merkle_root.to_vec(),
+                serde_json::to_string(&transfer_block)?
+            ],
+        )?;
+        for public_key in transfer_block.signature.public_keys() {
+            transaction.execute(
+                "INSERT INTO transfer_block_signers (height, public_key) VALUES (?1, ?2)",
+                params![height, key_column(&public_key.into())?],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_withdraw_totals(&self) -> CrateResult<AccountTotals> {
+        self.account_totals("withdraw_totals")
+    }
+
+    async fn get_account_withdraw_amount(&self, pubkey: &BlsPublicKey) -> CrateResult<u64> {
+        self.account_total("withdraw_totals", pubkey)
+    }
+
+    async fn get_deposit_totals(&self) -> CrateResult<AccountTotals> {
+        self.account_totals("deposit_totals")
+    }
+
+    async fn get_account_deposit_amount(&self, pubkey: &BlsPublicKey) -> CrateResult<u64> {
+        self.account_total("deposit_totals", pubkey)
+    }
+
+    async fn get_transfer_blocks(&self) -> CrateResult<Vec<TransferBlock>> {
+        self.transfer_blocks_where("1 = ?1", 1)
+    }
+
+    async fn get_account_transfer_blocks(
+        &self,
+        pubkey: &BlsPublicKey,
+    ) -> CrateResult<Vec<TransferBlock>> {
+        self.transfer_blocks_where(
+            "height IN (SELECT height FROM transfer_block_signers WHERE public_key = ?1)",
+            key_column(pubkey)?,
+        )
+    }
+
+    async fn get_transfer_block_by_root(
+        &self,
+        merkle_root: &U8_32,
+    ) -> CrateResult<Option<TransferBlock>> {
+        Ok(self
+            .transfer_blocks_where("merkle_root = ?1", merkle_root.to_vec())?
+            .into_iter()
+            .next())
+    }
+
+    async fn get_transfer_block_for_merkle_root_and_pubkey(
+        &self,
+        merkle_root: &U8_32,
+        pubkey: &BlsPublicKey,
+    ) -> CrateResult<Option<TransferBlock>> {
+        Ok(self
+            .transfer_blocks_where("merkle_root = ?1", merkle_root.to_vec())?
+            .into_iter()
+            .find(|transfer_block| transfer_block.contains_pubkey(pubkey)))
+    }
+}
+
+#[async_trait]
+impl MockRollupStateTrait for SqliteRollupState {
+    async fn add_deposit(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
+        add_to_total(&*self.connection()?, "deposit_totals", pubkey, amount)
+    }
+
+    async fn add_deposits(&mut self, entries: &[(BlsPublicKey, u64)]) -> CrateResult<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        for (pubkey, amount) in entries {
+            add_to_total(&transaction, "deposit_totals", pubkey, *amount)?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn add_deposit_with_id(
+        &mut self,
+        pubkey: &BlsPublicKey,
+        amount: u64,
+        deposit_id: U8_32,
+    ) -> CrateResult<bool> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        let inserted = transaction.execute(
+            "INSERT OR IGNORE INTO applied_deposit_ids (deposit_id) VALUES (?1)",
+            params![deposit_id.to_vec()],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+
+        add_to_total(&transaction, "deposit_totals", pubkey, amount)?;
+        transaction.commit()?;
+
+        Ok(true)
+    }
+
+    // The balance is checked in the same transaction as the withdraw is written, taking the write
+    // lock up front so another connection to the database can't withdraw in between
+    async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let deposits = account_total(&transaction, "deposit_totals", pubkey)?;
+        let withdraws = account_total(&transaction, "withdraw_totals", pubkey)?;
+        let balance =
+            deposits
+                .checked_sub(withdraws)
+                .ok_or(CrateError::WithdrawsExceedDeposits {
+                    public_key: pubkey.to_string(),
+                    deposits,
+                    withdraws,
+                })?;
+        if amount > balance {
+            return Err(anyhow!("Insufficient funds"));
+        }
+
+        add_to_total(&transaction, "withdraw_totals", pubkey, amount)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::traits::{MockRollupStateTrait, RollupStateTrait},
+        types::signatures::BlsSecretKey,
+        wallet::wallet::Wallet,
+    };
+
+    use super::SqliteRollupState;
+
+    #[tokio::test]
+    async fn test_deposit_with_the_same_id_is_only_counted_once() -> CrateResult<()> {
+        let mut rollup_state = SqliteRollupState::open_in_memory()?;
+        let public_key = BlsSecretKey::new().public_key();
+
+        assert!(
+            rollup_state
+                .add_deposit_with_id(&public_key, 100, [1; 32])
+                .await?
+        );
+        assert!(
+            !rollup_state
+                .add_deposit_with_id(&public_key, 100, [1; 32])
+                .await?
+        );
+        rollup_state
+            .add_deposit_with_id(&public_key, 50, [2; 32])
+            .await?;
+        rollup_state.add_deposit(&public_key, 25).await?;
+
+        assert_eq!(
+            rollup_state.get_account_deposit_amount(&public_key).await?,
+            175
+        );
+        assert_eq!(
+            rollup_state
+                .get_deposit_totals()
+                .await?
+                .get(&public_key.into()),
+            Some(&175)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_cant_exceed_deposits() -> CrateResult<()> {
+        let mut rollup_state = SqliteRollupState::open_in_memory()?;
+        let public_key = BlsSecretKey::new().public_key();
+        rollup_state.add_deposit(&public_key, 100).await?;
+
+        rollup_state.add_withdraw(&public_key, 60).await?;
+        assert!(rollup_state.add_withdraw(&public_key, 50).await.is_err());
+        rollup_state.add_withdraw(&public_key, 40).await?;
+
+        assert_eq!(
+            rollup_state
+                .get_account_withdraw_amount(&public_key)
+                .await?,
+            100
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_withdraws_from_separate_connections_cant_overdraw() -> CrateResult<()>
+    {
+        let storage_dir = tempfile::TempDir::new()?;
+        let path = storage_dir.path().join("rollup_state.sqlite");
+        let path = path.to_str().unwrap();
+        let public_key = BlsSecretKey::new().public_key();
+        SqliteRollupState::open(path)?
+            .add_deposit(&public_key, 100)
+            .await?;
+
+        let mut withdraws = vec![];
+        for _ in 0..8 {
+            let mut rollup_state = SqliteRollupState::open(path)?;
+            withdraws.push(tokio::spawn(async move {
+                rollup_state.add_withdraw(&public_key, 30).await.is_ok()
+            }));
+        }
+
+        let mut succeeded = 0;
+        for withdraw in withdraws {
+            if withdraw.await? {
+                succeeded += 1;
+            }
+        }
+
+        assert_eq!(succeeded, 3);
+        assert_eq!(
+            SqliteRollupState::open(path)?
+                .get_account_withdraw_amount(&public_key)
+                .await?,
+            90
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_blocks_are_found_by_signer_and_root() -> CrateResult<()> {
+        let mut rollup_state = SqliteRollupState::open_in_memory()?;
+        let mut senders = [Wallet::new(None), Wallet::new(None)];
+        let receiver = Wallet::new(None);
+        for account in senders.iter_mut() {
+            rollup_state.add_deposit(&account.public_key, 100).await?;
+            account.sync_rollup_state(&rollup_state).await?;
+        }
+
+        for index in [0, 1, 0] {
+            let account = &mut senders[index];
+            account.append_transaction_to_batch(receiver.public_key, 10)?;
+            let mut aggregator = Aggregator::new();
+            aggregator.add_batch(&account.produce_batch()?)?;
+            aggregator.start_collecting_signatures()?;
+            let proof = aggregator.generate_proof_for_pubkey(&account.public_key)?;
+            let signature = account.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&account.public_key, &signature)?;
+            rollup_state
+                .add_transfer_block(aggregator.finalise()?)
+                .await?;
+        }
+
+        let transfer_blocks = rollup_state.get_transfer_blocks().await?;
+        assert_eq!(
+            transfer_blocks
+                .iter()
+                .map(|transfer_block| transfer_block.height)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        let sender_blocks = rollup_state
+            .get_account_transfer_blocks(&senders[0].public_key)
+            .await?;
+        assert_eq!(
+            sender_blocks,
+            vec![transfer_blocks[0].clone(), transfer_blocks[2].clone()]
+        );
+
+        assert_eq!(
+            rollup_state
+                .get_transfer_block_for_merkle_root_and_pubkey(
+                    &transfer_blocks[1].merkle_root,
+                    &senders[1].public_key
+                )
+                .await?,
+            Some(transfer_blocks[1].clone())
+        );
+        assert_eq!(
+            rollup_state
+                .get_transfer_block_for_merkle_root_and_pubkey(
+                    &transfer_blocks[1].merkle_root,
+                    &senders[0].public_key
+                )
+                .await?,
+            None
+        );
+
+        assert_eq!(
+            rollup_state
+                .get_transfer_block_by_root(&transfer_blocks[2].merkle_root)
+                .await?,
+            Some(transfer_blocks[2].clone())
+        );
+
+        // The balance proof validates against the database like any other rollup
+        senders[0].sync_rollup_state(&rollup_state).await?;
+        assert_eq!(senders[0].balance, 80);
+
+        Ok(())
+    }
+}
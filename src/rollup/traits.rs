@@ -1,8 +1,14 @@
+use anyhow::anyhow;
 use async_trait::async_trait;
 
 use crate::{
-    errors::CrateResult,
-    types::{common::TransferBlock, public_key::AccountTotals, signatures::BlsPublicKey},
+    errors::{CrateError, CrateResult},
+    rollup::block_log::{block_log_from_transfer_blocks, BlockLogEntry},
+    types::{
+        common::{TransferBlock, U8_32},
+        public_key::AccountTotals,
+        signatures::BlsPublicKey,
+    },
 };
 
 #[async_trait]
@@ -23,6 +29,23 @@ pub trait RollupStateTrait {
         Ok(*deposit_totals.get(&pubkey.into()).unwrap_or(&0))
     }
 
+    // Deposits minus withdraws, what the account holds on the rollup before any transfers. A
+    // rollup shouldn't let withdraws exceed deposits, but if it did that's an error rather than
+    // an underflow
+    async fn get_account_balance(&self, pubkey: &BlsPublicKey) -> CrateResult<u64> {
+        let deposit_amount = self.get_account_deposit_amount(pubkey).await?;
+        let withdraw_amount = self.get_account_withdraw_amount(pubkey).await?;
+
+        deposit_amount.checked_sub(withdraw_amount).ok_or(
+            CrateError::WithdrawsExceedDeposits {
+                public_key: pubkey.to_string(),
+                deposits: deposit_amount,
+                withdraws: withdraw_amount,
+            }
+            .into(),
+        )
+    }
+
     async fn get_transfer_blocks(&self) -> CrateResult<Vec<TransferBlock>>;
 
     async fn get_account_transfer_blocks(
@@ -51,11 +74,63 @@ pub trait RollupStateTrait {
             })
             .cloned())
     }
+
+    // The block for a root whoever signed it, e.g. to check an arbitrary proof
+    async fn get_transfer_block_by_root(
+        &self,
+        merkle_root: &U8_32,
+    ) -> CrateResult<Option<TransferBlock>> {
+        let transfer_blocks = self.get_transfer_blocks().await?;
+        Ok(transfer_blocks
+            .into_iter()
+            .find(|transfer_block| transfer_block.merkle_root == *merkle_root))
+    }
+
+    // Number of transfer blocks from the one with this root to the tip, counting the block itself.
+    // The mock rollups count blocks added after it, so a transfer reaches a finality depth of n
+    // once n - 1 more blocks are added. A backend anchored to Bitcoin would report the
+    // confirmations of the anchoring transaction
+    async fn get_confirmations_for_block(&self, merkle_root: &U8_32) -> CrateResult<u64> {
+        let transfer_blocks = self.get_transfer_blocks().await?;
+        let position = transfer_blocks
+            .iter()
+            .position(|transfer_block| transfer_block.merkle_root == *merkle_root)
+            .ok_or(anyhow!("No transfer block found for merkle root"))?;
+
+        Ok((transfer_blocks.len() - position) as u64)
+    }
+
+    // Every transfer block as a hash chain, for explorers and auditors to check independently
+    async fn export_block_log(&self) -> CrateResult<Vec<BlockLogEntry>> {
+        let transfer_blocks = self.get_transfer_blocks().await?;
+
+        Ok(block_log_from_transfer_blocks(&transfer_blocks))
+    }
 }
 
 #[async_trait]
 pub trait MockRollupStateTrait: RollupStateTrait {
     async fn add_deposit(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()>;
 
+    // For seeding many accounts at once, backends that persist override this to apply them all in
+    // a single write
+    async fn add_deposits(&mut self, entries: &[(BlsPublicKey, u64)]) -> CrateResult<()> {
+        for (pubkey, amount) in entries {
+            self.add_deposit(pubkey, *amount).await?;
+        }
+
+        Ok(())
+    }
+
+    // Same as add_deposit, but a deposit_id that's already been applied is ignored so rescanning
+    // L1 can't count a deposit twice. A real rollup would use the L1 txid. Returns whether the
+    // deposit was applied
+    async fn add_deposit_with_id(
+        &mut self,
+        pubkey: &BlsPublicKey,
+        amount: u64,
+        deposit_id: U8_32,
+    ) -> CrateResult<bool>;
+
     async fn add_withdraw(&mut self, pubkey: &BlsPublicKey, amount: u64) -> CrateResult<()>;
 }
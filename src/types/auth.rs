@@ -0,0 +1,33 @@
+use crate::errors::{CrateError, CrateResult};
+
+use super::{
+    common::U8_32,
+    signatures::{BlsPublicKey, BlsSecretKey, BlsSignature},
+};
+
+// Prefixed so the response can never be mistaken for a signature over a root or a receipt
+const AUTH_DOMAIN: &[u8] = b"stateless-payments-auth";
+
+// What the client signs to prove it holds the secret key for the public key it connects with
+pub fn auth_challenge_message(nonce: &U8_32) -> Vec<u8> {
+    [AUTH_DOMAIN, nonce].concat()
+}
+
+pub fn sign_auth_challenge(secret_key: &BlsSecretKey, nonce: &U8_32) -> CrateResult<BlsSignature> {
+    Ok(secret_key.sign(
+        blsful::SignatureSchemes::MessageAugmentation,
+        &auth_challenge_message(nonce),
+    )?)
+}
+
+pub fn verify_auth_challenge(
+    public_key: &BlsPublicKey,
+    nonce: &U8_32,
+    signature: &BlsSignature,
+) -> CrateResult<()> {
+    signature
+        .verify(public_key, auth_challenge_message(nonce))
+        .map_err(|_| CrateError::InvalidAuthSignature)?;
+
+    Ok(())
+}
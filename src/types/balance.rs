@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use serde::{Deserialize, Serialize};
@@ -53,3 +53,53 @@ impl<'de> Deserialize<'de> for BalanceProofKey {
 }
 
 pub type BalanceProof = HashMap<BalanceProofKey, TransactionProof>;
+
+// The entries of a balance proof the receiver doesn't already hold, a receiver that has been paid
+// by the same sender before only needs what's changed since
+pub fn balance_proof_delta(
+    balance_proof: &BalanceProof,
+    known_keys: &HashSet<BalanceProofKey>,
+) -> BalanceProof {
+    balance_proof
+        .iter()
+        .filter(|(key, _)| !known_keys.contains(key))
+        .map(|(key, proof)| (key.clone(), proof.clone()))
+        .collect()
+}
+
+// The entries needed to back up an account's balance: every send and receive of the account, along
+// with the same for anyone who paid them (and so on), so every account included can be shown to
+// have had the funds it sent. Anything else is padding as far as the account is concerned
+pub fn balance_proof_for_account<'a>(
+    entries: impl Iterator<Item = (&'a BalanceProofKey, &'a TransactionProof)> + Clone,
+    public_key: BlsPublicKeyWrapper,
+) -> BalanceProof {
+    let mut accounts: HashSet<BlsPublicKeyWrapper> = HashSet::from([public_key]);
+    let mut balance_proof = BalanceProof::new();
+
+    loop {
+        let mut added_accounts = false;
+
+        for (key, proof) in entries.clone() {
+            if balance_proof.contains_key(key) {
+                continue;
+            }
+
+            let involves_account = accounts.contains(&key.public_key)
+                || proof
+                    .batch
+                    .transactions
+                    .iter()
+                    .any(|transaction| accounts.contains(&transaction.to.into()));
+
+            if involves_account {
+                added_accounts |= accounts.insert(key.public_key);
+                balance_proof.insert(key.clone(), proof.clone());
+            }
+        }
+
+        if !added_accounts {
+            return balance_proof;
+        }
+    }
+}
@@ -1,26 +1,79 @@
+#[cfg(any(test, feature = "simulation"))]
+use std::cell::RefCell;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use blsful::BlsResult;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::errors::CrateResult;
+use crate::errors::{CrateError, CrateResult};
 
 use super::public_key::BlsPublicKeyWrapper;
 use super::signatures::{
-    BlsAggregateSignature, BlsAggregateSignatureWrapper, BlsPublicKey, BlsSignature,
+    BlsAggregateSignature, BlsAggregateSignatureWrapper, BlsPublicKey, BlsSecretKey, BlsSignature,
     BlsSignatureWrapper,
 };
 
 pub type U8_32 = [u8; 32];
 
+#[cfg(any(test, feature = "simulation"))]
+thread_local! {
+    // When seeded, salts and keys generated on this thread come from this RNG instead of
+    // entropy, so simulations and tests can be reproduced from the seed
+    static SEEDED_RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+// Pass None to go back to entropy. Only built for tests and the simulation feature, a release
+// build never derives keys from a seed
+#[cfg(any(test, feature = "simulation"))]
+pub fn seed_rng(seed: Option<u64>) {
+    SEEDED_RNG.with(|rng| *rng.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+#[cfg(any(test, feature = "simulation"))]
+fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    SEEDED_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => f(rng),
+        None => f(&mut StdRng::from_entropy()),
+    })
+}
+
+#[cfg(not(any(test, feature = "simulation")))]
+fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    f(&mut StdRng::from_entropy())
+}
+
 pub fn generate_salt() -> U8_32 {
-    StdRng::from_entropy().gen::<U8_32>()
+    with_rng(|rng| rng.gen::<U8_32>())
+}
+
+pub fn generate_secret_key() -> BlsSecretKey {
+    with_rng(|rng| BlsSecretKey::random(rng))
+}
+
+// Milliseconds since the unix epoch, how times are sent between the client and server
+pub fn unix_timestamp_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Eq)]
 pub enum TransferBlockSignature {
     Aggregated(BlsAggregateSignatureWrapper, Vec<BlsPublicKeyWrapper>),
     Individual(BlsSignatureWrapper, BlsPublicKeyWrapper),
+    // Same as Aggregated but with each signer's weight (their stake when the block was signed),
+    // in the same order as the public keys. A separate variant so existing blocks still parse
+    WeightedAggregated(
+        BlsAggregateSignatureWrapper,
+        Vec<BlsPublicKeyWrapper>,
+        Vec<u64>,
+    ),
 }
 
 impl TransferBlockSignature {
@@ -47,6 +100,38 @@ impl TransferBlockSignature {
             ))
         }
     }
+
+    // Signers missing from the stake map have no weight
+    pub fn new_weighted(
+        values: Vec<(BlsPublicKey, BlsSignature)>,
+        stakes: &HashMap<BlsPublicKeyWrapper, u64>,
+    ) -> CrateResult<Self> {
+        let signatures = values
+            .iter()
+            .map(|(_, sig)| *sig)
+            .collect::<Vec<BlsSignature>>();
+        let aggregate_signature = BlsAggregateSignature::from_signatures(signatures)?;
+        let public_keys: Vec<BlsPublicKeyWrapper> =
+            values.iter().map(|(pk, _)| (*pk).into()).collect();
+        let weights = public_keys
+            .iter()
+            .map(|pk| stakes.get(pk).copied().unwrap_or(0))
+            .collect();
+
+        Ok(TransferBlockSignature::WeightedAggregated(
+            aggregate_signature.into(),
+            public_keys,
+            weights,
+        ))
+    }
+
+    pub fn public_keys(&self) -> Vec<BlsPublicKeyWrapper> {
+        match self {
+            TransferBlockSignature::Aggregated(_, public_keys)
+            | TransferBlockSignature::WeightedAggregated(_, public_keys, _) => public_keys.clone(),
+            TransferBlockSignature::Individual(_, public_key) => vec![*public_key],
+        }
+    }
 }
 
 // Need to compare TransactionProofs with TransferBlocks to find which roots have been included
@@ -54,12 +139,33 @@ impl TransferBlockSignature {
 pub struct TransferBlock {
     pub signature: TransferBlockSignature,
     pub merkle_root: U8_32,
+    // Number of leaves in the tree, so receivers can cross-check the total_leaves in the proofs
+    // they're sent. Blocks from before this was recorded don't have it
+    #[serde(default)]
+    pub total_leaves: Option<usize>,
+    // Credited with the fees of the batches in the block. Without one the fees are burned
+    #[serde(default)]
+    pub fee_recipient: Option<BlsPublicKeyWrapper>,
+    // Position in the chain and when it was added (unix millis), both set by the rollup when the
+    // block is added. Not signed or part of the commitment, the rollup is trusted for ordering
+    // anyway. Blocks from before these were recorded have 0 for both
+    #[serde(default)]
+    pub height: u64,
+    #[serde(default)]
+    pub timestamp: u64,
 }
 
 impl TransferBlock {
+    // Called by the rollup as the block is added
+    pub fn stamp(&mut self, height: u64) {
+        self.height = height;
+        self.timestamp = unix_timestamp_millis(SystemTime::now());
+    }
+
     pub fn verify(&self) -> BlsResult<()> {
         match &self.signature {
-            TransferBlockSignature::Aggregated(sig, public_keys) => {
+            TransferBlockSignature::Aggregated(sig, public_keys)
+            | TransferBlockSignature::WeightedAggregated(sig, public_keys, _) => {
                 let verify_message = public_keys
                     .iter()
                     .map(|pk| (pk.clone().into(), self.merkle_root))
@@ -75,12 +181,353 @@ impl TransferBlock {
         }
     }
 
+    // Verifies the signature and that the signers' summed stake meets the threshold. Weights
+    // carried by the block have to match the stake map, otherwise a block could claim more weight
+    // than its signers actually have. The aggregate verify accepts the same key signing twice, so
+    // duplicates are rejected here or a signer's stake could be counted more than once
+    pub fn verify_with_stake(
+        &self,
+        stakes: &HashMap<BlsPublicKeyWrapper, u64>,
+        threshold: u64,
+    ) -> CrateResult<()> {
+        self.verify()?;
+
+        let public_keys = self.signature.public_keys();
+        let mut signers = HashSet::new();
+        if let Some(duplicate) = public_keys.iter().find(|pk| !signers.insert(**pk)) {
+            return Err(CrateError::DuplicateSigner(
+                Into::<BlsPublicKey>::into(*duplicate).to_string(),
+            )
+            .into());
+        }
+        let stake_weights = public_keys
+            .iter()
+            .map(|pk| stakes.get(pk).copied().unwrap_or(0))
+            .collect::<Vec<u64>>();
+
+        if let TransferBlockSignature::WeightedAggregated(_, _, weights) = &self.signature {
+            if *weights != stake_weights {
+                return Err(CrateError::MismatchedSignerWeights.into());
+            }
+        }
+
+        let weight = stake_weights
+            .iter()
+            .fold(0u64, |total, weight| total.saturating_add(*weight));
+        if weight < threshold {
+            return Err(CrateError::InsufficientStake { weight, threshold }.into());
+        }
+
+        Ok(())
+    }
+
+    // Compact hash of the block for anchoring on-chain (e.g. in an OP_RETURN), the full block is
+    // kept off-chain. Public keys are sorted so the order signatures were aggregated in doesn't
+    // change the commitment
+    pub fn commitment(&self) -> U8_32 {
+        let signature = match &self.signature {
+            TransferBlockSignature::Aggregated(sig, _)
+            | TransferBlockSignature::WeightedAggregated(sig, _, _) => sig.to_compressed(),
+            TransferBlockSignature::Individual(sig, _) => sig.to_compressed(),
+        };
+        // Weights are kept alongside their public key so they're sorted together
+        let weights = match &self.signature {
+            TransferBlockSignature::WeightedAggregated(_, _, weights) => {
+                weights.iter().map(|weight| Some(*weight)).collect()
+            }
+            _ => vec![None; self.signature.public_keys().len()],
+        };
+        let mut public_keys = self
+            .signature
+            .public_keys()
+            .into_iter()
+            .map(|public_key| public_key.to_compressed())
+            .zip(weights)
+            .collect::<Vec<([u8; 96], Option<u64>)>>();
+        public_keys.sort();
+
+        // Every optional field gets a presence tag so a missing field can't be confused with the
+        // bytes of the one after it
+        let mut hasher = Sha256::new();
+        hasher.update(self.merkle_root);
+        for (public_key, weight) in public_keys.iter() {
+            hasher.update(public_key);
+            update_optional(&mut hasher, weight.map(u64::to_be_bytes));
+        }
+        hasher.update(signature);
+        update_optional(
+            &mut hasher,
+            self.total_leaves
+                .map(|total_leaves| (total_leaves as u64).to_be_bytes()),
+        );
+        update_optional(
+            &mut hasher,
+            self.fee_recipient
+                .map(|fee_recipient| fee_recipient.to_compressed()),
+        );
+
+        hasher.finalize().into()
+    }
+
+    pub fn verify_commitment(&self, commitment: &U8_32) -> bool {
+        self.commitment() == *commitment
+    }
+
+    // A proof with the wrong total_leaves can still pass merkle verification for some trees, so
+    // it's checked against the block when the block records it
+    pub fn validate_total_leaves(&self, total_leaves: usize) -> Result<(), CrateError> {
+        match self.total_leaves {
+            Some(block_total_leaves) if block_total_leaves != total_leaves => {
+                Err(CrateError::MalformedTransactionProof(format!(
+                    "total_leaves is {} but the transfer block has {}",
+                    total_leaves, block_total_leaves
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn contains_pubkey(&self, public_key: &BlsPublicKey) -> bool {
-        match &self.signature {
-            TransferBlockSignature::Aggregated(_, public_keys) => {
-                public_keys.contains(&(*public_key).into())
+        self.signature.public_keys().contains(&(*public_key).into())
+    }
+}
+
+fn update_optional<T: AsRef<[u8]>>(hasher: &mut Sha256, value: Option<T>) {
+    match value {
+        Some(value) => {
+            hasher.update([1]);
+            hasher.update(value);
+        }
+        None => hasher.update([0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        errors::{CrateError, CrateResult},
+        types::{
+            public_key::BlsPublicKeyWrapper,
+            signatures::{BlsPublicKey, BlsSecretKey, BlsSignature},
+        },
+    };
+
+    use super::{
+        generate_salt, generate_secret_key, seed_rng, TransferBlock, TransferBlockSignature,
+    };
+
+    fn sign_root(
+        secret_keys: &[BlsSecretKey],
+        merkle_root: [u8; 32],
+    ) -> CrateResult<Vec<(BlsPublicKey, BlsSignature)>> {
+        let mut values = vec![];
+        for secret_key in secret_keys {
+            values.push((
+                secret_key.public_key(),
+                secret_key.sign(blsful::SignatureSchemes::MessageAugmentation, &merkle_root)?,
+            ));
+        }
+
+        Ok(values)
+    }
+
+    fn signed_block(
+        secret_keys: &[BlsSecretKey],
+        merkle_root: [u8; 32],
+    ) -> CrateResult<TransferBlock> {
+        Ok(TransferBlock {
+            signature: TransferBlockSignature::new(sign_root(secret_keys, merkle_root)?)?,
+            merkle_root,
+            total_leaves: None,
+            fee_recipient: None,
+            height: 0,
+            timestamp: 0,
+        })
+    }
+
+    fn weighted_block(
+        secret_keys: &[BlsSecretKey],
+        stakes: &HashMap<BlsPublicKeyWrapper, u64>,
+    ) -> CrateResult<TransferBlock> {
+        Ok(TransferBlock {
+            signature: TransferBlockSignature::new_weighted(
+                sign_root(secret_keys, [1; 32])?,
+                stakes,
+            )?,
+            merkle_root: [1; 32],
+            total_leaves: None,
+            fee_recipient: None,
+            height: 0,
+            timestamp: 0,
+        })
+    }
+
+    fn stakes_for(
+        secret_keys: &[BlsSecretKey],
+        stakes: &[u64],
+    ) -> HashMap<BlsPublicKeyWrapper, u64> {
+        secret_keys
+            .iter()
+            .zip(stakes)
+            .map(|(secret_key, stake)| (secret_key.public_key().into(), *stake))
+            .collect()
+    }
+
+    #[test]
+    fn test_commitment_is_independent_of_public_key_order() -> CrateResult<()> {
+        let mut secret_keys = (0..3).map(|_| BlsSecretKey::new()).collect::<Vec<_>>();
+        let block = signed_block(&secret_keys, [1; 32])?;
+
+        secret_keys.reverse();
+        let reversed_block = signed_block(&secret_keys, [1; 32])?;
+
+        assert_ne!(block, reversed_block);
+        assert_eq!(block.commitment(), reversed_block.commitment());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_commitment() -> CrateResult<()> {
+        let secret_keys = vec![BlsSecretKey::new()];
+        let block = signed_block(&secret_keys, [1; 32])?;
+        let commitment = block.commitment();
+
+        assert!(block.verify_commitment(&commitment));
+        assert!(!signed_block(&secret_keys, [2; 32])?.verify_commitment(&commitment));
+        assert!(!signed_block(&[BlsSecretKey::new()], [1; 32])?.verify_commitment(&commitment));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_commitment_covers_optional_fields() -> CrateResult<()> {
+        let secret_keys = vec![BlsSecretKey::new()];
+        let block = signed_block(&secret_keys, [1; 32])?;
+
+        let mut with_total_leaves = block.clone();
+        with_total_leaves.total_leaves = Some(0);
+        assert_ne!(block.commitment(), with_total_leaves.commitment());
+
+        let mut with_fee_recipient = block.clone();
+        with_fee_recipient.fee_recipient = Some(secret_keys[0].public_key().into());
+        assert_ne!(block.commitment(), with_fee_recipient.commitment());
+        assert_ne!(
+            with_total_leaves.commitment(),
+            with_fee_recipient.commitment()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_with_stake_above_threshold() -> CrateResult<()> {
+        let secret_keys = (0..3).map(|_| BlsSecretKey::new()).collect::<Vec<_>>();
+        let stakes = stakes_for(&secret_keys, &[50, 30, 20]);
+
+        // Only the first two sign, 80 of the 100 staked
+        let block = weighted_block(&secret_keys[..2], &stakes)?;
+
+        block.verify()?;
+        block.verify_with_stake(&stakes, 80)?;
+        block.verify_with_stake(&stakes, 67)?;
+
+        // The unweighted path is checked against the same stake map
+        signed_block(&secret_keys[..2], [1; 32])?.verify_with_stake(&stakes, 80)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_with_stake_below_threshold() -> CrateResult<()> {
+        let secret_keys = (0..3).map(|_| BlsSecretKey::new()).collect::<Vec<_>>();
+        let stakes = stakes_for(&secret_keys, &[50, 30, 20]);
+
+        let block = weighted_block(&secret_keys[1..], &stakes)?;
+
+        assert_eq!(
+            block
+                .verify_with_stake(&stakes, 67)
+                .unwrap_err()
+                .downcast::<CrateError>()?,
+            CrateError::InsufficientStake {
+                weight: 50,
+                threshold: 67
             }
-            TransferBlockSignature::Individual(_, pk) => *pk == (*public_key).into(),
+        );
+
+        // Signers without any stake don't count towards the threshold
+        let unstaked_block = weighted_block(&[BlsSecretKey::new(), BlsSecretKey::new()], &stakes)?;
+        assert!(unstaked_block.verify_with_stake(&stakes, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_with_stake_rejects_inflated_weights() -> CrateResult<()> {
+        let secret_keys = (0..2).map(|_| BlsSecretKey::new()).collect::<Vec<_>>();
+        let stakes = stakes_for(&secret_keys, &[10, 10]);
+
+        let mut block = weighted_block(&secret_keys, &stakes_for(&secret_keys, &[100, 100]))?;
+        // The signature is still valid, the weights just don't match the verifier's stakes
+        block.verify()?;
+        assert_eq!(
+            block
+                .verify_with_stake(&stakes, 20)
+                .unwrap_err()
+                .downcast::<CrateError>()?,
+            CrateError::MismatchedSignerWeights
+        );
+
+        // Weights are part of the commitment
+        let commitment = block.commitment();
+        block = weighted_block(&secret_keys, &stakes)?;
+        assert!(!block.verify_commitment(&commitment));
+        block.verify_with_stake(&stakes, 20)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_with_stake_rejects_duplicate_signers() -> CrateResult<()> {
+        let secret_keys = (0..2).map(|_| BlsSecretKey::new()).collect::<Vec<_>>();
+        let stakes = stakes_for(&secret_keys, &[50, 50]);
+
+        // The first signer listed twice would otherwise reach the threshold on their own
+        let duplicated = vec![secret_keys[0].clone(), secret_keys[0].clone()];
+        for block in [
+            weighted_block(&duplicated, &stakes)?,
+            signed_block(&duplicated, [1; 32])?,
+        ] {
+            assert_eq!(
+                block
+                    .verify_with_stake(&stakes, 100)
+                    .unwrap_err()
+                    .downcast::<CrateError>()?,
+                CrateError::DuplicateSigner(secret_keys[0].public_key().to_string())
+            );
         }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_rng_is_reproducible() {
+        let generate = || {
+            seed_rng(Some(42));
+            let values = (generate_salt(), generate_secret_key().public_key());
+            seed_rng(None);
+            values
+        };
+
+        let (salt, public_key) = generate();
+        let (reseeded_salt, reseeded_public_key) = generate();
+
+        assert_eq!(salt, reseeded_salt);
+        assert_eq!(public_key, reseeded_public_key);
+        // Back to entropy once the seed is cleared
+        assert_ne!(generate_salt(), generate_salt());
     }
 }
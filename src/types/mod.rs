@@ -1,5 +1,8 @@
+pub mod auth;
 pub mod balance;
 pub mod common;
+pub mod policy;
 pub mod public_key;
+pub mod receipt;
 pub mod signatures;
 pub mod transaction;
@@ -0,0 +1,116 @@
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+
+use crate::errors::CrateResult;
+
+use super::transaction::{SimpleTransaction, TransactionBatch};
+
+// Admission rules for transactions, consulted by the wallet when building a batch and by the
+// aggregator when accepting one. Deployments can swap in their own rules (minimum amounts,
+// allowlisted recipients, etc.)
+pub trait TransactionPolicy: Debug + Send + Sync {
+    fn validate(&self, transaction: &SimpleTransaction) -> CrateResult<()>;
+
+    // Override for rules that depend on the batch as a whole, e.g. a maximum batch value
+    fn validate_batch(&self, batch: &TransactionBatch) -> CrateResult<()> {
+        for transaction in batch.transactions.iter() {
+            self.validate(transaction)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Non-zero amounts and no sending to yourself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultPolicy;
+
+impl TransactionPolicy for DefaultPolicy {
+    fn validate(&self, transaction: &SimpleTransaction) -> CrateResult<()> {
+        if transaction.to == transaction.from {
+            return Err(anyhow!("Cannot send to self"));
+        }
+
+        if transaction.amount == 0 {
+            return Err(anyhow!("Amount must be greater than 0"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::{mock_rollup_memory::MockRollupMemory, traits::MockRollupStateTrait},
+        types::{
+            common::generate_salt,
+            signatures::BlsSecretKey,
+            transaction::{SimpleTransaction, TransactionBatch},
+        },
+        wallet::wallet::Wallet,
+    };
+
+    use super::{DefaultPolicy, TransactionPolicy};
+
+    #[derive(Debug)]
+    struct MinimumAmountPolicy(u64);
+
+    impl TransactionPolicy for MinimumAmountPolicy {
+        fn validate(&self, transaction: &SimpleTransaction) -> CrateResult<()> {
+            DefaultPolicy.validate(transaction)?;
+
+            if transaction.amount < self.0 {
+                return Err(anyhow!("Amount is below the minimum of {}", self.0));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_policy_rejects_small_transactions() -> CrateResult<()> {
+        let policy = Arc::new(MinimumAmountPolicy(50));
+        let receiver = BlsSecretKey::new().public_key();
+
+        let mut rollup_state = MockRollupMemory::new();
+        let mut wallet = Wallet::new(None);
+        wallet.set_transaction_policy(policy.clone());
+        rollup_state.add_deposit(&wallet.public_key, 100).await?;
+        wallet.sync_rollup_state(&rollup_state).await?;
+
+        assert!(wallet.append_transaction_to_batch(receiver, 10).is_err());
+        // Nothing was debited for the rejected transaction
+        assert_eq!(wallet.balance, 100);
+        wallet.append_transaction_to_batch(receiver, 50)?;
+
+        // The aggregator applies the policy to batches that didn't come from a wallet using it
+        let sender = BlsSecretKey::new();
+        let mut batch = TransactionBatch::new(sender.public_key());
+        batch.transactions.push(SimpleTransaction {
+            to: receiver,
+            from: sender.public_key(),
+            amount: 10,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        batch.sign(&sender)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.set_transaction_policy(policy);
+        assert!(aggregator.add_batch(&batch).is_err());
+
+        // The default policy accepts it
+        Aggregator::new().add_batch(&batch)?;
+
+        Ok(())
+    }
+}
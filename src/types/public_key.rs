@@ -19,6 +19,11 @@ impl<'de> Deserialize<'de> for BlsPublicKeyWrapper {
     where
         D: serde::Deserializer<'de>,
     {
+        // Binary formats hold the key itself rather than a string
+        if !deserializer.is_human_readable() {
+            return BlsPublicKey::deserialize(deserializer).map(BlsPublicKeyWrapper);
+        }
+
         let s = String::deserialize(deserializer)?;
         let formatted_string = format!("\"{}\"", s);
 
@@ -28,6 +33,12 @@ impl<'de> Deserialize<'de> for BlsPublicKeyWrapper {
     }
 }
 
+impl BlsPublicKeyWrapper {
+    pub fn to_compressed(self) -> [u8; 96] {
+        self.0 .0.to_compressed()
+    }
+}
+
 impl PartialEq for BlsPublicKeyWrapper {
     fn eq(&self, other: &Self) -> bool {
         // Implement equality as needed for PublicKey
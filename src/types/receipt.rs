@@ -0,0 +1,134 @@
+use crate::errors::{CrateError, CrateResult};
+
+use super::{
+    common::U8_32,
+    public_key::BlsPublicKeyWrapper,
+    signatures::{BlsPublicKey, BlsSignature},
+};
+
+// Prefixed so a receipt can never be mistaken for the receiver's signature over a bare root
+const RECEIPT_DOMAIN: &[u8] = b"stateless-payments-receipt";
+
+// What the receiver signs to acknowledge a payment of amount from sender in the block with this
+// root. The sender is included so one receipt can't be claimed by anyone else who paid the same
+// amount in that block
+pub fn receipt_message(root: &U8_32, sender: &BlsPublicKey, amount: u64) -> Vec<u8> {
+    [
+        RECEIPT_DOMAIN,
+        root,
+        &BlsPublicKeyWrapper::from(sender).to_compressed(),
+        &amount.to_be_bytes(),
+    ]
+    .concat()
+}
+
+// Checks a receipt from Wallet::sign_receipt, the sender keeps it as evidence the receiver
+// accepted the funds
+pub fn verify_receipt(
+    receiver: &BlsPublicKey,
+    sender: &BlsPublicKey,
+    root: &U8_32,
+    amount: u64,
+    signature: &BlsSignature,
+) -> CrateResult<()> {
+    signature
+        .verify(receiver, receipt_message(root, sender, amount))
+        .map_err(|_| CrateError::InvalidReceiptSignature)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::{CrateError, CrateResult},
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        wallet::wallet::Wallet,
+    };
+
+    use super::verify_receipt;
+
+    #[tokio::test]
+    async fn test_receipt_round_trips() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut sender = Wallet::new(None);
+        let mut receiver = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(receiver.public_key, 40)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        // Nothing to acknowledge until the payment has been received
+        assert!(receiver
+            .sign_receipt(proof.root, sender.public_key, 40)
+            .is_err());
+
+        receiver
+            .add_receiving_transaction(&proof, &sender.balance_proof, &rollup_state)
+            .await?;
+        assert!(receiver
+            .sign_receipt(proof.root, sender.public_key, 50)
+            .is_err());
+        assert!(receiver
+            .sign_receipt(proof.root, Wallet::new(None).public_key, 40)
+            .is_err());
+
+        let receipt = receiver.sign_receipt(proof.root, sender.public_key, 40)?;
+        verify_receipt(
+            &receiver.public_key,
+            &sender.public_key,
+            &proof.root,
+            40,
+            &receipt,
+        )?;
+
+        let invalid = |result: CrateResult<()>| {
+            result.unwrap_err().downcast_ref::<CrateError>()
+                == Some(&CrateError::InvalidReceiptSignature)
+        };
+        assert!(invalid(verify_receipt(
+            &receiver.public_key,
+            &sender.public_key,
+            &proof.root,
+            41,
+            &receipt
+        )));
+        assert!(invalid(verify_receipt(
+            &receiver.public_key,
+            &sender.public_key,
+            &[0; 32],
+            40,
+            &receipt
+        )));
+        assert!(invalid(verify_receipt(
+            &sender.public_key,
+            &sender.public_key,
+            &proof.root,
+            40,
+            &receipt
+        )));
+        // Someone else who paid the receiver in the same block can't pass it off as theirs
+        assert!(invalid(verify_receipt(
+            &receiver.public_key,
+            &Wallet::new(None).public_key,
+            &proof.root,
+            40,
+            &receipt
+        )));
+
+        Ok(())
+    }
+}
@@ -18,6 +18,12 @@ impl<'de> Deserialize<'de> for BlsAggregateSignatureWrapper {
     where
         D: serde::Deserializer<'de>,
     {
+        // Binary formats hold the signature itself rather than a string
+        if !deserializer.is_human_readable() {
+            return BlsAggregateSignature::deserialize(deserializer)
+                .map(BlsAggregateSignatureWrapper);
+        }
+
         // Deserialize the map
         #[allow(non_snake_case)]
         #[derive(Deserialize)]
@@ -38,6 +44,17 @@ impl<'de> Deserialize<'de> for BlsAggregateSignatureWrapper {
     }
 }
 
+impl BlsAggregateSignatureWrapper {
+    // The compressed curve point, so hashing a signature doesn't depend on a serialisation format
+    pub fn to_compressed(self) -> [u8; 48] {
+        match &self.0 {
+            BlsAggregateSignature::Basic(sig)
+            | BlsAggregateSignature::MessageAugmentation(sig)
+            | BlsAggregateSignature::ProofOfPossession(sig) => sig.to_compressed(),
+        }
+    }
+}
+
 impl Into<BlsAggregateSignature> for BlsAggregateSignatureWrapper {
     fn into(self) -> BlsAggregateSignature {
         self.0
@@ -50,6 +67,12 @@ impl From<BlsAggregateSignature> for BlsAggregateSignatureWrapper {
     }
 }
 
+impl BlsSignatureWrapper {
+    pub fn to_compressed(self) -> [u8; 48] {
+        self.0.as_raw_value().to_compressed()
+    }
+}
+
 impl Into<BlsSignature> for BlsSignatureWrapper {
     fn into(self) -> BlsSignature {
         self.0
@@ -67,6 +90,11 @@ impl<'de> Deserialize<'de> for BlsSignatureWrapper {
     where
         D: serde::Deserializer<'de>,
     {
+        // Binary formats hold the signature itself rather than a string
+        if !deserializer.is_human_readable() {
+            return BlsSignature::deserialize(deserializer).map(BlsSignatureWrapper);
+        }
+
         // Deserialize the map
         #[allow(non_snake_case)]
         #[derive(Deserialize)]
@@ -105,6 +133,10 @@ impl<'de> Deserialize<'de> for BlsSecretKeyWrapper {
     where
         D: serde::Deserializer<'de>,
     {
+        if !deserializer.is_human_readable() {
+            return BlsSecretKey::deserialize(deserializer).map(BlsSecretKeyWrapper);
+        }
+
         let s = String::deserialize(deserializer)?;
         let formatted_string = format!("\"{}\"", s);
 
@@ -1,20 +1,70 @@
-use rs_merkle::MerkleProof;
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rs_merkle::{utils::indices::proof_indices_by_layers, MerkleProof};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
     aggregator::Sha256Algorithm,
+    errors::{CrateError, CrateResult},
     types::{common::U8_32, public_key::BlsPublicKeyWrapper},
 };
 
-use super::signatures::BlsPublicKey;
+use super::signatures::{BlsPublicKey, BlsSecretKey, BlsSignature, BlsSignatureWrapper};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+// Prefixed so a batch signature is never valid as a signature over a root
+const BATCH_SIGNATURE_DOMAIN: &[u8] = b"stateless-payments-batch";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SimpleTransaction {
     pub to: BlsPublicKey,
     pub from: BlsPublicKey,
     pub amount: u64,
-    pub salt: U8_32,
+    // Makes the tx_hash unique, either a random salt or the sender's nonce. Whichever is unset is
+    // left out of the serialized transaction, so salted transactions hash the same as they did
+    // before nonces existed
+    pub salt: Option<U8_32>,
+    pub nonce: Option<u64>,
+    // Set by the sender so the receiver can match the payment up, e.g. to an invoice. Left out
+    // when unset like the salt and nonce, and committed to by tx_hash through the serialized
+    // transaction
+    pub reference: Option<u64>,
+}
+
+impl Serialize for SimpleTransaction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Binary formats read fields by position, so nothing can be left out of them
+        let skip_salt = serializer.is_human_readable() && self.salt.is_none();
+        let skip_nonce = serializer.is_human_readable() && self.nonce.is_none();
+        let skip_reference = serializer.is_human_readable() && self.reference.is_none();
+
+        let num_fields =
+            3 + usize::from(!skip_salt) + usize::from(!skip_nonce) + usize::from(!skip_reference);
+        let mut state = serializer.serialize_struct("SimpleTransaction", num_fields)?;
+        state.serialize_field("to", &self.to)?;
+        state.serialize_field("from", &self.from)?;
+        state.serialize_field("amount", &self.amount)?;
+        if skip_salt {
+            state.skip_field("salt")?;
+        } else {
+            state.serialize_field("salt", &self.salt)?;
+        }
+        if skip_nonce {
+            state.skip_field("nonce")?;
+        } else {
+            state.serialize_field("nonce", &self.nonce)?;
+        }
+        if skip_reference {
+            state.skip_field("reference")?;
+        } else {
+            state.serialize_field("reference", &self.reference)?;
+        }
+
+        state.end()
+    }
 }
 
 impl<'de> Deserialize<'de> for SimpleTransaction {
@@ -27,7 +77,12 @@ impl<'de> Deserialize<'de> for SimpleTransaction {
             to: BlsPublicKeyWrapper,
             from: BlsPublicKeyWrapper,
             amount: u64,
-            salt: U8_32,
+            #[serde(default)]
+            salt: Option<U8_32>,
+            #[serde(default)]
+            nonce: Option<u64>,
+            #[serde(default)]
+            reference: Option<u64>,
         }
 
         let SimpleTransactionWrapper {
@@ -35,6 +90,8 @@ impl<'de> Deserialize<'de> for SimpleTransaction {
             from,
             amount,
             salt,
+            nonce,
+            reference,
         } = SimpleTransactionWrapper::deserialize(deserializer)?;
 
         Ok(SimpleTransaction {
@@ -42,6 +99,8 @@ impl<'de> Deserialize<'de> for SimpleTransaction {
             from: from.into(),
             amount,
             salt,
+            nonce,
+            reference,
         })
     }
 }
@@ -56,12 +115,17 @@ impl Into<U8_32> for SimpleTransaction {
 }
 
 impl SimpleTransaction {
-    // Calculate the hash of the transaction including the salt
+    // Calculate the hash of the transaction including the salt or nonce
     pub fn tx_hash(&self) -> U8_32 {
         let txhash: U8_32 = self.clone().into();
         let mut hasher = Sha256::new();
         hasher.update(&txhash);
-        hasher.update(self.salt);
+        if let Some(salt) = self.salt {
+            hasher.update(salt);
+        }
+        if let Some(nonce) = self.nonce {
+            hasher.update(nonce.to_be_bytes());
+        }
 
         hasher.finalize().into()
     }
@@ -71,6 +135,11 @@ impl SimpleTransaction {
 pub struct TransactionBatch {
     pub from: BlsPublicKey,
     pub transactions: Vec<SimpleTransaction>,
+    // The sender's signature over the batch's tx_hash, binds authorship to the batch itself
+    // rather than just the merkle root it ends up in
+    pub signature: Option<BlsSignature>,
+    // Paid to the aggregator's fee recipient on top of the transactions, see TransferBlock
+    pub fee: u64,
 }
 
 impl<'de> Deserialize<'de> for TransactionBatch {
@@ -82,14 +151,24 @@ impl<'de> Deserialize<'de> for TransactionBatch {
         struct TransactionBatchWrapper {
             from: BlsPublicKeyWrapper,
             transactions: Vec<SimpleTransaction>,
+            #[serde(default)]
+            signature: Option<BlsSignatureWrapper>,
+            #[serde(default)]
+            fee: u64,
         }
 
-        let TransactionBatchWrapper { from, transactions } =
-            TransactionBatchWrapper::deserialize(deserializer)?;
+        let TransactionBatchWrapper {
+            from,
+            transactions,
+            signature,
+            fee,
+        } = TransactionBatchWrapper::deserialize(deserializer)?;
 
         Ok(TransactionBatch {
             from: from.into(),
             transactions,
+            signature: signature.map(Into::into),
+            fee,
         })
     }
 }
@@ -99,17 +178,120 @@ impl TransactionBatch {
         TransactionBatch {
             from,
             transactions: Vec::new(),
+            signature: None,
+            fee: 0,
         }
     }
 
+    // A zero fee isn't hashed, so batches from before fees existed keep their hash
     pub fn tx_hash(&self) -> U8_32 {
         let mut hasher = Sha256::new();
         for tx in &self.transactions {
             hasher.update(&tx.tx_hash());
         }
+        if self.fee > 0 {
+            hasher.update(self.fee.to_be_bytes());
+        }
 
         hasher.finalize().into()
     }
+
+    // Everything the sender is debited for the batch
+    pub fn total_amount(&self) -> u64 {
+        self.transactions
+            .iter()
+            .fold(self.fee, |total, transaction| {
+                total.saturating_add(transaction.amount)
+            })
+    }
+
+    // Everything the batch pays the key, a batch can pay the same recipient more than once
+    pub fn total_to(&self, public_key: &BlsPublicKey) -> u64 {
+        self.transactions
+            .iter()
+            .filter(|transaction| transaction.to == *public_key)
+            .fold(0, |total, transaction| {
+                total.saturating_add(transaction.amount)
+            })
+    }
+
+    // Zero amount transactions are always rejected, a batch that didn't come from a wallet could
+    // still have one. Each transaction needs exactly one of a salt or nonce, without either two
+    // identical transactions would share a tx_hash. Every transaction has to be from the batch's
+    // sender, the signature only covers spending their own funds, and an empty batch is rejected
+    // as anyone could add one and two with no fee would hash to the same leaf. Multiple
+    // transactions to the same recipient are valid but are usually a mistake, callers can choose
+    // whether to treat them as an error
+    pub fn validate(&self, allow_duplicate_recipients: bool) -> Result<(), CrateError> {
+        if self.transactions.is_empty() {
+            return Err(CrateError::EmptyBatch);
+        }
+
+        for tx in &self.transactions {
+            if tx.from != self.from {
+                return Err(CrateError::ForeignBatchTransaction {
+                    batch_from: self.from.to_string(),
+                    transaction_from: tx.from.to_string(),
+                });
+            }
+
+            if tx.amount == 0 {
+                return Err(CrateError::ZeroAmountTransaction(tx.to.to_string()));
+            }
+
+            match (tx.salt, tx.nonce) {
+                (None, None) => {
+                    return Err(CrateError::MissingTransactionSaltOrNonce(tx.to.to_string()))
+                }
+                (Some(_), Some(_)) => {
+                    return Err(CrateError::TransactionHasSaltAndNonce(tx.to.to_string()))
+                }
+                _ => {}
+            }
+        }
+
+        if allow_duplicate_recipients {
+            return Ok(());
+        }
+
+        let mut recipients = HashSet::new();
+        for tx in &self.transactions {
+            if !recipients.insert(BlsPublicKeyWrapper::from(tx.to)) {
+                return Err(CrateError::DuplicateBatchRecipient(tx.to.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn sign(&mut self, secret_key: &BlsSecretKey) -> CrateResult<()> {
+        if secret_key.public_key() != self.from {
+            return Err(CrateError::InvalidBatchSignature.into());
+        }
+
+        self.signature = Some(secret_key.sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &self.signature_message(),
+        )?);
+
+        Ok(())
+    }
+
+    pub fn verify_signature(&self) -> CrateResult<()> {
+        let signature = self.signature.ok_or(CrateError::MissingBatchSignature)?;
+
+        signature
+            .verify(&self.from, self.signature_message())
+            .map_err(|_| CrateError::InvalidBatchSignature)?;
+
+        Ok(())
+    }
+
+    // A round with a single batch can have a root equal to the batch's tx_hash, so the batch is
+    // signed over a domain tagged message instead
+    pub fn signature_message(&self) -> Vec<u8> {
+        [BATCH_SIGNATURE_DOMAIN, &self.tx_hash()].concat()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -122,15 +304,524 @@ pub struct TransactionProof {
 }
 
 impl TransactionProof {
-    pub fn verify(&self) -> bool {
+    // Sanity checks the structure of the proof before doing the merkle verification, so a
+    // malformed proof is reported separately from one that has been tampered with
+    pub fn verify(&self) -> CrateResult<()> {
+        self.verify_inclusion()?;
+
+        self.batch.verify_signature()?;
+
+        Ok(())
+    }
+
+    // Just the structure and merkle checks, without the batch signature
+    pub fn verify_inclusion(&self) -> CrateResult<()> {
+        self.validate_structure()?;
+
         let merkle_proof: MerkleProof<Sha256Algorithm> =
             MerkleProof::new(self.proof_hashes.clone());
 
-        merkle_proof.verify(
+        if !merkle_proof.verify(
             self.root,
             &[self.index],
             &[self.batch.tx_hash()],
             self.total_leaves,
+        ) {
+            return Err(CrateError::InvalidTransactionProof.into());
+        }
+
+        Ok(())
+    }
+
+    fn validate_structure(&self) -> Result<(), CrateError> {
+        if self.total_leaves == 0 {
+            return Err(CrateError::MalformedTransactionProof(
+                "total_leaves is 0".to_string(),
+            ));
+        }
+
+        if self.index >= self.total_leaves {
+            return Err(CrateError::MalformedTransactionProof(format!(
+                "index {} is out of bounds for {} leaves",
+                self.index, self.total_leaves
+            )));
+        }
+
+        // A single leaf proof can never contain more hashes than the depth of the tree
+        let tree_depth = (self.total_leaves as f64).log2().ceil() as usize;
+        if self.proof_hashes.len() > tree_depth {
+            return Err(CrateError::MalformedTransactionProof(format!(
+                "{} proof hashes exceeds the tree depth of {}",
+                self.proof_hashes.len(),
+                tree_depth
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// Checks the inclusion of many proofs from the same transfer block with a single multi-leaf merkle
+// verification, rather than walking each proof up to the root separately. The hashes each proof
+// carries are pooled and the ones a multi-proof needs are picked out of them. Batch signatures
+// aren't checked
+pub fn verify_proofs_for_root(root: &U8_32, proofs: &[&TransactionProof]) -> bool {
+    let Some(total_leaves) = proofs.first().map(|proof| proof.total_leaves) else {
+        return true;
+    };
+
+    let mut leaves: BTreeMap<usize, U8_32> = BTreeMap::new();
+    // Hashes of nodes in the tree keyed by (layer, index)
+    let mut known_hashes: HashMap<(usize, usize), U8_32> = HashMap::new();
+
+    for proof in proofs {
+        if proof.root != *root
+            || proof.total_leaves != total_leaves
+            || proof.validate_structure().is_err()
+        {
+            return false;
+        }
+
+        let leaf = proof.batch.tx_hash();
+        if *leaves.entry(proof.index).or_insert(leaf) != leaf {
+            return false;
+        }
+
+        let positions = proof_positions(&[proof.index], total_leaves);
+        if positions.len() != proof.proof_hashes.len() {
+            return false;
+        }
+
+        for (position, hash) in positions.into_iter().zip(&proof.proof_hashes) {
+            // Proofs from the same tree must agree on every node they share
+            if *known_hashes.entry(position).or_insert(*hash) != *hash {
+                return false;
+            }
+        }
+    }
+
+    let indices: Vec<usize> = leaves.keys().copied().collect();
+    let mut proof_hashes = vec![];
+    for position in proof_positions(&indices, total_leaves) {
+        match known_hashes.get(&position) {
+            Some(hash) => proof_hashes.push(*hash),
+            None => return false,
+        }
+    }
+
+    MerkleProof::<Sha256Algorithm>::new(proof_hashes).verify(
+        *root,
+        &indices,
+        &leaves.into_values().collect::<Vec<U8_32>>(),
+        total_leaves,
+    )
+}
+
+// The (layer, index) of each hash in a proof for the given leaves, in the order rs_merkle
+// expects them
+fn proof_positions(sorted_leaf_indices: &[usize], total_leaves: usize) -> Vec<(usize, usize)> {
+    proof_indices_by_layers(sorted_leaf_indices, total_leaves)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(layer, indices)| indices.into_iter().map(move |index| (layer, index)))
+        .collect()
+}
+
+// A leaf committed to by a root along with its merkle proof
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NeighbourLeaf {
+    pub leaf: U8_32,
+    pub index: usize,
+    pub proof_hashes: Vec<U8_32>,
+}
+
+impl NeighbourLeaf {
+    fn verify(&self, root: &U8_32, total_leaves: usize) -> bool {
+        MerkleProof::<Sha256Algorithm>::new(self.proof_hashes.clone()).verify(
+            *root,
+            &[self.index],
+            &[self.leaf],
+            total_leaves,
         )
     }
 }
+
+// Proves a batch hash isn't in a transfer block. Leaves are sorted, so it's enough to show the
+// leaves either side of where it would be are adjacent. At the edges of the tree only one
+// neighbour exists
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NonInclusionProof {
+    pub tx_hash: U8_32,
+    pub total_leaves: usize,
+    pub left: Option<NeighbourLeaf>,
+    pub right: Option<NeighbourLeaf>,
+}
+
+impl NonInclusionProof {
+    pub fn verify(&self, root: &U8_32) -> bool {
+        if let Some(left) = &self.left {
+            if left.leaf >= self.tx_hash || !left.verify(root, self.total_leaves) {
+                return false;
+            }
+        }
+
+        if let Some(right) = &self.right {
+            if right.leaf <= self.tx_hash || !right.verify(root, self.total_leaves) {
+                return false;
+            }
+        }
+
+        match (&self.left, &self.right) {
+            (Some(left), Some(right)) => right.index == left.index + 1,
+            (None, Some(right)) => right.index == 0,
+            (Some(left), None) => left.index + 1 == self.total_leaves,
+            (None, None) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use sha2::{Digest, Sha256};
+
+    use crate::{
+        aggregator::Aggregator,
+        errors::{CrateError, CrateResult},
+        types::{
+            common::U8_32,
+            signatures::{BlsPublicKey, BlsSecretKey},
+            transaction::TransactionProof,
+        },
+    };
+
+    use super::{verify_proofs_for_root, SimpleTransaction, TransactionBatch};
+
+    // Proofs for every batch in a round, in the order the senders were created
+    fn setup_proofs(num_batches: usize) -> CrateResult<Vec<TransactionProof>> {
+        let mut aggregator = Aggregator::new();
+        let receiver = BlsSecretKey::new().public_key();
+        let mut senders = vec![];
+
+        for _ in 0..num_batches {
+            let secret_key = BlsSecretKey::new();
+            let from = secret_key.public_key();
+            let mut batch = TransactionBatch::new(from);
+            batch.transactions.push(SimpleTransaction {
+                to: receiver,
+                from,
+                amount: 100,
+                salt: Some([0; 32]),
+                nonce: None,
+                reference: None,
+            });
+            batch.sign(&secret_key)?;
+
+            aggregator.add_batch(&batch)?;
+            senders.push(from);
+        }
+
+        aggregator.start_collecting_signatures()?;
+
+        senders
+            .iter()
+            .map(|sender| aggregator.generate_proof_for_pubkey(sender))
+            .collect()
+    }
+
+    fn setup_proof(num_batches: usize) -> CrateResult<TransactionProof> {
+        Ok(setup_proofs(num_batches)?.remove(0))
+    }
+
+    #[test]
+    fn test_verify_succeeds_for_valid_proof() -> CrateResult<()> {
+        let proof = setup_proof(5)?;
+
+        proof.verify()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_rejects_out_of_bounds_index() -> CrateResult<()> {
+        let mut proof = setup_proof(5)?;
+        proof.index = proof.total_leaves;
+
+        let err = proof.verify().unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<CrateError>(),
+            Some(CrateError::MalformedTransactionProof(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_rejects_zero_total_leaves() -> CrateResult<()> {
+        let mut proof = setup_proof(1)?;
+        proof.total_leaves = 0;
+
+        let err = proof.verify().unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<CrateError>(),
+            Some(CrateError::MalformedTransactionProof(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_rejects_too_many_proof_hashes() -> CrateResult<()> {
+        let mut proof = setup_proof(4)?;
+        proof.proof_hashes.push([0; 32]);
+
+        let err = proof.verify().unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<CrateError>(),
+            Some(CrateError::MalformedTransactionProof(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_batch() -> CrateResult<()> {
+        let mut proof = setup_proof(5)?;
+        proof.batch.transactions[0].amount = 1000;
+
+        let err = proof.verify().unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::InvalidTransactionProof)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_signature_rejects_missing_and_forged() -> CrateResult<()> {
+        let secret_key = BlsSecretKey::new();
+        let forger = BlsSecretKey::new();
+        let mut batch = TransactionBatch::new(secret_key.public_key());
+        batch.transactions.push(SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from: secret_key.public_key(),
+            amount: 100,
+            salt: Some([0; 32]),
+            nonce: None,
+            reference: None,
+        });
+
+        let err = Aggregator::new().add_batch(&batch).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::MissingBatchSignature)
+        );
+
+        // Only the sender can sign their batch
+        assert!(batch.sign(&forger).is_err());
+
+        batch.signature = Some(forger.sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &batch.tx_hash(),
+        )?);
+        let err = Aggregator::new().add_batch(&batch).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::InvalidBatchSignature)
+        );
+
+        batch.sign(&secret_key)?;
+        Aggregator::new().add_batch(&batch)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_salted_tx_hash_is_unchanged_by_nonce_support() {
+        let from = BlsSecretKey::new().public_key();
+        let to = BlsSecretKey::new().public_key();
+        let salt = [7; 32];
+        let transaction = SimpleTransaction {
+            to,
+            from,
+            amount: 100,
+            salt: Some(salt),
+            nonce: None,
+            reference: None,
+        };
+
+        // How a transaction was hashed when the salt was required
+        #[derive(Serialize)]
+        struct LegacyTransaction {
+            to: BlsPublicKey,
+            from: BlsPublicKey,
+            amount: u64,
+            salt: U8_32,
+        }
+        let legacy_json = serde_json::to_vec(&LegacyTransaction {
+            to,
+            from,
+            amount: 100,
+            salt,
+        })
+        .unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(Sha256::digest(&legacy_json));
+        hasher.update(salt);
+        let legacy_hash: U8_32 = hasher.finalize().into();
+
+        assert_eq!(serde_json::to_vec(&transaction).unwrap(), legacy_json);
+        assert_eq!(transaction.tx_hash(), legacy_hash);
+    }
+
+    #[test]
+    fn test_nonce_tx_hash_is_deterministic() {
+        let from = BlsSecretKey::new().public_key();
+        let transaction = SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from,
+            amount: 100,
+            salt: None,
+            nonce: Some(0),
+            reference: None,
+        };
+        let next_transaction = SimpleTransaction {
+            nonce: Some(1),
+            reference: None,
+            ..transaction.clone()
+        };
+
+        assert_eq!(transaction.tx_hash(), transaction.clone().tx_hash());
+        assert_ne!(transaction.tx_hash(), next_transaction.tx_hash());
+    }
+
+    #[test]
+    fn test_validate_flags_duplicate_recipients() {
+        let from = BlsSecretKey::new().public_key();
+        let receiver = BlsSecretKey::new().public_key();
+        let mut batch = TransactionBatch::new(from);
+        for _ in 0..2 {
+            batch.transactions.push(SimpleTransaction {
+                to: receiver,
+                from,
+                amount: 100,
+                salt: Some([0; 32]),
+                nonce: None,
+                reference: None,
+            });
+        }
+
+        assert!(batch.validate(true).is_ok());
+        assert_eq!(
+            batch.validate(false),
+            Err(CrateError::DuplicateBatchRecipient(receiver.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_batch() {
+        let batch = TransactionBatch::new(BlsSecretKey::new().public_key());
+
+        assert_eq!(batch.validate(true), Err(CrateError::EmptyBatch));
+    }
+
+    #[test]
+    fn test_validate_rejects_transaction_from_another_sender() {
+        let from = BlsSecretKey::new().public_key();
+        let someone_else = BlsSecretKey::new().public_key();
+        let mut batch = TransactionBatch::new(from);
+        batch.transactions.push(SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from: someone_else,
+            amount: 100,
+            salt: Some([0; 32]),
+            nonce: None,
+            reference: None,
+        });
+
+        assert_eq!(
+            batch.validate(true),
+            Err(CrateError::ForeignBatchTransaction {
+                batch_from: from.to_string(),
+                transaction_from: someone_else.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_rejects_proof_with_forged_batch_signature() -> CrateResult<()> {
+        let mut proof = setup_proof(3)?;
+        proof.batch.signature = Some(BlsSecretKey::new().sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &proof.batch.tx_hash(),
+        )?);
+
+        let err = proof.verify().unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::InvalidBatchSignature)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_proofs_for_root() -> CrateResult<()> {
+        // An uneven number of leaves so some layers have an unpaired node
+        let proofs = setup_proofs(7)?;
+        let root = proofs[0].root;
+
+        let all = proofs.iter().collect::<Vec<_>>();
+        assert!(verify_proofs_for_root(&root, &all));
+
+        let subset = proofs.iter().step_by(3).collect::<Vec<_>>();
+        assert!(verify_proofs_for_root(&root, &subset));
+
+        let mut tampered = proofs[4].clone();
+        tampered.batch.transactions[0].amount = 1000;
+        let mut with_tampered = all.clone();
+        with_tampered[4] = &tampered;
+        assert!(!verify_proofs_for_root(&root, &with_tampered));
+
+        let other_round = setup_proof(7)?;
+        let mut with_other_round = all.clone();
+        with_other_round.push(&other_round);
+        assert!(!verify_proofs_for_root(&root, &with_other_round));
+
+        Ok(())
+    }
+
+    // Run with `cargo test bench_verify_proofs_for_root -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_verify_proofs_for_root() -> CrateResult<()> {
+        for num_batches in [64, 256, 1024] {
+            let proofs = setup_proofs(num_batches)?;
+            let root = proofs[0].root;
+            let proofs = proofs.iter().collect::<Vec<_>>();
+
+            let start = std::time::Instant::now();
+            for proof in proofs.iter() {
+                proof.verify_inclusion()?;
+            }
+            let individually = start.elapsed();
+
+            let start = std::time::Instant::now();
+            assert!(verify_proofs_for_root(&root, &proofs));
+            let together = start.elapsed();
+
+            println!(
+                "{} proofs: individually {:?}, together {:?}",
+                num_batches, individually, together
+            );
+        }
+
+        Ok(())
+    }
+}
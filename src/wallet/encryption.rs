@@ -0,0 +1,71 @@
+use anyhow::anyhow;
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{CrateError, CrateResult},
+    types::signatures::{BlsSecretKey, BlsSecretKeyWrapper},
+};
+
+// The secret key as it's written to an encrypted wallet file. The key is derived from the
+// passphrase with Argon2 and the salt, and the secret key is sealed with XChaCha20-Poly1305 so a
+// wrong passphrase (or a tampered file) fails to decrypt rather than producing another key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedSecretKey {
+    pub salt: [u8; 16],
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> CrateResult<[u8; 32]> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive key from passphrase: {}", e))?;
+
+    Ok(key)
+}
+
+pub fn encrypt_secret_key(
+    secret_key: &BlsSecretKey,
+    passphrase: &str,
+) -> CrateResult<EncryptedSecretKey> {
+    let mut salt = [0; 16];
+    let mut nonce = [0; 24];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let plaintext = serde_json::to_vec(&BlsSecretKeyWrapper(secret_key.clone()))?;
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?.into());
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| anyhow!("Failed to encrypt secret key"))?;
+
+    Ok(EncryptedSecretKey {
+        salt,
+        nonce,
+        ciphertext,
+    })
+}
+
+pub fn decrypt_secret_key(
+    encrypted: &EncryptedSecretKey,
+    passphrase: &str,
+) -> CrateResult<BlsSecretKey> {
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &encrypted.salt)?.into());
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(&encrypted.nonce),
+            encrypted.ciphertext.as_slice(),
+        )
+        .map_err(|_| CrateError::WrongPassphrase)?;
+
+    let secret_key: BlsSecretKeyWrapper = serde_json::from_slice(&plaintext)?;
+
+    Ok(secret_key.into())
+}
@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::{
+    balance::BalanceProof, common::U8_32, public_key::BlsPublicKeyWrapper,
+    signatures::BlsPublicKey, transaction::TransactionProof,
+};
+
+use super::wallet::Wallet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Sent,
+    Received,
+}
+
+// A single transaction the wallet sent or received, as seen from the wallet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub root: U8_32,
+    pub direction: TransferDirection,
+    // The receiver for sent transactions, the sender for received ones
+    pub counterparty: BlsPublicKeyWrapper,
+    pub amount: u64,
+    pub reference: Option<u64>,
+}
+
+impl HistoryEntry {
+    // The transactions in the proof's batch that involve this public key
+    pub fn from_proof(public_key: &BlsPublicKey, proof: &TransactionProof) -> Vec<HistoryEntry> {
+        proof
+            .batch
+            .transactions
+            .iter()
+            .filter_map(|transaction| {
+                let (direction, counterparty) = if transaction.from == *public_key {
+                    (TransferDirection::Sent, transaction.to)
+                } else if transaction.to == *public_key {
+                    (TransferDirection::Received, transaction.from)
+                } else {
+                    return None;
+                };
+
+                Some(HistoryEntry {
+                    root: proof.root,
+                    direction,
+                    counterparty: counterparty.into(),
+                    amount: transaction.amount,
+                    reference: transaction.reference,
+                })
+            })
+            .collect()
+    }
+}
+
+// For wallet files written before the history was kept, the order can't be recovered
+pub fn history_from_balance_proof(
+    public_key: &BlsPublicKey,
+    balance_proof: &BalanceProof,
+) -> Vec<HistoryEntry> {
+    balance_proof
+        .values()
+        .flat_map(|proof| HistoryEntry::from_proof(public_key, proof))
+        .collect()
+}
+
+impl Wallet {
+    // Lets a merchant find the payments made against e.g. an invoice
+    pub fn transactions_with_reference(&self, reference: u64) -> Vec<HistoryEntry> {
+        self.history()
+            .iter()
+            .filter(|entry| entry.reference == Some(reference))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        types::transaction::TransactionProof,
+        wallet::wallet::Wallet,
+    };
+
+    use super::{HistoryEntry, TransferDirection};
+
+    #[tokio::test]
+    async fn test_history_can_be_filtered_by_reference() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut customer = Wallet::new(None);
+        let mut merchant = Wallet::new(None);
+        rollup_state.add_deposit(&customer.public_key, 100).await?;
+        customer.sync_rollup_state(&rollup_state).await?;
+
+        customer.append_transaction_to_batch_with_reference(merchant.public_key, 30, Some(7))?;
+        let batch = customer.produce_batch()?;
+        // The reference is committed to by the hash
+        let mut unreferenced = batch.transactions[0].clone();
+        unreferenced.reference = None;
+        assert_ne!(unreferenced.tx_hash(), batch.transactions[0].tx_hash());
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&customer.public_key)?;
+        let signature = customer.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&customer.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        merchant
+            .add_receiving_transaction(&proof, &customer.balance_proof, &rollup_state)
+            .await?;
+
+        assert_eq!(
+            merchant.transactions_with_reference(7),
+            vec![HistoryEntry {
+                root: proof.root,
+                direction: TransferDirection::Received,
+                counterparty: customer.public_key.into(),
+                amount: 30,
+                reference: Some(7),
+            }]
+        );
+        assert!(merchant.transactions_with_reference(8).is_empty());
+        assert_eq!(
+            customer.transactions_with_reference(7)[0].direction,
+            TransferDirection::Sent
+        );
+
+        Ok(())
+    }
+
+    async fn send(
+        sender: &mut Wallet,
+        receiver: &mut Wallet,
+        amount: u64,
+        rollup_state: &mut MockRollupMemory,
+    ) -> CrateResult<TransactionProof> {
+        sender.append_transaction_to_batch(receiver.public_key, amount)?;
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        receiver
+            .add_receiving_transaction(&proof, &sender.balance_proof, rollup_state)
+            .await?;
+
+        Ok(proof)
+    }
+
+    #[tokio::test]
+    async fn test_history_records_sends_and_receives_in_order() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let mut rollup_state = MockRollupMemory::new();
+        let mut wallet = Wallet::with_storage_dir("alice", storage_dir.path())?;
+        let mut other = Wallet::new(None);
+        for account in [&mut wallet, &mut other] {
+            rollup_state.add_deposit(&account.public_key, 100).await?;
+            account.sync_rollup_state(&rollup_state).await?;
+        }
+
+        let sent = send(&mut wallet, &mut other, 30, &mut rollup_state).await?;
+        let received = send(&mut other, &mut wallet, 50, &mut rollup_state).await?;
+
+        // Receiving the same payment again doesn't record it twice
+        wallet
+            .add_receiving_transaction(&received, &other.balance_proof, &rollup_state)
+            .await?;
+
+        let expected = vec![
+            HistoryEntry {
+                root: sent.root,
+                direction: TransferDirection::Sent,
+                counterparty: other.public_key.into(),
+                amount: 30,
+                reference: None,
+            },
+            HistoryEntry {
+                root: received.root,
+                direction: TransferDirection::Received,
+                counterparty: other.public_key.into(),
+                amount: 50,
+                reference: None,
+            },
+        ];
+        assert_eq!(wallet.history(), expected);
+
+        let loaded_wallet = Wallet::with_storage_dir("alice", storage_dir.path())?;
+        assert_eq!(loaded_wallet.history(), expected);
+
+        Ok(())
+    }
+}
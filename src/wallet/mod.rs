@@ -1,2 +1,4 @@
-mod utils;
+pub mod encryption;
+pub mod history;
+pub mod utils;
 pub mod wallet;
@@ -5,7 +5,13 @@ use anyhow::anyhow;
 use crate::{
     errors::{CrateError, CrateResult},
     rollup::traits::RollupStateTrait,
-    types::{balance::BalanceProof, public_key::BlsPublicKeyWrapper},
+    types::{
+        balance::{BalanceProof, BalanceProofKey},
+        common::{TransferBlock, U8_32},
+        public_key::BlsPublicKeyWrapper,
+        signatures::BlsPublicKey,
+        transaction::{verify_proofs_for_root, TransactionProof},
+    },
 };
 
 pub fn merge_balance_proofs(
@@ -31,20 +37,134 @@ pub async fn calculate_balances_and_validate_balance_proof(
     rollup_state: &(impl RollupStateTrait + Sync),
     balance_proof: &BalanceProof,
 ) -> CrateResult<HashMap<BlsPublicKeyWrapper, u64>> {
+    calculate_balances_up_to_height(rollup_state, balance_proof, None).await
+}
+
+// What an account's balance was once the first `height` transfer blocks had been added, useful
+// for proving an account held an amount at a given block. Deposits and withdraws aren't ordered
+// against transfer blocks, so for a past height this only works out for an account that has
+// neither, anything else would mix in deposits and withdraws made after that height
+pub async fn balance_at_height(
+    rollup_state: &(impl RollupStateTrait + Sync),
+    public_key: &BlsPublicKey,
+    height: usize,
+    balance_proof: &BalanceProof,
+) -> CrateResult<u64> {
+    let is_past_height = height < rollup_state.get_transfer_blocks().await?.len();
+    if is_past_height
+        && (rollup_state.get_account_deposit_amount(public_key).await? > 0
+            || rollup_state.get_account_withdraw_amount(public_key).await? > 0)
+    {
+        return Err(CrateError::BalanceAtHeightUnknown(height).into());
+    }
+
+    let balances =
+        calculate_balances_up_to_height(rollup_state, balance_proof, Some(height)).await?;
+
+    if let Some(balance) = balances.get(&public_key.into()) {
+        return Ok(*balance);
+    }
+
+    rollup_state.get_account_balance(public_key).await
+}
+
+// Validates that every transaction is included in its merkle root, proofs from the same transfer
+// block are checked together. If a group fails the proofs are checked one at a time to report
+// which one is bad
+fn verify_balance_proof_inclusion(balance_proof: &BalanceProof) -> CrateResult<()> {
+    let mut proofs_by_root: HashMap<U8_32, Vec<&TransactionProof>> = HashMap::new();
+    for transaction_proof in balance_proof.values() {
+        proofs_by_root
+            .entry(transaction_proof.root)
+            .or_default()
+            .push(transaction_proof);
+    }
+
+    for (root, proofs) in proofs_by_root {
+        if proofs.len() > 1 && verify_proofs_for_root(&root, &proofs) {
+            continue;
+        }
+
+        for proof in proofs {
+            proof.verify_inclusion()?;
+        }
+    }
+
+    Ok(())
+}
+
+// A sender could leave a send out of the proof they forward to look richer than they are, so every
+// block a sender signed up to their latest counted send has to be in the proof. Applied to every
+// sender in the proof, which covers whoever paid them as well since their proofs are merged in
+fn verify_complete_send_history(
+    transfer_blocks: &[TransferBlock],
+    balance_proof: &BalanceProof,
+    latest_sends: &HashMap<BlsPublicKeyWrapper, usize>,
+) -> CrateResult<()> {
+    for (block_height, transfer_block) in transfer_blocks.iter().enumerate() {
+        for public_key in transfer_block.signature.public_keys() {
+            let Some(latest_send) = latest_sends.get(&public_key) else {
+                continue;
+            };
+
+            let key = BalanceProofKey {
+                root: transfer_block.merkle_root,
+                public_key,
+            };
+            if block_height <= *latest_send && !balance_proof.contains_key(&key) {
+                return Err(CrateError::MissingBalanceProofLink {
+                    public_key: Into::<BlsPublicKey>::into(public_key).to_string(),
+                    root: transfer_block.merkle_root,
+                }
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Roots of the blocks the key signed that the balance proof has no entry for. Without them the
+// key's sends can't be counted, so any balance worked out from the proof would be too high
+pub async fn unproven_sends(
+    rollup_state: &(impl RollupStateTrait + Sync),
+    public_key: &BlsPublicKey,
+    balance_proof: &BalanceProof,
+) -> CrateResult<Vec<U8_32>> {
+    Ok(rollup_state
+        .get_account_transfer_blocks(public_key)
+        .await?
+        .into_iter()
+        .map(|transfer_block| transfer_block.merkle_root)
+        .filter(|root| {
+            !balance_proof.contains_key(&BalanceProofKey {
+                root: *root,
+                public_key: public_key.into(),
+            })
+        })
+        .collect())
+}
+
+// Same as calculate_balances_and_validate_balance_proof, but when a height is given only the
+// transactions in the first `height` transfer blocks are counted
+async fn calculate_balances_up_to_height(
+    rollup_state: &(impl RollupStateTrait + Sync),
+    balance_proof: &BalanceProof,
+    height: Option<usize>,
+) -> CrateResult<HashMap<BlsPublicKeyWrapper, u64>> {
+    let transfer_blocks = rollup_state.get_transfer_blocks().await?;
     // Use i128 to avoid underflow, we don't check deposit, withdrawal and tx ordering. We just
     // ensure the balance is > 0 for accounts at the end
     let mut unchecked_balances: HashMap<BlsPublicKeyWrapper, i128> = HashMap::new();
+    // Height of the latest counted block each sender sent in
+    let mut latest_sends: HashMap<BlsPublicKeyWrapper, usize> = HashMap::new();
+
+    verify_balance_proof_inclusion(balance_proof)?;
 
     for transaction_proof in balance_proof.values() {
         let batch = &transaction_proof.batch;
 
-        // Validates that the transaction is included in the merkle root
-        if !transaction_proof.verify() {
-            return Err(anyhow!(format!(
-                "Invalid transaction proof for transaction: {:?}",
-                batch
-            )));
-        }
+        batch.verify_signature()?;
 
         // Ensures the merkle root and sender was included in a transfer block
         let transfer_block = rollup_state
@@ -53,10 +173,36 @@ pub async fn calculate_balances_and_validate_balance_proof(
                 &batch.from.into(),
             )
             .await?
-            .ok_or(CrateError::BatchNotInATransferBlock(batch.clone()))?;
+            .ok_or(CrateError::BatchNotInATransferBlock(Box::new(
+                batch.clone(),
+            )))?;
 
         // Validates the aggregated signature
         transfer_block.verify()?;
+        transfer_block.validate_total_leaves(transaction_proof.total_leaves)?;
+
+        let block_height = transfer_blocks
+            .iter()
+            .position(|block| *block == transfer_block)
+            .ok_or(anyhow!("Transfer block missing from the rollup's blocks"))?;
+
+        if height.is_some_and(|height| block_height >= height) {
+            continue;
+        }
+
+        latest_sends
+            .entry(batch.from.into())
+            .and_modify(|latest| *latest = (*latest).max(block_height))
+            .or_insert(block_height);
+
+        if batch.fee > 0 {
+            let fee: i128 = batch.fee.into();
+
+            *unchecked_balances.entry(batch.from.into()).or_insert(0) -= fee;
+            if let Some(fee_recipient) = transfer_block.fee_recipient {
+                *unchecked_balances.entry(fee_recipient).or_insert(0) += fee;
+            }
+        }
 
         for transaction in batch.transactions.iter() {
             // u64 can safely be converted to i128
@@ -74,6 +220,8 @@ pub async fn calculate_balances_and_validate_balance_proof(
         }
     }
 
+    verify_complete_send_history(&transfer_blocks, balance_proof, &latest_sends)?;
+
     let mut balances: HashMap<BlsPublicKeyWrapper, u64> = HashMap::new();
 
     for (public_key, amount) in unchecked_balances {
@@ -86,12 +234,245 @@ pub async fn calculate_balances_and_validate_balance_proof(
 
         let balance = amount + deposit_amount as i128 - withdraw_amount as i128;
 
-        let balance = balance
-            .try_into()
-            .map_err(|_| anyhow!(format!("Balance for {:?} is negative", public_key)))?;
+        // The sends are all there, so a proof of something that paid this account is missing
+        let balance = balance.try_into().map_err(|_| {
+            CrateError::UnfundedBalanceProofSender(
+                Into::<BlsPublicKey>::into(public_key).to_string(),
+            )
+        })?;
 
         balances.insert(public_key, balance);
     }
 
     Ok(balances)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        aggregator::Aggregator,
+        errors::{CrateError, CrateResult},
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        types::balance::BalanceProofKey,
+        wallet::wallet::Wallet,
+    };
+
+    use super::calculate_balances_and_validate_balance_proof;
+
+    async fn send_all(
+        sender: &mut Wallet,
+        receiver: &mut Wallet,
+        rollup_state: &mut MockRollupMemory,
+    ) -> CrateResult<()> {
+        let amount = sender.balance;
+
+        send(sender, receiver, amount, rollup_state).await
+    }
+
+    async fn send(
+        sender: &mut Wallet,
+        receiver: &mut Wallet,
+        amount: u64,
+        rollup_state: &mut MockRollupMemory,
+    ) -> CrateResult<()> {
+        let mut aggregator = Aggregator::new();
+
+        sender.append_transaction_to_batch(receiver.public_key, amount)?;
+        let batch = sender.produce_batch()?;
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        receiver
+            .add_receiving_transaction(&proof, &sender.balance_proof, rollup_state)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_balance_at_height_along_a_chain() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut wallets = (0..3).map(|_| Wallet::new(None)).collect::<Vec<_>>();
+
+        rollup_state
+            .add_deposit(&wallets[0].public_key, 100)
+            .await?;
+        wallets[0].sync_rollup_state(&rollup_state).await?;
+
+        let (first, rest) = wallets.split_at_mut(1);
+        send_all(&mut first[0], &mut rest[0], &mut rollup_state).await?;
+        let (second, third) = rest.split_at_mut(1);
+        send_all(&mut second[0], &mut third[0], &mut rollup_state).await?;
+
+        // The last receiver's proof covers the whole chain. The first wallet was funded by a
+        // deposit, which can't be placed against the blocks, so only its current balance is known
+        for (height, expected) in [
+            (0, [None, Some(0), Some(0)]),
+            (1, [None, Some(100), Some(0)]),
+            (2, [Some(0), Some(0), Some(100)]),
+        ] {
+            for (wallet, expected_balance) in wallets.iter().zip(expected) {
+                let balance = wallets[2]
+                    .balance_at_height(&wallet.public_key, height, &rollup_state)
+                    .await;
+
+                match expected_balance {
+                    Some(expected_balance) => {
+                        assert_eq!(balance?, expected_balance, "height {}", height)
+                    }
+                    None => assert_eq!(
+                        balance.unwrap_err().downcast_ref::<CrateError>(),
+                        Some(&CrateError::BalanceAtHeightUnknown(height)),
+                        "height {}",
+                        height
+                    ),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tampered_total_leaves_is_rejected() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut aggregator = Aggregator::new();
+        let receiver = Wallet::new(None);
+        let mut senders = (0..3).map(|_| Wallet::new(None)).collect::<Vec<_>>();
+
+        for sender in senders.iter_mut() {
+            rollup_state.add_deposit(&sender.public_key, 100).await?;
+            sender.sync_rollup_state(&rollup_state).await?;
+            sender.append_transaction_to_batch(receiver.public_key, 100)?;
+            aggregator.add_batch(&sender.produce_batch()?)?;
+        }
+
+        aggregator.start_collecting_signatures()?;
+
+        let mut first_leaf_proof = None;
+        for sender in senders.iter_mut() {
+            let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&sender.public_key, &signature)?;
+
+            if proof.index == 0 {
+                first_leaf_proof = Some(proof);
+            }
+        }
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        // With 3 leaves the last one is promoted a layer, so the first leaf's proof also verifies
+        // as if the tree had 4 leaves
+        let mut proof = first_leaf_proof.unwrap();
+        proof.total_leaves = 4;
+        proof.verify()?;
+
+        let balance_proof = HashMap::from([(
+            BalanceProofKey {
+                root: proof.root,
+                public_key: proof.batch.from.into(),
+            },
+            proof,
+        )]);
+        let err = calculate_balances_and_validate_balance_proof(&rollup_state, &balance_proof)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<CrateError>(),
+            Some(CrateError::MalformedTransactionProof(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proof_missing_an_earlier_hop_is_rejected() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut carol = Wallet::new(None);
+        let mut alice = Wallet::new(None);
+        let mut bob = Wallet::new(None);
+
+        rollup_state.add_deposit(&carol.public_key, 100).await?;
+        carol.sync_rollup_state(&rollup_state).await?;
+        send_all(&mut carol, &mut alice, &mut rollup_state).await?;
+        send_all(&mut alice, &mut bob, &mut rollup_state).await?;
+
+        let balances =
+            calculate_balances_and_validate_balance_proof(&rollup_state, &bob.balance_proof)
+                .await?;
+        assert_eq!(balances.get(&bob.public_key.into()), Some(&100));
+
+        // Without Carol's payment nothing shows where Alice's funds came from
+        let carol_root = rollup_state.get_transfer_blocks().await?[0].merkle_root;
+        let mut balance_proof = bob.balance_proof.clone();
+        balance_proof.remove(&BalanceProofKey {
+            root: carol_root,
+            public_key: carol.public_key.into(),
+        });
+
+        let err = calculate_balances_and_validate_balance_proof(&rollup_state, &balance_proof)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::UnfundedBalanceProofSender(
+                alice.public_key.to_string()
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proof_hiding_an_earlier_send_is_rejected() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut carol = Wallet::new(None);
+        let mut alice = Wallet::new(None);
+        let mut bob = Wallet::new(None);
+        let mut dave = Wallet::new(None);
+
+        for wallet in [&mut carol, &mut alice] {
+            rollup_state.add_deposit(&wallet.public_key, 100).await?;
+            wallet.sync_rollup_state(&rollup_state).await?;
+        }
+        send_all(&mut carol, &mut alice, &mut rollup_state).await?;
+        send(&mut alice, &mut dave, 100, &mut rollup_state).await?;
+        send(&mut alice, &mut bob, 100, &mut rollup_state).await?;
+
+        calculate_balances_and_validate_balance_proof(&rollup_state, &bob.balance_proof).await?;
+
+        // Alice's balance still adds up without her payment to Dave, but the block she signed for
+        // it is on the rollup
+        let dave_root = rollup_state.get_transfer_blocks().await?[1].merkle_root;
+        let mut balance_proof = bob.balance_proof.clone();
+        balance_proof.remove(&BalanceProofKey {
+            root: dave_root,
+            public_key: alice.public_key.into(),
+        });
+
+        let err = calculate_balances_and_validate_balance_proof(&rollup_state, &balance_proof)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::MissingBalanceProofLink {
+                public_key: alice.public_key.to_string(),
+                root: dave_root,
+            })
+        );
+
+        Ok(())
+    }
+}
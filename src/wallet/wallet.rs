@@ -1,86 +1,336 @@
 use std::{
-    collections::HashMap,
-    fs::{create_dir_all, OpenOptions},
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{create_dir_all, remove_file, rename, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::anyhow;
 use fs2::FileExt;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_reader, to_writer};
 
 use crate::{
-    errors::CrateResult,
-    rollup::traits::RollupStateTrait,
+    constants::WALLET_STORAGE_DIR,
+    errors::{CrateError, CrateResult},
+    rollup::traits::{MockRollupStateTrait, RollupStateTrait},
     types::{
-        balance::{BalanceProof, BalanceProofKey},
-        common::generate_salt,
+        auth::sign_auth_challenge,
+        balance::{balance_proof_for_account, BalanceProof, BalanceProofKey},
+        common::{generate_salt, generate_secret_key, U8_32},
+        policy::{DefaultPolicy, TransactionPolicy},
+        public_key::BlsPublicKeyWrapper,
+        receipt::receipt_message,
         signatures::{BlsPublicKey, BlsSecretKey, BlsSecretKeyWrapper, BlsSignature},
         transaction::{SimpleTransaction, TransactionBatch, TransactionProof},
     },
 };
 
-use super::utils::{calculate_balances_and_validate_balance_proof, merge_balance_proofs};
+use super::encryption::{decrypt_secret_key, encrypt_secret_key, EncryptedSecretKey};
+use super::history::{history_from_balance_proof, HistoryEntry, TransferDirection};
+use super::utils::{
+    balance_at_height, calculate_balances_and_validate_balance_proof, merge_balance_proofs,
+    unproven_sends,
+};
 
 #[derive(Debug)]
 pub struct Wallet {
     pub wallet_name: Option<String>,
+    // Directory the wallet file lives in, only used for named wallets
+    storage_dir: PathBuf,
     pub public_key: BlsPublicKey,
     private_key: BlsSecretKey,
+    // When set, this is written in place of the plaintext secret key. Kept around so saving
+    // doesn't need the passphrase
+    encrypted_private_key: Option<EncryptedSecretKey>,
 
     // Mapping of (Merkle Root, Sender pub key) -> TransactionProof
     pub balance_proof: BalanceProof,
     pub transaction_batch: TransactionBatch,
     batch_is_pending: bool,
+    // Proofs for roots our batch was signed under before the aggregator rebuilt its tree (see
+    // resign_proof). The aggregator still holds a valid signature over these, so they're kept in
+    // case the old root lands on the rollup as well
+    superseded_proofs: BalanceProof,
 
     pub balance: u64,
+
+    // Roots of our own batches that have already been forwarded to the receivers
+    pub forwarded_roots: HashSet<U8_32>,
+    // Roots of payments that the receivers have confirmed they applied
+    pub confirmed_deliveries: HashSet<U8_32>,
+
+    // When set, debug builds recompute the balance after each async mutation and assert it
+    // matches the cached value
+    pub assert_cached_balance: bool,
+
+    // Confirmations an incoming transfer's block needs before it's added to the balance, 0
+    // settles transfers as soon as they're received
+    pub finality_depth: u64,
+    // Fee paid to the aggregator with each batch, debited along with the batch's first
+    // transaction
+    pub batch_fee: u64,
+    // Incoming transfers that are valid but whose block hasn't reached the finality depth yet
+    pending_finality: Vec<(TransactionProof, BalanceProof)>,
+    // When set, only the part of a sender's balance proof that backs up the sender's funds is
+    // validated and merged, any unrelated entries they padded it with are dropped
+    pub relevant_proofs_only: bool,
+
+    pub persistence_format: PersistenceFormat,
+
+    // When set, transactions are made unique with the wallet's nonce rather than a random salt,
+    // which makes them smaller and their hashes reproducible
+    pub use_nonces: bool,
+    next_nonce: u64,
+
+    // Funds set aside for a withdrawal that hasn't landed on-chain yet
+    withdrawal_lock: Option<WithdrawalLock>,
+
+    // Rules every transaction added to the batch has to pass
+    transaction_policy: Arc<dyn TransactionPolicy>,
+
+    // Address book of alias -> public key, so recipients don't have to be pasted in full
+    contacts: BTreeMap<String, BlsPublicKeyWrapper>,
+
+    // Every transaction sent or received, oldest first. Kept separately from the balance proof so
+    // it survives the proof being pruned or reset
+    history: Vec<HistoryEntry>,
+
+    // Makes every persist fail, to test what a crash before the write leaves behind
+    #[cfg(test)]
+    fail_persist: bool,
+    // How many times the wallet file has been written, to check receives are batched
+    #[cfg(test)]
+    persist_count: std::sync::atomic::AtomicUsize,
+}
+
+// Locked funds can't be spent on the L2, otherwise the same funds could be withdrawn on-chain and
+// sent to another user while the withdrawal is pending
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalLock {
+    pub amount: u64,
+    // The account's on-chain withdraw total when the funds were locked, the withdrawal has landed
+    // once the total has grown by the locked amount
+    pub withdrawn_before: u64,
+}
+
+// How the wallet file is written. Loading detects the format, so either can be read regardless of
+// what's selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PersistenceFormat {
+    // Human readable, handy for debugging
+    #[default]
+    Json,
+    // Bincode, much smaller for wallets with large balance proofs
+    Compact,
+}
+
+// Written before the bincode of compact wallet files, a JSON file can't start with it
+const COMPACT_MAGIC_BYTES: [u8; 4] = *b"SPW\x01";
+
+impl PersistenceFormat {
+    fn detect(contents: &[u8]) -> Self {
+        if contents.starts_with(&COMPACT_MAGIC_BYTES) {
+            PersistenceFormat::Compact
+        } else {
+            PersistenceFormat::Json
+        }
+    }
+}
+
+// Summary of how large the balance proof has grown, it's shipped to receivers with every payment
+// and persisted with the wallet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofStats {
+    pub entries: usize,
+    pub distinct_roots: usize,
+    // Size of the balance proof as it's sent to receivers
+    pub serialized_bytes: usize,
+    // Entries whose root and sender aren't in any transfer block on the rollup, e.g. from rounds
+    // that failed. They can't count towards a balance so are safe to drop
+    pub prunable_entries: usize,
+}
+
+// The cached balance split up by where the funds are. Transactions are debited as soon as they're
+// appended, so the balance alone doesn't show what's waiting in the batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceBreakdown {
+    // Held before anything in the current batch is sent
+    pub confirmed: u64,
+    // Total of the transactions in the current batch, pending or not
+    pub pending_outgoing: u64,
+    // What can still be added to the batch, i.e. not in the batch or locked for a withdrawal
+    pub spendable: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct WalletPersistState {
     pub balance_proof: BalanceProof,
-    pub private_key: BlsSecretKeyWrapper,
+    // Exactly one of these is set, depending on whether the wallet is encrypted. Always written,
+    // even when unset, since bincode can't tell a skipped field is missing
+    #[serde(default)]
+    pub private_key: Option<BlsSecretKeyWrapper>,
+    #[serde(default)]
+    pub encrypted_private_key: Option<EncryptedSecretKey>,
     pub wallet_name: Option<String>,
+    // Defaulted so wallet files written before these sets existed still load
+    #[serde(default)]
+    pub forwarded_roots: HashSet<U8_32>,
+    #[serde(default)]
+    pub confirmed_deliveries: HashSet<U8_32>,
+    #[serde(default)]
+    pub next_nonce: u64,
+    #[serde(default)]
+    pub withdrawal_lock: Option<WithdrawalLock>,
+    #[serde(default)]
+    pub contacts: BTreeMap<String, BlsPublicKeyWrapper>,
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    #[serde(default)]
+    pub superseded_proofs: BalanceProof,
+    #[serde(default)]
+    pub pending_finality: Vec<(TransactionProof, BalanceProof)>,
 }
 
-impl Into<Wallet> for WalletPersistState {
-    fn into(self) -> Wallet {
-        let private_key: BlsSecretKey = self.private_key.into();
+impl WalletPersistState {
+    // An encrypted key needs the passphrase. Passing one for a plaintext key encrypts it, so it's
+    // written encrypted from then on
+    fn into_wallet(self, passphrase: Option<&str>) -> CrateResult<Wallet> {
+        let (private_key, encrypted_private_key) =
+            match (self.private_key, self.encrypted_private_key, passphrase) {
+                (_, Some(encrypted), Some(passphrase)) => {
+                    (decrypt_secret_key(&encrypted, passphrase)?, Some(encrypted))
+                }
+                (_, Some(_), None) => return Err(CrateError::PassphraseRequired.into()),
+                (Some(private_key), None, Some(passphrase)) => {
+                    let private_key: BlsSecretKey = private_key.into();
+                    let encrypted = encrypt_secret_key(&private_key, passphrase)?;
+                    (private_key, Some(encrypted))
+                }
+                (Some(private_key), None, None) => (private_key.into(), None),
+                (None, None, _) => return Err(anyhow!("Wallet file has no secret key")),
+            };
         let public_key = private_key.public_key();
+        let history = if self.history.is_empty() {
+            history_from_balance_proof(&public_key, &self.balance_proof)
+        } else {
+            self.history
+        };
 
-        Wallet {
+        Ok(Wallet {
             wallet_name: self.wallet_name,
+            storage_dir: PathBuf::from(WALLET_STORAGE_DIR),
             public_key,
             private_key,
+            encrypted_private_key,
             balance_proof: self.balance_proof,
             transaction_batch: TransactionBatch::new(public_key),
             batch_is_pending: false,
+            superseded_proofs: self.superseded_proofs,
             balance: 0,
-        }
+            forwarded_roots: self.forwarded_roots,
+            confirmed_deliveries: self.confirmed_deliveries,
+            assert_cached_balance: false,
+            finality_depth: 0,
+            batch_fee: 0,
+            pending_finality: self.pending_finality,
+            relevant_proofs_only: false,
+            persistence_format: PersistenceFormat::default(),
+            use_nonces: false,
+            next_nonce: self.next_nonce,
+            withdrawal_lock: self.withdrawal_lock,
+            transaction_policy: Arc::new(DefaultPolicy),
+            contacts: self.contacts,
+            history,
+            #[cfg(test)]
+            fail_persist: false,
+            #[cfg(test)]
+            persist_count: std::sync::atomic::AtomicUsize::new(0),
+        })
     }
 }
 
 impl Wallet {
     pub fn new(wallet_name: Option<String>) -> Wallet {
         match wallet_name.clone() {
-            Some(wallet_name) => Wallet::load_wallet_state(&wallet_name).unwrap(),
+            Some(wallet_name) => {
+                Wallet::load_wallet_state(&wallet_name, Path::new(WALLET_STORAGE_DIR), None)
+                    .unwrap()
+            }
             None => {
                 info!("Creating new temp wallet");
                 WalletPersistState {
                     balance_proof: HashMap::new(),
-                    private_key: BlsSecretKey::new().into(),
+                    private_key: Some(generate_secret_key().into()),
+                    encrypted_private_key: None,
                     wallet_name: None,
+                    forwarded_roots: HashSet::new(),
+                    confirmed_deliveries: HashSet::new(),
+                    next_nonce: 0,
+                    withdrawal_lock: None,
+                    contacts: BTreeMap::new(),
+                    history: vec![],
+                    superseded_proofs: HashMap::new(),
+                    pending_finality: vec![],
                 }
-                .into()
+                .into_wallet(None)
+                .unwrap()
             }
         }
     }
 
+    // Same as new with a wallet name, but the wallet file is read from and written to this
+    // directory rather than the default one
+    pub fn with_storage_dir(
+        wallet_name: &str,
+        storage_dir: impl Into<PathBuf>,
+    ) -> CrateResult<Wallet> {
+        Wallet::load_wallet_state(wallet_name, &storage_dir.into(), None)
+    }
+
+    // The secret key is stored encrypted with a key derived from the passphrase, and the same
+    // passphrase is needed to load the wallet again. An existing plaintext wallet is encrypted
+    // when it's loaded this way
+    pub fn with_encryption(
+        wallet_name: &str,
+        storage_dir: impl Into<PathBuf>,
+        passphrase: &str,
+    ) -> CrateResult<Wallet> {
+        Wallet::load_wallet_state(wallet_name, &storage_dir.into(), Some(passphrase))
+    }
+
+    #[cfg(test)]
+    pub fn persist_count(&self) -> usize {
+        self.persist_count
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn storage_dir(&self) -> &Path {
+        &self.storage_dir
+    }
+
+    pub fn batch_is_pending(&self) -> bool {
+        self.batch_is_pending
+    }
+
     /// Core logic of the wallet
     pub fn append_transaction_to_batch(
         &mut self,
         to: BlsPublicKey,
         amount: u64,
+    ) -> CrateResult<&TransactionBatch> {
+        self.append_transaction_to_batch_with_reference(to, amount, None)
+    }
+
+    // The reference travels with the transaction so the receiver can match the payment up, see
+    // transactions_with_reference
+    pub fn append_transaction_to_batch_with_reference(
+        &mut self,
+        to: BlsPublicKey,
+        amount: u64,
+        reference: Option<u64>,
     ) -> CrateResult<&TransactionBatch> {
         info!("Appending transaction to batch");
 
@@ -88,33 +338,227 @@ impl Wallet {
             return Err(anyhow!("Batch is currently pending"));
         }
 
-        let salt = generate_salt();
-
-        if to == self.public_key {
-            return Err(anyhow!("Cannot send to self"));
-        }
-
-        if amount == 0 {
-            return Err(anyhow!("Amount must be greater than 0"));
-        }
+        // The nonce is only used up once the transaction is accepted
+        let (salt, nonce) = if self.use_nonces {
+            (None, Some(self.next_nonce))
+        } else {
+            (Some(generate_salt()), None)
+        };
 
         let transaction = SimpleTransaction {
             to,
             from: self.public_key,
             amount,
             salt,
+            nonce,
+            reference,
         };
 
-        self.balance = self
-            .balance
-            .checked_sub(amount)
-            .ok_or_else(|| anyhow!("Insufficient balance"))?;
+        self.transaction_policy.validate(&transaction)?;
+
+        let mut transaction_batch = self.transaction_batch.clone();
+        if transaction_batch.transactions.is_empty() {
+            transaction_batch.fee = self.batch_fee;
+        }
+        transaction_batch.transactions.push(transaction);
+        self.transaction_policy.validate_batch(&transaction_batch)?;
+
+        // The fee is only charged once, with the first transaction
+        let debit = transaction_batch
+            .total_amount()
+            .saturating_sub(self.transaction_batch.total_amount());
+
+        if debit > self.spendable_balance() {
+            return Err(match self.withdrawal_lock {
+                Some(lock) if debit <= self.balance => anyhow!(
+                    "Insufficient balance, {} is locked for a pending withdrawal",
+                    lock.amount
+                ),
+                _ => anyhow!("Insufficient balance"),
+            });
+        }
+
+        // Written straight away, a restart that handed out the same nonce again would give the
+        // next transaction to this recipient the same tx_hash
+        if self.use_nonces {
+            self.next_nonce += 1;
+            if let Err(e) = self.save_wallet_state() {
+                self.next_nonce -= 1;
+                return Err(e);
+            }
+        }
+        self.balance -= debit;
 
         info!("New balance: {}", self.balance);
 
-        self.transaction_batch
-            .transactions
-            .push(transaction.clone());
+        self.transaction_batch = transaction_batch;
+
+        Ok(&self.transaction_batch)
+    }
+
+    pub fn set_transaction_policy(&mut self, transaction_policy: Arc<dyn TransactionPolicy>) {
+        self.transaction_policy = transaction_policy;
+    }
+
+    // The balance minus anything locked for a pending withdrawal
+    pub fn spendable_balance(&self) -> u64 {
+        let locked = self.withdrawal_lock.map_or(0, |lock| lock.amount);
+
+        self.balance.saturating_sub(locked)
+    }
+
+    pub fn balance_breakdown(&self) -> BalanceBreakdown {
+        let pending_outgoing = self.pending_batch_amount();
+
+        BalanceBreakdown {
+            confirmed: self.balance + pending_outgoing,
+            pending_outgoing,
+            spendable: self.spendable_balance(),
+        }
+    }
+
+    pub fn withdrawal_lock(&self) -> Option<WithdrawalLock> {
+        self.withdrawal_lock
+    }
+
+    // Call before submitting the withdrawal on-chain. Only one withdrawal can be pending at a time
+    pub async fn lock_for_withdrawal(
+        &mut self,
+        amount: u64,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        if self.withdrawal_lock.is_some() {
+            return Err(anyhow!("A withdrawal is already pending"));
+        }
+
+        if amount == 0 || amount > self.balance {
+            return Err(anyhow!("Insufficient balance to withdraw {}", amount));
+        }
+
+        self.withdrawal_lock = Some(WithdrawalLock {
+            amount,
+            withdrawn_before: rollup_state
+                .get_account_withdraw_amount(&self.public_key)
+                .await?,
+        });
+        self.save_wallet_state()?;
+
+        Ok(())
+    }
+
+    // Withdraws straight away rather than locking the funds first, the amount is checked against
+    // what the balance proof backs up rather than the cached balance
+    pub async fn request_withdrawal(
+        &mut self,
+        amount: u64,
+        rollup_state: &mut (impl MockRollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        if self.withdrawal_lock.is_some() {
+            return Err(anyhow!("A withdrawal is already pending"));
+        }
+
+        if amount == 0 {
+            return Err(anyhow!("Amount must be greater than 0"));
+        }
+
+        let available = self.recompute_balance(rollup_state).await?;
+        if amount > available {
+            return Err(CrateError::InsufficientWithdrawalBalance { amount, available }.into());
+        }
+
+        rollup_state.add_withdraw(&self.public_key, amount).await?;
+        self.balance = available - amount;
+
+        info!("Withdrew {}, new balance: {}", amount, self.balance);
+
+        Ok(())
+    }
+
+    // For a withdrawal that was never submitted or failed on-chain, the funds become spendable again
+    pub fn abort_withdrawal(&mut self) -> CrateResult<()> {
+        if self.withdrawal_lock.take().is_none() {
+            return Err(anyhow!("No withdrawal is pending"));
+        }
+
+        self.save_wallet_state()
+    }
+
+    // The balance already reflects the withdrawal once it's on-chain, so the lock can go
+    async fn release_landed_withdrawal(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let Some(lock) = self.withdrawal_lock else {
+            return Ok(());
+        };
+
+        let withdrawn = rollup_state
+            .get_account_withdraw_amount(&self.public_key)
+            .await?;
+        if withdrawn >= lock.withdrawn_before + lock.amount {
+            info!("Withdrawal of {} landed, releasing the lock", lock.amount);
+            self.withdrawal_lock = None;
+            self.save_wallet_state()?;
+        }
+
+        Ok(())
+    }
+
+    // Merges transactions to the same recipient into one, keeps the batch (and the proof for it)
+    // smaller. The balance is unchanged since the total amount is the same
+    pub fn consolidate_batch(&mut self) -> CrateResult<&TransactionBatch> {
+        if self.batch_is_pending {
+            return Err(anyhow!("Batch is currently pending"));
+        }
+
+        let mut consolidated: Vec<SimpleTransaction> = vec![];
+        for transaction in self.transaction_batch.transactions.drain(..) {
+            match consolidated.iter_mut().find(|tx| tx.to == transaction.to) {
+                Some(existing) => {
+                    existing.amount = existing
+                        .amount
+                        .checked_add(transaction.amount)
+                        .ok_or_else(|| anyhow!("Consolidated amount overflowed"))?;
+                    // A nonce is already unique, only salted transactions need a fresh salt
+                    if existing.salt.is_some() {
+                        existing.salt = Some(generate_salt());
+                    }
+                }
+                None => consolidated.push(transaction),
+            }
+        }
+
+        self.transaction_batch.transactions = consolidated;
+
+        Ok(&self.transaction_batch)
+    }
+
+    // The other transactions keep their salt (or nonce), so their hashes don't change
+    pub fn remove_transaction_from_batch(
+        &mut self,
+        index: usize,
+    ) -> CrateResult<&TransactionBatch> {
+        if self.batch_is_pending {
+            return Err(anyhow!("Batch is currently pending"));
+        }
+
+        if index >= self.transaction_batch.transactions.len() {
+            return Err(anyhow!(
+                "No transaction at index {}, the batch has {}",
+                index,
+                self.transaction_batch.transactions.len()
+            ));
+        }
+
+        let transaction = self.transaction_batch.transactions.remove(index);
+        self.balance += transaction.amount;
+        // Nothing left to pay a fee on
+        if self.transaction_batch.transactions.is_empty() {
+            self.balance += self.transaction_batch.fee;
+            self.transaction_batch.fee = 0;
+        }
+
+        info!("Removed transaction, new balance: {}", self.balance);
 
         Ok(&self.transaction_batch)
     }
@@ -128,37 +572,189 @@ impl Wallet {
             return Err(anyhow!("Batch is already pending"));
         }
 
+        self.transaction_batch.sign(&self.private_key)?;
         self.batch_is_pending = true;
 
         Ok(self.transaction_batch.clone())
     }
 
-    // Called when another client sends funds to this client
-    //
-    // TODO: This should validate that the rollup contract doesn't have any additional transactions
-    // that weren't apart of the senders balance proof. If they do that means the sender may be trying
-    // to double spend
+    // The balance was debited when the transactions were appended, but the rollup may have changed
+    // since (e.g. a withdrawal landed), so the batch is checked against a fresh provable balance.
+    // Funds locked for a pending withdrawal can't cover it either
+    pub async fn verify_batch_funding(
+        &self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let provable_balance = self.provable_balance(rollup_state).await?;
+        let required =
+            self.pending_batch_amount() + self.withdrawal_lock.map_or(0, |lock| lock.amount);
+
+        if required > provable_balance {
+            return Err(anyhow!(
+                "Batch needs {} but the provable balance is only {}",
+                required,
+                provable_balance
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Same as produce_batch, but rejects the batch if it's no longer funded
+    pub async fn produce_funded_batch(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<TransactionBatch> {
+        self.verify_batch_funding(rollup_state).await?;
+
+        self.produce_batch()
+    }
+
+    // Returns a pending batch to the draft state when the round it was sent in failed, the
+    // transactions stay in the batch (and stay debited) so it can be sent again
+    pub fn abort_pending_batch(&mut self) -> CrateResult<()> {
+        if !self.batch_is_pending {
+            return Err(anyhow!("No batch is pending"));
+        }
+
+        self.batch_is_pending = false;
+
+        Ok(())
+    }
+
+    // Unlike abort_pending_batch the transactions are dropped and refunded, for when the batch
+    // won't be sent again. Only safe if the round it was sent in won't be finalised, if it is the
+    // next sync will come up short
+    pub fn cancel_pending_batch(&mut self) -> CrateResult<()> {
+        if !self.batch_is_pending {
+            return Err(anyhow!("No batch is pending"));
+        }
+
+        self.balance += self.pending_batch_amount();
+        self.transaction_batch = TransactionBatch::new(self.public_key);
+        self.batch_is_pending = false;
+
+        info!("Cancelled pending batch, new balance: {}", self.balance);
+
+        Ok(())
+    }
+
+    // Called when another client sends funds to this client. The sender's proof has to include
+    // every block they (and anyone who paid them) signed up to their last send, otherwise they may
+    // be trying to double spend, see verify_complete_send_history
     pub async fn add_receiving_transaction(
         &mut self,
         transaction_proof: &TransactionProof,
         senders_balance_proof: &BalanceProof,
         rollup_contract: &(impl RollupStateTrait + Send + Sync),
     ) -> CrateResult<()> {
-        // Iterate over the batch and ensure one is addressed to this user
-        if !transaction_proof
-            .batch
-            .transactions
-            .iter()
-            .any(|t| t.to == self.public_key)
-        {
+        self.add_receiving_transactions(
+            &[(transaction_proof.clone(), senders_balance_proof.clone())],
+            rollup_contract,
+        )
+        .await
+    }
+
+    // Applies several incoming transfers with a single merge, balance calculation and persist.
+    // Nothing is applied if any of them fail validation
+    pub async fn add_receiving_transactions(
+        &mut self,
+        receives: &[(TransactionProof, BalanceProof)],
+        rollup_contract: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        for (transaction_proof, senders_balance_proof) in receives {
+            self.validate_receiving_transaction(transaction_proof, senders_balance_proof)?;
+        }
+
+        let mut final_balance_proofs = vec![];
+        let mut held = vec![];
+        for (transaction_proof, senders_balance_proof) in receives {
+            // Receives are idempotent, the proof in the balance proof was validated when it was added
+            if self.has_received(transaction_proof) {
+                info!("Transfer already received, skipping");
+                continue;
+            }
+
+            // The receiver usually isn't a signer, so the block is looked up by root alone
+            rollup_contract
+                .get_transfer_block_by_root(&transaction_proof.root)
+                .await?
+                .ok_or(CrateError::BatchNotInATransferBlock(Box::new(
+                    transaction_proof.batch.clone(),
+                )))?
+                .verify()?;
+
+            let senders_balance_proof = if self.relevant_proofs_only {
+                let relevant = balance_proof_for_account(
+                    senders_balance_proof.iter(),
+                    transaction_proof.batch.from.into(),
+                );
+                if relevant.len() < senders_balance_proof.len() {
+                    info!(
+                        "Dropping {} unrelated entries from the sender's balance proof",
+                        senders_balance_proof.len() - relevant.len()
+                    );
+                }
+
+                relevant
+            } else {
+                senders_balance_proof.clone()
+            };
+
+            if self
+                .is_final(&transaction_proof.root, rollup_contract)
+                .await?
+            {
+                final_balance_proofs.push(senders_balance_proof);
+            } else {
+                info!("Transfer hasn't reached the finality depth, holding until it does");
+                held.push((transaction_proof.clone(), senders_balance_proof));
+            }
+        }
+
+        // Held back until the rest applied, so a receive that failed on an unavailable rollup
+        // leaves nothing behind and can be retried as a whole
+        self.apply_balance_proofs(
+            &final_balance_proofs.iter().collect::<Vec<_>>(),
+            rollup_contract,
+        )
+        .await?;
+
+        if !held.is_empty() {
+            self.pending_finality.extend(held);
+            self.save_wallet_state()?;
+        }
+
+        Ok(())
+    }
+
+    fn has_received(&self, transaction_proof: &TransactionProof) -> bool {
+        let key = BalanceProofKey {
+            root: transaction_proof.root,
+            public_key: transaction_proof.batch.from.into(),
+        };
+
+        self.balance_proof.get(&key) == Some(transaction_proof)
+            || self
+                .pending_finality
+                .iter()
+                .any(|(pending_proof, _)| pending_proof == transaction_proof)
+    }
+
+    fn validate_receiving_transaction(
+        &self,
+        transaction_proof: &TransactionProof,
+        senders_balance_proof: &BalanceProof,
+    ) -> CrateResult<()> {
+        // The balance is recalculated from the proofs, so every transaction to this user in the
+        // batch is credited, not just the first
+        if transaction_proof.batch.total_to(&self.public_key) == 0 {
             return Err(anyhow!("No transaction addressed to this user"));
         }
 
         // This isn't really needed because validate_and_sign_transaction will be called first and
         // it checks this, but it's here for completeness
-        if !transaction_proof.verify() {
-            return Err(anyhow::anyhow!("Invalid transaction"));
-        }
+        transaction_proof.verify()?;
 
         if !senders_balance_proof.contains_key(&BalanceProofKey {
             root: transaction_proof.root,
@@ -169,8 +765,94 @@ impl Wallet {
             ));
         }
 
-        let merged_proof =
-            merge_balance_proofs(self.balance_proof.clone(), senders_balance_proof.clone())?;
+        Ok(())
+    }
+
+    // Amount sent to this wallet by transfers that are waiting to reach the finality depth, not
+    // included in the spendable balance
+    pub fn pending_finality_balance(&self) -> u64 {
+        self.pending_finality
+            .iter()
+            .map(|(transaction_proof, _)| transaction_proof.batch.total_to(&self.public_key))
+            .sum()
+    }
+
+    pub fn has_pending_finality(&self) -> bool {
+        !self.pending_finality.is_empty()
+    }
+
+    // Applies the held transfers that have now reached the finality depth. If the rollup can't be
+    // read the held transfers are left as they were, to be settled on a later attempt
+    pub async fn settle_pending_transfers(
+        &mut self,
+        rollup_contract: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let mut final_balance_proofs = vec![];
+        let mut still_pending = vec![];
+        for (transaction_proof, senders_balance_proof) in self.pending_finality.iter() {
+            if self
+                .is_final(&transaction_proof.root, rollup_contract)
+                .await?
+            {
+                final_balance_proofs.push(senders_balance_proof.clone());
+            } else {
+                still_pending.push((transaction_proof.clone(), senders_balance_proof.clone()));
+            }
+        }
+
+        let previous_pending = std::mem::replace(&mut self.pending_finality, still_pending);
+        let result = self
+            .apply_balance_proofs(
+                &final_balance_proofs.iter().collect::<Vec<_>>(),
+                rollup_contract,
+            )
+            .await;
+
+        if result.is_err() {
+            self.pending_finality = previous_pending;
+        }
+
+        result
+    }
+
+    async fn is_final(
+        &self,
+        root: &U8_32,
+        rollup_contract: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<bool> {
+        if self.finality_depth == 0 {
+            return Ok(true);
+        }
+
+        Ok(rollup_contract.get_confirmations_for_block(root).await? >= self.finality_depth)
+    }
+
+    // Merges proofs fetched from elsewhere (e.g. the server's proof store) into the wallet's own,
+    // for when the wallet lost some of its proofs. Nothing is merged unless it all validates
+    pub async fn restore_balance_proof(
+        &mut self,
+        balance_proof: &BalanceProof,
+        rollup_contract: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        self.apply_balance_proofs(&[balance_proof], rollup_contract)
+            .await
+    }
+
+    // The senders' balance proofs already contain the transaction proofs, which were checked
+    // before getting here
+    async fn apply_balance_proofs(
+        &mut self,
+        senders_balance_proofs: &[&BalanceProof],
+        rollup_contract: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        if senders_balance_proofs.is_empty() {
+            return Ok(());
+        }
+
+        let mut merged_proof = self.balance_proof.clone();
+        for senders_balance_proof in senders_balance_proofs {
+            merged_proof = merge_balance_proofs(merged_proof, (*senders_balance_proof).clone())?;
+        }
 
         let balances =
             calculate_balances_and_validate_balance_proof(rollup_contract, &merged_proof).await?;
@@ -179,9 +861,30 @@ impl Wallet {
             "Current user's balance not found in merged balance proof"
         ))?;
 
-        self.balance = *current_users_balance;
+        // Transactions in the current batch are debited locally but aren't in the proof yet
+        let balance = current_users_balance
+            .checked_sub(self.pending_batch_amount())
+            .ok_or(anyhow!("Pending batch exceeds the provable balance"))?;
+
+        let mut history = self.history.clone();
+        for (key, proof) in merged_proof.iter() {
+            if !self.balance_proof.contains_key(key) {
+                history.extend(
+                    HistoryEntry::from_proof(&self.public_key, proof)
+                        .into_iter()
+                        .filter(|entry| entry.direction == TransferDirection::Received),
+                );
+            }
+        }
+
+        // Persisted before anything in memory changes, if it fails the wallet is left as it was on
+        // both and the receive can be retried
+        self.persist_with_balance_proof(&merged_proof, &history)?;
+        self.balance = balance;
         self.balance_proof = merged_proof;
-        self.save_wallet_state()?;
+        self.history = history;
+
+        self.debug_assert_cached_balance(rollup_contract).await?;
 
         Ok(())
     }
@@ -210,9 +913,7 @@ impl Wallet {
             return Err(anyhow!("Transaction batch not from this user"));
         }
 
-        if !transaction_proof.verify() {
-            return Err(anyhow::anyhow!("Invalid transaction proof"));
-        }
+        transaction_proof.verify()?;
 
         let signature = self.private_key.sign(
             blsful::SignatureSchemes::MessageAugmentation,
@@ -226,6 +927,10 @@ impl Wallet {
             },
             transaction_proof.clone(),
         );
+        self.history.extend(HistoryEntry::from_proof(
+            &self.public_key,
+            transaction_proof,
+        ));
 
         self.transaction_batch = TransactionBatch::new(self.public_key);
         self.batch_is_pending = false;
@@ -234,100 +939,575 @@ impl Wallet {
         Ok(signature)
     }
 
-    // This is called somewhat intermittently to ensure the client is in sync with the contract
-    // It mainly ensures that the user's deposits and withdraws are accounted for
-    pub async fn sync_rollup_state(
+    // Signs the same batch again under a new root, for when the aggregator rebuilt its tree
+    // without the batches that didn't sign (see Aggregator::finalise_with_signed_only). The proof
+    // for the old root is swapped for the new one but kept in superseded_proofs, nothing stops the
+    // aggregator posting the old root too and the batch would then be debited twice
+    pub fn resign_proof(
         &mut self,
-        rollup_state: &(impl RollupStateTrait + Send + Sync),
-    ) -> CrateResult<()> {
-        let balances =
-            calculate_balances_and_validate_balance_proof(rollup_state, &self.balance_proof)
-                .await?;
+        previous_root: &U8_32,
+        transaction_proof: &TransactionProof,
+    ) -> CrateResult<BlsSignature> {
+        let previous_key = BalanceProofKey {
+            root: *previous_root,
+            public_key: self.public_key.into(),
+        };
+        let previous_proof = self
+            .balance_proof
+            .get(&previous_key)
+            .ok_or(anyhow!("No signed batch for the previous root"))?;
 
-        if let Some(current_users_balance) = balances.get(&self.public_key.into()) {
-            self.balance = *current_users_balance;
-        } else {
-            let deposit_amount = rollup_state
-                .get_account_deposit_amount(&self.public_key)
-                .await?;
-            let withdraw_amount = rollup_state
-                .get_account_withdraw_amount(&self.public_key)
-                .await?;
+        if previous_proof.batch.tx_hash() != transaction_proof.batch.tx_hash() {
+            return Err(anyhow!("Provided proof doesn't match the signed batch"));
+        }
+
+        transaction_proof.verify()?;
+
+        let signature = self.private_key.sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &transaction_proof.root,
+        )?;
+
+        if let Some(previous_proof) = self.balance_proof.remove(&previous_key) {
+            self.superseded_proofs.insert(previous_key, previous_proof);
+        }
+        self.balance_proof.insert(
+            BalanceProofKey {
+                root: transaction_proof.root,
+                public_key: self.public_key.into(),
+            },
+            transaction_proof.clone(),
+        );
+        for entry in self
+            .history
+            .iter_mut()
+            .filter(|entry| entry.root == *previous_root)
+        {
+            entry.root = transaction_proof.root;
+        }
+        self.save_wallet_state()?;
+
+        Ok(signature)
+    }
+
+    // Proves to the server that this wallet holds the key it's connecting with
+    pub fn sign_auth_challenge(&self, nonce: &U8_32) -> CrateResult<BlsSignature> {
+        sign_auth_challenge(&self.private_key, nonce)
+    }
+
+    // Acknowledges a payment from sender received in the block with this root, for the sender to
+    // hold onto in case of a dispute. Only payments already in the balance proof can be
+    // acknowledged
+    pub fn sign_receipt(
+        &self,
+        root: U8_32,
+        sender: BlsPublicKey,
+        amount: u64,
+    ) -> CrateResult<BlsSignature> {
+        let sender = BlsPublicKeyWrapper::from(sender);
+        let received = self.history().iter().any(|entry| {
+            entry.root == root
+                && entry.direction == TransferDirection::Received
+                && entry.counterparty == sender
+                && entry.amount == amount
+        });
+
+        if !received {
+            return Err(anyhow!(
+                "No received payment of {} from that sender in that block",
+                amount
+            ));
+        }
+
+        Ok(self.private_key.sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &receipt_message(&root, &sender.into(), amount),
+        )?)
+    }
+
+    // Aliases are case sensitive and limited to letters, digits, '_' and '-', an alias can't be
+    // pointed at another key without removing it first
+    pub fn add_contact(&mut self, alias: &str, public_key: BlsPublicKey) -> CrateResult<()> {
+        if alias.is_empty()
+            || !alias
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(anyhow!(
+                "Invalid alias {:?}, only letters, digits, '_' and '-' are allowed",
+                alias
+            ));
+        }
+
+        if let Some(existing) = self.contacts.get(alias) {
+            if *existing == public_key.into() {
+                return Ok(());
+            }
 
-            self.balance = deposit_amount - withdraw_amount;
+            return Err(anyhow!(
+                "Alias @{} is already used by another contact",
+                alias
+            ));
         }
 
+        self.contacts.insert(alias.to_string(), public_key.into());
+        self.save_wallet_state()
+    }
+
+    pub fn remove_contact(&mut self, alias: &str) -> CrateResult<()> {
+        self.contacts
+            .remove(alias)
+            .ok_or(anyhow!("Unknown contact @{}", alias))?;
+
+        self.save_wallet_state()
+    }
+
+    pub fn resolve_contact(&self, alias: &str) -> CrateResult<BlsPublicKey> {
+        self.contacts
+            .get(alias)
+            .map(|key| (*key).into())
+            .ok_or(anyhow!(
+                "Unknown contact @{}, add it with add_contact first",
+                alias
+            ))
+    }
+
+    // Transactions this wallet sent or received that made it into a transfer block, oldest first.
+    // The pending batch isn't included
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
+    }
+
+    pub fn contacts(&self) -> &BTreeMap<String, BlsPublicKeyWrapper> {
+        &self.contacts
+    }
+
+    // This is called somewhat intermittently to ensure the client is in sync with the contract
+    // It mainly ensures that the user's deposits and withdraws are accounted for
+    pub async fn sync_rollup_state(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        self.restore_landed_superseded_proofs(rollup_state).await?;
+        self.balance = self.recompute_balance(rollup_state).await?;
+        self.release_landed_withdrawal(rollup_state).await?;
+
+        self.debug_assert_cached_balance(rollup_state).await?;
+
         Ok(())
     }
 
-    /// PERISTENCE
-    fn get_wallet_path(wallet_name: &str) -> CrateResult<String> {
-        let path = format!("wallet_data/{}.json", wallet_name);
+    // A superseded root that made it into a block spent the batch again, its proof goes back in
+    // the balance proof so the balance reflects it and the send history stays complete
+    async fn restore_landed_superseded_proofs(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let mut landed = vec![];
+        for key in self.superseded_proofs.keys() {
+            if rollup_state
+                .get_transfer_block_for_merkle_root_and_pubkey(&key.root, &key.public_key.into())
+                .await?
+                .is_some()
+            {
+                landed.push(key.clone());
+            }
+        }
+
+        if landed.is_empty() {
+            return Ok(());
+        }
+
+        for key in landed {
+            let transaction_proof = self.superseded_proofs.remove(&key).unwrap();
+            warn!(
+                "Superseded root {:?} landed on the rollup, the batch was spent twice",
+                key.root
+            );
+            self.history.extend(HistoryEntry::from_proof(
+                &self.public_key,
+                &transaction_proof,
+            ));
+            self.balance_proof.insert(key, transaction_proof);
+        }
+
+        self.save_wallet_state()
+    }
+
+    // Throws away all local state except the keypair and history, and rebuilds the balance from
+    // the rollup and the recovered proof (e.g. from the server's proof store). Every block this key
+    // signed has to be in the recovered proof, otherwise its sends would be forgotten and the
+    // balance overstated, so the reset is refused and nothing is thrown away. Funds only provable
+    // with the discarded proofs (e.g. incoming transfers) are unspendable until they're fetched
+    // again from their senders
+    pub async fn reset_local_state(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+        recovered_proof: &BalanceProof,
+    ) -> CrateResult<()> {
+        let unproven = unproven_sends(rollup_state, &self.public_key, recovered_proof).await?;
+        if !unproven.is_empty() {
+            return Err(CrateError::UnprovenSends(unproven.len()).into());
+        }
+        calculate_balances_and_validate_balance_proof(rollup_state, recovered_proof).await?;
+
+        info!("Resetting local wallet state");
+
+        self.balance_proof = recovered_proof.clone();
+        self.superseded_proofs = HashMap::new();
+        self.transaction_batch = TransactionBatch::new(self.public_key);
+        self.batch_is_pending = false;
+        self.forwarded_roots.clear();
+        self.confirmed_deliveries.clear();
+        self.pending_finality.clear();
+        self.balance = 0;
+        self.save_wallet_state()?;
+
+        self.sync_rollup_state(rollup_state).await
+    }
+
+    // Derives the balance from scratch using the balance proof and the rollup state, ignoring the
+    // cached `balance` entirely. Transactions in the current batch have already been debited
+    // locally but aren't in the balance proof yet, so they're deducted here as well
+    pub async fn recompute_balance(
+        &self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<u64> {
+        self.provable_balance(rollup_state)
+            .await?
+            .checked_sub(self.pending_batch_amount())
+            .ok_or(anyhow!("Pending batch exceeds the provable balance"))
+    }
+
+    // What the balance proof and rollup state say the wallet holds, before anything in the batch
+    async fn provable_balance(
+        &self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<u64> {
+        self.provable_balance_with(rollup_state, &self.balance_proof)
+            .await
+    }
+
+    async fn provable_balance_with(
+        &self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+        balance_proof: &BalanceProof,
+    ) -> CrateResult<u64> {
+        let balances =
+            calculate_balances_and_validate_balance_proof(rollup_state, balance_proof).await?;
+
+        match balances.get(&self.public_key.into()) {
+            Some(current_users_balance) => Ok(*current_users_balance),
+            None => rollup_state.get_account_balance(&self.public_key).await,
+        }
+    }
+
+    // Balance of any account covered by this wallet's balance proof as of the given number of
+    // transfer blocks, e.g. to back up a claim that an account held an amount at that point. Errors
+    // for a past height if the account has deposits or withdraws, see utils::balance_at_height
+    pub async fn balance_at_height(
+        &self,
+        public_key: &BlsPublicKey,
+        height: usize,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<u64> {
+        balance_at_height(rollup_state, public_key, height, &self.balance_proof).await
+    }
+
+    pub async fn balance_proof_stats(
+        &self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<ProofStats> {
+        let transfer_blocks = rollup_state.get_transfer_blocks().await?;
+        let prunable_entries = self
+            .balance_proof
+            .keys()
+            .filter(|key| {
+                !transfer_blocks.iter().any(|transfer_block| {
+                    transfer_block.merkle_root == key.root
+                        && transfer_block.contains_pubkey(&key.public_key.into())
+                })
+            })
+            .count();
+
+        Ok(ProofStats {
+            entries: self.balance_proof.len(),
+            distinct_roots: self
+                .balance_proof
+                .keys()
+                .map(|key| key.root)
+                .collect::<HashSet<U8_32>>()
+                .len(),
+            serialized_bytes: serde_json::to_vec(&self.balance_proof)?.len(),
+            prunable_entries,
+        })
+    }
+
+    // Drops the balance proof entries that aren't needed to back up the balance, returning how many
+    // were removed. Anything outside the account's closure goes first (see
+    // balance_proof_for_account), then each other sender is dropped as a whole if the proof still
+    // validates to the same balance without them, e.g. an account whose deposits cover everything
+    // it sent doesn't need the proofs of what it received. A sender's sends can't be dropped one at
+    // a time, verify_complete_send_history wants all of them
+    pub async fn prune_balance_proof(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<usize> {
+        let balance = self.provable_balance(rollup_state).await?;
+        let mut balance_proof =
+            balance_proof_for_account(self.balance_proof.iter(), self.public_key.into());
+
+        // Dropping a sender can free up whoever paid them, so go until nothing else can be dropped
+        loop {
+            let senders: HashSet<BlsPublicKeyWrapper> = balance_proof
+                .keys()
+                .map(|key| key.public_key)
+                .filter(|public_key| *public_key != self.public_key.into())
+                .collect();
+
+            let mut dropped_sender = false;
+            for sender in senders {
+                let pruned: BalanceProof = balance_proof
+                    .iter()
+                    .filter(|(key, _)| key.public_key != sender)
+                    .map(|(key, proof)| (key.clone(), proof.clone()))
+                    .collect();
+
+                // Any failure means the sender's entries are still needed
+                if let Ok(pruned_balance) = self.provable_balance_with(rollup_state, &pruned).await
+                {
+                    if pruned_balance == balance {
+                        balance_proof = pruned;
+                        dropped_sender = true;
+                    }
+                }
+            }
+
+            if !dropped_sender {
+                break;
+            }
+        }
+
+        let removed = self.balance_proof.len() - balance_proof.len();
+        if removed > 0 {
+            info!("Pruned {} entries from the balance proof", removed);
+            self.balance_proof = balance_proof;
+            self.save_wallet_state()?;
+        }
+
+        Ok(removed)
+    }
+
+    fn pending_batch_amount(&self) -> u64 {
+        self.transaction_batch.total_amount()
+    }
 
-        if let Some(parent) = std::path::Path::new(&path).parent() {
-            create_dir_all(parent)?;
+    // Safety net for bugs in the paths that mutate the cached balance, only runs in debug builds
+    // when `assert_cached_balance` is set
+    async fn debug_assert_cached_balance(
+        &self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        if cfg!(debug_assertions) && self.assert_cached_balance {
+            let recomputed_balance = self.recompute_balance(rollup_state).await?;
+
+            debug_assert_eq!(
+                self.balance, recomputed_balance,
+                "Cached balance doesn't match the balance recomputed from the balance proof"
+            );
         }
 
-        Ok(path)
+        Ok(())
+    }
+
+    /// PERISTENCE
+    fn get_wallet_path(storage_dir: &Path, wallet_name: &str) -> CrateResult<PathBuf> {
+        create_dir_all(storage_dir)?;
+
+        Ok(storage_dir.join(format!("{}.json", wallet_name)))
     }
 
     fn save_wallet_state(&self) -> CrateResult<()> {
+        self.persist_with_balance_proof(&self.balance_proof, &self.history)
+    }
+
+    // Writes the wallet as it would be with this balance proof, so callers can persist a new proof
+    // before swapping it in and memory never gets ahead of what's on disk. Written to a temporary
+    // file which is then renamed over the wallet file, being killed mid-write leaves the previous
+    // state intact
+    fn persist_with_balance_proof(
+        &self,
+        balance_proof: &BalanceProof,
+        history: &[HistoryEntry],
+    ) -> CrateResult<()> {
         if self.wallet_name.is_none() {
             return Ok(());
         }
 
+        #[cfg(test)]
+        if self.fail_persist {
+            return Err(anyhow!("Injected persist failure"));
+        }
+
         let wallet_name = self.wallet_name.as_ref().unwrap();
 
         let wallet_state = WalletPersistState {
-            balance_proof: self.balance_proof.clone(),
-            private_key: self.private_key.clone().into(),
+            balance_proof: balance_proof.clone(),
+            private_key: match self.encrypted_private_key {
+                Some(_) => None,
+                None => Some(self.private_key.clone().into()),
+            },
+            encrypted_private_key: self.encrypted_private_key.clone(),
             wallet_name: self.wallet_name.clone(),
+            forwarded_roots: self.forwarded_roots.clone(),
+            confirmed_deliveries: self.confirmed_deliveries.clone(),
+            next_nonce: self.next_nonce,
+            withdrawal_lock: self.withdrawal_lock,
+            contacts: self.contacts.clone(),
+            history: history.to_vec(),
+            superseded_proofs: self.superseded_proofs.clone(),
+            pending_finality: self.pending_finality.clone(),
         };
 
-        let path = Wallet::get_wallet_path(wallet_name)?;
+        let path = Wallet::get_wallet_path(&self.storage_dir, wallet_name)?;
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
+        // The wallet file itself is replaced by the rename, so writers lock a sibling file that
+        // stays put instead
+        let lock_file = Wallet::open_lock_file(&path)?;
+        lock_file.lock_exclusive()?;
+
+        let result = self.write_temp_and_rename(&path, &wallet_state);
+
+        lock_file.unlock()?;
+
+        #[cfg(test)]
+        if result.is_ok() {
+            self.persist_count
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    fn write_temp_and_rename(
+        &self,
+        path: &Path,
+        wallet_state: &WalletPersistState,
+    ) -> CrateResult<()> {
+        // Unique per write, so a writer that doesn't hold the lock (e.g. an older build) can't
+        // clobber this one's temp file before it's renamed
+        let temp_path = path.with_extension(format!("json.{:016x}.tmp", rand::random::<u64>()));
+        let result = self
+            .write_temp_file(&temp_path, wallet_state)
+            .and_then(|_| {
+                rename(&temp_path, path)?;
+                Ok(())
+            });
+        if result.is_err() {
+            remove_file(&temp_path).ok();
+        }
+        result?;
+
+        // The rename only survives a crash once the directory entry is on disk too
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
 
-        file.lock_exclusive()?;
+        Ok(())
+    }
 
-        to_writer(&file, &wallet_state)?;
+    fn write_temp_file(
+        &self,
+        temp_path: &Path,
+        wallet_state: &WalletPersistState,
+    ) -> CrateResult<()> {
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(temp_path)?;
+
+        match self.persistence_format {
+            PersistenceFormat::Json => to_writer(&temp_file, wallet_state)?,
+            PersistenceFormat::Compact => {
+                (&temp_file).write_all(&COMPACT_MAGIC_BYTES)?;
+                bincode::serialize_into(&temp_file, wallet_state)?;
+            }
+        }
+        temp_file.sync_all()?;
 
-        file.unlock()?;
         Ok(())
     }
 
-    fn load_wallet_state(wallet_name: &str) -> CrateResult<Wallet> {
+    fn open_lock_file(path: &Path) -> CrateResult<File> {
+        Ok(OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.with_extension("json.lock"))?)
+    }
+
+    fn load_wallet_state(
+        wallet_name: &str,
+        storage_dir: &Path,
+        passphrase: Option<&str>,
+    ) -> CrateResult<Wallet> {
         info!("Loading wallet with name: {}", wallet_name);
-        let path = Wallet::get_wallet_path(wallet_name)?;
+        let path = Wallet::get_wallet_path(storage_dir, wallet_name)?;
+        let lock_file = Wallet::open_lock_file(&path)?;
+        lock_file.lock_shared()?;
+
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)?;
-
-        let state: WalletPersistState = match from_reader(&file) {
-            Ok(state) => Ok(state),
-            Err(e) => {
-                if e.is_eof() {
-                    info!("Creating new wallet file for: {}", wallet_name);
-                    Ok(WalletPersistState {
-                        balance_proof: HashMap::new(),
-                        private_key: BlsSecretKey::new().into(),
-                        wallet_name: Some(wallet_name.to_string()),
-                    })
-                } else {
-                    Err(e)
-                }
+            .truncate(false)
+            .open(&path)?;
+
+        let mut contents = vec![];
+        (&file).read_to_end(&mut contents)?;
+        lock_file.unlock()?;
+
+        let persistence_format = PersistenceFormat::detect(&contents);
+
+        // Only a file that was just created starts a new wallet. Anything else that doesn't parse
+        // (e.g. truncated by a crash) is an error, a fresh key would lose the funds for good
+        let state: WalletPersistState = if contents.is_empty() {
+            info!("Creating new wallet file for: {}", wallet_name);
+            WalletPersistState {
+                balance_proof: HashMap::new(),
+                private_key: Some(BlsSecretKey::new().into()),
+                encrypted_private_key: None,
+                wallet_name: Some(wallet_name.to_string()),
+                forwarded_roots: HashSet::new(),
+                confirmed_deliveries: HashSet::new(),
+                next_nonce: 0,
+                withdrawal_lock: None,
+                contacts: BTreeMap::new(),
+                history: vec![],
+                superseded_proofs: HashMap::new(),
+                pending_finality: vec![],
             }
-        }?;
-
-        file.unlock().expect("Unable to unlock file");
+        } else {
+            let parsed_state: CrateResult<WalletPersistState> = match persistence_format {
+                PersistenceFormat::Json => from_reader(contents.as_slice()).map_err(Into::into),
+                PersistenceFormat::Compact => {
+                    bincode::deserialize(&contents[COMPACT_MAGIC_BYTES.len()..]).map_err(Into::into)
+                }
+            };
+
+            parsed_state.map_err(|e| {
+                anyhow!(
+                    "Wallet file for {} can't be parsed, leaving it untouched: {}",
+                    wallet_name,
+                    e
+                )
+            })?
+        };
 
-        let wallet = state.into();
+        // Keep writing in the format the file was already in
+        let mut wallet = state.into_wallet(passphrase)?;
+        wallet.persistence_format = persistence_format;
+        wallet.storage_dir = storage_dir.to_path_buf();
         Wallet::save_wallet_state(&wallet)?;
 
         Ok(wallet)
@@ -336,17 +1516,24 @@ impl Wallet {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::{HashMap, HashSet};
 
     use crate::{
-        aggregator::Aggregator,
+        aggregator::{Aggregator, SignedOnlyFinalisation},
         errors::{CrateError, CrateResult},
         rollup::{
             mock_rollup_memory::MockRollupMemory,
             traits::{MockRollupStateTrait, RollupStateTrait},
         },
+        types::{
+            balance::BalanceProofKey,
+            common::{generate_salt, TransferBlock, TransferBlockSignature},
+            signatures::BlsSecretKeyWrapper,
+        },
+        wallet::utils::calculate_balances_and_validate_balance_proof,
     };
 
-    use super::Wallet;
+    use super::{BalanceBreakdown, PersistenceFormat, ProofStats, Wallet, COMPACT_MAGIC_BYTES};
 
     async fn setup(initial_deposit: u64) -> CrateResult<(Wallet, MockRollupMemory)> {
         let mut client = Wallet::new(None);
@@ -360,6 +1547,74 @@ mod tests {
         Ok((client, rollup_state))
     }
 
+    #[tokio::test]
+    async fn test_superseded_root_landing_is_debited() -> CrateResult<()> {
+        let (mut sender, mut rollup_state) = setup(100).await?;
+        let mut idle_sender = Wallet::new(None);
+        let receiver = Wallet::new(None);
+        rollup_state
+            .add_deposit(&idle_sender.public_key, 100)
+            .await?;
+        idle_sender.sync_rollup_state(&rollup_state).await?;
+
+        let mut aggregator = Aggregator::new();
+        sender.append_transaction_to_batch(receiver.public_key, 30)?;
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        idle_sender.append_transaction_to_batch(receiver.public_key, 30)?;
+        aggregator.add_batch(&idle_sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+
+        let original_proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let original_signature = sender.validate_and_sign_proof(&original_proof)?;
+        aggregator.add_signature(&sender.public_key, &original_signature)?;
+
+        let SignedOnlyFinalisation::Resign(proofs) = aggregator.finalise_with_signed_only()? else {
+            panic!("Round with an unsigned batch should need resigning");
+        };
+        let signature = sender.resign_proof(&original_proof.root, &proofs[0])?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        let SignedOnlyFinalisation::Finalised(transfer_block, _) =
+            aggregator.finalise_with_signed_only()?
+        else {
+            panic!("Every remaining batch is signed");
+        };
+        rollup_state.add_transfer_block(*transfer_block).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(sender.balance, 70);
+
+        // The aggregator still has the signature over the old root and posts that block as well
+        rollup_state
+            .add_transfer_block(TransferBlock {
+                signature: TransferBlockSignature::new(vec![(
+                    sender.public_key,
+                    original_signature,
+                )])?,
+                merkle_root: original_proof.root,
+                total_leaves: Some(original_proof.total_leaves),
+                fee_recipient: None,
+                height: 0,
+                timestamp: 0,
+            })
+            .await?;
+
+        sender.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(sender.balance, 40);
+        assert!(sender.balance_proof.contains_key(&BalanceProofKey {
+            root: original_proof.root,
+            public_key: sender.public_key.into(),
+        }));
+        assert_eq!(
+            sender
+                .history()
+                .iter()
+                .filter(|entry| entry.amount == 30)
+                .count(),
+            2
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_balance_increases_with_deposits_when_syncing_rollup_state() -> CrateResult<()> {
         let (client, _) = setup(100).await?;
@@ -369,6 +1624,39 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_withdraws_exceeding_deposits_error_instead_of_underflowing() -> CrateResult<()> {
+        let mut client = Wallet::new(None);
+        let mut rollup_state = MockRollupMemory::new();
+        rollup_state.add_deposit(&client.public_key, 50).await?;
+        // add_withdraw won't allow this, a faulty rollup could still report it
+        rollup_state
+            .withdraw_totals
+            .insert(client.public_key.into(), 80);
+
+        let err = client.sync_rollup_state(&rollup_state).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::WithdrawsExceedDeposits {
+                public_key: client.public_key.to_string(),
+                deposits: 50,
+                withdraws: 80,
+            })
+        );
+
+        rollup_state
+            .withdraw_totals
+            .insert(client.public_key.into(), 30);
+        assert_eq!(
+            rollup_state.get_account_balance(&client.public_key).await?,
+            20
+        );
+        client.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(client.balance, 20);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_balance_decreases_with_withdrawals_when_syncing_rollup_state() -> CrateResult<()>
     {
@@ -475,52 +1763,182 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_add_receiving_transaction_succeeds() -> CrateResult<()> {
-        let mut aggregator = Aggregator::new();
-        let (mut client, mut rollup_state) = setup(300).await?;
-        let mut alice = Wallet::new(None);
+    async fn test_consolidate_batch_merges_duplicate_recipients() -> CrateResult<()> {
+        let (mut client, _) = setup(300).await?;
+        let alice = Wallet::new(None);
+        let mary = Wallet::new(None);
 
         client.append_transaction_to_batch(alice.public_key, 100)?;
-        let batch = client.produce_batch()?;
+        client.append_transaction_to_batch(mary.public_key, 50)?;
+        client.append_transaction_to_batch(alice.public_key, 25)?;
 
-        aggregator.add_batch(&batch)?;
-        aggregator.start_collecting_signatures()?;
-        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        assert!(client.transaction_batch.validate(false).is_err());
 
-        let signature = client.validate_and_sign_proof(&merkle_tree_proof)?;
+        let batch = client.consolidate_batch()?;
 
-        aggregator.add_signature(&client.public_key, &signature)?;
+        assert_eq!(batch.transactions.len(), 2);
+        assert!(batch.validate(false).is_ok());
+        assert_eq!(batch.transactions[0].to, alice.public_key);
+        assert_eq!(batch.transactions[0].amount, 125);
+        assert_eq!(batch.transactions[1].amount, 50);
+        assert_eq!(client.balance, 125);
 
-        let transfer_block = aggregator.finalise()?;
+        Ok(())
+    }
 
-        rollup_state.add_transfer_block(transfer_block).await?;
+    #[tokio::test]
+    async fn test_remove_transaction_from_batch_refunds_it() -> CrateResult<()> {
+        let (mut client, _) = setup(100).await?;
+        let receiver = Wallet::new(None);
 
-        alice
-            .add_receiving_transaction(&merkle_tree_proof, &client.balance_proof, &rollup_state)
-            .await?;
+        for amount in [10, 20, 30] {
+            client.append_transaction_to_batch(receiver.public_key, amount)?;
+        }
+        let transactions = client.transaction_batch.transactions.clone();
 
-        assert_eq!(alice.balance, 100);
-        assert_eq!(client.balance, 200);
+        assert!(client.remove_transaction_from_batch(3).is_err());
+
+        let batch = client.remove_transaction_from_batch(1)?;
+
+        assert_eq!(batch.transactions.len(), 2);
+        assert_eq!(
+            batch
+                .transactions
+                .iter()
+                .map(|transaction| transaction.tx_hash())
+                .collect::<Vec<_>>(),
+            vec![transactions[0].tx_hash(), transactions[2].tx_hash()]
+        );
+        assert_eq!(client.balance, 60);
+
+        client.produce_batch()?;
+        assert!(client.remove_transaction_from_batch(0).is_err());
 
         Ok(())
     }
 
-    async fn complete_aggregator_round(
-        sender: &mut Wallet,
-        rollup_state: &mut MockRollupMemory,
-        amount: u64,
-    ) -> CrateResult<Wallet> {
-        let mut aggregator = Aggregator::new();
+    #[tokio::test]
+    async fn test_batch_fee_is_paid_to_the_fee_recipient() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+        let receiver = Wallet::new(None);
+        let fee_recipient = Wallet::new(None);
+        client.batch_fee = 5;
 
-        let mut receiver = Wallet::new(None);
+        client.append_transaction_to_batch(receiver.public_key, 30)?;
+        client.append_transaction_to_batch(receiver.public_key, 10)?;
+        assert_eq!(client.balance, 55);
 
-        sender.append_transaction_to_batch(receiver.public_key, amount)?;
-        let batch = sender.produce_batch()?;
+        // Dropping back to an empty batch refunds the fee too
+        client.remove_transaction_from_batch(1)?;
+        client.remove_transaction_from_batch(0)?;
+        assert_eq!(client.balance, 100);
+        assert_eq!(client.transaction_batch.fee, 0);
+
+        client.append_transaction_to_batch(receiver.public_key, 30)?;
+        let batch = client.produce_batch()?;
+        assert_eq!(batch.fee, 5);
 
+        let mut aggregator = Aggregator::new();
+        aggregator.set_fee_recipient(Some(fee_recipient.public_key));
         aggregator.add_batch(&batch)?;
         aggregator.start_collecting_signatures()?;
-        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
-        let signature = sender.validate_and_sign_proof(&merkle_tree_proof)?;
+        let proof = aggregator.generate_proof_for_pubkey(&client.public_key)?;
+        let signature = client.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&client.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        let balances =
+            calculate_balances_and_validate_balance_proof(&rollup_state, &client.balance_proof)
+                .await?;
+        assert_eq!(balances.get(&client.public_key.into()), Some(&65));
+        assert_eq!(balances.get(&receiver.public_key.into()), Some(&30));
+        assert_eq!(balances.get(&fee_recipient.public_key.into()), Some(&5));
+
+        client.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(client.balance, 65);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_receiver_paid_twice_in_one_batch_is_credited_both() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+        let mut receiver = Wallet::new(None);
+
+        client.append_transaction_to_batch(receiver.public_key, 20)?;
+        client.append_transaction_to_batch(receiver.public_key, 30)?;
+        let batch = client.produce_batch()?;
+        assert_eq!(batch.total_to(&receiver.public_key), 50);
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&client.public_key)?;
+        let signature = client.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&client.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        receiver
+            .add_receiving_transaction(&proof, &client.balance_proof, &rollup_state)
+            .await?;
+
+        assert_eq!(receiver.balance, 50);
+        assert_eq!(receiver.history().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_receiving_transaction_succeeds() -> CrateResult<()> {
+        let mut aggregator = Aggregator::new();
+        let (mut client, mut rollup_state) = setup(300).await?;
+        let mut alice = Wallet::new(None);
+
+        client.append_transaction_to_batch(alice.public_key, 100)?;
+        let batch = client.produce_batch()?;
+
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+
+        let signature = client.validate_and_sign_proof(&merkle_tree_proof)?;
+
+        aggregator.add_signature(&client.public_key, &signature)?;
+
+        let transfer_block = aggregator.finalise()?;
+
+        rollup_state.add_transfer_block(transfer_block).await?;
+
+        alice
+            .add_receiving_transaction(&merkle_tree_proof, &client.balance_proof, &rollup_state)
+            .await?;
+
+        assert_eq!(alice.balance, 100);
+        assert_eq!(client.balance, 200);
+
+        Ok(())
+    }
+
+    async fn complete_aggregator_round(
+        sender: &mut Wallet,
+        rollup_state: &mut MockRollupMemory,
+        amount: u64,
+    ) -> CrateResult<Wallet> {
+        let mut aggregator = Aggregator::new();
+
+        let mut receiver = Wallet::new(None);
+
+        sender.append_transaction_to_batch(receiver.public_key, amount)?;
+        let batch = sender.produce_batch()?;
+
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        let signature = sender.validate_and_sign_proof(&merkle_tree_proof)?;
 
         aggregator.add_signature(&sender.public_key, &signature)?;
 
@@ -535,6 +1953,36 @@ mod tests {
         Ok(receiver)
     }
 
+    #[tokio::test]
+    async fn test_pruned_balance_proof_still_backs_up_the_balance() -> CrateResult<()> {
+        let (client, mut rollup_state) = setup(10).await?;
+
+        // Every hop deposits as much as it forwards, so only the last sender's proof is needed
+        let mut next_sender = client;
+        for _ in 0..5 {
+            let mut receiver =
+                complete_aggregator_round(&mut next_sender, &mut rollup_state, 10).await?;
+            rollup_state.add_deposit(&receiver.public_key, 10).await?;
+            receiver.sync_rollup_state(&rollup_state).await?;
+
+            next_sender = receiver;
+        }
+        assert_eq!(next_sender.balance_proof.len(), 5);
+
+        assert_eq!(next_sender.prune_balance_proof(&rollup_state).await?, 4);
+        assert_eq!(next_sender.balance_proof.len(), 1);
+        assert_eq!(next_sender.prune_balance_proof(&rollup_state).await?, 0);
+
+        next_sender.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(next_sender.balance, 20);
+
+        // The next receiver accepts the pruned proof
+        let receiver = complete_aggregator_round(&mut next_sender, &mut rollup_state, 20).await?;
+        assert_eq!(receiver.balance, 20);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_long_chain_of_transactions_still_can_be_spent() -> CrateResult<()> {
         let amount = 100;
@@ -591,7 +2039,9 @@ mod tests {
                 let custom_error = err.downcast_ref::<CrateError>();
                 assert_eq!(
                     custom_error,
-                    Some(&CrateError::BatchNotInATransferBlock(batch.clone()))
+                    Some(&CrateError::BatchNotInATransferBlock(Box::new(
+                        batch.clone()
+                    )))
                 );
             }
             _ => assert!(false, "Expected an error"),
@@ -600,6 +2050,51 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_relevant_proofs_only_drops_padding_from_senders_proof() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+        let mut receiver = Wallet::new(None);
+
+        // An unrelated transfer whose block never made it to the rollup, validating it would fail
+        let (mut stranger, _) = setup(50).await?;
+        stranger.append_transaction_to_batch(Wallet::new(None).public_key, 50)?;
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&stranger.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let stranger_proof = aggregator.generate_proof_for_pubkey(&stranger.public_key)?;
+        stranger.validate_and_sign_proof(&stranger_proof)?;
+
+        client.append_transaction_to_batch(receiver.public_key, 40)?;
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&client.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&client.public_key)?;
+        let signature = client.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&client.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        let mut padded_balance_proof = client.balance_proof.clone();
+        padded_balance_proof.extend(stranger.balance_proof.clone());
+
+        // Merging everything validates the padding too
+        assert!(receiver
+            .add_receiving_transaction(&proof, &padded_balance_proof, &rollup_state)
+            .await
+            .is_err());
+
+        receiver.relevant_proofs_only = true;
+        receiver
+            .add_receiving_transaction(&proof, &padded_balance_proof, &rollup_state)
+            .await?;
+
+        assert_eq!(receiver.balance, 40);
+        assert_eq!(receiver.balance_proof, client.balance_proof);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_wallet_persisted() -> CrateResult<()> {
         let mut rollup_state = MockRollupMemory::new();
@@ -634,4 +2129,761 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_wallet_persisted_to_storage_dir() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let mut rollup_state = MockRollupMemory::new();
+        let mut client = Wallet::with_storage_dir("alice", storage_dir.path())?;
+        rollup_state.add_deposit(&client.public_key, 100).await?;
+        client.sync_rollup_state(&rollup_state).await?;
+        client.add_contact("bob", Wallet::new(None).public_key)?;
+
+        assert!(storage_dir.path().join("alice.json").exists());
+
+        let loaded_wallet = Wallet::with_storage_dir("alice", storage_dir.path())?;
+
+        assert_eq!(loaded_wallet.public_key, client.public_key);
+        assert_eq!(loaded_wallet.contacts(), client.contacts());
+        assert_eq!(loaded_wallet.storage_dir(), storage_dir.path());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_wallet_file_errors_instead_of_replacing_the_key() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let path = storage_dir.path().join("alice.json");
+        let public_key = Wallet::with_storage_dir("alice", storage_dir.path())?.public_key;
+        let contents = std::fs::read(&path)?;
+
+        // Truncated part way through, as a crash mid-write would leave it, and plain garbage
+        for corrupted in [&contents[..contents.len() / 2], b"not a wallet".as_slice()] {
+            std::fs::write(&path, corrupted)?;
+
+            assert!(Wallet::with_storage_dir("alice", storage_dir.path()).is_err());
+            assert_eq!(std::fs::read(&path)?, corrupted);
+        }
+
+        std::fs::write(&path, &contents)?;
+        assert_eq!(
+            Wallet::with_storage_dir("alice", storage_dir.path())?.public_key,
+            public_key
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_persists_leave_a_valid_file() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let public_key = Wallet::with_storage_dir("alice", storage_dir.path())?.public_key;
+
+        // Two handles on the same wallet file, e.g. the CLI and a second process
+        let writers = (0..2)
+            .map(|_| {
+                let wallet = Wallet::with_storage_dir("alice", storage_dir.path())?;
+                Ok(std::thread::spawn(move || -> CrateResult<()> {
+                    for _ in 0..50 {
+                        wallet.save_wallet_state()?;
+                    }
+                    Ok(())
+                }))
+            })
+            .collect::<CrateResult<Vec<_>>>()?;
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+
+        assert_eq!(
+            Wallet::with_storage_dir("alice", storage_dir.path())?.public_key,
+            public_key
+        );
+        // Every write cleaned up after itself
+        let leftover_temp_files = std::fs::read_dir(storage_dir.path())?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|entry| entry.path().extension() == Some("tmp".as_ref()))
+            })
+            .count();
+        assert_eq!(leftover_temp_files, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_wallet_round_trips() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let path = storage_dir.path().join("alice.json");
+        let mut client = Wallet::with_encryption("alice", storage_dir.path(), "correct horse")?;
+        client.add_contact("bob", Wallet::new(None).public_key)?;
+
+        // Neither the key nor anything that parses back into it is in the file
+        let contents = std::fs::read_to_string(&path)?;
+        let plaintext_key =
+            serde_json::to_string(&BlsSecretKeyWrapper(client.private_key.clone()))?;
+        assert!(!contents.contains(plaintext_key.trim_matches('"')));
+        assert!(contents.contains("encrypted_private_key"));
+
+        let loaded_wallet = Wallet::with_encryption("alice", storage_dir.path(), "correct horse")?;
+
+        assert_eq!(loaded_wallet.public_key, client.public_key);
+        assert_eq!(loaded_wallet.contacts(), client.contacts());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_wallet_rejects_wrong_passphrase() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let path = storage_dir.path().join("alice.json");
+        Wallet::with_encryption("alice", storage_dir.path(), "correct horse")?;
+        let contents = std::fs::read(&path)?;
+
+        let err =
+            Wallet::with_encryption("alice", storage_dir.path(), "battery staple").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::WrongPassphrase)
+        );
+        // Loading without a passphrase doesn't fall back to a new key either
+        let err = Wallet::with_storage_dir("alice", storage_dir.path()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::PassphraseRequired)
+        );
+        assert_eq!(std::fs::read(&path)?, contents);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_persist_leaves_receive_unapplied() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+        let wallet_name = rand::random::<u64>().to_string();
+        let mut receiver = Wallet::new(Some(wallet_name.clone()));
+
+        client.append_transaction_to_batch(receiver.public_key, 40)?;
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&client.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&client.public_key)?;
+        let signature = client.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&client.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        // Crashing before the write made it to disk
+        receiver.fail_persist = true;
+        assert!(receiver
+            .add_receiving_transaction(&proof, &client.balance_proof, &rollup_state)
+            .await
+            .is_err());
+
+        // Memory and disk still agree on the state from before the receive
+        assert_eq!(receiver.balance, 0);
+        assert!(receiver.balance_proof.is_empty());
+        let mut recovered = Wallet::new(Some(wallet_name.clone()));
+        recovered.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(recovered.balance_proof, receiver.balance_proof);
+        assert_eq!(recovered.balance, receiver.balance);
+
+        // So the receive can just be retried
+        receiver.fail_persist = false;
+        receiver
+            .add_receiving_transaction(&proof, &client.balance_proof, &rollup_state)
+            .await?;
+        let mut recovered = Wallet::new(Some(wallet_name.clone()));
+        recovered.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(recovered.balance_proof, receiver.balance_proof);
+        assert_eq!(recovered.balance, 40);
+
+        std::fs::remove_file(format!("wallet_data/{}.json", wallet_name)).ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wallet_persists_forwarded_roots_and_confirmed_deliveries() -> CrateResult<()> {
+        let wallet_name = rand::random::<u64>().to_string();
+        let mut client = Wallet::new(Some(wallet_name.clone()));
+
+        let forwarded_root = generate_salt();
+        let confirmed_root = generate_salt();
+
+        client.forwarded_roots.insert(forwarded_root);
+        client.confirmed_deliveries.insert(confirmed_root);
+        client.save_wallet_state()?;
+
+        let loaded_wallet = Wallet::new(Some(wallet_name.clone()));
+
+        assert_eq!(loaded_wallet.forwarded_roots, client.forwarded_roots);
+        assert_eq!(
+            loaded_wallet.confirmed_deliveries,
+            client.confirmed_deliveries
+        );
+        assert!(loaded_wallet.forwarded_roots.contains(&forwarded_root));
+        assert!(loaded_wallet.confirmed_deliveries.contains(&confirmed_root));
+
+        std::fs::remove_file(format!("wallet_data/{}.json", wallet_name)).ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wallet_persists_contacts() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let mut client = Wallet::with_storage_dir("bob", storage_dir.path())?;
+        let alice = Wallet::new(None).public_key;
+
+        client.add_contact("alice", alice)?;
+
+        let mut loaded_wallet = Wallet::with_storage_dir("bob", storage_dir.path())?;
+        assert_eq!(loaded_wallet.resolve_contact("alice")?, alice);
+
+        loaded_wallet.remove_contact("alice")?;
+        assert!(Wallet::with_storage_dir("bob", storage_dir.path())?
+            .resolve_contact("alice")
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recompute_balance_ignores_cached_value() -> CrateResult<()> {
+        let (mut client, rollup_state) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        client.append_transaction_to_batch(receiver.public_key, 40)?;
+
+        assert_eq!(client.recompute_balance(&rollup_state).await?, 60);
+
+        // Corrupt the cached value, the recomputed balance is derived from the proof alone
+        client.balance = 1000;
+
+        assert_eq!(client.recompute_balance(&rollup_state).await?, 60);
+
+        client.sync_rollup_state(&rollup_state).await?;
+
+        assert_eq!(client.balance, 60);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cached_balance_matches_recomputed_balance_through_a_round() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(300).await?;
+        let mut receiver = Wallet::new(None);
+        client.assert_cached_balance = true;
+        receiver.assert_cached_balance = true;
+
+        let mut aggregator = Aggregator::new();
+
+        client.append_transaction_to_batch(receiver.public_key, 100)?;
+        let batch = client.produce_batch()?;
+
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        let signature = client.validate_and_sign_proof(&merkle_tree_proof)?;
+        aggregator.add_signature(&client.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        // The receiver has a pending batch of its own when the funds arrive
+        rollup_state.add_deposit(&receiver.public_key, 50).await?;
+        receiver.sync_rollup_state(&rollup_state).await?;
+        receiver.append_transaction_to_batch(client.public_key, 20)?;
+
+        receiver
+            .add_receiving_transaction(&merkle_tree_proof, &client.balance_proof, &rollup_state)
+            .await?;
+        client.sync_rollup_state(&rollup_state).await?;
+
+        assert_eq!(
+            client.balance,
+            client.recompute_balance(&rollup_state).await?
+        );
+        assert_eq!(receiver.balance, 130);
+        assert_eq!(
+            receiver.balance,
+            receiver.recompute_balance(&rollup_state).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aborted_batch_can_be_produced_again() -> CrateResult<()> {
+        let (mut client, _) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        assert!(client.abort_pending_batch().is_err());
+
+        client.append_transaction_to_batch(receiver.public_key, 40)?;
+        let batch = client.produce_batch()?;
+
+        client.abort_pending_batch()?;
+
+        assert_eq!(client.balance, 60);
+        assert_eq!(client.produce_batch()?, batch);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_batch_is_refunded() -> CrateResult<()> {
+        let (mut client, _) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        assert!(client.cancel_pending_batch().is_err());
+
+        client.append_transaction_to_batch(receiver.public_key, 40)?;
+        client.append_transaction_to_batch(receiver.public_key, 20)?;
+        client.produce_batch()?;
+
+        client.cancel_pending_batch()?;
+
+        assert_eq!(client.balance, 100);
+        assert!(!client.batch_is_pending());
+        assert!(client.transaction_batch.transactions.is_empty());
+
+        client.append_transaction_to_batch(receiver.public_key, 100)?;
+        assert_eq!(client.balance, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incoming_transfer_waits_for_finality_depth() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let (mut sender, mut rollup_state) = setup(300).await?;
+        let mut receiver = Wallet::with_storage_dir("bob", storage_dir.path())?;
+        receiver.finality_depth = 2;
+
+        let mut aggregator = Aggregator::new();
+        sender.append_transaction_to_batch(receiver.public_key, 100)?;
+        let batch = sender.produce_batch()?;
+
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        let signature = sender.validate_and_sign_proof(&merkle_tree_proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        receiver
+            .add_receiving_transaction(&merkle_tree_proof, &sender.balance_proof, &rollup_state)
+            .await?;
+
+        assert_eq!(receiver.balance, 0);
+        assert_eq!(receiver.pending_finality_balance(), 100);
+
+        // Held transfers survive a restart
+        let mut receiver = Wallet::with_storage_dir("bob", storage_dir.path())?;
+        receiver.finality_depth = 2;
+        assert_eq!(receiver.pending_finality_balance(), 100);
+
+        // Still only one confirmation
+        receiver.settle_pending_transfers(&rollup_state).await?;
+        assert_eq!(receiver.balance, 0);
+
+        // Another block on top gives the transfer its second confirmation
+        complete_aggregator_round(&mut sender, &mut rollup_state, 50).await?;
+        receiver.settle_pending_transfers(&rollup_state).await?;
+
+        assert_eq!(receiver.balance, 100);
+        assert_eq!(receiver.pending_finality_balance(), 0);
+        assert!(!receiver.has_pending_finality());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset_local_state_rebuilds_from_rollup() -> CrateResult<()> {
+        let (mut sender, mut rollup_state) = setup(100).await?;
+        let mut receiver = Wallet::new(None);
+        let public_key = receiver.public_key;
+
+        let mut aggregator = Aggregator::new();
+        sender.append_transaction_to_batch(receiver.public_key, 40)?;
+        let batch = sender.produce_batch()?;
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        let signature = sender.validate_and_sign_proof(&merkle_tree_proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        receiver
+            .add_receiving_transaction(&merkle_tree_proof, &sender.balance_proof, &rollup_state)
+            .await?;
+        receiver.forwarded_roots.insert(merkle_tree_proof.root);
+        assert_eq!(receiver.balance, 40);
+
+        receiver
+            .reset_local_state(&rollup_state, &HashMap::new())
+            .await?;
+
+        assert_eq!(receiver.public_key, public_key);
+        assert_eq!(receiver.balance, 0);
+        assert!(receiver.balance_proof.is_empty());
+        assert!(receiver.forwarded_roots.is_empty());
+
+        // Re-fetching the proof from the sender makes the funds spendable again
+        receiver
+            .add_receiving_transaction(&merkle_tree_proof, &sender.balance_proof, &rollup_state)
+            .await?;
+        assert_eq!(receiver.balance, 40);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset_local_state_is_refused_without_proofs_of_sends() -> CrateResult<()> {
+        let (mut sender, mut rollup_state) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        let mut aggregator = Aggregator::new();
+        sender.append_transaction_to_batch(receiver.public_key, 40)?;
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        let balance_proof = sender.balance_proof.clone();
+
+        // Rebuilding from deposits alone would give back the 40 that was sent
+        assert_eq!(
+            sender
+                .reset_local_state(&rollup_state, &HashMap::new())
+                .await
+                .unwrap_err()
+                .downcast::<CrateError>()?,
+            CrateError::UnprovenSends(1)
+        );
+        assert_eq!(sender.balance, 60);
+        assert_eq!(sender.balance_proof, balance_proof);
+
+        sender
+            .reset_local_state(&rollup_state, &balance_proof)
+            .await?;
+        assert_eq!(sender.balance, 60);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_receiving_transaction_is_idempotent() -> CrateResult<()> {
+        let (mut sender, mut rollup_state) = setup(100).await?;
+        let mut receiver = Wallet::new(None);
+
+        let mut aggregator = Aggregator::new();
+        sender.append_transaction_to_batch(receiver.public_key, 40)?;
+        let batch = sender.produce_batch()?;
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let merkle_tree_proof = aggregator.generate_proof_for_pubkey(&batch.from)?;
+        let signature = sender.validate_and_sign_proof(&merkle_tree_proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        for _ in 0..2 {
+            receiver
+                .add_receiving_transaction(&merkle_tree_proof, &sender.balance_proof, &rollup_state)
+                .await?;
+
+            assert_eq!(receiver.balance, 40);
+            assert_eq!(receiver.balance_proof.len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nonce_transactions_are_received() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(300).await?;
+        client.use_nonces = true;
+
+        let receiver = complete_aggregator_round(&mut client, &mut rollup_state, 100).await?;
+        let second_receiver =
+            complete_aggregator_round(&mut client, &mut rollup_state, 100).await?;
+
+        let nonces = client
+            .balance_proof
+            .values()
+            .map(|proof| {
+                (
+                    proof.batch.transactions[0].salt,
+                    proof.batch.transactions[0].nonce,
+                )
+            })
+            .collect::<HashSet<_>>();
+
+        assert_eq!(nonces, HashSet::from([(None, Some(0)), (None, Some(1))]));
+        assert_eq!(receiver.balance, 100);
+        assert_eq!(second_receiver.balance, 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_next_nonce_survives_a_restart() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let mut rollup_state = MockRollupMemory::new();
+
+        let mut client = Wallet::with_storage_dir("alice", storage_dir.path())?;
+        client.use_nonces = true;
+        rollup_state.add_deposit(&client.public_key, 100).await?;
+        client.sync_rollup_state(&rollup_state).await?;
+        client.append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+
+        // Killed before the batch was sent, the nonce is still used up
+        let reloaded = Wallet::with_storage_dir("alice", storage_dir.path())?;
+        assert_eq!(reloaded.next_nonce, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_wallet_persisted() -> CrateResult<()> {
+        let storage_dir = tempfile::TempDir::new()?;
+        let path = storage_dir.path().join("alice.json");
+        let mut rollup_state = MockRollupMemory::new();
+
+        let mut client = Wallet::with_storage_dir("alice", storage_dir.path())?;
+        rollup_state.add_deposit(&client.public_key, 100).await?;
+        client.sync_rollup_state(&rollup_state).await?;
+
+        let mut aggregator = Aggregator::new();
+        client.append_transaction_to_batch(Wallet::new(None).public_key, 100)?;
+        let batch = client.produce_batch()?;
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        client.validate_and_sign_proof(&aggregator.generate_proof_for_pubkey(&batch.from)?)?;
+        let json_size = std::fs::metadata(&path)?.len();
+
+        client.persistence_format = PersistenceFormat::Compact;
+        client.add_contact("bob", Wallet::new(None).public_key)?;
+        let contents = std::fs::read(&path)?;
+        assert!(contents.starts_with(&COMPACT_MAGIC_BYTES));
+        assert!((contents.len() as u64) < json_size);
+
+        let loaded_wallet = Wallet::with_storage_dir("alice", storage_dir.path())?;
+
+        assert_eq!(loaded_wallet.persistence_format, PersistenceFormat::Compact);
+        assert_eq!(loaded_wallet.public_key, client.public_key);
+        assert_eq!(client.balance_proof, loaded_wallet.balance_proof);
+        assert_eq!(client.contacts(), loaded_wallet.contacts());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_balance_proof_stats() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(300).await?;
+
+        assert_eq!(
+            client.balance_proof_stats(&rollup_state).await?,
+            ProofStats {
+                entries: 0,
+                distinct_roots: 0,
+                serialized_bytes: 2,
+                prunable_entries: 0,
+            }
+        );
+
+        complete_aggregator_round(&mut client, &mut rollup_state, 100).await?;
+        complete_aggregator_round(&mut client, &mut rollup_state, 100).await?;
+
+        let stats = client.balance_proof_stats(&rollup_state).await?;
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.distinct_roots, 2);
+        assert_eq!(
+            stats.serialized_bytes,
+            serde_json::to_vec(&client.balance_proof)?.len()
+        );
+        assert_eq!(stats.prunable_entries, 0);
+
+        // An entry for a root that never made it on chain, like one from a failed round
+        let (key, proof) = client
+            .balance_proof
+            .iter()
+            .next()
+            .map(|(key, proof)| (key.clone(), proof.clone()))
+            .unwrap();
+        client.balance_proof.insert(
+            BalanceProofKey {
+                root: [7; 32],
+                ..key
+            },
+            proof,
+        );
+
+        let stats = client.balance_proof_stats(&rollup_state).await?;
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.distinct_roots, 3);
+        assert_eq!(stats.prunable_entries, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_balance_breakdown_tracks_the_batch() -> CrateResult<()> {
+        let (mut client, _) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        client.append_transaction_to_batch(receiver.public_key, 30)?;
+        client.append_transaction_to_batch(receiver.public_key, 20)?;
+
+        assert_eq!(
+            client.balance_breakdown(),
+            BalanceBreakdown {
+                confirmed: 100,
+                pending_outgoing: 50,
+                spendable: 50,
+            }
+        );
+
+        client.produce_batch()?;
+        client.cancel_pending_batch()?;
+
+        assert_eq!(
+            client.balance_breakdown(),
+            BalanceBreakdown {
+                confirmed: 100,
+                pending_outgoing: 0,
+                spendable: 100,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_funds_locked_for_withdrawal_cannot_be_spent() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        client.lock_for_withdrawal(60, &rollup_state).await?;
+
+        assert_eq!(client.spendable_balance(), 40);
+        assert_eq!(
+            client
+                .append_transaction_to_batch(receiver.public_key, 50)
+                .unwrap_err()
+                .to_string(),
+            "Insufficient balance, 60 is locked for a pending withdrawal"
+        );
+        assert!(client.lock_for_withdrawal(10, &rollup_state).await.is_err());
+
+        client.append_transaction_to_batch(receiver.public_key, 40)?;
+        assert_eq!(client.spendable_balance(), 0);
+
+        // The lock is released once the withdrawal lands on-chain
+        rollup_state.add_withdraw(&client.public_key, 60).await?;
+        client.sync_rollup_state(&rollup_state).await?;
+
+        assert_eq!(client.withdrawal_lock(), None);
+        assert_eq!(client.balance, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_withdrawal_is_limited_to_the_provable_balance() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+
+        complete_aggregator_round(&mut client, &mut rollup_state, 40).await?;
+        client.sync_rollup_state(&rollup_state).await?;
+
+        let err = client
+            .request_withdrawal(70, &mut rollup_state)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::InsufficientWithdrawalBalance {
+                amount: 70,
+                available: 60
+            })
+        );
+        assert_eq!(
+            rollup_state
+                .get_account_withdraw_amount(&client.public_key)
+                .await?,
+            0
+        );
+
+        client.request_withdrawal(60, &mut rollup_state).await?;
+
+        assert_eq!(client.balance, 0);
+        assert_eq!(
+            rollup_state
+                .get_account_withdraw_amount(&client.public_key)
+                .await?,
+            60
+        );
+
+        client.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(client.balance, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aborted_withdrawal_releases_the_lock() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+
+        client.lock_for_withdrawal(100, &rollup_state).await?;
+
+        // An unrelated deposit doesn't release the lock
+        rollup_state.add_deposit(&client.public_key, 10).await?;
+        client.sync_rollup_state(&rollup_state).await?;
+        assert_eq!(client.spendable_balance(), 10);
+
+        client.abort_withdrawal()?;
+
+        assert_eq!(client.spendable_balance(), 110);
+        assert!(client.abort_withdrawal().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_produce_funded_batch_rejects_unfunded_batch() -> CrateResult<()> {
+        let (mut client, mut rollup_state) = setup(100).await?;
+        let receiver = Wallet::new(None);
+
+        client.append_transaction_to_batch(receiver.public_key, 80)?;
+        client.verify_batch_funding(&rollup_state).await?;
+
+        // A withdrawal lands between appending and producing
+        rollup_state.add_withdraw(&client.public_key, 50).await?;
+
+        assert_eq!(
+            client
+                .produce_funded_batch(&rollup_state)
+                .await
+                .unwrap_err()
+                .to_string(),
+            "Batch needs 80 but the provable balance is only 50"
+        );
+        assert!(!client.batch_is_pending());
+
+        Ok(())
+    }
 }
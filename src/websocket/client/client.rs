@@ -1,55 +1,230 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::ready,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 use anyhow::anyhow;
 use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+    stream::{poll_fn, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use log::{error, info, warn};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, Message},
 };
-use log::{error, info};
-use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle, time::timeout};
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
 use crate::{
-    errors::CrateResult,
+    constants::{KEEPALIVE_INTERVAL_SECONDS, KEEPALIVE_MAX_MISSED_PONGS},
+    errors::{is_rollup_unavailable, CrateError, CrateResult},
     rollup::traits::RollupStateTrait,
     types::{
         balance::{BalanceProof, BalanceProofKey},
-        common::{TransferBlock, U8_32},
-        signatures::BlsPublicKey,
+        common::{unix_timestamp_millis, TransferBlock, U8_32},
+        signatures::{BlsPublicKey, BlsSignature},
         transaction::TransactionProof,
     },
     wallet::wallet::Wallet,
-    websocket::ws_message::{parse_ws_message, WsMessage},
+    websocket::{
+        server::server_state::ServerState,
+        transport::{ClientTransport, InProcessTransport, WebSocketTransport},
+        ws_message::{parse_ws_message, Codec, WsMessage},
+    },
 };
 
-use super::constants::TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS;
+use super::{
+    constants::{
+        AUTH_CHALLENGE_TIMEOUT_SECONDS, BALANCE_QUERY_TIMEOUT_SECONDS,
+        CODEC_NEGOTIATION_TIMEOUT_SECONDS, RECONNECT_INITIAL_BACKOFF_MILLIS,
+        RECONNECT_MAX_BACKOFF_MILLIS, STORED_PROOF_TIMEOUT_SECONDS,
+        TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS,
+    },
+    mempool::{BatchStatus, Mempool},
+};
+
+// Funds credited to the wallet by a transaction in another account's batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomingTransfer {
+    pub from: BlsPublicKey,
+    pub amount: u64,
+    pub root: U8_32,
+    pub reference: Option<u64>,
+}
+
+// Waiting balance queries, oldest first. The server answers queries in the order they're sent.
+// Shared outside the client's lock so the receive handler can answer them without taking it
+type BalanceQueries = Arc<std::sync::Mutex<VecDeque<oneshot::Sender<u64>>>>;
+
+// Waiting stored balance proof requests, oldest first. None is a request_stored_balance_proof,
+// whose answer is merged into the wallet, the rest are handed back to the caller
+type StoredProofRequests = Arc<std::sync::Mutex<VecDeque<Option<oneshot::Sender<BalanceProof>>>>>;
+
+// Pings the server has left unanswered on a websocket connection, reset by anything it sends
+type MissedPongs = Arc<AtomicU32>;
+
+// How the receive handler gets back to the server after the websocket drops
+#[derive(Debug, Clone)]
+struct Reconnect {
+    port: u16,
+    codecs: Vec<Codec>,
+    max_attempts: u32,
+}
 
 #[derive(Debug)]
 pub struct Client {
     pub wallet: Wallet,
-    ws_send: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+    transport: Box<dyn ClientTransport>,
+    // Batches this client has sent and how far they've got
+    mempool: Mempool,
+    incoming_transfers: Option<mpsc::UnboundedSender<IncomingTransfer>>,
+    // When disabled, transfers from the server are queued in pending_receives until drained
+    auto_receive: bool,
+    pending_receives: Vec<(TransactionProof, BalanceProof)>,
+    // Signatures waiting to be flushed to the server, with the root and tx hash they're for
+    unsent_signatures: Vec<(U8_32, U8_32, BlsSignature)>,
+    // Set by shutdown, so the connection closing isn't mistaken for it dropping
+    closed: bool,
+    balance_queries: BalanceQueries,
+    stored_proof_requests: StoredProofRequests,
 }
 
 impl Client {
+    // When the connection drops the client reconnects with backoff, up to max_reconnect_attempts
+    // times in a row. 0 disables reconnecting
     pub async fn new(
-        mut wallet: Wallet,
+        wallet: Wallet,
         rollup_state: impl RollupStateTrait + Send + Clone + Sync + 'static,
         port: u16,
+        max_reconnect_attempts: u32,
     ) -> CrateResult<(
         Arc<Mutex<Self>>,
         JoinHandle<CrateResult<()>>,
         JoinHandle<CrateResult<()>>,
     )> {
-        wallet.sync_rollup_state(&rollup_state).await?;
+        Self::new_with_codecs(
+            wallet,
+            rollup_state,
+            port,
+            &[Codec::Json],
+            max_reconnect_attempts,
+        )
+        .await
+    }
 
+    // Negotiates one of the codecs, in order of preference, with the server before anything else
+    // is sent. Fails if the server supports none of them
+    pub async fn new_with_codecs(
+        wallet: Wallet,
+        rollup_state: impl RollupStateTrait + Send + Clone + Sync + 'static,
+        port: u16,
+        codecs: &[Codec],
+        max_reconnect_attempts: u32,
+    ) -> CrateResult<(
+        Arc<Mutex<Self>>,
+        JoinHandle<CrateResult<()>>,
+        JoinHandle<CrateResult<()>>,
+    )> {
+        let (transport, messages, missed_pongs) = Self::open_websocket(port).await?;
+        let reconnect = (max_reconnect_attempts > 0).then(|| Reconnect {
+            port,
+            codecs: codecs.to_vec(),
+            max_attempts: max_reconnect_attempts,
+        });
+
+        Self::connect(
+            wallet,
+            transport,
+            messages,
+            rollup_state,
+            codecs,
+            Some(missed_pongs),
+            reconnect,
+        )
+        .await
+    }
+
+    async fn open_websocket(
+        port: u16,
+    ) -> CrateResult<(
+        WebSocketTransport,
+        BoxStream<'static, CrateResult<WsMessage>>,
+        MissedPongs,
+    )> {
         let (socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
-        let (mut ws_send, ws_receive) = socket.split();
+        let (ws_send, ws_receive) = socket.split();
+
+        let missed_pongs = MissedPongs::default();
+        let heard_from_server = missed_pongs.clone();
+        let messages = ws_receive
+            .filter_map(move |msg| {
+                heard_from_server.store(0, Ordering::Relaxed);
+
+                // Pings are answered by tungstenite, neither they nor pongs are messages
+                ready(match msg {
+                    Ok(Message::Ping(_) | Message::Pong(_)) => None,
+                    msg => Some(msg.map_err(Into::into).and_then(parse_ws_message)),
+                })
+            })
+            .boxed();
 
-        // Register the wallet's public key with the server
-        let message: Message = WsMessage::CAddConnection(wallet.public_key.clone()).into();
-        ws_send.send(message).await?;
+        Ok((WebSocketTransport::new(ws_send), messages, missed_pongs))
+    }
+
+    // Connects directly to a server running in the same process, skipping the websocket
+    pub async fn new_in_process(
+        wallet: Wallet,
+        server_state: Arc<Mutex<ServerState>>,
+        rollup_state: impl RollupStateTrait + Send + Clone + Sync + 'static,
+    ) -> CrateResult<(
+        Arc<Mutex<Self>>,
+        JoinHandle<CrateResult<()>>,
+        JoinHandle<CrateResult<()>>,
+    )> {
+        let (transport, mut receiver) = InProcessTransport::new(server_state);
+        let messages = poll_fn(move |cx| receiver.poll_recv(cx).map(|msg| msg.map(Ok)));
+
+        Self::connect(
+            wallet,
+            transport,
+            messages,
+            rollup_state,
+            &[Codec::Json],
+            None,
+            None,
+        )
+        .await
+    }
+
+    async fn connect(
+        mut wallet: Wallet,
+        mut transport: impl ClientTransport + 'static,
+        mut messages: impl Stream<Item = CrateResult<WsMessage>> + Send + Unpin + 'static,
+        rollup_state: impl RollupStateTrait + Send + Clone + Sync + 'static,
+        codecs: &[Codec],
+        missed_pongs: Option<MissedPongs>,
+        reconnect: Option<Reconnect>,
+    ) -> CrateResult<(
+        Arc<Mutex<Self>>,
+        JoinHandle<CrateResult<()>>,
+        JoinHandle<CrateResult<()>>,
+    )> {
+        wallet.sync_rollup_state(&rollup_state).await?;
+
+        Self::handshake(&wallet, &mut transport, &mut messages, codecs).await?;
 
-        let client = Arc::new(Mutex::new(Self { wallet, ws_send }));
+        let client = Arc::new(Mutex::new(Self::new_without_background_tasks(
+            wallet, transport,
+        )));
 
         let automatic_sync_handler = Self::spawn_automatic_sync_thread(
             client.clone(),
@@ -58,19 +233,248 @@ impl Client {
         )
         .await?;
 
-        let ws_receive_handler =
-            Self::spawn_ws_receive_handler(client.clone(), ws_receive, rollup_state);
+        let ws_receive_handler = Self::spawn_ws_receive_handler(
+            client.clone(),
+            messages,
+            rollup_state,
+            missed_pongs,
+            reconnect,
+        )
+        .await;
 
         Ok((client, automatic_sync_handler, ws_receive_handler))
     }
 
+    // Run on every new connection, including reconnects
+    async fn handshake(
+        wallet: &Wallet,
+        transport: &mut impl ClientTransport,
+        messages: &mut (impl Stream<Item = CrateResult<WsMessage>> + Unpin),
+        codecs: &[Codec],
+    ) -> CrateResult<()> {
+        // Register the wallet's public key with the server
+        transport.add_connection(wallet.public_key).await?;
+
+        if transport.requires_authentication() {
+            let challenge = timeout(
+                Duration::from_secs(AUTH_CHALLENGE_TIMEOUT_SECONDS),
+                messages.next(),
+            )
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the server's auth challenge"))?;
+
+            let Some(Ok(WsMessage::SAuthChallenge(nonce))) = challenge else {
+                return Err(anyhow!(
+                    "Expected the server's auth challenge, got {:?}",
+                    challenge
+                ));
+            };
+
+            transport
+                .authenticate(wallet.sign_auth_challenge(&nonce)?)
+                .await?;
+        }
+
+        // Both sides start out on JSON, so there's only something to agree on for other codecs
+        if codecs != [Codec::Json] {
+            transport.negotiate_codec(codecs.to_vec()).await?;
+
+            let reply = timeout(
+                Duration::from_secs(CODEC_NEGOTIATION_TIMEOUT_SECONDS),
+                messages.next(),
+            )
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the server to pick a codec"))?;
+
+            match reply {
+                Some(Ok(WsMessage::SCodecSelected(codec))) => {
+                    info!("Negotiated codec {:?}", codec);
+                    transport.set_codec(codec);
+                }
+                Some(Ok(WsMessage::SCodecRejected(supported_codecs))) => {
+                    return Err(anyhow!(CrateError::NoCommonCodec).context(format!(
+                        "Server only supports {:?}, requested {:?}",
+                        supported_codecs, codecs
+                    )));
+                }
+                reply => {
+                    return Err(anyhow!(
+                        "Expected the server's codec choice, got {:?}",
+                        reply
+                    ));
+                }
+            }
+        }
+        transport
+            .request_proof_delta(wallet.balance_proof.keys().cloned().collect())
+            .await?;
+
+        // If the batch was sent before a disconnect, the round may still be waiting on our signature
+        if wallet.batch_is_pending() {
+            transport.resume_round().await?;
+        }
+
+        Ok(())
+    }
+
+    // Waits longer after each failed attempt. The handshake is redone on the new connection
+    // before it replaces the dead transport
+    async fn reconnect(
+        client: &Arc<Mutex<Client>>,
+        reconnect: &Reconnect,
+    ) -> CrateResult<(BoxStream<'static, CrateResult<WsMessage>>, MissedPongs)> {
+        let max_backoff = Duration::from_millis(RECONNECT_MAX_BACKOFF_MILLIS);
+        let mut backoff = Duration::from_millis(RECONNECT_INITIAL_BACKOFF_MILLIS);
+
+        for attempt in 1..=reconnect.max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(max_backoff);
+
+            let result = async {
+                let (mut transport, mut messages, missed_pongs) =
+                    Self::open_websocket(reconnect.port).await?;
+
+                let mut client = client.lock().await;
+                Self::handshake(
+                    &client.wallet,
+                    &mut transport,
+                    &mut messages,
+                    &reconnect.codecs,
+                )
+                .await?;
+                client.transport = Box::new(transport);
+
+                CrateResult::Ok((messages, missed_pongs))
+            }
+            .await;
+
+            match result {
+                Ok(connection) => {
+                    info!("Reconnected to the server on attempt {}", attempt);
+                    return Ok(connection);
+                }
+                Err(e) => warn!(
+                    "Reconnect attempt {} of {} failed: {:?}",
+                    attempt, reconnect.max_attempts, e
+                ),
+            }
+        }
+
+        Err(CrateError::ReconnectFailed(reconnect.max_attempts).into())
+    }
+
+    // Doesn't connect, sync or listen for server messages, the caller drives the client directly
+    pub fn new_without_background_tasks(
+        wallet: Wallet,
+        transport: impl ClientTransport + 'static,
+    ) -> Self {
+        Self {
+            wallet,
+            transport: Box::new(transport),
+            mempool: Mempool::default(),
+            incoming_transfers: None,
+            auto_receive: true,
+            pending_receives: vec![],
+            unsent_signatures: vec![],
+            closed: false,
+            balance_queries: BalanceQueries::default(),
+            stored_proof_requests: StoredProofRequests::default(),
+        }
+    }
+
+    // Every transfer credited to the wallet from then on is sent to the returned receiver,
+    // subscribing again replaces the previous receiver
+    pub fn subscribe_incoming_transfers(&mut self) -> mpsc::UnboundedReceiver<IncomingTransfer> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.incoming_transfers = Some(sender);
+
+        receiver
+    }
+
+    // Anything new in the balance proof that pays this wallet has just been credited
+    fn notify_incoming_transfers(&mut self, previous_keys: &HashSet<BalanceProofKey>) {
+        let Some(sender) = &self.incoming_transfers else {
+            return;
+        };
+
+        for (key, proof) in self.wallet.balance_proof.iter() {
+            if previous_keys.contains(key) {
+                continue;
+            }
+
+            // One event per transaction, so each reference can be matched up separately
+            for transaction in proof.batch.transactions.iter() {
+                if transaction.to != self.wallet.public_key || transaction.amount == 0 {
+                    continue;
+                }
+
+                let transfer = IncomingTransfer {
+                    from: proof.batch.from,
+                    amount: transaction.amount,
+                    root: proof.root,
+                    reference: transaction.reference,
+                };
+
+                if sender.send(transfer).is_err() {
+                    info!("Incoming transfer receiver was dropped, unsubscribing");
+                    self.incoming_transfers = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Settles incoming transfers that have reached the finality depth
+    async fn settle_pending_transfers(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let previous_keys = self.wallet.balance_proof.keys().cloned().collect();
+
+        let result = self.wallet.settle_pending_transfers(rollup_state).await;
+
+        self.notify_incoming_transfers(&previous_keys);
+
+        result
+    }
+
+    // Lets an embedding application choose when incoming transfers change the balance, e.g. to
+    // pause them during maintenance. Re-enabling doesn't apply what's queued, that's left to
+    // drain_pending_receives
+    pub fn set_auto_receive(&mut self, enabled: bool) {
+        self.auto_receive = enabled;
+    }
+
+    pub fn pending_receives(&self) -> usize {
+        self.pending_receives.len()
+    }
+
+    pub async fn drain_pending_receives(
+        &mut self,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let receives = std::mem::take(&mut self.pending_receives);
+        if receives.is_empty() {
+            return Ok(());
+        }
+
+        self.apply_receiving_transactions(receives, rollup_state)
+            .await
+    }
+
+    pub fn mempool(&self) -> Vec<BatchStatus> {
+        self.mempool.statuses()
+    }
+
     pub async fn send_transaction_batch(&mut self) -> CrateResult<()> {
         info!("Sending transaction batch to server");
 
         let batch = self.wallet.produce_batch()?;
-        let message: Message = WsMessage::CSendTransactionBatch(batch).into();
+        let tx_hash = batch.tx_hash();
+
+        self.transport.send_transaction_batch(batch).await?;
 
-        self.ws_send.send(message).await?;
+        self.mempool.track_sent(tx_hash);
 
         Ok(())
     }
@@ -78,16 +482,57 @@ impl Client {
     pub async fn validate_sign_proof_send_signature(
         &mut self,
         proof: &TransactionProof,
+    ) -> CrateResult<()> {
+        self.validate_sign_proof_queue_signature(proof)?;
+
+        self.flush_signatures().await
+    }
+
+    // Signs the proof but holds onto the signature until the next flush, so signatures for
+    // several proofs go to the server in one message
+    pub fn validate_sign_proof_queue_signature(
+        &mut self,
+        proof: &TransactionProof,
     ) -> CrateResult<()> {
         info!("Validating and signing proof");
 
         let signature = self.wallet.validate_and_sign_proof(&proof)?;
 
-        let message: Message =
-            WsMessage::CSendTransactionBatchSignature(self.wallet.public_key, signature).into();
+        self.unsent_signatures
+            .push((proof.root, proof.batch.tx_hash(), signature));
 
-        info!("Sending signature to server");
-        self.ws_send.send(message).await?;
+        Ok(())
+    }
+
+    // A single signature is sent on its own so servers without batch signature support still
+    // accept it
+    pub async fn flush_signatures(&mut self) -> CrateResult<()> {
+        let signatures = std::mem::take(&mut self.unsent_signatures);
+
+        match signatures.as_slice() {
+            [] => return Ok(()),
+            [(root, _, signature)] => {
+                info!("Sending signature to server");
+                self.transport
+                    .send_transaction_batch_signature(self.wallet.public_key, *root, *signature)
+                    .await?;
+            }
+            _ => {
+                info!("Sending {} signatures to server", signatures.len());
+                self.transport
+                    .send_batch_signatures(
+                        signatures
+                            .iter()
+                            .map(|(root, _, signature)| (*root, *signature))
+                            .collect(),
+                    )
+                    .await?;
+            }
+        }
+
+        for (root, tx_hash, _) in signatures {
+            self.mempool.mark_signed(&tx_hash, root);
+        }
 
         Ok(())
     }
@@ -104,30 +549,96 @@ impl Client {
             return Err(anyhow!("No proof found for the given root and public key"));
         }
 
-        let message: Message = WsMessage::CSendBatchToReceivers(
-            proof.unwrap().clone(),
-            self.wallet.balance_proof.clone(),
-        )
-        .into();
-
-        self.ws_send.send(message).await?;
+        self.transport
+            .send_batch_to_receivers(proof.unwrap().clone(), self.wallet.balance_proof.clone())
+            .await?;
 
         Ok(())
     }
 
-    async fn add_receiving_transaction(
+    async fn add_receiving_transactions(
         &mut self,
-        proof: &TransactionProof,
-        senders_balance_proof: &BalanceProof,
+        receives: Vec<(TransactionProof, BalanceProof)>,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        if !self.auto_receive {
+            info!(
+                "Auto receive is disabled, queueing {} receives",
+                receives.len()
+            );
+            self.pending_receives.extend(receives);
+            return Ok(());
+        }
+
+        self.apply_receiving_transactions(receives, rollup_state)
+            .await
+    }
+
+    // Receives that arrive together are applied with one merge and persist, if that fails they're
+    // retried one at a time so a single bad transfer doesn't block the rest
+    async fn apply_receiving_transactions(
+        &mut self,
+        receives: Vec<(TransactionProof, BalanceProof)>,
         rollup_state: &(impl RollupStateTrait + Send + Sync),
     ) -> CrateResult<()> {
-        info!("Adding receive transaction to wallet");
+        info!("Adding {} receive transactions to wallet", receives.len());
 
         let previous_balance = self.wallet.balance;
+        let previous_keys = self.wallet.balance_proof.keys().cloned().collect();
+
+        if let Err(e) = self
+            .wallet
+            .add_receiving_transactions(&receives, rollup_state)
+            .await
+        {
+            // Nothing wrong with the transfers, so they're queued for the sync thread to retry
+            if is_rollup_unavailable(&e) {
+                warn!(
+                    "Rollup unavailable, retrying {} receives later: {:?}",
+                    receives.len(),
+                    e
+                );
+                self.pending_receives.extend(receives);
+                return Ok(());
+            }
+
+            if receives.len() == 1 {
+                return Err(e);
+            }
+
+            warn!(
+                "Failed to add receives together, adding individually: {:?}",
+                e
+            );
+            for (proof, senders_balance_proof) in receives.into_iter() {
+                if let Err(e) = self
+                    .wallet
+                    .add_receiving_transaction(&proof, &senders_balance_proof, rollup_state)
+                    .await
+                {
+                    if is_rollup_unavailable(&e) {
+                        warn!("Rollup unavailable, retrying receive later: {:?}", e);
+                        self.pending_receives.push((proof, senders_balance_proof));
+                    } else {
+                        error!("Failed to add receive transaction: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        self.notify_incoming_transfers(&previous_keys);
+
+        // So the next balance proof forwarded to us skips what we've just merged
+        if self.wallet.balance_proof.len() != previous_keys.len() {
+            if let Err(e) = self
+                .transport
+                .request_proof_delta(self.wallet.balance_proof.keys().cloned().collect())
+                .await
+            {
+                warn!("Failed to update the balance proof keys we hold: {:?}", e);
+            }
+        }
 
-        self.wallet
-            .add_receiving_transaction(proof, senders_balance_proof, rollup_state)
-            .await?;
         info!(
             "Previous balance: {}, new balance: {}",
             previous_balance, self.wallet.balance
@@ -173,11 +684,36 @@ impl Client {
 
         let mut last_sync_state = get_sync_state(&rollup_state, &public_key).await?;
 
+        // Rollup errors are logged and retried on the next tick, a rollup that's briefly
+        // unreachable shouldn't stop the wallet syncing for good
         Ok(tokio::spawn(async move {
             loop {
                 tokio::time::sleep(tokio::time::Duration::from_secs(sync_rate_seconds)).await;
 
-                let new_sync_state = get_sync_state(&rollup_state, &public_key).await?;
+                {
+                    let mut client = client.lock().await;
+                    if client.wallet.has_pending_finality() {
+                        if let Err(e) = client.settle_pending_transfers(&rollup_state).await {
+                            warn!("Failed to settle pending transfers, retrying: {:?}", e);
+                        }
+                    }
+
+                    // Only receives that hit an unavailable rollup are queued while auto receive
+                    // is on
+                    if client.auto_receive {
+                        if let Err(e) = client.drain_pending_receives(&rollup_state).await {
+                            error!("Failed to add queued receives: {:?}", e);
+                        }
+                    }
+                }
+
+                let new_sync_state = match get_sync_state(&rollup_state, &public_key).await {
+                    Ok(sync_state) => sync_state,
+                    Err(e) => {
+                        warn!("Failed to read the rollup state, retrying: {:?}", e);
+                        continue;
+                    }
+                };
 
                 if new_sync_state != last_sync_state {
                     if new_sync_state.transfer_blocks != last_sync_state.transfer_blocks {
@@ -198,20 +734,25 @@ impl Client {
                             .collect::<Vec<TransferBlock>>();
 
                         for block in new_transfer_blocks {
+                            let mut client = client.lock().await;
+                            client.mempool.mark_confirmed(&block.merkle_root);
                             client
-                                .lock()
-                                .await
                                 .send_batch_with_root_to_receivers(block.merkle_root)
                                 .await?;
                         }
                     } else {
                         info!("Detected new deposit or withdraw, syncing state...");
-                        client
+                        if let Err(e) = client
                             .lock()
                             .await
                             .wallet
                             .sync_rollup_state(&rollup_state)
-                            .await?;
+                            .await
+                        {
+                            // Keeping the last good state means it's picked up again next tick
+                            warn!("Failed to sync the rollup state, retrying: {:?}", e);
+                            continue;
+                        }
                     }
                 }
 
@@ -220,33 +761,99 @@ impl Client {
         }))
     }
 
-    fn spawn_ws_receive_handler(
+    // Without reconnect the handler exits once the connection drops. With missed_pongs the server
+    // is pinged, and the connection is treated as dropped once it stops answering
+    async fn spawn_ws_receive_handler(
         client: Arc<Mutex<Client>>,
-        mut ws_receive: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+        messages: impl Stream<Item = CrateResult<WsMessage>> + Send + Unpin + 'static,
         rollup_state: impl RollupStateTrait + Send + Sync + 'static,
+        mut missed_pongs: Option<MissedPongs>,
+        reconnect: Option<Reconnect>,
     ) -> JoinHandle<CrateResult<()>> {
         async fn handle_ws_message(
             client: Arc<Mutex<Client>>,
-            msg: Result<Message, tokio_tungstenite::tungstenite::Error>,
+            ws_message: CrateResult<WsMessage>,
             rollup_state: &(impl RollupStateTrait + Send + Sync),
+            balance_queries: &BalanceQueries,
+            stored_proof_requests: &StoredProofRequests,
         ) -> CrateResult<()> {
-            let ws_message = parse_ws_message(msg?)?;
+            let ws_message = ws_message?;
 
             match ws_message {
-                WsMessage::SSendTransactionInclusionProof(proof) => {
+                WsMessage::SSendTransactionInclusionProof(proof, sign_by) => {
+                    let now = unix_timestamp_millis(SystemTime::now());
+                    if now > sign_by {
+                        warn!(
+                            "Signing deadline passed {}ms ago, the signature may be too late",
+                            now - sign_by
+                        );
+                    } else {
+                        info!("Signing inclusion proof, {}ms left to sign", sign_by - now);
+                    }
+
+                    // Flushed once the rest of the burst has been handled
                     client
                         .lock()
                         .await
-                        .validate_sign_proof_send_signature(&proof)
-                        .await?;
+                        .validate_sign_proof_queue_signature(&proof)?;
                 }
                 WsMessage::SReceiveTransaction(proof, balance_proof) => {
                     client
                         .lock()
                         .await
-                        .add_receiving_transaction(&proof, &balance_proof, rollup_state)
+                        .add_receiving_transactions(vec![(proof, balance_proof)], rollup_state)
                         .await?
                 }
+                WsMessage::SStoredBalanceProof(balance_proof) => {
+                    let request = stored_proof_requests
+                        .lock()
+                        .map_err(|_| anyhow!("Stored proof requests lock was poisoned"))?
+                        .pop_front();
+
+                    match request {
+                        // The caller may have timed out and stopped waiting
+                        Some(Some(request)) => {
+                            let _ = request.send(balance_proof);
+                        }
+                        _ => {
+                            client
+                                .lock()
+                                .await
+                                .wallet
+                                .restore_balance_proof(&balance_proof, rollup_state)
+                                .await?
+                        }
+                    }
+                }
+                WsMessage::SRateLimited => {
+                    warn!("Transaction batch was rate limited by the server");
+                }
+                WsMessage::SBalanceResponse(balance) => {
+                    let query = balance_queries
+                        .lock()
+                        .map_err(|_| anyhow!("Balance queries lock was poisoned"))?
+                        .pop_front();
+
+                    match query {
+                        // The caller may have timed out and stopped waiting
+                        Some(query) => {
+                            let _ = query.send(balance);
+                        }
+                        None => warn!("Received a balance nobody asked for"),
+                    }
+                }
+                // The server closes the connection next, it's left closed rather than reconnected
+                WsMessage::SConnectionReplaced => {
+                    warn!("Another connection for this wallet took over, not reconnecting");
+                    client.lock().await.closed = true;
+                }
+                WsMessage::SRoundFailed(root) => {
+                    warn!("Round {:?} failed, aborting pending batch", root);
+                    let mut client = client.lock().await;
+                    let tx_hash = client.wallet.transaction_batch.tx_hash();
+                    client.wallet.abort_pending_batch()?;
+                    client.mempool.mark_dropped(&tx_hash);
+                }
                 _ => {
                     return Err(anyhow!("Invalid message type"));
                 }
@@ -255,30 +862,270 @@ impl Client {
             Ok(())
         }
 
+        async fn handle_receives(
+            client: Arc<Mutex<Client>>,
+            receives: Vec<(TransactionProof, BalanceProof)>,
+            rollup_state: &(impl RollupStateTrait + Send + Sync),
+        ) {
+            if receives.is_empty() {
+                return;
+            }
+
+            if let Err(e) = client
+                .lock()
+                .await
+                .add_receiving_transactions(receives, rollup_state)
+                .await
+            {
+                error!("Error handling message: {:?}", e);
+            }
+        }
+
+        // Errors from the socket itself rather than a message that couldn't be parsed
+        fn is_connection_lost(ws_message: &CrateResult<WsMessage>) -> bool {
+            matches!(ws_message, Err(e) if e.downcast_ref::<tungstenite::Error>().is_some())
+        }
+
+        let mut messages = messages.boxed();
+
+        // Shared with the task up front, so it never needs the client's lock to answer a query
+        let (balance_queries, stored_proof_requests) = {
+            let client = client.lock().await;
+            (
+                client.balance_queries.clone(),
+                client.stored_proof_requests.clone(),
+            )
+        };
+
         tokio::spawn(async move {
+            let mut ping_interval =
+                tokio::time::interval(Duration::from_secs(KEEPALIVE_INTERVAL_SECONDS));
+            ping_interval.tick().await;
+
             loop {
-                if let Some(msg) = ws_receive.next().await {
-                    if let Err(e) = handle_ws_message(client.clone(), msg, &rollup_state).await {
-                        error!("Error handling message: {:?}", e);
+                loop {
+                    let ws_message = tokio::select! {
+                        ws_message = messages.next() => ws_message,
+                        _ = ping_interval.tick(), if missed_pongs.is_some() => {
+                            let missed = missed_pongs
+                                .as_ref()
+                                .map_or(0, |missed| missed.fetch_add(1, Ordering::Relaxed));
+                            if missed >= KEEPALIVE_MAX_MISSED_PONGS {
+                                warn!("Server missed {} pongs in a row", missed);
+                                break;
+                            }
+
+                            if let Err(e) = client.lock().await.transport.ping().await {
+                                warn!("Failed to ping the server: {:?}", e);
+                            }
+
+                            continue;
+                        }
+                    };
+
+                    let Some(ws_message) = ws_message else {
+                        break;
+                    };
+
+                    let mut ws_messages = vec![ws_message];
+                    // Drain whatever else is already queued, so a burst of receives is coalesced
+                    while let Some(Some(ws_message)) = messages.next().now_or_never() {
+                        ws_messages.push(ws_message);
+                    }
+
+                    let connection_lost = ws_messages.iter().any(is_connection_lost);
+
+                    let mut receives = vec![];
+                    for ws_message in ws_messages {
+                        if let Ok(WsMessage::SReceiveTransaction(proof, balance_proof)) = ws_message
+                        {
+                            receives.push((proof, balance_proof));
+                            continue;
+                        }
+
+                        // Keep the order relative to other messages
+                        handle_receives(
+                            client.clone(),
+                            std::mem::take(&mut receives),
+                            &rollup_state,
+                        )
+                        .await;
+
+                        if let Err(e) = handle_ws_message(
+                            client.clone(),
+                            ws_message,
+                            &rollup_state,
+                            &balance_queries,
+                            &stored_proof_requests,
+                        )
+                        .await
+                        {
+                            error!("Error handling message: {:?}", e);
+                        }
+                    }
+
+                    handle_receives(client.clone(), receives, &rollup_state).await;
+
+                    if connection_lost {
+                        break;
+                    }
+
+                    if let Err(e) = client.lock().await.flush_signatures().await {
+                        error!("Error sending signatures: {:?}", e);
                     }
                 }
+
+                let Some(reconnect) = &reconnect else {
+                    return Ok(());
+                };
+
+                if client.lock().await.closed {
+                    return Ok(());
+                }
+
+                // Queries sent on the old connection will never be answered, dropping them fails
+                // their callers rather than pairing them up with answers to newer queries
+                if let Ok(mut balance_queries) = balance_queries.lock() {
+                    balance_queries.clear();
+                }
+                if let Ok(mut stored_proof_requests) = stored_proof_requests.lock() {
+                    stored_proof_requests.clear();
+                }
+
+                warn!("Lost the connection to the server, reconnecting");
+                let (new_messages, new_missed_pongs) = Self::reconnect(&client, reconnect).await?;
+                messages = new_messages;
+                missed_pongs = Some(new_missed_pongs);
+
+                // Anything signed while disconnected goes out on the new connection
+                if let Err(e) = client.lock().await.flush_signatures().await {
+                    error!("Error sending signatures: {:?}", e);
+                }
             }
         })
     }
 
-    pub async fn shutdown(&mut self) -> CrateResult<()> {
-        let _ = timeout(Duration::from_secs(2), self.ws_send.close()).await;
+    // Asks the server for the batches in a block that pay this wallet, for when the sender never
+    // forwarded them. They're applied like any other receive once they arrive
+    pub async fn request_proof_for_root(&mut self, root: U8_32) -> CrateResult<()> {
+        self.transport.request_proof_for_root(root).await
+    }
+
+    // Asks the server for the proofs it stored for this wallet, for when the wallet lost its own.
+    // They're merged into the wallet's balance proof once they arrive
+    pub async fn request_stored_balance_proof(&mut self) -> CrateResult<()> {
+        self.send_stored_balance_proof_request(None).await
+    }
+
+    // Same request, but the proofs are handed to the returned receiver rather than merged. Await
+    // it without holding the client's lock, the receive handler needs it for anything ahead of
+    // the answer
+    pub async fn fetch_stored_balance_proof(
+        &mut self,
+    ) -> CrateResult<oneshot::Receiver<BalanceProof>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_stored_balance_proof_request(Some(sender)).await?;
+
+        Ok(receiver)
+    }
+
+    async fn send_stored_balance_proof_request(
+        &mut self,
+        request: Option<oneshot::Sender<BalanceProof>>,
+    ) -> CrateResult<()> {
+        self.stored_proof_requests
+            .lock()
+            .map_err(|_| anyhow!("Stored proof requests lock was poisoned"))?
+            .push_back(request);
+
+        if let Err(e) = self.transport.request_stored_balance_proof().await {
+            // Requests are sent under the client's lock, so the newest one is this one
+            if let Ok(mut stored_proof_requests) = self.stored_proof_requests.lock() {
+                stored_proof_requests.pop_back();
+            }
+
+            return Err(e);
+        }
 
         Ok(())
     }
+
+    // Resets the wallet's local state (see Wallet::reset_local_state) with whatever the server's
+    // proof store has for it. Refused if the store doesn't cover every block the wallet signed,
+    // including when the server doesn't store proofs at all
+    pub async fn reset_local_state(
+        client: &Mutex<Client>,
+        rollup_state: &(impl RollupStateTrait + Send + Sync),
+    ) -> CrateResult<()> {
+        let receiver = client.lock().await.fetch_stored_balance_proof().await?;
+
+        let recovered_proof =
+            match timeout(Duration::from_secs(STORED_PROOF_TIMEOUT_SECONDS), receiver).await {
+                Ok(Ok(balance_proof)) => balance_proof,
+                _ => {
+                    warn!("Server didn't send its stored proofs, resetting without them");
+                    HashMap::new()
+                }
+            };
+
+        client
+            .lock()
+            .await
+            .wallet
+            .reset_local_state(rollup_state, &recovered_proof)
+            .await
+    }
+
+    // Asks the server for the balance the rollup has for this wallet, the receiver gets the answer.
+    // Await it without holding the client's lock, see query_balance
+    pub async fn request_balance(&mut self) -> CrateResult<oneshot::Receiver<u64>> {
+        let (sender, receiver) = oneshot::channel();
+        self.balance_queries
+            .lock()
+            .map_err(|_| anyhow!("Balance queries lock was poisoned"))?
+            .push_back(sender);
+
+        if let Err(e) = self.transport.query_balance(self.wallet.public_key).await {
+            // Queries are sent under the client's lock, so the newest one is this one
+            if let Ok(mut balance_queries) = self.balance_queries.lock() {
+                balance_queries.pop_back();
+            }
+
+            return Err(e);
+        }
+
+        Ok(receiver)
+    }
+
+    // The balance the rollup has for this wallet, deposits less withdraws, as the server sees it.
+    // The lock is only held to send the query, the receive handler needs it for anything the
+    // server sent ahead of the answer
+    pub async fn query_balance(client: &Mutex<Client>) -> CrateResult<u64> {
+        let receiver = client.lock().await.request_balance().await?;
+
+        timeout(Duration::from_secs(BALANCE_QUERY_TIMEOUT_SECONDS), receiver)
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the server to answer the balance query"))?
+            .map_err(|_| anyhow!("Connection dropped before the balance query was answered"))
+    }
+
+    pub async fn shutdown(&mut self) -> CrateResult<()> {
+        self.closed = true;
+        self.transport.close().await
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::aggregator::Aggregator;
+    use crate::rollup::faulty_rollup::FaultyRollup;
     use crate::rollup::mock_rollup_memory::MockRollupMemory;
-    use crate::rollup::traits::MockRollupStateTrait;
+    use crate::rollup::traits::{MockRollupStateTrait, RollupStateTrait};
     use crate::websocket::client::constants::TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS;
+    use crate::websocket::client::mempool::BatchState;
+    use crate::websocket::server::server::spawn_aggregator_server;
     use crate::websocket::server::server_state::ServerState;
+    use crate::websocket::transport::ChannelTransport;
 
     use super::*;
 
@@ -292,13 +1139,438 @@ mod tests {
         // Delay 1s to allow the server to start
         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
 
-        let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port).await?;
+        let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port, 0).await?;
 
         Ok((server.clone(), client, rollup_state))
     }
 
+    // Polls until the server has a connection for the key
+    async fn wait_for_connection(
+        server_state: &Arc<Mutex<ServerState>>,
+        public_key: &BlsPublicKey,
+    ) -> CrateResult<()> {
+        timeout(Duration::from_secs(10), async {
+            while !server_state
+                .lock()
+                .await
+                .connection_report()
+                .iter()
+                .any(|status| status.public_key == *public_key)
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("Client never registered with the server"))
+    }
+
+    #[tokio::test]
+    async fn test_client_reregisters_after_the_server_restarts() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        // Long delays so the block producer never runs a round on its own
+        let server =
+            spawn_aggregator_server(rollup_state.clone(), None, Some(1000), Some(1000)).await?;
+        let port = server.port;
+
+        let (client, _, receive_handler) =
+            Client::new(Wallet::new(None), rollup_state.clone(), port, 20).await?;
+        let public_key = client.lock().await.wallet.public_key;
+        wait_for_connection(&server.server_state, &public_key).await?;
+
+        server.shutdown().await?;
+
+        let server =
+            spawn_aggregator_server(rollup_state.clone(), Some(port), Some(1000), Some(1000))
+                .await?;
+
+        wait_for_connection(&server.server_state, &public_key).await?;
+        assert!(!receive_handler.is_finished());
+
+        // Shutting down the client doesn't set off another reconnect
+        client.lock().await.shutdown().await?;
+        timeout(Duration::from_secs(5), receive_handler).await???;
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn test_replaced_client_doesnt_reconnect() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+        let storage_dir = tempfile::TempDir::new()?;
+
+        // Both load the same key
+        let (first, _, first_receive_handler) = Client::new(
+            Wallet::with_storage_dir("alice", storage_dir.path())?,
+            rollup_state.clone(),
+            port,
+            20,
+        )
+        .await?;
+        let public_key = first.lock().await.wallet.public_key;
+        wait_for_connection(&server, &public_key).await?;
+
+        let (second, _, second_receive_handler) = Client::new(
+            Wallet::with_storage_dir("alice", storage_dir.path())?,
+            rollup_state.clone(),
+            port,
+            20,
+        )
+        .await?;
+        assert_eq!(second.lock().await.wallet.public_key, public_key);
+
+        // The first client gives up its connection rather than taking it back
+        timeout(Duration::from_secs(5), first_receive_handler).await???;
+        assert!(!second_receive_handler.is_finished());
+        assert_eq!(server.lock().await.connection_report().len(), 1);
+        assert_eq!(Client::query_balance(&second).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_sends_messages_through_transport() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let (transport, mut sent) = ChannelTransport::new();
+        let mut client = Client::new_without_background_tasks(Wallet::new(None), transport);
+        let public_key = client.wallet.public_key;
+
+        rollup_state.add_deposit(&public_key, 100).await?;
+        client.wallet.sync_rollup_state(&rollup_state).await?;
+        client
+            .wallet
+            .append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+
+        client.send_transaction_batch().await?;
+
+        let batch = match sent.try_recv().ok() {
+            Some(WsMessage::CSendTransactionBatch(batch)) => batch,
+            message => panic!("Expected CSendTransactionBatch, got {:?}", message),
+        };
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&batch)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&public_key)?;
+
+        client.validate_sign_proof_send_signature(&proof).await?;
+        client.send_batch_with_root_to_receivers(proof.root).await?;
+
+        assert!(matches!(
+            sent.try_recv()?,
+            WsMessage::CSendTransactionBatchSignature(from, root, _)
+                if from == public_key && root == proof.root
+        ));
+        assert!(matches!(
+            sent.try_recv()?,
+            WsMessage::CSendBatchToReceivers(sent_proof, _) if sent_proof == proof
+        ));
+        assert!(sent.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mempool_tracks_sent_batches() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let (transport, _sent) = ChannelTransport::new();
+        let mut client = Client::new_without_background_tasks(Wallet::new(None), transport);
+        let public_key = client.wallet.public_key;
+
+        rollup_state.add_deposit(&public_key, 100).await?;
+        client.wallet.sync_rollup_state(&rollup_state).await?;
+        client
+            .wallet
+            .append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+        client.send_transaction_batch().await?;
+
+        let tx_hash = client.wallet.transaction_batch.tx_hash();
+        assert_eq!(client.mempool()[0].tx_hash, tx_hash);
+        assert_eq!(client.mempool()[0].state, BatchState::Sent);
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&client.wallet.transaction_batch)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&public_key)?;
+        client.validate_sign_proof_send_signature(&proof).await?;
+
+        assert_eq!(client.mempool()[0].state, BatchState::Signed);
+        assert_eq!(client.mempool()[0].root, Some(proof.root));
+
+        client.mempool.mark_confirmed(&proof.root);
+
+        assert_eq!(client.mempool().len(), 1);
+        assert_eq!(client.mempool()[0].state, BatchState::Confirmed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flood_of_receives_is_applied() -> CrateResult<()> {
+        const NUM_SENDERS: u64 = 20;
+
+        let storage_dir = tempfile::TempDir::new()?;
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (transport, _sent) = ChannelTransport::new();
+        let client = Arc::new(Mutex::new(Client::new_without_background_tasks(
+            Wallet::with_storage_dir("bob", storage_dir.path())?,
+            transport,
+        )));
+        let receiver_public_key = client.lock().await.wallet.public_key;
+        let persists_before = client.lock().await.wallet.persist_count();
+
+        // Queue every receive up front so the handler sees them all at once
+        let (ws_send, mut ws_receive) = tokio::sync::mpsc::unbounded_channel();
+        for _ in 0..NUM_SENDERS {
+            let mut sender = Wallet::new(None);
+            rollup_state.add_deposit(&sender.public_key, 10).await?;
+            sender.sync_rollup_state(&rollup_state).await?;
+            sender.append_transaction_to_batch(receiver_public_key, 10)?;
+
+            let mut aggregator = Aggregator::new();
+            aggregator.add_batch(&sender.produce_batch()?)?;
+            aggregator.start_collecting_signatures()?;
+            let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&sender.public_key, &signature)?;
+            rollup_state
+                .add_transfer_block(aggregator.finalise()?)
+                .await?;
+
+            ws_send.send(Ok(WsMessage::SReceiveTransaction(
+                proof,
+                sender.balance_proof.clone(),
+            )))?;
+        }
+        drop(ws_send);
+
+        let messages = poll_fn(move |cx| ws_receive.poll_recv(cx));
+        Client::spawn_ws_receive_handler(
+            client.clone(),
+            messages,
+            rollup_state.clone(),
+            None,
+            None,
+        )
+        .await
+        .await??;
+
+        let client = client.lock().await;
+        assert_eq!(client.wallet.balance, NUM_SENDERS * 10);
+        assert_eq!(client.wallet.balance_proof.len(), NUM_SENDERS as usize);
+        // All of them were merged and written in one go
+        assert_eq!(client.wallet.persist_count(), persists_before + 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incoming_transfer_event_fires() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let (transport, mut sent) = ChannelTransport::new();
+        let mut client = Client::new_without_background_tasks(Wallet::new(None), transport);
+        let mut incoming_transfers = client.subscribe_incoming_transfers();
+
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(client.wallet.public_key, 40)?;
+        sender.append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        let receive = (proof.clone(), sender.balance_proof.clone());
+        client
+            .add_receiving_transactions(vec![receive.clone()], &rollup_state)
+            .await?;
+
+        assert_eq!(
+            incoming_transfers.try_recv()?,
+            IncomingTransfer {
+                from: sender.public_key,
+                amount: 40,
+                root: proof.root,
+                reference: None,
+            }
+        );
+        // The server is told which entries we now hold, so they aren't forwarded again
+        match sent.try_recv()? {
+            WsMessage::CRequestProofDelta { have_keys } => assert_eq!(
+                have_keys.into_iter().collect::<HashSet<_>>(),
+                client.wallet.balance_proof.keys().cloned().collect()
+            ),
+            message => panic!("Expected CRequestProofDelta, got {:?}", message),
+        }
+
+        // Receiving the same transfer again doesn't credit it twice
+        client
+            .add_receiving_transactions(vec![receive], &rollup_state)
+            .await?;
+        assert!(incoming_transfers.try_recv().is_err());
+        assert!(sent.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disabled_auto_receive_queues_transfers_until_drained() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (transport, _sent) = ChannelTransport::new();
+        let client = Arc::new(Mutex::new(Client::new_without_background_tasks(
+            Wallet::new(None),
+            transport,
+        )));
+        client.lock().await.set_auto_receive(false);
+        let receiver_public_key = client.lock().await.wallet.public_key;
+
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(receiver_public_key, 30)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        let (ws_send, mut ws_receive) = tokio::sync::mpsc::unbounded_channel();
+        ws_send.send(Ok(WsMessage::SReceiveTransaction(
+            proof,
+            sender.balance_proof.clone(),
+        )))?;
+        drop(ws_send);
+
+        let messages = poll_fn(move |cx| ws_receive.poll_recv(cx));
+        Client::spawn_ws_receive_handler(
+            client.clone(),
+            messages,
+            rollup_state.clone(),
+            None,
+            None,
+        )
+        .await
+        .await??;
+
+        let mut client = client.lock().await;
+        assert_eq!(client.wallet.balance, 0);
+        assert_eq!(client.pending_receives(), 1);
+
+        client.drain_pending_receives(&rollup_state).await?;
+
+        assert_eq!(client.wallet.balance, 30);
+        assert_eq!(client.pending_receives(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_thread_survives_unavailable_rollup() -> CrateResult<()> {
+        let mut rollup_state = FaultyRollup::new(Arc::new(Mutex::new(MockRollupMemory::new())));
+        let (transport, _sent) = ChannelTransport::new();
+        let client = Arc::new(Mutex::new(Client::new_without_background_tasks(
+            Wallet::new(None),
+            transport,
+        )));
+        let receiver_public_key = client.lock().await.wallet.public_key;
+
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(receiver_public_key, 30)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        rollup_state
+            .add_transfer_block(aggregator.finalise()?)
+            .await?;
+
+        let sync_handle =
+            Client::spawn_automatic_sync_thread(client.clone(), rollup_state.clone(), 1).await?;
+
+        rollup_state.set_failing(true);
+        rollup_state.add_deposit(&receiver_public_key, 100).await?;
+
+        // The transfer itself is fine, so it's queued rather than rejected
+        client
+            .lock()
+            .await
+            .add_receiving_transactions(vec![(proof, sender.balance_proof.clone())], &rollup_state)
+            .await?;
+        assert_eq!(client.lock().await.pending_receives(), 1);
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        assert!(!sync_handle.is_finished());
+        assert_eq!(client.lock().await.wallet.balance, 0);
+
+        rollup_state.set_failing(false);
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+        let client = client.lock().await;
+        assert_eq!(client.wallet.balance, 130);
+        assert_eq!(client.pending_receives(), 0);
+
+        Ok(())
+    }
+
     const SLEEP_TIME_SECONDS: u64 = TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS + 1;
 
+    #[tokio::test]
+    async fn test_query_balance_doesnt_hold_the_client_while_waiting() -> CrateResult<()> {
+        let (transport, mut sent) = ChannelTransport::new();
+        let client = Arc::new(Mutex::new(Client::new_without_background_tasks(
+            Wallet::new(None),
+            transport,
+        )));
+
+        let query = tokio::spawn({
+            let client = client.clone();
+            async move { Client::query_balance(&client).await }
+        });
+
+        // Nothing answers, the client is still free for the receive handler meanwhile
+        assert!(matches!(
+            sent.recv().await,
+            Some(WsMessage::CQueryBalance(_))
+        ));
+        assert!(client.try_lock().is_ok());
+        assert!(!query.is_finished());
+        query.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_balance_returns_the_servers_view() -> CrateResult<()> {
+        let (_, client, mut rollup_state) = setup().await?;
+        let public_key = client.lock().await.wallet.public_key;
+
+        rollup_state.add_deposit(&public_key, 100).await?;
+        // Answered straight away, without waiting for the automatic sync
+        assert_eq!(Client::query_balance(&client).await?, 100);
+
+        rollup_state.add_withdraw(&public_key, 30).await?;
+        assert_eq!(Client::query_balance(&client).await?, 70);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_auto_syncs_deposits() -> CrateResult<()> {
         let (_, client, mut rollup_state) = setup().await?;
@@ -1 +1,24 @@
 pub const TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS: u64 = 1;
+
+// Number of batches the client's mempool remembers before evicting the oldest settled ones
+pub const MEMPOOL_HISTORY_LIMIT: usize = 50;
+
+// How long the client waits for the server to answer a codec negotiation when connecting
+pub const CODEC_NEGOTIATION_TIMEOUT_SECONDS: u64 = 5;
+
+// How long the client waits for the server's auth challenge after adding the connection
+pub const AUTH_CHALLENGE_TIMEOUT_SECONDS: u64 = 5;
+
+// How long Client::query_balance waits for the server to answer
+pub const BALANCE_QUERY_TIMEOUT_SECONDS: u64 = 5;
+
+// How long a reset waits for the server to send the proofs it stored for the wallet
+pub const STORED_PROOF_TIMEOUT_SECONDS: u64 = 5;
+
+// Used by the CLI wallet, how many times the client tries to get back to the server after the
+// connection drops
+pub const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+// The delay before each reconnect attempt doubles from the initial one up to the max
+pub const RECONNECT_INITIAL_BACKOFF_MILLIS: u64 = 100;
+pub const RECONNECT_MAX_BACKOFF_MILLIS: u64 = 5_000;
@@ -0,0 +1,87 @@
+use indexmap::IndexMap;
+
+use crate::types::common::U8_32;
+
+use super::constants::MEMPOOL_HISTORY_LIMIT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchState {
+    // Sent to the aggregator, waiting on the inclusion proof
+    Sent,
+    // Signed the round's root, waiting for the transfer block to land
+    Signed,
+    // The transfer block is on the rollup
+    Confirmed,
+    // The round failed without the batch
+    Dropped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchStatus {
+    pub tx_hash: U8_32,
+    // Root of the round the batch was signed in
+    pub root: Option<U8_32>,
+    pub state: BatchState,
+}
+
+// The client's view of the batches it has sent, keyed by the batch's tx_hash in the order they
+// were sent. Settled batches are kept around for a while so UIs can show recent history
+#[derive(Debug, Default)]
+pub struct Mempool {
+    batches: IndexMap<U8_32, BatchStatus>,
+}
+
+impl Mempool {
+    // A batch that's resent after its round failed goes back to Sent
+    pub fn track_sent(&mut self, tx_hash: U8_32) {
+        self.batches.shift_remove(&tx_hash);
+        self.batches.insert(
+            tx_hash,
+            BatchStatus {
+                tx_hash,
+                root: None,
+                state: BatchState::Sent,
+            },
+        );
+
+        self.evict_settled();
+    }
+
+    pub fn mark_signed(&mut self, tx_hash: &U8_32, root: U8_32) {
+        if let Some(status) = self.batches.get_mut(tx_hash) {
+            status.root = Some(root);
+            status.state = BatchState::Signed;
+        }
+    }
+
+    pub fn mark_confirmed(&mut self, root: &U8_32) {
+        for status in self.batches.values_mut() {
+            if status.root.as_ref() == Some(root) {
+                status.state = BatchState::Confirmed;
+            }
+        }
+    }
+
+    pub fn mark_dropped(&mut self, tx_hash: &U8_32) {
+        if let Some(status) = self.batches.get_mut(tx_hash) {
+            status.state = BatchState::Dropped;
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<BatchStatus> {
+        self.batches.values().cloned().collect()
+    }
+
+    // In flight batches are never evicted, only the oldest confirmed or dropped ones
+    fn evict_settled(&mut self) {
+        while self.batches.len() > MEMPOOL_HISTORY_LIMIT {
+            let Some(index) = self.batches.values().position(|status| {
+                matches!(status.state, BatchState::Confirmed | BatchState::Dropped)
+            }) else {
+                break;
+            };
+
+            self.batches.shift_remove_index(index);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 pub mod client;
 pub mod server;
+#[cfg(test)]
 pub mod tests;
-mod transport;
+pub mod transport;
 pub mod ws_message;
@@ -1,17 +1,19 @@
 use anyhow::anyhow;
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
 use log::*;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::Mutex,
-    task::{self, JoinHandle},
+    task::JoinHandle,
+    time::timeout,
 };
 use tokio_tungstenite::{accept_async, tungstenite::Message};
 
 use crate::{
-    errors::CrateResult,
-    types::signatures::BlsPublicKey,
+    constants::AUTH_RESPONSE_TIMEOUT_SECONDS,
+    errors::{CrateError, CrateResult},
+    types::{auth::verify_auth_challenge, signatures::BlsPublicKey},
     websocket::{
         server::server_state::Connection,
         ws_message::{parse_ws_message, WsMessage},
@@ -27,11 +29,18 @@ pub async fn spawn_websocket_server(
     let addr = format!("127.0.0.1:{}", port.unwrap_or(0));
     let listener = TcpListener::bind(&addr).await?;
     let port = listener.local_addr().unwrap().port();
+    let mut shutdown = server_state.lock().await.shutdown_receiver();
     let handler = tokio::spawn(async move {
         info!("Listening on: {}", addr);
 
         loop {
-            let listener_value = listener.accept().await;
+            let listener_value = tokio::select! {
+                listener_value = listener.accept() => listener_value,
+                _ = shutdown.wait_for(|is_shutdown| *is_shutdown) => {
+                    info!("Stopped listening on: {}", addr);
+                    return Ok(());
+                }
+            };
 
             let server_state = server_state.clone();
 
@@ -59,23 +68,6 @@ pub async fn spawn_websocket_server(
     Ok((handler, port))
 }
 
-struct ConnectionGuard {
-    public_key: BlsPublicKey,
-    server_state: Arc<Mutex<ServerState>>,
-}
-
-impl Drop for ConnectionGuard {
-    fn drop(&mut self) {
-        let server_state = self.server_state.clone();
-        let public_key = self.public_key.clone();
-        task::spawn(async move {
-            // Perform the cleanup asynchronously
-            let mut state = server_state.lock().await;
-            state.remove_connection(&public_key).await
-        });
-    }
-}
-
 pub async fn handle_connection(
     peer: SocketAddr,
     stream: TcpStream,
@@ -83,60 +75,207 @@ pub async fn handle_connection(
 ) -> CrateResult<()> {
     let ws_stream = accept_async(stream).await.expect("Failed to accept");
     info!("New WebSocket connection: {}", peer);
-    let (ws_sender, mut ws_receiver) = ws_stream.split();
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
     let msg = ws_receiver
         .next()
         .await
         .ok_or(anyhow!("Must send public key as first message"))?;
 
-    // Declare the guard here so that it is dropped when the function returns, which will remove the connection
-    let _guard: ConnectionGuard;
+    let public_key = if let WsMessage::CAddConnection(public_key) = parse_ws_message(msg?)? {
+        public_key
+    } else {
+        return Err(anyhow!("Must send public key as first message"));
+    };
+
+    // Anyone can claim a public key, so the client has to prove it holds the secret key before
+    // anything meant for that key is routed to it
+    let nonce = rand::random();
+    ws_sender
+        .send(WsMessage::SAuthChallenge(nonce).into())
+        .await?;
 
-    if let WsMessage::CAddConnection(public_key) = parse_ws_message(msg?)? {
-        info!(
-            "Received public key, adding connection: {:?}",
-            serde_json::to_string(&public_key)?
-        );
+    let msg = timeout(
+        Duration::from_secs(AUTH_RESPONSE_TIMEOUT_SECONDS),
+        ws_receiver.next(),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out waiting for the auth response"))?
+    .ok_or(anyhow!(
+        "Connection closed before answering the auth challenge"
+    ))?;
 
-        let connection = Connection {
-            public_key: public_key.clone(),
-            ws_send: ws_sender,
-        };
-        _guard = ConnectionGuard {
-            public_key: public_key.clone(),
-            server_state: server_state.clone(),
-        };
+    let WsMessage::CAuthResponse(signature) = parse_ws_message(msg?)? else {
+        return Err(anyhow!(
+            "Must answer the auth challenge after adding the connection"
+        ));
+    };
 
-        server_state.lock().await.add_connection(connection);
-    } else {
-        return Err(anyhow!("Must send public key as first message"));
+    if let Err(e) = verify_auth_challenge(&public_key, &nonce, &signature) {
+        warn!("Rejecting connection {}, auth challenge failed", peer);
+        let _ = ws_sender.close().await;
+
+        return Err(e);
     }
 
-    loop {
-        if let Some(msg) = ws_receiver.next().await {
-            // Intentionally ignore errors here, as we don't want to drop the connection
-            if let Err(e) = handle_loop(msg, server_state.clone()).await {
-                error!("Error handling message: {:?}", e);
+    info!(
+        "Authenticated public key, adding connection: {:?}",
+        serde_json::to_string(&public_key)?
+    );
+
+    let connection = Connection::new(public_key, Box::new(ws_sender));
+    let (id, (keepalive_interval, max_missed_pongs)) = {
+        let mut server_state = server_state.lock().await;
+        // The connections may already have been closed, one added now would be left open
+        if server_state.is_shutting_down() {
+            return Err(anyhow!("Server is shutting down"));
+        }
+
+        (
+            server_state.add_connection(connection).await,
+            server_state.keepalive(),
+        )
+    };
+
+    // The first tick is immediate, there's no point pinging a connection that was just made
+    let mut ping_interval = tokio::time::interval(keepalive_interval);
+    ping_interval.tick().await;
+    let mut missed_pongs = 0;
+
+    let result = loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => msg,
+            _ = ping_interval.tick() => {
+                // A half open connection never answers, without this it would stay in the
+                // connections forever
+                if missed_pongs >= max_missed_pongs {
+                    warn!("Dropping connection {} after {} missed pongs", peer, missed_pongs);
+                    break Err(CrateError::MissedPongs(missed_pongs).into());
+                }
+
+                missed_pongs += 1;
+                if let Err(e) = server_state.lock().await.ping_connection(&public_key, id).await {
+                    warn!("Failed to ping connection {}: {:?}", peer, e);
+                }
+
+                continue;
             }
-        } else {
-            return Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed.into());
+        };
+
+        let Some(msg) = msg else {
+            break Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed.into());
+        };
+
+        // Anything from the client shows it's still there, not just pongs. Pings are answered by
+        // tungstenite
+        missed_pongs = 0;
+        if let Ok(Message::Ping(_) | Message::Pong(_)) = msg {
+            continue;
         }
-    }
+
+        // Intentionally ignore errors here, as we don't want to drop the connection
+        let ws_message = msg.map_err(Into::into).and_then(parse_ws_message);
+        let result = match ws_message {
+            Ok(ws_message) => handle_message(&public_key, ws_message, server_state.clone()).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("Error handling message: {:?}", e);
+        }
+    };
+
+    // Removed before returning rather than from a detached task, so the connection is gone by the
+    // time this finishes. A newer connection for the same key is left alone
+    server_state
+        .lock()
+        .await
+        .remove_connection_with_id(&public_key, id)
+        .await?;
+
+    result
 }
 
-async fn handle_loop(
-    msg: Result<Message, tokio_tungstenite::tungstenite::Error>,
+// Handles a message from an added connection, shared by the websocket and in process transports
+pub async fn handle_message(
+    public_key: &BlsPublicKey,
+    ws_message: WsMessage,
     server_state: Arc<Mutex<ServerState>>,
 ) -> CrateResult<()> {
-    let ws_message = parse_ws_message(msg?)?;
-
     match ws_message {
         WsMessage::CSendTransactionBatch(transaction_batch) => {
-            server_state.lock().await.add_batch(&transaction_batch)?;
+            if transaction_batch.from != *public_key {
+                return Err(anyhow!(
+                    "Transaction batch isn't from this connection's key"
+                ));
+            }
+
+            let mut server_state = server_state.lock().await;
+
+            // Rather than turning the batch away, the full round is moved on and it goes in the
+            // next one
+            if server_state.round_is_full() {
+                server_state.start_collecting_signatures().await?;
+            }
+
+            if let Err(e) = server_state.add_batch(&transaction_batch) {
+                if e.downcast_ref::<CrateError>() == Some(&CrateError::RateLimited) {
+                    server_state
+                        .send_to_connection(public_key, WsMessage::SRateLimited)
+                        .await?;
+                }
+
+                return Err(e);
+            }
+        }
+        WsMessage::CSendTransactionBatchSignature(from, root, signature) => {
+            server_state
+                .lock()
+                .await
+                .add_signature(&from, &root, &signature)?;
+        }
+        WsMessage::CSendBatchSignatures(signatures) => {
+            server_state
+                .lock()
+                .await
+                .add_signatures(public_key, &signatures)?;
+        }
+        WsMessage::CNegotiateCodec(codecs) => {
+            server_state
+                .lock()
+                .await
+                .negotiate_codec(public_key, &codecs)
+                .await?;
+        }
+        WsMessage::CRequestProofForRoot { root } => {
+            server_state
+                .lock()
+                .await
+                .send_stored_proofs_for_root(public_key, &root)
+                .await?;
+        }
+        WsMessage::CRequestStoredBalanceProof => {
+            server_state
+                .lock()
+                .await
+                .send_stored_balance_proof(public_key)
+                .await?;
+        }
+        WsMessage::CQueryBalance(account) => {
+            server_state
+                .lock()
+                .await
+                .send_account_balance(public_key, &account)
+                .await?;
+        }
+        WsMessage::CResumeRound => {
+            server_state.lock().await.resume_round(public_key).await?;
         }
-        WsMessage::CSendTransactionBatchSignature(from, signature) => {
-            server_state.lock().await.add_signature(&from, &signature)?;
+        WsMessage::CRequestProofDelta { have_keys } => {
+            server_state
+                .lock()
+                .await
+                .set_known_balance_proof_keys(public_key, have_keys)?;
         }
         WsMessage::CSendBatchToReceivers(proof, balance_proof) => {
             server_state
@@ -152,3 +291,310 @@ async fn handle_loop(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::{net::TcpListener, sync::Mutex, time::timeout};
+    use tokio_tungstenite::connect_async;
+
+    use crate::{
+        errors::{CrateError, CrateResult},
+        rollup::{mock_rollup_memory::MockRollupMemory, traits::RollupStateTrait},
+        types::{
+            auth::sign_auth_challenge,
+            common::generate_salt,
+            signatures::{BlsPublicKey, BlsSecretKey},
+            transaction::{SimpleTransaction, TransactionBatch},
+        },
+        wallet::wallet::Wallet,
+        websocket::{
+            client::client::Client,
+            server::server_state::{Connection, ServerState},
+            tests::add_raw_connection,
+            ws_message::{parse_ws_message, WsMessage},
+        },
+    };
+
+    use super::{handle_connection, handle_message, spawn_websocket_server};
+
+    #[tokio::test]
+    async fn test_connection_is_added_once_the_auth_challenge_is_answered() -> CrateResult<()> {
+        let server_state = Arc::new(Mutex::new(ServerState::new(MockRollupMemory::new())?));
+        let (_, port) = spawn_websocket_server(server_state.clone(), None).await?;
+
+        let secret_key = BlsSecretKey::new();
+        let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+        add_raw_connection(&mut socket, &secret_key).await?;
+
+        timeout(Duration::from_secs(5), async {
+            while server_state.lock().await.connection_report().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(
+            server_state.lock().await.connection_report()[0].public_key,
+            secret_key.public_key()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forged_public_key_is_rejected() -> CrateResult<()> {
+        let server_state = Arc::new(Mutex::new(ServerState::new(MockRollupMemory::new())?));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        // The victim is already connected, a successful forgery would take over their connection
+        let victim = BlsSecretKey::new().public_key();
+        let (transport, _received) = tokio::sync::mpsc::unbounded_channel::<WsMessage>();
+        server_state
+            .lock()
+            .await
+            .add_connection(Connection::new(victim, Box::new(transport)))
+            .await;
+
+        // Claims the victim's key but can only sign with its own
+        let attacker = tokio::spawn(async move {
+            let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+            socket
+                .send(WsMessage::CAddConnection(victim).into())
+                .await?;
+
+            let WsMessage::SAuthChallenge(nonce) = parse_ws_message(socket.next().await.unwrap()?)?
+            else {
+                panic!("Expected SAuthChallenge");
+            };
+            let signature = sign_auth_challenge(&BlsSecretKey::new(), &nonce)?;
+            socket
+                .send(WsMessage::CAuthResponse(signature).into())
+                .await?;
+
+            CrateResult::Ok(socket)
+        });
+
+        let (stream, peer) = listener.accept().await?;
+        let err = handle_connection(peer, stream, server_state.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::InvalidAuthSignature)
+        );
+
+        // The server closed the attacker's socket and left the victim's connection alone
+        let mut socket = attacker.await??;
+        assert!(socket.next().await.unwrap()?.is_close());
+        let connections = server_state.lock().await.connection_report();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].public_key, victim);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_is_removed_when_handler_returns() -> CrateResult<()> {
+        let server_state = Arc::new(Mutex::new(ServerState::new(MockRollupMemory::new())?));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let client = tokio::spawn(async move {
+            let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+            add_raw_connection(&mut socket, &BlsSecretKey::new()).await?;
+            socket.close(None).await?;
+
+            CrateResult::Ok(())
+        });
+
+        let (stream, peer) = listener.accept().await?;
+        assert!(handle_connection(peer, stream, server_state.clone())
+            .await
+            .is_err());
+        client.await??;
+
+        assert!(server_state.lock().await.connection_report().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_isnt_added_once_shutting_down() -> CrateResult<()> {
+        let server_state = Arc::new(Mutex::new(ServerState::new(MockRollupMemory::new())?));
+        server_state.lock().await.shutdown().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let client = tokio::spawn(async move {
+            let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+            add_raw_connection(&mut socket, &BlsSecretKey::new()).await?;
+
+            CrateResult::Ok(socket)
+        });
+
+        let (stream, peer) = listener.accept().await?;
+        assert!(handle_connection(peer, stream, server_state.clone())
+            .await
+            .is_err());
+        drop(client.await??);
+
+        assert!(server_state.lock().await.connection_report().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_is_dropped_after_missing_pongs() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let server_state = Arc::new(Mutex::new(ServerState::new(rollup_state.clone())?));
+        server_state
+            .lock()
+            .await
+            .set_keepalive(Duration::from_millis(50), 2);
+        let (_, port) = spawn_websocket_server(server_state.clone(), None).await?;
+
+        // Registers and then never reads, so the server's pings are never answered
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+        let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+        add_raw_connection(&mut socket, &secret_key).await?;
+
+        // A client that's listening answers the pings and stays connected
+        let (client, _, _) = Client::new(Wallet::new(None), rollup_state, port, 0).await?;
+        let client_public_key = client.lock().await.wallet.public_key;
+
+        let is_connected = |public_key: BlsPublicKey| {
+            let server_state = server_state.clone();
+            async move {
+                server_state
+                    .lock()
+                    .await
+                    .connection_report()
+                    .iter()
+                    .any(|status| status.public_key == public_key)
+            }
+        };
+
+        timeout(Duration::from_secs(5), async {
+            while !is_connected(public_key).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        timeout(Duration::from_secs(5), async {
+            while is_connected(public_key).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(is_connected(client_public_key).await);
+
+        drop(socket);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_for_a_full_round_starts_the_next_one() -> CrateResult<()> {
+        let server_state = Arc::new(Mutex::new(ServerState::new(MockRollupMemory::new())?));
+        server_state.lock().await.set_max_batches_per_block(1);
+
+        let mut public_keys = vec![];
+        for _ in 0..2 {
+            let secret_key = BlsSecretKey::new();
+            let public_key = secret_key.public_key();
+            let (transport, _received) = tokio::sync::mpsc::unbounded_channel::<WsMessage>();
+            server_state
+                .lock()
+                .await
+                .add_connection(Connection::new(public_key, Box::new(transport)))
+                .await;
+
+            let mut batch = TransactionBatch::new(public_key);
+            batch.transactions.push(SimpleTransaction {
+                to: BlsSecretKey::new().public_key(),
+                from: public_key,
+                amount: 10,
+                salt: Some(generate_salt()),
+                nonce: None,
+                reference: None,
+            });
+            batch.sign(&secret_key)?;
+
+            handle_message(
+                &public_key,
+                WsMessage::CSendTransactionBatch(batch),
+                server_state.clone(),
+            )
+            .await?;
+            public_keys.push(public_key);
+        }
+
+        // The first round filled up and is collecting signatures, the second batch is in a new one
+        let server = server_state.lock().await;
+        assert!(server.inclusion_proof(&public_keys[0]).is_ok());
+        assert!(server.inclusion_proof(&public_keys[1]).is_err());
+        assert!(server.round_is_full());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_signatures_sign_multiple_rounds_in_one_message() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let server_state = Arc::new(Mutex::new(ServerState::new(rollup_state.clone())?));
+
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+        let (transport, _received) = tokio::sync::mpsc::unbounded_channel::<WsMessage>();
+        server_state
+            .lock()
+            .await
+            .add_connection(Connection::new(public_key, Box::new(transport)))
+            .await;
+
+        // A batch in each of two rounds, the second submitted while the first collects signatures
+        let mut signatures = vec![];
+        for amount in [10, 20] {
+            let mut batch = TransactionBatch::new(public_key);
+            batch.transactions.push(SimpleTransaction {
+                to: BlsSecretKey::new().public_key(),
+                from: public_key,
+                amount,
+                salt: Some(generate_salt()),
+                nonce: None,
+                reference: None,
+            });
+            batch.sign(&secret_key)?;
+
+            let mut server = server_state.lock().await;
+            server.add_batch(&batch)?;
+            let root = server.start_collecting_signatures().await?.unwrap();
+
+            signatures.push((
+                root,
+                secret_key.sign(blsful::SignatureSchemes::MessageAugmentation, &root)?,
+            ));
+        }
+
+        handle_message(
+            &public_key,
+            WsMessage::CSendBatchSignatures(signatures),
+            server_state.clone(),
+        )
+        .await?;
+
+        // Rounds without a signature can't be finalised
+        let mut server = server_state.lock().await;
+        server.finalise().await?;
+        server.finalise().await?;
+
+        assert_eq!(rollup_state.get_transfer_blocks().await?.len(), 2);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
 pub mod connection;
+pub mod proof_store;
 pub mod server;
 pub mod server_state;
+pub mod webhook;
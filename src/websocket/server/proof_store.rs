@@ -0,0 +1,235 @@
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+
+use crate::{
+    constants::{PROOF_STORE_MAX_PROOFS, PROOF_STORE_RETENTION_SECONDS},
+    types::{
+        balance::{balance_proof_for_account, BalanceProof, BalanceProofKey},
+        common::U8_32,
+        signatures::BlsPublicKey,
+        transaction::TransactionProof,
+    },
+};
+
+// Inclusion proofs of finalised rounds, kept so clients can re-fetch them when the sender never
+// forwarded them or they lost their own. Proofs are dropped once they're older than the retention
+// window, or oldest first once there are more than max_proofs
+#[derive(Debug, Clone)]
+pub struct ProofStore {
+    // Ordered oldest first, along with when each proof was stored
+    proofs: IndexMap<BalanceProofKey, (TransactionProof, Instant)>,
+    max_proofs: usize,
+    retention: Duration,
+}
+
+impl Default for ProofStore {
+    fn default() -> Self {
+        Self::new(
+            PROOF_STORE_MAX_PROOFS,
+            Duration::from_secs(PROOF_STORE_RETENTION_SECONDS),
+        )
+    }
+}
+
+impl ProofStore {
+    pub fn new(max_proofs: usize, retention: Duration) -> Self {
+        Self {
+            proofs: IndexMap::new(),
+            max_proofs,
+            retention,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.proofs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proofs.is_empty()
+    }
+
+    pub fn insert(&mut self, proof: TransactionProof, now: Instant) {
+        let key = BalanceProofKey {
+            root: proof.root,
+            public_key: proof.batch.from.into(),
+        };
+
+        // Storing it again makes it the newest
+        self.proofs.shift_remove(&key);
+        self.proofs.insert(key, (proof, now));
+
+        self.evict(now);
+    }
+
+    pub fn evict(&mut self, now: Instant) {
+        while let Some((_, (_, stored_at))) = self.proofs.first() {
+            if now.duration_since(*stored_at) < self.retention {
+                break;
+            }
+
+            self.proofs.shift_remove_index(0);
+        }
+
+        while self.proofs.len() > self.max_proofs {
+            self.proofs.shift_remove_index(0);
+        }
+    }
+
+    pub fn get(&self, key: &BalanceProofKey) -> Option<&TransactionProof> {
+        self.proofs.get(key).map(|(proof, _)| proof)
+    }
+
+    pub fn proofs_for_root(&self, root: &U8_32) -> Vec<&TransactionProof> {
+        self.proofs
+            .values()
+            .map(|(proof, _)| proof)
+            .filter(|proof| proof.root == *root)
+            .collect()
+    }
+
+    // Stands in for an account's own balance proof, see balance_proof_for_account. Evicted proofs
+    // can leave it short
+    pub fn balance_proof_for(&self, public_key: &BlsPublicKey) -> BalanceProof {
+        balance_proof_for_account(
+            self.proofs.iter().map(|(key, (proof, _))| (key, proof)),
+            public_key.into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::{mock_rollup_memory::MockRollupMemory, traits::MockRollupStateTrait},
+        types::{
+            balance::BalanceProofKey,
+            common::generate_salt,
+            signatures::BlsSecretKey,
+            transaction::{SimpleTransaction, TransactionBatch, TransactionProof},
+        },
+        wallet::wallet::Wallet,
+    };
+
+    use super::ProofStore;
+
+    // A round with a batch from each of the new keys, returns their proofs
+    fn proofs_for_round(num_batches: usize) -> CrateResult<Vec<TransactionProof>> {
+        let mut aggregator = Aggregator::new();
+        let mut secret_keys = vec![];
+        for _ in 0..num_batches {
+            let secret_key = BlsSecretKey::new();
+            let mut batch = TransactionBatch::new(secret_key.public_key());
+            batch.transactions.push(SimpleTransaction {
+                to: BlsSecretKey::new().public_key(),
+                from: secret_key.public_key(),
+                amount: 10,
+                salt: Some(generate_salt()),
+                nonce: None,
+                reference: None,
+            });
+            batch.sign(&secret_key)?;
+            aggregator.add_batch(&batch)?;
+            secret_keys.push(secret_key);
+        }
+        aggregator.start_collecting_signatures()?;
+
+        secret_keys
+            .iter()
+            .map(|secret_key| aggregator.generate_proof_for_pubkey(&secret_key.public_key()))
+            .collect()
+    }
+
+    #[test]
+    fn test_stores_and_fetches_proofs_by_root() -> CrateResult<()> {
+        let mut store = ProofStore::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+
+        let first_round = proofs_for_round(2)?;
+        let second_round = proofs_for_round(1)?;
+        for proof in first_round.iter().chain(second_round.iter()) {
+            store.insert(proof.clone(), now);
+        }
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.proofs_for_root(&first_round[0].root).len(), 2);
+        assert_eq!(
+            store.proofs_for_root(&second_round[0].root),
+            vec![&second_round[0]]
+        );
+        assert_eq!(
+            store.get(&BalanceProofKey {
+                root: first_round[1].root,
+                public_key: first_round[1].batch.from.into(),
+            }),
+            Some(&first_round[1])
+        );
+        assert!(store.proofs_for_root(&[0; 32]).is_empty());
+
+        // Only the account's own proof, nobody paid it
+        let balance_proof = store.balance_proof_for(&second_round[0].batch.from);
+        assert_eq!(balance_proof.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evicts_expired_and_oldest_proofs() -> CrateResult<()> {
+        let mut store = ProofStore::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let proofs = proofs_for_round(3)?;
+
+        store.insert(proofs[0].clone(), now);
+        store.insert(proofs[1].clone(), now + Duration::from_secs(30));
+        store.insert(proofs[2].clone(), now + Duration::from_secs(40));
+
+        // Over capacity, so the oldest went first
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&key(&proofs[0])).is_none());
+
+        // The second proof has outlived the retention window, the third hasn't
+        store.evict(now + Duration::from_secs(95));
+        assert_eq!(store.len(), 1);
+        assert!(store.get(&key(&proofs[2])).is_some());
+
+        store.evict(now + Duration::from_secs(100));
+        assert!(store.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_balance_proof_includes_whoever_paid_the_account() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut alice = Wallet::new(None);
+        let bob = Wallet::new(None);
+        rollup_state.add_deposit(&alice.public_key, 100).await?;
+        alice.sync_rollup_state(&rollup_state).await?;
+        alice.append_transaction_to_batch(bob.public_key, 10)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&alice.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let alice_proof = aggregator.generate_proof_for_pubkey(&alice.public_key)?;
+
+        let mut store = ProofStore::default();
+        store.insert(alice_proof.clone(), Instant::now());
+
+        // Bob never sent anything, but the proof of his funds comes from Alice's batch
+        let balance_proof = store.balance_proof_for(&bob.public_key);
+        assert_eq!(balance_proof.get(&key(&alice_proof)), Some(&alice_proof));
+
+        Ok(())
+    }
+
+    fn key(proof: &TransactionProof) -> BalanceProofKey {
+        BalanceProofKey {
+            root: proof.root,
+            public_key: proof.batch.from.into(),
+        }
+    }
+}
@@ -1,58 +1,171 @@
 use log::*;
-use std::sync::Arc;
-use tokio::{sync::Mutex, task::JoinHandle};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
 
-use crate::{constants::WEBSOCKET_PORT, errors::CrateResult, rollup::mock_rollup_fs::MockRollupFS};
+use crate::{
+    clock::Clock,
+    constants::{ROLLUP_SQLITE_PATH, SIGNATURE_WINDOW_SECONDS, WEBSOCKET_PORT},
+    errors::{CrateError, CrateResult},
+    rollup::{
+        mock_rollup_fs::MockRollupFS, sqlite_rollup::SqliteRollupState, traits::RollupStateTrait,
+    },
+};
 
-use super::server_state::ServerState;
+use super::server_state::{RoundStep, ServerState};
 
-pub async fn run_aggregator_server() -> CrateResult<()> {
-    let rollup_state = MockRollupFS::new()?;
-    let (server_state, websocket_server, _) =
-        ServerState::new_with_ws_server(rollup_state, Some(WEBSOCKET_PORT)).await?;
-    let block_producer = spawn_block_producer(server_state.clone(), Some(10));
+// Owns the tasks of a running aggregator server so it can be stopped cleanly
+pub struct AggregatorServerHandle {
+    pub server_state: Arc<Mutex<ServerState>>,
+    pub port: u16,
+    websocket_server: JoinHandle<CrateResult<()>>,
+    block_producer: JoinHandle<CrateResult<()>>,
+}
+
+impl AggregatorServerHandle {
+    // Stops accepting new connections, finalises any rounds still collecting signatures, closes
+    // all connections and joins the server tasks
+    pub async fn shutdown(self) -> CrateResult<()> {
+        info!("Shutting down aggregator server");
+
+        self.server_state.lock().await.signal_shutdown();
 
-    // Combine the two tasks into one
-    // This will allow us to return an error if either of the tasks fail
-    let (websocket_result, block_producer_result) =
-        tokio::try_join!(websocket_server, block_producer)?;
+        let block_producer_result = self.block_producer.await?;
 
-    if let Err(e) = websocket_result {
-        error!("Websocket server error: {}", e);
+        self.server_state.lock().await.shutdown().await;
+
+        let websocket_result = self.websocket_server.await?;
+
+        if let Err(e) = &block_producer_result {
+            error!("Block producer error: {}", e);
+        }
+
+        if let Err(e) = &websocket_result {
+            error!("Websocket server error: {}", e);
+        }
+
+        block_producer_result.and(websocket_result)
     }
+}
 
-    if let Err(e) = block_producer_result {
-        error!("Block producer error: {}", e);
+// Where the server keeps the rollup state, chosen at startup
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RollupBackend {
+    #[default]
+    Fs,
+    Sqlite,
+}
+
+impl FromStr for RollupBackend {
+    type Err = CrateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(RollupBackend::Fs),
+            "sqlite" => Ok(RollupBackend::Sqlite),
+            _ => Err(CrateError::UnknownRollupBackend(s.to_string())),
+        }
     }
+}
+
+pub async fn run_aggregator_server(
+    rollup_backend: RollupBackend,
+) -> CrateResult<AggregatorServerHandle> {
+    info!("Using the {:?} rollup backend", rollup_backend);
 
-    Ok(())
+    match rollup_backend {
+        RollupBackend::Fs => {
+            spawn_aggregator_server(MockRollupFS::new()?, Some(WEBSOCKET_PORT), Some(10), None)
+                .await
+        }
+        RollupBackend::Sqlite => {
+            spawn_aggregator_server(
+                SqliteRollupState::open(ROLLUP_SQLITE_PATH)?,
+                Some(WEBSOCKET_PORT),
+                Some(10),
+                None,
+            )
+            .await
+        }
+    }
+}
+
+pub async fn spawn_aggregator_server(
+    rollup_state: impl RollupStateTrait + Send + Clone + Sync + 'static,
+    port: Option<u16>,
+    production_delay_seconds: Option<u64>,
+    signature_window_seconds: Option<u64>,
+) -> CrateResult<AggregatorServerHandle> {
+    let (server_state, websocket_server, port) =
+        ServerState::new_with_ws_server(rollup_state, port).await?;
+    let block_producer = spawn_block_producer(
+        server_state.clone(),
+        production_delay_seconds,
+        signature_window_seconds,
+    );
+
+    Ok(AggregatorServerHandle {
+        server_state,
+        port,
+        websocket_server,
+        block_producer,
+    })
+}
+
+// Sleeps for the given duration, returning true if shutdown was signalled in the meantime
+async fn sleep_or_shutdown(
+    clock: &dyn Clock,
+    seconds: u64,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool {
+    let shutdown_signalled = tokio::select! {
+        _ = clock.sleep(Duration::from_secs(seconds)) => false,
+        _ = shutdown.wait_for(|is_shutdown| *is_shutdown) => true,
+    };
+
+    shutdown_signalled || *shutdown.borrow()
 }
 
 pub fn spawn_block_producer(
     server_state: Arc<Mutex<ServerState>>,
     production_delay_seconds: Option<u64>,
+    signature_window_seconds: Option<u64>,
 ) -> JoinHandle<CrateResult<()>> {
     tokio::spawn(async move {
+        let signature_window = signature_window_seconds.unwrap_or(SIGNATURE_WINDOW_SECONDS);
+        let (clock, mut shutdown) = {
+            let mut server_state = server_state.lock().await;
+            // So the deadline sent with inclusion proofs matches when the round is finalised
+            server_state.set_signature_window(Duration::from_secs(signature_window));
+            (server_state.clock(), server_state.shutdown_receiver())
+        };
+
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(
+            if sleep_or_shutdown(
+                clock.as_ref(),
                 production_delay_seconds.unwrap_or(10),
-            ))
-            .await;
+                &mut shutdown,
+            )
+            .await
+            {
+                break;
+            }
 
             println!("Starting block production");
             // Start collecting signatures, only if there are transactions
-            // The method returns None if there are no transactions
-            match server_state
-                .lock()
-                .await
-                .start_collecting_signatures()
-                .await
-            {
-                Ok(value) => {
-                    if value.is_none() {
-                        info!("No transactions to start collecting signatures for");
-                        continue;
-                    }
+            match server_state.lock().await.step_round().await {
+                Ok(RoundStep::CollectingSignatures(_)) => {}
+                Ok(RoundStep::Idle) => {
+                    info!("No transactions to start collecting signatures for");
+                    continue;
+                }
+                // Only reachable if a round was left collecting by someone else, it's been
+                // finalised so there's nothing to wait for
+                Ok(step) => {
+                    info!("Stepped a leftover round: {:?}", step);
+                    continue;
                 }
                 Err(e) => {
                     error!("Error collecting signatures: {}", e);
@@ -62,12 +175,182 @@ pub fn spawn_block_producer(
             }
 
             info!("Waiting for clients to send signatures");
-            // Wait for clients to send signatures
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+            // Wait for clients to send signatures, on shutdown the round is finalised by whoever
+            // is shutting the server down
+            if sleep_or_shutdown(clock.as_ref(), signature_window, &mut shutdown).await {
+                break;
+            }
 
-            if let Err(e) = server_state.lock().await.finalise().await {
-                error!("Error finalising: {}", e);
+            match server_state.lock().await.step_round().await {
+                // Nobody signed, the participants have been told and there's nothing else to do
+                Ok(RoundStep::Failed(_)) => info!("Round had no signatures, skipping"),
+                Ok(_) => {}
+                Err(e) => error!("Error finalising: {}", e),
             }
         }
+
+        info!("Block producer stopped");
+
+        Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, sync::Arc, time::Duration};
+
+    use tokio::sync::Mutex;
+    use tokio_tungstenite::connect_async;
+
+    use crate::{
+        clock::MockClock,
+        errors::{CrateError, CrateResult},
+        rollup::{
+            mock_rollup_memory::MockRollupMemory,
+            traits::{MockRollupStateTrait, RollupStateTrait},
+        },
+        wallet::wallet::Wallet,
+        websocket::{client::client::Client, server::server_state::ServerState},
+    };
+
+    use super::{spawn_aggregator_server, spawn_block_producer};
+
+    // Polls the condition while the block producer catches up, only real time the test spends
+    async fn wait_until<F: Future<Output = bool>>(condition: impl Fn() -> F) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Condition wasn't met in time");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finalises_collecting_rounds_and_stops_accepting() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        // Long delays so the block producer never runs a round on its own
+        let server =
+            spawn_aggregator_server(rollup_state.clone(), None, Some(1000), Some(1000)).await?;
+        let port = server.port;
+
+        let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port, 0).await?;
+
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(client.lock().await.wallet.public_key, 10)?;
+
+        {
+            let mut server_state = server.server_state.lock().await;
+            server_state.add_batch(&sender.produce_batch()?)?;
+            let root = server_state.start_collecting_signatures().await?.unwrap();
+            let proof = server_state.generate_proof_for_pubkey(&root, &sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            server_state.add_signature(&sender.public_key, &root, &signature)?;
+        }
+
+        server.shutdown().await?;
+
+        // The round that was collecting signatures is finalised during the drain
+        assert_eq!(rollup_state.get_transfer_blocks().await?.len(), 1);
+        assert!(connect_async(format!("ws://127.0.0.1:{}", port))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_client_connections_and_joins_the_tasks() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let server =
+            spawn_aggregator_server(rollup_state.clone(), None, Some(1000), Some(1000)).await?;
+        let server_state = server.server_state.clone();
+
+        let (client, _, receive_handler) =
+            Client::new(Wallet::new(None), rollup_state.clone(), server.port, 0).await?;
+        let public_key = client.lock().await.wallet.public_key;
+        wait_until(|| async {
+            server_state
+                .lock()
+                .await
+                .connection_report()
+                .iter()
+                .any(|status| status.public_key == public_key)
+        })
+        .await;
+
+        // Only returns once the websocket server and block producer have both joined
+        tokio::time::timeout(Duration::from_secs(5), server.shutdown()).await??;
+
+        // The client's connection was closed, so its receive handler has nothing left to do
+        tokio::time::timeout(Duration::from_secs(5), receive_handler).await???;
+        assert!(server_state.lock().await.connection_report().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_producer_runs_on_the_server_clock() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let server_state = Arc::new(Mutex::new(ServerState::new(rollup_state.clone())?));
+        let clock = MockClock::new();
+        server_state.lock().await.set_clock(Arc::new(clock.clone()));
+
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+        server_state
+            .lock()
+            .await
+            .add_batch(&sender.produce_batch()?)?;
+
+        let block_producer = spawn_block_producer(server_state.clone(), Some(60), Some(30));
+
+        // Nothing happens until the production delay has passed on the clock
+        wait_until(|| async { clock.pending_sleeps() == 1 }).await;
+        assert_eq!(
+            server_state
+                .lock()
+                .await
+                .inclusion_proof(&sender.public_key)
+                .unwrap_err()
+                .downcast::<CrateError>()?,
+            CrateError::RoundNotReadyForProofs
+        );
+
+        clock.advance(Duration::from_secs(60));
+        wait_until(|| async {
+            server_state
+                .lock()
+                .await
+                .inclusion_proof(&sender.public_key)
+                .is_ok()
+        })
+        .await;
+
+        let proof = server_state
+            .lock()
+            .await
+            .inclusion_proof(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        server_state
+            .lock()
+            .await
+            .add_signature(&sender.public_key, &proof.root, &signature)?;
+
+        // Then the round is finalised once the signature window has passed
+        wait_until(|| async { clock.pending_sleeps() == 1 }).await;
+        assert!(rollup_state.get_transfer_blocks().await?.is_empty());
+
+        clock.advance(Duration::from_secs(30));
+        wait_until(|| async { rollup_state.get_transfer_blocks().await.unwrap().len() == 1 }).await;
+
+        server_state.lock().await.signal_shutdown();
+        block_producer.await??;
+
+        Ok(())
+    }
+}
@@ -1,38 +1,130 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures_util::{stream::SplitSink, SinkExt};
+use anyhow::anyhow;
+use indexmap::IndexMap;
 use log::{error, info, warn};
-use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
 
 use crate::{
-    aggregator::Aggregator,
-    errors::CrateResult,
+    aggregator::{Aggregator, AggregatorState},
+    clock::{Clock, SystemClock},
+    constants::{
+        BATCH_RATE_LIMIT, BATCH_RATE_LIMIT_WINDOW_SECONDS, KEEPALIVE_INTERVAL_SECONDS,
+        KEEPALIVE_MAX_MISSED_PONGS, PROOF_REQUEST_RETRY_MILLISECONDS,
+        PROOF_REQUEST_TIMEOUT_SECONDS, SIGNATURE_WINDOW_SECONDS,
+    },
+    errors::{CrateError, CrateResult},
     rollup::traits::RollupStateTrait,
     types::{
-        balance::BalanceProof,
+        balance::{balance_proof_delta, BalanceProof, BalanceProofKey},
+        common::{unix_timestamp_millis, U8_32},
+        policy::TransactionPolicy,
         public_key::BlsPublicKeyWrapper,
         signatures::{BlsPublicKey, BlsSignature},
         transaction::{TransactionBatch, TransactionProof},
     },
-    websocket::ws_message::WsMessage,
+    websocket::{
+        transport::ServerTransport,
+        ws_message::{Codec, WsMessage},
+    },
 };
 
-use super::connection::spawn_websocket_server;
+use super::{
+    connection::spawn_websocket_server, proof_store::ProofStore, webhook::spawn_block_webhook,
+};
 
 pub struct Connection {
     pub public_key: BlsPublicKey,
-    // To send messages to the client over their websocket connection
-    pub ws_send: SplitSink<WebSocketStream<TcpStream>, Message>,
+    // To send messages to the client, over their websocket connection or in process
+    pub transport: Box<dyn ServerTransport>,
+    // Assigned by the server when the connection is added, tells apart successive connections
+    // for the same public key
+    id: u64,
+    // Balance proof entries the client says it holds, left out of balance proofs forwarded to it
+    known_balance_proof_keys: HashSet<BalanceProofKey>,
+    // Agreed with the client through CNegotiateCodec
+    codec: Codec,
+}
+
+impl Connection {
+    pub fn new(public_key: BlsPublicKey, transport: Box<dyn ServerTransport>) -> Self {
+        Self {
+            public_key,
+            transport,
+            id: 0,
+            known_balance_proof_keys: HashSet::new(),
+            codec: Codec::default(),
+        }
+    }
+
+    pub async fn send(&mut self, message: WsMessage) -> CrateResult<()> {
+        self.transport.send(message, self.codec).await
+    }
+}
+
+// Snapshot of a connected client's part in the current rounds, for operator tooling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStatus {
+    pub public_key: BlsPublicKey,
+    // Sent a batch that hasn't been signed yet
+    pub has_pending_batch: bool,
+    pub has_signed: bool,
+}
+
+// What a call to ServerState::step_round did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundStep {
+    // The open round had no batches, nothing happened
+    Idle,
+    // The open round started collecting signatures, inclusion proofs have been sent out
+    CollectingSignatures(U8_32),
+    // The round's transfer block was added to the rollup
+    Finalised(U8_32),
+    // Nobody signed the round, the participants have been told
+    Failed(U8_32),
 }
 
 pub struct ServerState {
     connections: HashMap<BlsPublicKeyWrapper, Connection>,
-    // Indexes which connections have transactions, the value is initially false when they send a transaction and then set to true when they send a signature
-    connections_with_tx: HashMap<BlsPublicKeyWrapper, bool>,
+    next_connection_id: u64,
+    // The open round, which accepts new batches while previous rounds collect signatures
     aggregator: Aggregator,
+    // Rounds that are collecting signatures keyed by their merkle root, ordered oldest first
+    collecting_rounds: IndexMap<U8_32, Aggregator>,
     // rollup_state: MockRollupFS,
     rollup_state: Box<dyn RollupStateTrait + Send + Sync>,
+    // Flipped to true once the server is shutting down, the server tasks subscribe to this
+    shutdown: watch::Sender<bool>,
+    // Sliding window of batch submission times per public key, oldest first
+    batch_submissions: HashMap<BlsPublicKeyWrapper, VecDeque<Instant>>,
+    batch_rate_limit: usize,
+    batch_rate_limit_window: Duration,
+    // How long participants have to sign once a round starts collecting signatures, and the
+    // resulting deadline of each collecting round in unix milliseconds
+    signature_window: Duration,
+    round_deadlines: HashMap<U8_32, u64>,
+    // Finalised blocks are posted here when set, see webhook.rs
+    webhook_url: Option<String>,
+    clock: Arc<dyn Clock>,
+    // Codecs clients can pick from when negotiating, every codec by default
+    supported_codecs: Vec<Codec>,
+    // When set, the proofs of finalised rounds are kept so clients can fetch them if the sender
+    // never forwards them or they lose their own
+    proof_store: Option<ProofStore>,
+    // When set, batches the sender can't cover are dropped before a round starts collecting
+    // signatures, see drop_unfunded_batches
+    require_funded_batches: bool,
+    // How often websocket connections are pinged, and how many pongs they can miss in a row
+    // before they're dropped
+    keepalive_interval: Duration,
+    keepalive_max_missed_pongs: u32,
 }
 
 impl ServerState {
@@ -41,9 +133,23 @@ impl ServerState {
     ) -> CrateResult<ServerState> {
         Ok(ServerState {
             connections: HashMap::new(),
+            next_connection_id: 0,
             aggregator: Aggregator::new(),
-            connections_with_tx: HashMap::new(),
+            collecting_rounds: IndexMap::new(),
             rollup_state: Box::new(rollup_state),
+            shutdown: watch::channel(false).0,
+            batch_submissions: HashMap::new(),
+            batch_rate_limit: BATCH_RATE_LIMIT,
+            batch_rate_limit_window: Duration::from_secs(BATCH_RATE_LIMIT_WINDOW_SECONDS),
+            signature_window: Duration::from_secs(SIGNATURE_WINDOW_SECONDS),
+            round_deadlines: HashMap::new(),
+            webhook_url: None,
+            clock: Arc::new(SystemClock),
+            supported_codecs: Codec::ALL.to_vec(),
+            proof_store: None,
+            require_funded_batches: false,
+            keepalive_interval: Duration::from_secs(KEEPALIVE_INTERVAL_SECONDS),
+            keepalive_max_missed_pongs: KEEPALIVE_MAX_MISSED_PONGS,
         })
     }
 
@@ -56,16 +162,74 @@ impl ServerState {
         Ok((server_state, websocket_server, port))
     }
 
-    pub fn add_connection(&mut self, connection: Connection) {
-        self.connections
-            .insert(connection.public_key.clone().into(), connection);
+    // Only one connection per public key is kept, a new connection takes over from the old one
+    // which is closed so its socket isn't leaked. Returns the id of the added connection
+    pub async fn add_connection(&mut self, mut connection: Connection) -> u64 {
+        connection.id = self.next_connection_id;
+        self.next_connection_id += 1;
+
+        let id = connection.id;
+
+        if let Some(mut previous_connection) = self
+            .connections
+            .insert(connection.public_key.into(), connection)
+        {
+            warn!(
+                "New connection took over from an existing one for public key: {:?}",
+                previous_connection.public_key
+            );
+
+            if let Err(e) = previous_connection
+                .send(WsMessage::SConnectionReplaced)
+                .await
+            {
+                warn!(
+                    "Failed to tell the previous connection it was replaced: {:?}",
+                    e
+                );
+            }
+
+            if let Err(e) = previous_connection.transport.close().await {
+                warn!("Failed to close the previous connection: {:?}", e);
+            }
+        }
+
+        id
     }
 
-    pub async fn remove_connection(&mut self, public_key: &BlsPublicKey) -> CrateResult<()> {
+    // Removes the connection only if it hasn't since been taken over by a newer connection
+    pub async fn remove_connection_with_id(
+        &mut self,
+        public_key: &BlsPublicKey,
+        id: u64,
+    ) -> CrateResult<()> {
+        let is_current = self
+            .connections
+            .get(&public_key.into())
+            .is_some_and(|connection| connection.id == id);
+
+        if !is_current {
+            return Ok(());
+        }
+
+        self.remove_connection(public_key).await
+    }
+
+    // Like remove_connection_with_id, a connection that's been taken over isn't pinged
+    pub async fn ping_connection(&mut self, public_key: &BlsPublicKey, id: u64) -> CrateResult<()> {
         match self.connections.get_mut(&public_key.into()) {
-            Some(connection) => {
-                connection.ws_send.close().await?;
-                self.connections.remove(&public_key.into());
+            Some(connection) if connection.id == id => connection.transport.ping().await,
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn remove_connection(&mut self, public_key: &BlsPublicKey) -> CrateResult<()> {
+        match self.connections.remove(&public_key.into()) {
+            // Removed even if closing fails, the client has usually already gone
+            Some(mut connection) => {
+                if let Err(e) = connection.transport.close().await {
+                    warn!("Failed to close connection: {:?}", e);
+                }
             }
             None => {
                 println!("No connection with tx for public key: {:?}", public_key);
@@ -75,25 +239,122 @@ impl ServerState {
         Ok(())
     }
 
-    pub async fn start_collecting_signatures(&mut self) -> CrateResult<Option<()>> {
+    // Who the server is connected to and which of them the rounds are still waiting on
+    pub fn connection_report(&self) -> Vec<ConnectionStatus> {
+        self.connections
+            .iter()
+            .map(|(key, connection)| {
+                let has_signed = self.batch_status(key);
+
+                ConnectionStatus {
+                    public_key: connection.public_key,
+                    has_pending_batch: has_signed == Some(false),
+                    has_signed: has_signed == Some(true),
+                }
+            })
+            .collect()
+    }
+
+    // Which keys have a batch in a round, and whether they've signed it. Derived from the rounds
+    // so it can't drift from them, a key in several rounds gets the status of the newest
+    pub fn connections_with_tx(&self) -> HashMap<BlsPublicKeyWrapper, bool> {
+        self.collecting_rounds
+            .values()
+            .chain(std::iter::once(&self.aggregator))
+            .flat_map(|round| round.participants())
+            .map(|(public_key, has_signed)| (*public_key, has_signed))
+            .collect()
+    }
+
+    // Whether the key has signed its batch in the newest round it has one in, None if it has no
+    // batch in any round
+    fn batch_status(&self, public_key: &BlsPublicKeyWrapper) -> Option<bool> {
+        std::iter::once(&self.aggregator)
+            .chain(self.collecting_rounds.values().rev())
+            .find_map(|round| round.has_signed(public_key))
+    }
+
+    // The block producer takes its timing from the server's clock
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn shutdown_receiver(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn signal_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    // Stops the server tasks and new connections, finalises any rounds still collecting
+    // signatures and then closes every connection. Anything that was finalising when this was
+    // called has finished by the time the lock is acquired
+    pub async fn shutdown(&mut self) {
+        self.signal_shutdown();
+        self.finalise_collecting_rounds().await;
+        self.close_connections().await;
+    }
+
+    pub async fn close_connections(&mut self) {
+        for (_, mut connection) in self.connections.drain() {
+            if let Err(e) = connection.transport.close().await {
+                warn!(
+                    "Failed to close connection for public key: {:?}, {:?}",
+                    connection.public_key, e
+                );
+            }
+        }
+    }
+
+    // Moves the open round into the collecting rounds and sends each participant their inclusion
+    // proof, a fresh round is opened immediately so new batches don't have to wait for this one
+    // to be finalised
+    pub async fn start_collecting_signatures(&mut self) -> CrateResult<Option<U8_32>> {
         if self.aggregator.tx_hash_to_metadata.len() == 0 {
             return Ok(None);
         }
 
+        if self.require_funded_batches {
+            self.drop_unfunded_batches().await?;
+
+            if self.aggregator.tx_hash_to_metadata.is_empty() {
+                return Ok(None);
+            }
+        }
+
         // Validates that there are transactions to collect signatures for
         self.aggregator.start_collecting_signatures()?;
 
-        info!("Starting to collect signatures");
-        for (connection, _) in self.connections_with_tx.iter() {
-            match self.connections.get_mut(connection) {
+        let root = self.aggregator.root()?;
+        let mut next_round = Aggregator::new_with_config(
+            self.aggregator.transaction_policy(),
+            self.aggregator.max_batches(),
+        );
+        next_round.set_fee_recipient(self.aggregator.fee_recipient());
+        let round = std::mem::replace(&mut self.aggregator, next_round);
+        let sign_by = unix_timestamp_millis(self.clock.system_time() + self.signature_window);
+        self.round_deadlines.insert(root, sign_by);
+
+        info!(
+            "Starting to collect signatures for root: {:?}, proofs contain up to {} hashes",
+            root,
+            round.estimated_proof_size()
+        );
+        for public_key in round.tx_hash_to_metadata.keys() {
+            match self.connections.get_mut(public_key) {
                 Some(connection) => {
-                    if let Ok(proof) = self
-                        .aggregator
-                        .generate_proof_for_pubkey(&connection.public_key)
-                    {
+                    if let Ok(proof) = round.generate_proof_for_pubkey(&connection.public_key) {
                         if let Err(e) = connection
-                            .ws_send
-                            .send(WsMessage::SSendTransactionInclusionProof(proof).into())
+                            .send(WsMessage::SSendTransactionInclusionProof(proof, sign_by))
                             .await
                         {
                             error!(
@@ -109,12 +370,132 @@ impl ServerState {
                     }
                 }
                 None => {
-                    warn!("Connection not found for public key: {:?}", connection);
+                    warn!("Connection not found for public key: {:?}", public_key);
+                }
+            }
+        }
+
+        self.collecting_rounds.insert(root, round);
+
+        Ok(Some(root))
+    }
+
+    // Batches don't come with the sender's balance proof, so funds are what the server can see:
+    // deposits minus withdraws on the rollup, plus whatever the stored proofs move in or out of
+    // the account, fees included. Without a proof store transfers can't be counted at all, so
+    // nothing is dropped rather than every sender funded on the L2
+    async fn drop_unfunded_batches(&mut self) -> CrateResult<()> {
+        let Some(proof_store) = &self.proof_store else {
+            info!("No proof store to check batches are funded against, skipping the check");
+            return Ok(());
+        };
+
+        let mut unfunded = vec![];
+
+        for batch in self.aggregator.batches() {
+            let deposit_amount = self
+                .rollup_state
+                .get_account_deposit_amount(&batch.from)
+                .await?;
+            let withdraw_amount = self
+                .rollup_state
+                .get_account_withdraw_amount(&batch.from)
+                .await?;
+            let mut funds = deposit_amount as i128 - withdraw_amount as i128;
+
+            let public_key: BlsPublicKeyWrapper = batch.from.into();
+            for proof in proof_store.balance_proof_for(&batch.from).values() {
+                let is_sender = BlsPublicKeyWrapper::from(proof.batch.from) == public_key;
+                if is_sender {
+                    funds -= proof.batch.fee as i128;
+                }
+
+                for transaction in proof.batch.transactions.iter() {
+                    if is_sender {
+                        funds -= transaction.amount as i128;
+                    }
+                    if BlsPublicKeyWrapper::from(transaction.to) == public_key {
+                        funds += transaction.amount as i128;
+                    }
                 }
             }
+
+            let batch_amount = batch.total_amount() as i128;
+
+            if batch_amount > funds {
+                warn!(
+                    "Dropping unfunded batch from {:?}, sends {} with {} available",
+                    batch.from, batch_amount, funds
+                );
+                unfunded.push(batch.from.into());
+            }
+        }
+
+        for public_key in unfunded {
+            self.aggregator.remove_batch(&public_key)?;
         }
 
-        Ok(Some(()))
+        Ok(())
+    }
+
+    pub fn set_require_funded_batches(&mut self, require_funded_batches: bool) {
+        self.require_funded_batches = require_funded_batches;
+    }
+
+    // Should match how long the block producer waits before finalising
+    // Applies to connections made from then on
+    pub fn set_keepalive(&mut self, interval: Duration, max_missed_pongs: u32) {
+        self.keepalive_interval = interval;
+        self.keepalive_max_missed_pongs = max_missed_pongs;
+    }
+
+    pub fn keepalive(&self) -> (Duration, u32) {
+        (self.keepalive_interval, self.keepalive_max_missed_pongs)
+    }
+
+    pub fn set_signature_window(&mut self, window: Duration) {
+        self.signature_window = window;
+    }
+
+    pub fn set_webhook_url(&mut self, webhook_url: Option<String>) {
+        self.webhook_url = webhook_url;
+    }
+
+    // Carried over to each new open round
+    pub fn set_transaction_policy(&mut self, transaction_policy: Arc<dyn TransactionPolicy>) {
+        self.aggregator.set_transaction_policy(transaction_policy);
+    }
+
+    // Carried over to each new open round
+    pub fn set_fee_recipient(&mut self, fee_recipient: Option<BlsPublicKey>) {
+        self.aggregator.set_fee_recipient(fee_recipient);
+    }
+
+    // Carried over to each new open round
+    pub fn set_max_batches_per_block(&mut self, max_batches: usize) {
+        self.aggregator.set_max_batches(max_batches);
+    }
+
+    // A full round has to start collecting signatures before it can take more batches
+    pub fn round_is_full(&self) -> bool {
+        self.aggregator.is_full()
+    }
+
+    pub fn set_proof_store(&mut self, proof_store: Option<ProofStore>) {
+        self.proof_store = proof_store;
+    }
+
+    pub fn proof_store(&self) -> Option<&ProofStore> {
+        self.proof_store.as_ref()
+    }
+
+    pub fn set_supported_codecs(&mut self, supported_codecs: Vec<Codec>) {
+        self.supported_codecs = supported_codecs;
+    }
+
+    pub fn set_batch_rate_limit(&mut self, max_batches: usize, window: Duration) {
+        self.batch_rate_limit = max_batches;
+        self.batch_rate_limit_window = window;
     }
 
     pub fn add_batch(&mut self, batch: &TransactionBatch) -> CrateResult<()> {
@@ -123,17 +504,96 @@ impl ServerState {
             serde_json::to_string(&batch.from)?,
         );
 
+        // Checked before the rate limit, otherwise anyone could use up another key's allowance by
+        // submitting batches in their name
+        batch.verify_signature()?;
+
+        self.check_batch_rate_limit(&batch.from)?;
+
         self.aggregator.add_batch(batch)?;
 
-        self.connections_with_tx
-            .insert(batch.from.clone().into(), false);
+        Ok(())
+    }
+
+    // Every signed submission counts towards the limit, even ones the aggregator goes on to reject,
+    // so a client can't flood the server with invalid batches either
+    fn check_batch_rate_limit(&mut self, public_key: &BlsPublicKey) -> CrateResult<()> {
+        let now = self.clock.now();
+        let submissions = self.batch_submissions.entry(public_key.into()).or_default();
+
+        while let Some(submitted_at) = submissions.front() {
+            if now.duration_since(*submitted_at) < self.batch_rate_limit_window {
+                break;
+            }
+
+            submissions.pop_front();
+        }
+
+        if submissions.len() >= self.batch_rate_limit {
+            return Err(CrateError::RateLimited.into());
+        }
+
+        submissions.push_back(now);
 
         Ok(())
     }
 
+    pub fn generate_proof_for_pubkey(
+        &self,
+        root: &U8_32,
+        public_key: &BlsPublicKey,
+    ) -> CrateResult<TransactionProof> {
+        self.collecting_rounds
+            .get(root)
+            .ok_or(anyhow!("No round collecting signatures for root"))?
+            .generate_proof_for_pubkey(public_key)
+    }
+
+    // Finds the proof for the key's batch in whichever round it's in, a batch still in the open
+    // round gives RoundNotReadyForProofs
+    pub fn inclusion_proof(&self, public_key: &BlsPublicKey) -> CrateResult<TransactionProof> {
+        let key: BlsPublicKeyWrapper = public_key.into();
+
+        let round = self
+            .collecting_rounds
+            .values()
+            .find(|round| round.tx_hash_to_metadata.contains_key(&key))
+            .unwrap_or(&self.aggregator);
+
+        round.generate_proof_for_pubkey(public_key)
+    }
+
+    // A client can ask for its proof before the block producer has moved the round on, rather than
+    // erroring the request is held and retried until the round starts collecting signatures
+    pub async fn wait_for_inclusion_proof(
+        server_state: Arc<Mutex<ServerState>>,
+        public_key: &BlsPublicKey,
+    ) -> CrateResult<TransactionProof> {
+        let clock = server_state.lock().await.clock();
+        let deadline = clock.now() + Duration::from_secs(PROOF_REQUEST_TIMEOUT_SECONDS);
+
+        loop {
+            let result = server_state.lock().await.inclusion_proof(public_key);
+
+            match result {
+                Err(e)
+                    if e.downcast_ref::<CrateError>()
+                        == Some(&CrateError::RoundNotReadyForProofs)
+                        && clock.now() < deadline =>
+                {
+                    clock
+                        .sleep(Duration::from_millis(PROOF_REQUEST_RETRY_MILLISECONDS))
+                        .await;
+                }
+                result => return result,
+            }
+        }
+    }
+
     pub fn add_signature(
         &mut self,
         public_key: &BlsPublicKey,
+        root: &U8_32,
         signature: &BlsSignature,
     ) -> CrateResult<()> {
         info!(
@@ -141,15 +601,219 @@ impl ServerState {
             serde_json::to_string(&public_key)?,
         );
 
+        let round = self
+            .collecting_rounds
+            .get_mut(root)
+            .ok_or(anyhow!("No round collecting signatures for root"))?;
+
         // This checks for the existence of the transaction and public key
-        self.aggregator.add_signature(public_key, signature)?;
+        round.add_signature(public_key, signature)?;
+
+        Ok(())
+    }
+
+    // Adds each signature to the round with its root. A bad signature doesn't stop the rest from
+    // being added, the first error is returned once they've all been tried
+    pub fn add_signatures(
+        &mut self,
+        public_key: &BlsPublicKey,
+        signatures: &[(U8_32, BlsSignature)],
+    ) -> CrateResult<()> {
+        let mut first_error = None;
+
+        for (root, signature) in signatures {
+            if let Err(e) = self.add_signature(public_key, root, signature) {
+                error!("Failed to add signature for root {:?}: {:?}", root, e);
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Re-sends the inclusion proof to a participant that hasn't signed a round collecting
+    // signatures yet, e.g. when they disconnected after the proof was originally sent. Returns
+    // the root of the round if a proof was sent
+    pub async fn resume_round(&mut self, public_key: &BlsPublicKey) -> CrateResult<Option<U8_32>> {
+        let key: BlsPublicKeyWrapper = public_key.into();
+
+        let Some((root, round)) = self
+            .collecting_rounds
+            .iter()
+            .find(|(_, round)| round.has_signed(&key) == Some(false))
+        else {
+            return Ok(None);
+        };
+
+        let root = *root;
+        let proof = round.generate_proof_for_pubkey(public_key)?;
+
+        let sign_by = self
+            .round_deadlines
+            .get(&root)
+            .copied()
+            .unwrap_or_else(|| unix_timestamp_millis(self.clock.system_time()));
+
+        info!("Resending inclusion proof for root: {:?}", root);
+        self.send_to_connection(
+            public_key,
+            WsMessage::SSendTransactionInclusionProof(proof, sign_by),
+        )
+        .await?;
 
-        self.connections_with_tx
-            .insert(public_key.clone().into(), true);
+        Ok(Some(root))
+    }
+
+    pub async fn send_to_connection(
+        &mut self,
+        public_key: &BlsPublicKey,
+        message: WsMessage,
+    ) -> CrateResult<()> {
+        let connection = self
+            .connections
+            .get_mut(&public_key.into())
+            .ok_or(anyhow!("Connection not found for public key"))?;
+
+        connection.send(message).await?;
+
+        Ok(())
+    }
+
+    pub fn set_known_balance_proof_keys(
+        &mut self,
+        public_key: &BlsPublicKey,
+        keys: Vec<BalanceProofKey>,
+    ) -> CrateResult<()> {
+        let connection = self
+            .connections
+            .get_mut(&public_key.into())
+            .ok_or(anyhow!("Connection not found for public key"))?;
+
+        connection.known_balance_proof_keys = keys.into_iter().collect();
 
         Ok(())
     }
 
+    // Picks the first of the client's codecs that the server supports, the reply is already sent
+    // in the chosen codec. If there's nothing in common the connection stays on its current codec
+    pub async fn negotiate_codec(
+        &mut self,
+        public_key: &BlsPublicKey,
+        codecs: &[Codec],
+    ) -> CrateResult<Codec> {
+        let supported_codecs = self.supported_codecs.clone();
+        let connection = self
+            .connections
+            .get_mut(&public_key.into())
+            .ok_or(anyhow!("Connection not found for public key"))?;
+
+        let Some(codec) = codecs
+            .iter()
+            .find(|codec| supported_codecs.contains(codec))
+            .copied()
+        else {
+            connection
+                .send(WsMessage::SCodecRejected(supported_codecs))
+                .await?;
+
+            return Err(CrateError::NoCommonCodec.into());
+        };
+
+        info!("Connection {:?} negotiated codec {:?}", public_key, codec);
+
+        connection.codec = codec;
+        connection.send(WsMessage::SCodecSelected(codec)).await?;
+
+        Ok(codec)
+    }
+
+    // Sends the requester every stored batch in the root that pays them, as if the senders had
+    // forwarded them. Returns how many were sent
+    pub async fn send_stored_proofs_for_root(
+        &mut self,
+        public_key: &BlsPublicKey,
+        root: &U8_32,
+    ) -> CrateResult<usize> {
+        let now = self.clock.now();
+        let proof_store = self
+            .proof_store
+            .as_mut()
+            .ok_or(anyhow!("Server doesn't store proofs"))?;
+        proof_store.evict(now);
+
+        let messages = proof_store
+            .proofs_for_root(root)
+            .into_iter()
+            .filter(|proof| {
+                proof
+                    .batch
+                    .transactions
+                    .iter()
+                    .any(|transaction| transaction.to == *public_key)
+            })
+            .map(|proof| {
+                let balance_proof = proof_store.balance_proof_for(&proof.batch.from);
+                (proof.clone(), balance_proof)
+            })
+            .collect::<Vec<_>>();
+
+        if messages.is_empty() {
+            return Err(anyhow!("No stored proofs for root paying this key"));
+        }
+
+        let connection = self
+            .connections
+            .get_mut(&public_key.into())
+            .ok_or(anyhow!("Connection not found for public key"))?;
+
+        let num_proofs = messages.len();
+        for (proof, balance_proof) in messages {
+            connection
+                .send(WsMessage::SReceiveTransaction(
+                    proof,
+                    balance_proof_delta(&balance_proof, &connection.known_balance_proof_keys),
+                ))
+                .await?;
+        }
+
+        Ok(num_proofs)
+    }
+
+    // The server never sees balance proofs, so this is the rollup's view of the account and
+    // leaves out transfers
+    pub async fn send_account_balance(
+        &mut self,
+        public_key: &BlsPublicKey,
+        account: &BlsPublicKey,
+    ) -> CrateResult<()> {
+        let balance = self.rollup_state.get_account_balance(account).await?;
+
+        self.send_to_connection(public_key, WsMessage::SBalanceResponse(balance))
+            .await
+    }
+
+    // For clients that lost their local state, everything the store has for the key along with
+    // what's needed to validate it
+    pub async fn send_stored_balance_proof(
+        &mut self,
+        public_key: &BlsPublicKey,
+    ) -> CrateResult<()> {
+        let now = self.clock.now();
+        let proof_store = self
+            .proof_store
+            .as_mut()
+            .ok_or(anyhow!("Server doesn't store proofs"))?;
+        proof_store.evict(now);
+
+        let balance_proof = proof_store.balance_proof_for(public_key);
+
+        self.send_to_connection(public_key, WsMessage::SStoredBalanceProof(balance_proof))
+            .await
+    }
+
     pub async fn send_batch_to_receivers(
         &mut self,
         proof: &TransactionProof,
@@ -166,8 +830,10 @@ impl ServerState {
             let connection = connection.unwrap();
 
             if let Err(e) = connection
-                .ws_send
-                .send(WsMessage::SReceiveTransaction(proof.clone(), balance_proof.clone()).into())
+                .send(WsMessage::SReceiveTransaction(
+                    proof.clone(),
+                    balance_proof_delta(balance_proof, &connection.known_balance_proof_keys),
+                ))
                 .await
             {
                 // Don't propogate again so we can continue to send to other connections
@@ -178,21 +844,121 @@ impl ServerState {
         Ok(())
     }
 
+    // Moves the round lifecycle on by one step: finalises the oldest round collecting signatures
+    // if there is one, otherwise starts collecting signatures for the open round. The block
+    // producer calls this on its timer, tests can call it directly once whatever signatures they
+    // want are in
+    pub async fn step_round(&mut self) -> CrateResult<RoundStep> {
+        if let Some(root) = self.collecting_rounds.keys().next().copied() {
+            return match self.finalise().await {
+                Ok(()) => Ok(RoundStep::Finalised(root)),
+                Err(e) if e.downcast_ref::<CrateError>() == Some(&CrateError::EmptyRound) => {
+                    Ok(RoundStep::Failed(root))
+                }
+                Err(e) => Err(e),
+            };
+        }
+
+        Ok(match self.start_collecting_signatures().await? {
+            Some(root) => RoundStep::CollectingSignatures(root),
+            None => RoundStep::Idle,
+        })
+    }
+
+    // Finalises every round still collecting signatures, rounds that can't be finalised (e.g.
+    // nobody signed) are dropped. A round the rollup rejected is put back by finalise, so this
+    // stops there rather than retrying it forever
+    pub async fn finalise_collecting_rounds(&mut self) {
+        while !self.collecting_rounds.is_empty() {
+            let remaining = self.collecting_rounds.len();
+
+            match self.finalise().await {
+                Err(e) if e.downcast_ref::<CrateError>() == Some(&CrateError::EmptyRound) => {
+                    info!("Dropped a round with no signatures");
+                }
+                Err(e) => {
+                    error!("Error finalising round: {}", e);
+
+                    if self.collecting_rounds.len() == remaining {
+                        error!("Leaving {} round(s) unfinalised", remaining);
+                        break;
+                    }
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    // Finalises the oldest round that is collecting signatures. If nobody signed the round it
+    // can't be finalised, so the participants are told the round failed and can abort their
+    // pending batches
     pub async fn finalise(&mut self) -> CrateResult<()> {
         info!("Finalising aggregator");
 
+        let (root, mut round) = self
+            .collecting_rounds
+            .shift_remove_index(0)
+            .ok_or(anyhow!("No round collecting signatures to finalise"))?;
+        let deadline = self.round_deadlines.remove(&root);
+
+        if !round.has_signatures() {
+            for public_key in round.tx_hash_to_metadata.keys() {
+                if let Err(e) = self
+                    .send_to_connection(&(*public_key).into(), WsMessage::SRoundFailed(root))
+                    .await
+                {
+                    warn!("Failed to notify participant the round failed: {:?}", e);
+                }
+            }
+
+            return Err(CrateError::EmptyRound.into());
+        }
+
+        // Proofs can't be generated once the round is finalised
+        let signed_proofs = match self.proof_store {
+            Some(_) => round.signed_proofs()?,
+            None => vec![],
+        };
+
+        let outstanding_signers = round.outstanding_signers();
+        if !outstanding_signers.is_empty() {
+            info!(
+                "Finalising without {} participant(s) that haven't signed: {:?}",
+                outstanding_signers.len(),
+                outstanding_signers
+            );
+        }
+
         // Finalise and message all the connections
         // aggregator.finalise does a variety of checks to ensure the aggregator is in the correct state
-        let transfer_block = self.aggregator.finalise()?;
+        let transfer_block = round.finalise()?;
 
-        self.rollup_state
+        // Everything's signed, so a rollup error (e.g. it's briefly unavailable) puts the round
+        // back to be finalised again rather than losing it
+        if let Err(e) = self
+            .rollup_state
             .add_transfer_block(transfer_block.clone())
-            .await?;
+            .await
+        {
+            round.state = AggregatorState::CollectSignatures;
+            self.collecting_rounds.shift_insert(0, root, round);
+            if let Some(deadline) = deadline {
+                self.round_deadlines.insert(root, deadline);
+            }
 
-        self.connections_with_tx.clear();
+            return Err(e);
+        }
+
+        if let Some(proof_store) = self.proof_store.as_mut() {
+            let now = self.clock.now();
+            for proof in signed_proofs {
+                proof_store.insert(proof, now);
+            }
+        }
 
-        // Create a new aggregator now we have finalised
-        self.aggregator = Aggregator::new();
+        if let Some(webhook_url) = &self.webhook_url {
+            spawn_block_webhook(webhook_url.clone(), &transfer_block);
+        }
 
         Ok(())
     }
@@ -200,23 +966,60 @@ impl ServerState {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
 
-    use tokio::sync::Mutex;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::Mutex,
+    };
+    use tokio_tungstenite::connect_async;
 
     use crate::{
-        errors::CrateResult,
+        aggregator::Aggregator,
+        errors::{CrateError, CrateResult},
         rollup::{
+            faulty_rollup::FaultyRollup,
             mock_rollup_memory::MockRollupMemory,
             traits::{MockRollupStateTrait, RollupStateTrait},
         },
+        types::{
+            balance::BalanceProofKey,
+            common::{generate_salt, unix_timestamp_millis, U8_32},
+            signatures::BlsSecretKey,
+            transaction::{SimpleTransaction, TransactionBatch},
+        },
         wallet::wallet::Wallet,
-        websocket::client::{
-            client::Client, constants::TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS,
+        websocket::{
+            client::{client::Client, constants::TESTING_WALLET_AUTOMATIC_SYNC_RATE_SECONDS},
+            server::webhook::BlockWebhookPayload,
+            tests::add_raw_connection,
+            ws_message::{parse_ws_message, Codec, WsMessage},
         },
     };
 
-    use super::ServerState;
+    use super::{Connection, ConnectionStatus, ProofStore, RoundStep, ServerState};
+
+    // A signed batch with a single transaction to a new key
+    fn signed_batch(secret_key: &BlsSecretKey) -> CrateResult<TransactionBatch> {
+        let mut batch = TransactionBatch::new(secret_key.public_key());
+        batch.transactions.push(SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from: secret_key.public_key(),
+            amount: 10,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        batch.sign(secret_key)?;
+
+        Ok(batch)
+    }
 
     async fn setup() -> CrateResult<(
         Arc<Mutex<ServerState>>,
@@ -228,7 +1031,7 @@ mod tests {
         // Delay 1s to allow the server to start
         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
 
-        let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port).await?;
+        let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port, 0).await?;
 
         Ok((server.clone(), client, rollup_state))
     }
@@ -281,7 +1084,7 @@ mod tests {
             server
                 .lock()
                 .await
-                .connections_with_tx
+                .connections_with_tx()
                 .get(&client_public_key.into())
                 .is_some(),
             true
@@ -307,26 +1110,30 @@ mod tests {
 
         server.lock().await.add_batch(&batch)?;
 
-        server.lock().await.start_collecting_signatures().await?;
+        let root = server
+            .lock()
+            .await
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
 
         let proof = server
             .lock()
             .await
-            .aggregator
-            .generate_proof_for_pubkey(&client_public_key)?;
+            .generate_proof_for_pubkey(&root, &client_public_key)?;
 
         let signature = client.lock().await.wallet.validate_and_sign_proof(&proof)?;
 
         server
             .lock()
             .await
-            .add_signature(&client_public_key, &signature)?;
+            .add_signature(&client_public_key, &root, &signature)?;
 
         assert_eq!(
             server
                 .lock()
                 .await
-                .connections_with_tx
+                .connections_with_tx()
                 .get(&client_public_key.into()),
             Some(&true)
         );
@@ -335,10 +1142,10 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_finalise() -> CrateResult<()> {
+    async fn test_proof_request_is_held_until_round_transitions() -> CrateResult<()> {
         let (server, client, mut rollup_state) = setup().await?;
         let receiver = Wallet::new(None);
-        let client_public_key = client.lock().await.wallet.public_key.clone();
+        let client_public_key = client.lock().await.wallet.public_key;
         rollup_state.add_deposit(&client_public_key, 100).await?;
 
         tokio::time::sleep(tokio::time::Duration::from_secs(SLEEP_TIME_SECONDS)).await;
@@ -352,30 +1159,980 @@ mod tests {
 
         server.lock().await.add_batch(&batch)?;
 
-        server.lock().await.start_collecting_signatures().await?;
-
-        let proof = server
+        let err = server
             .lock()
             .await
-            .aggregator
-            .generate_proof_for_pubkey(&client_public_key)?;
+            .inclusion_proof(&client_public_key)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::RoundNotReadyForProofs)
+        );
 
-        let signature = client.lock().await.wallet.validate_and_sign_proof(&proof)?;
+        // Request the proof while the round is still open
+        let request = tokio::spawn({
+            let server = server.clone();
+            async move { ServerState::wait_for_inclusion_proof(server, &client_public_key).await }
+        });
 
-        server
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert!(!request.is_finished());
+
+        let root = server
             .lock()
             .await
-            .add_signature(&client_public_key, &signature)?;
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
 
-        server.lock().await.finalise().await?;
+        let proof = request.await??;
 
-        assert_eq!(server.lock().await.aggregator.tx_hash_to_metadata.len(), 0);
+        assert_eq!(proof.root, root);
+        assert_eq!(proof.batch, batch);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_report_tracks_signing_status() -> CrateResult<()> {
+        let (server, client, mut rollup_state) = setup().await?;
+        let receiver = Wallet::new(None);
+        let client_public_key = client.lock().await.wallet.public_key;
+        rollup_state.add_deposit(&client_public_key, 100).await?;
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(SLEEP_TIME_SECONDS)).await;
+
+        let status = |has_pending_batch, has_signed| {
+            vec![ConnectionStatus {
+                public_key: client_public_key,
+                has_pending_batch,
+                has_signed,
+            }]
+        };
+
+        assert_eq!(
+            server.lock().await.connection_report(),
+            status(false, false)
+        );
+
+        client
+            .lock()
+            .await
+            .wallet
+            .append_transaction_to_batch(receiver.public_key, 10)?;
+        let batch = client.lock().await.wallet.produce_batch()?;
+        server.lock().await.add_batch(&batch)?;
+
+        assert_eq!(server.lock().await.connection_report(), status(true, false));
+
+        let root = server
+            .lock()
+            .await
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
+        let proof = server
+            .lock()
+            .await
+            .generate_proof_for_pubkey(&root, &client_public_key)?;
+        let signature = client.lock().await.wallet.validate_and_sign_proof(&proof)?;
+        server
+            .lock()
+            .await
+            .add_signature(&client_public_key, &root, &signature)?;
+
+        assert_eq!(server.lock().await.connection_report(), status(false, true));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalise() -> CrateResult<()> {
+        let (server, client, mut rollup_state) = setup().await?;
+        let receiver = Wallet::new(None);
+        let client_public_key = client.lock().await.wallet.public_key.clone();
+        rollup_state.add_deposit(&client_public_key, 100).await?;
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(SLEEP_TIME_SECONDS)).await;
+        client
+            .lock()
+            .await
+            .wallet
+            .append_transaction_to_batch(receiver.public_key, 10)?;
+
+        let batch = client.lock().await.wallet.produce_batch()?;
+
+        server.lock().await.add_batch(&batch)?;
+
+        let root = server
+            .lock()
+            .await
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
+
+        let proof = server
+            .lock()
+            .await
+            .generate_proof_for_pubkey(&root, &client_public_key)?;
+
+        let signature = client.lock().await.wallet.validate_and_sign_proof(&proof)?;
+
+        server
+            .lock()
+            .await
+            .add_signature(&client_public_key, &root, &signature)?;
+
+        server.lock().await.finalise().await?;
+
+        assert_eq!(server.lock().await.aggregator.tx_hash_to_metadata.len(), 0);
+        assert_eq!(server.lock().await.collecting_rounds.len(), 0);
         assert_eq!(
-            server.lock().await.connections_with_tx.len(),
+            server.lock().await.connections_with_tx().len(),
             0,
             "Connections with tx should be empty"
         );
         assert_eq!(rollup_state.get_transfer_blocks().await?.len(), 1);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_signed_round_survives_the_rollup_being_unavailable() -> CrateResult<()> {
+        let mut rollup_state = FaultyRollup::new(MockRollupMemory::new());
+        let mut server = ServerState::new(rollup_state.clone())?;
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+
+        server.add_batch(&sender.produce_batch()?)?;
+        let root = server.start_collecting_signatures().await?.unwrap();
+        let proof = server.generate_proof_for_pubkey(&root, &sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        server.add_signature(&sender.public_key, &root, &signature)?;
+
+        rollup_state.set_failing(true);
+        assert!(server.finalise().await.is_err());
+        assert_eq!(server.collecting_rounds.keys().next(), Some(&root));
+        assert!(server.round_deadlines.contains_key(&root));
+
+        rollup_state.set_failing(false);
+        server.finalise().await?;
+        assert!(server.collecting_rounds.is_empty());
+        assert_eq!(
+            server.rollup_state.get_transfer_blocks().await?[0].merkle_root,
+            root
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_round_accepts_batches_while_previous_round_collects_signatures(
+    ) -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut server = ServerState::new(rollup_state.clone())?;
+        let receiver = Wallet::new(None);
+        let mut first_sender = Wallet::new(None);
+        let mut second_sender = Wallet::new(None);
+
+        for sender in [&mut first_sender, &mut second_sender] {
+            rollup_state.add_deposit(&sender.public_key, 100).await?;
+            sender.sync_rollup_state(&rollup_state).await?;
+            sender.append_transaction_to_batch(receiver.public_key, 10)?;
+        }
+
+        server.add_batch(&first_sender.produce_batch()?)?;
+        let first_root = server.start_collecting_signatures().await?.unwrap();
+
+        // The second batch lands in the next round while the first is still collecting
+        server.add_batch(&second_sender.produce_batch()?)?;
+        let second_root = server.start_collecting_signatures().await?.unwrap();
+
+        assert_ne!(first_root, second_root);
+        assert_eq!(server.collecting_rounds.len(), 2);
+
+        // Signatures are routed to the round by root, so they can arrive in any order
+        for (sender, root) in [
+            (&mut second_sender, second_root),
+            (&mut first_sender, first_root),
+        ] {
+            let proof = server.generate_proof_for_pubkey(&root, &sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+
+            // A signature for a root that isn't collecting is rejected
+            assert!(server
+                .add_signature(&sender.public_key, &[0; 32], &signature)
+                .is_err());
+
+            server.add_signature(&sender.public_key, &root, &signature)?;
+        }
+
+        server.finalise().await?;
+        server.finalise().await?;
+
+        let transfer_blocks = server.rollup_state.get_transfer_blocks().await?;
+        assert_eq!(
+            transfer_blocks
+                .iter()
+                .map(|block| block.merkle_root)
+                .collect::<Vec<_>>(),
+            vec![first_root, second_root]
+        );
+        assert_eq!(server.collecting_rounds.len(), 0);
+        assert_eq!(server.connections_with_tx().len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_batch_is_rate_limited_per_public_key() -> CrateResult<()> {
+        let mut server = ServerState::new(MockRollupMemory::new())?;
+        server.set_batch_rate_limit(1, Duration::from_millis(200));
+
+        let sender = BlsSecretKey::new();
+        let other_sender = BlsSecretKey::new();
+        let batch = signed_batch(&sender)?;
+        let other_batch = signed_batch(&other_sender)?;
+
+        server.add_batch(&batch)?;
+        // Move the batch into a collecting round so the open round would accept another one
+        server.start_collecting_signatures().await?;
+
+        let err = server.add_batch(&batch).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::RateLimited)
+        );
+
+        // The limit is per public key
+        server.add_batch(&other_batch)?;
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        server.add_batch(&batch)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_participants_are_notified_when_nobody_signs() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+
+        // Use a raw connection so nothing signs the inclusion proof automatically
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+        let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+        add_raw_connection(&mut socket, &secret_key).await?;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut batch = TransactionBatch::new(public_key);
+        batch.transactions.push(SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from: public_key,
+            amount: 10,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        batch.sign(&secret_key)?;
+        server.lock().await.add_batch(&batch)?;
+
+        let root = server
+            .lock()
+            .await
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
+
+        assert!(matches!(
+            parse_ws_message(socket.next().await.unwrap()?)?,
+            WsMessage::SSendTransactionInclusionProof(_, _)
+        ));
+
+        let err = server.lock().await.finalise().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::EmptyRound)
+        );
+
+        match parse_ws_message(socket.next().await.unwrap()?)? {
+            WsMessage::SRoundFailed(failed_root) => assert_eq!(failed_root, root),
+            message => panic!("Expected SRoundFailed, got {:?}", message),
+        }
+
+        assert_eq!(server.lock().await.collecting_rounds.len(), 0);
+        assert_eq!(server.lock().await.connections_with_tx().len(), 0);
+        assert_eq!(rollup_state.get_transfer_blocks().await?.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_round_resends_inclusion_proof() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+        let mut batch = TransactionBatch::new(public_key);
+        batch.transactions.push(SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from: public_key,
+            amount: 10,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        batch.sign(&secret_key)?;
+        server.lock().await.add_batch(&batch)?;
+
+        // Nothing to resume before the round starts collecting signatures
+        assert_eq!(server.lock().await.resume_round(&public_key).await?, None);
+
+        server
+            .lock()
+            .await
+            .set_signature_window(Duration::from_secs(30));
+        let started_at = unix_timestamp_millis(SystemTime::now());
+
+        // The participant isn't connected when the proofs go out
+        let root = server
+            .lock()
+            .await
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
+
+        let (mut socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+        add_raw_connection(&mut socket, &secret_key).await?;
+        socket.send(WsMessage::CResumeRound.into()).await?;
+
+        match parse_ws_message(socket.next().await.unwrap()?)? {
+            WsMessage::SSendTransactionInclusionProof(proof, sign_by) => {
+                assert_eq!(proof.root, root);
+                // The deadline is still the original one, resuming doesn't extend it
+                let deadline = started_at + 30_000;
+                assert!((deadline..deadline + 1_000).contains(&sign_by));
+            }
+            message => panic!("Expected SSendTransactionInclusionProof, got {:?}", message),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_connection_closes_the_previous_one() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+        let secret_key = BlsSecretKey::new();
+        let public_key = secret_key.public_key();
+
+        let (mut first_socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+        add_raw_connection(&mut first_socket, &secret_key).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (mut second_socket, _) = connect_async(format!("ws://127.0.0.1:{}", port)).await?;
+        add_raw_connection(&mut second_socket, &secret_key).await?;
+
+        // The server tells the first socket why before closing it once the second takes over
+        assert!(matches!(
+            parse_ws_message(first_socket.next().await.unwrap()?)?,
+            WsMessage::SConnectionReplaced
+        ));
+        assert!(first_socket.next().await.unwrap()?.is_close());
+
+        // Give the first connection time to clean up, it mustn't remove the new connection
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(server.lock().await.connections.len(), 1);
+
+        server
+            .lock()
+            .await
+            .send_to_connection(&public_key, WsMessage::SRateLimited)
+            .await?;
+        assert!(matches!(
+            parse_ws_message(second_socket.next().await.unwrap()?)?,
+            WsMessage::SRateLimited
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_impersonating_batches_dont_use_the_victims_rate_limit() -> CrateResult<()> {
+        let mut server = ServerState::new(MockRollupMemory::new())?;
+        server.set_batch_rate_limit(1, Duration::from_secs(60));
+
+        let victim = BlsSecretKey::new();
+        let attacker = BlsSecretKey::new();
+
+        let mut impersonating_batch = signed_batch(&victim)?;
+        impersonating_batch.signature = Some(attacker.sign(
+            blsful::SignatureSchemes::MessageAugmentation,
+            &impersonating_batch.tx_hash(),
+        )?);
+
+        let err = server.add_batch(&impersonating_batch).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::InvalidBatchSignature)
+        );
+
+        server.add_batch(&signed_batch(&victim)?)?;
+
+        Ok(())
+    }
+
+    // Accepts a single HTTP request, responds with the given status and returns the request body
+    async fn respond_to_request(listener: &TcpListener, status: &str) -> CrateResult<String> {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+        let body_start = loop {
+            let read = stream.read(&mut buffer).await?;
+            request.extend_from_slice(&buffer[..read]);
+
+            if let Some(position) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position + 4;
+            }
+        };
+
+        let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+        let content_length = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map(|length| length.trim().parse::<usize>())
+            .transpose()?
+            .unwrap_or(0);
+
+        while request.len() < body_start + content_length {
+            let read = stream.read(&mut buffer).await?;
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        stream
+            .write_all(format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status).as_bytes())
+            .await?;
+
+        Ok(String::from_utf8(request[body_start..].to_vec())?)
+    }
+
+    // A server with a round collecting signatures that the only participant has signed, so it's
+    // ready to finalise
+    async fn server_with_signed_round(
+        mut rollup_state: Arc<Mutex<MockRollupMemory>>,
+        webhook_url: String,
+    ) -> CrateResult<(ServerState, U8_32)> {
+        let mut wallet = Wallet::new(None);
+        rollup_state.add_deposit(&wallet.public_key, 100).await?;
+        wallet.sync_rollup_state(&rollup_state).await?;
+
+        let mut server = ServerState::new(rollup_state)?;
+        server.set_webhook_url(Some(webhook_url));
+
+        wallet.append_transaction_to_batch(Wallet::new(None).public_key, 10)?;
+        let batch = wallet.produce_batch()?;
+        server.add_batch(&batch)?;
+
+        let root = server.start_collecting_signatures().await?.unwrap();
+        let proof = server.generate_proof_for_pubkey(&root, &wallet.public_key)?;
+        let signature = wallet.validate_and_sign_proof(&proof)?;
+        server.add_signature(&wallet.public_key, &root, &signature)?;
+
+        Ok((server, root))
+    }
+
+    #[tokio::test]
+    async fn test_finalise_posts_block_to_webhook() -> CrateResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let webhook_url = format!("http://{}/blocks", listener.local_addr()?);
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+
+        let (mut server, root) = server_with_signed_round(rollup_state, webhook_url).await?;
+        server.finalise().await?;
+
+        // The first attempt fails and is retried
+        respond_to_request(&listener, "500 Internal Server Error").await?;
+        let body = respond_to_request(&listener, "200 OK").await?;
+        let payload: BlockWebhookPayload = serde_json::from_str(&body)?;
+
+        assert_eq!(payload.merkle_root, root);
+        assert_eq!(payload.transfer_block.merkle_root, root);
+        assert_eq!(payload.commitment, payload.transfer_block.commitment());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalise_succeeds_when_webhook_is_unreachable() -> CrateResult<()> {
+        // Bind then drop a listener so nothing is listening on the port
+        let webhook_url = {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            format!("http://{}/blocks", listener.local_addr()?)
+        };
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+
+        let (mut server, _) = server_with_signed_round(rollup_state.clone(), webhook_url).await?;
+        server.finalise().await?;
+
+        assert_eq!(rollup_state.get_transfer_blocks().await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_only_new_balance_proof_entries_are_forwarded() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut server = ServerState::new(rollup_state.clone())?;
+
+        let mut receiver = Wallet::new(None);
+        let (to_receiver, mut receiver_messages) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .add_connection(Connection::new(receiver.public_key, Box::new(to_receiver)))
+            .await;
+
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+
+        let mut forwarded_roots = vec![];
+        for _ in 0..2 {
+            sender.append_transaction_to_batch(receiver.public_key, 10)?;
+            let mut aggregator = Aggregator::new();
+            aggregator.add_batch(&sender.produce_batch()?)?;
+            aggregator.start_collecting_signatures()?;
+            let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            aggregator.add_signature(&sender.public_key, &signature)?;
+            rollup_state
+                .add_transfer_block(aggregator.finalise()?)
+                .await?;
+
+            server
+                .send_batch_to_receivers(&proof, &sender.balance_proof)
+                .await?;
+
+            let WsMessage::SReceiveTransaction(proof, balance_proof) =
+                receiver_messages.try_recv()?
+            else {
+                panic!("Expected SReceiveTransaction");
+            };
+            forwarded_roots.push(balance_proof.keys().map(|key| key.root).collect::<Vec<_>>());
+
+            receiver
+                .add_receiving_transaction(&proof, &balance_proof, &rollup_state)
+                .await?;
+            // What the client sends once it has merged the transfer
+            server.set_known_balance_proof_keys(
+                &receiver.public_key,
+                receiver.balance_proof.keys().cloned().collect(),
+            )?;
+        }
+
+        // The second transfer only carries the root the receiver didn't already have
+        let sender_roots = sender
+            .balance_proof
+            .values()
+            .map(|proof| proof.root)
+            .collect::<HashSet<_>>();
+        assert_eq!(forwarded_roots[0].len(), 1);
+        assert_eq!(forwarded_roots[1].len(), 1);
+        assert_ne!(forwarded_roots[0], forwarded_roots[1]);
+        assert!(forwarded_roots
+            .iter()
+            .flatten()
+            .all(|root| sender_roots.contains(root)));
+        assert_eq!(receiver.balance, 20);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_negotiates_bincode_and_sends_a_batch() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+
+        let wallet = Wallet::new(None);
+        let public_key = wallet.public_key;
+        rollup_state.add_deposit(&public_key, 100).await?;
+
+        let (client, _, _) = Client::new_with_codecs(
+            wallet,
+            rollup_state.clone(),
+            port,
+            &[Codec::Bincode, Codec::Json],
+            0,
+        )
+        .await?;
+
+        client
+            .lock()
+            .await
+            .wallet
+            .append_transaction_to_batch(BlsSecretKey::new().public_key(), 10)?;
+        client.lock().await.send_transaction_batch().await?;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let status = |has_pending_batch, has_signed| {
+            vec![ConnectionStatus {
+                public_key,
+                has_pending_batch,
+                has_signed,
+            }]
+        };
+        assert_eq!(server.lock().await.connection_report(), status(true, false));
+
+        // The inclusion proof goes out as bincode too, the client signs it automatically
+        server.lock().await.start_collecting_signatures().await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.lock().await.connection_report(), status(false, true));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_codec_negotiation_fails_without_a_common_codec() -> CrateResult<()> {
+        let rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+        server.lock().await.set_supported_codecs(vec![Codec::Json]);
+
+        let err =
+            Client::new_with_codecs(Wallet::new(None), rollup_state, port, &[Codec::Bincode], 0)
+                .await
+                .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::NoCommonCodec)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_receiver_recovers_transfer_from_server_when_sender_goes_dark() -> CrateResult<()>
+    {
+        let (server, receiver, mut rollup_state) = setup().await?;
+        server
+            .lock()
+            .await
+            .set_proof_store(Some(ProofStore::default()));
+        let receiver_public_key = receiver.lock().await.wallet.public_key;
+
+        // The sender signs its batch and goes offline before forwarding the proof
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(receiver_public_key, 40)?;
+        let batch = sender.produce_batch()?;
+
+        let root = {
+            let mut server = server.lock().await;
+            server.add_batch(&batch)?;
+            let root = server.start_collecting_signatures().await?.unwrap();
+            let proof = server.generate_proof_for_pubkey(&root, &sender.public_key)?;
+            let signature = sender.validate_and_sign_proof(&proof)?;
+            server.add_signature(&sender.public_key, &root, &signature)?;
+            server.finalise().await?;
+            root
+        };
+
+        assert_eq!(receiver.lock().await.wallet.balance, 0);
+
+        receiver.lock().await.request_proof_for_root(root).await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert_eq!(receiver.lock().await.wallet.balance, 40);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sender_restores_lost_proofs_from_the_proof_store() -> CrateResult<()> {
+        let (server, client, mut rollup_state) = setup().await?;
+        server
+            .lock()
+            .await
+            .set_proof_store(Some(ProofStore::default()));
+        let public_key = client.lock().await.wallet.public_key;
+        rollup_state.add_deposit(&public_key, 100).await?;
+        client
+            .lock()
+            .await
+            .wallet
+            .sync_rollup_state(&rollup_state)
+            .await?;
+
+        client
+            .lock()
+            .await
+            .wallet
+            .append_transaction_to_batch(BlsSecretKey::new().public_key(), 10)?;
+        client.lock().await.send_transaction_batch().await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // The client signs automatically
+        let root = server
+            .lock()
+            .await
+            .start_collecting_signatures()
+            .await?
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        server.lock().await.finalise().await?;
+
+        let key = BalanceProofKey {
+            root,
+            public_key: public_key.into(),
+        };
+        client.lock().await.wallet.balance_proof.clear();
+
+        client.lock().await.request_stored_balance_proof().await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let client = client.lock().await;
+        assert!(client.wallet.balance_proof.contains_key(&key));
+        assert_eq!(client.wallet.balance, 90);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset_recovers_sends_from_the_proof_store() -> CrateResult<()> {
+        let (server, client, mut rollup_state) = setup().await?;
+        server
+            .lock()
+            .await
+            .set_proof_store(Some(ProofStore::default()));
+        let public_key = client.lock().await.wallet.public_key;
+        rollup_state.add_deposit(&public_key, 100).await?;
+        client
+            .lock()
+            .await
+            .wallet
+            .sync_rollup_state(&rollup_state)
+            .await?;
+
+        client
+            .lock()
+            .await
+            .wallet
+            .append_transaction_to_batch(BlsSecretKey::new().public_key(), 10)?;
+        client.lock().await.send_transaction_batch().await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        server.lock().await.start_collecting_signatures().await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        server.lock().await.finalise().await?;
+
+        Client::reset_local_state(&client, &rollup_state).await?;
+        assert_eq!(client.lock().await.wallet.balance, 90);
+
+        // Without the store the send can't be proven, so the wallet is left as it was
+        server.lock().await.set_proof_store(None);
+        assert_eq!(
+            Client::reset_local_state(&client, &rollup_state)
+                .await
+                .unwrap_err()
+                .downcast::<CrateError>()?,
+            CrateError::UnprovenSends(1)
+        );
+        assert_eq!(client.lock().await.wallet.balance, 90);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_round_drives_a_round_without_the_block_producer() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let mut server = ServerState::new(rollup_state.clone())?;
+        let mut sender = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(BlsSecretKey::new().public_key(), 10)?;
+
+        assert_eq!(server.step_round().await?, RoundStep::Idle);
+
+        server.add_batch(&sender.produce_batch()?)?;
+        let RoundStep::CollectingSignatures(root) = server.step_round().await? else {
+            panic!("Expected the round to start collecting signatures");
+        };
+
+        let proof = server.inclusion_proof(&sender.public_key)?;
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        server.add_signature(&sender.public_key, &root, &signature)?;
+
+        assert_eq!(server.step_round().await?, RoundStep::Finalised(root));
+        assert_eq!(
+            rollup_state
+                .get_transfer_blocks()
+                .await?
+                .iter()
+                .map(|block| block.merkle_root)
+                .collect::<Vec<_>>(),
+            vec![root]
+        );
+        assert_eq!(server.step_round().await?, RoundStep::Idle);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connections_with_tx_follows_the_rounds() -> CrateResult<()> {
+        let mut server = ServerState::new(MockRollupMemory::new())?;
+        let first = BlsSecretKey::new();
+        let second = BlsSecretKey::new();
+
+        let status = |entries: &[(&BlsSecretKey, bool)]| {
+            entries
+                .iter()
+                .map(|(secret_key, has_signed)| (secret_key.public_key().into(), *has_signed))
+                .collect::<HashMap<_, _>>()
+        };
+
+        server.add_batch(&signed_batch(&first)?)?;
+        assert_eq!(server.connections_with_tx(), status(&[(&first, false)]));
+
+        let first_root = server.start_collecting_signatures().await?.unwrap();
+        server.add_signature(
+            &first.public_key(),
+            &first_root,
+            &first.sign(blsful::SignatureSchemes::MessageAugmentation, &first_root)?,
+        )?;
+        assert_eq!(server.connections_with_tx(), status(&[(&first, true)]));
+
+        // The newest round decides the status of a key with batches in several
+        server.add_batch(&signed_batch(&first)?)?;
+        server.add_batch(&signed_batch(&second)?)?;
+        assert_eq!(
+            server.connections_with_tx(),
+            status(&[(&first, false), (&second, false)])
+        );
+
+        server.start_collecting_signatures().await?;
+        server.finalise().await?;
+        assert_eq!(
+            server.connections_with_tx(),
+            status(&[(&first, false), (&second, false)])
+        );
+
+        // Nobody signed the second round, so dropping it leaves nothing behind
+        let err = server.finalise().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CrateError>(),
+            Some(&CrateError::EmptyRound)
+        );
+        assert!(server.connections_with_tx().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unfunded_batches_are_dropped_before_collecting_signatures() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let mut server = ServerState::new(rollup_state.clone())?;
+        server.set_require_funded_batches(true);
+        server.set_proof_store(Some(ProofStore::default()));
+
+        let mut funded = Wallet::new(None);
+        rollup_state.add_deposit(&funded.public_key, 100).await?;
+        funded.sync_rollup_state(&rollup_state).await?;
+        funded.append_transaction_to_batch(BlsSecretKey::new().public_key(), 10)?;
+        server.add_batch(&funded.produce_batch()?)?;
+
+        // Never deposited, so there's nothing to cover the transfer
+        let unfunded = BlsSecretKey::new();
+        let mut unfunded_batch = TransactionBatch::new(unfunded.public_key());
+        unfunded_batch.transactions.push(SimpleTransaction {
+            to: BlsSecretKey::new().public_key(),
+            from: unfunded.public_key(),
+            amount: 10,
+            salt: Some(generate_salt()),
+            nonce: None,
+            reference: None,
+        });
+        unfunded_batch.sign(&unfunded)?;
+        server.add_batch(&unfunded_batch)?;
+
+        let RoundStep::CollectingSignatures(root) = server.step_round().await? else {
+            panic!("Expected the round to start collecting signatures");
+        };
+
+        assert!(server.inclusion_proof(&unfunded.public_key()).is_err());
+
+        let proof = server.inclusion_proof(&funded.public_key)?;
+        let signature = funded.validate_and_sign_proof(&proof)?;
+        server.add_signature(&funded.public_key, &root, &signature)?;
+
+        assert_eq!(server.step_round().await?, RoundStep::Finalised(root));
+
+        let transfer_blocks = rollup_state.get_transfer_blocks().await?;
+        assert_eq!(transfer_blocks.len(), 1);
+        assert_eq!(
+            transfer_blocks[0].signature.public_keys(),
+            vec![funded.public_key.into()]
+        );
+        // The unfunded batch never made it into the tree either
+        assert_eq!(transfer_blocks[0].total_leaves, Some(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_paid_fees_count_against_a_senders_funds() -> CrateResult<()> {
+        let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+        let mut server = ServerState::new(rollup_state.clone())?;
+        server.set_require_funded_batches(true);
+        server.set_proof_store(Some(ProofStore::default()));
+
+        let sender = BlsSecretKey::new();
+        rollup_state.add_deposit(&sender.public_key(), 100).await?;
+        let batch_for = |amount, fee| -> CrateResult<TransactionBatch> {
+            let mut batch = signed_batch(&sender)?;
+            batch.transactions[0].amount = amount;
+            batch.fee = fee;
+            batch.sign(&sender)?;
+
+            Ok(batch)
+        };
+
+        // 90 sent plus a fee of 10 spends the whole deposit
+        server.add_batch(&batch_for(90, 10)?)?;
+        let RoundStep::CollectingSignatures(root) = server.step_round().await? else {
+            panic!("Expected the round to start collecting signatures");
+        };
+        let signature = sender.sign(blsful::SignatureSchemes::MessageAugmentation, &root)?;
+        server.add_signature(&sender.public_key(), &root, &signature)?;
+        assert_eq!(server.step_round().await?, RoundStep::Finalised(root));
+
+        // Nothing is left, leaving the fee out would make it look like 10 is
+        server.add_batch(&batch_for(10, 0)?)?;
+        assert_eq!(server.step_round().await?, RoundStep::Idle);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_funding_check_is_skipped_without_a_proof_store() -> CrateResult<()> {
+        let mut server = ServerState::new(MockRollupMemory::new())?;
+        server.set_require_funded_batches(true);
+
+        // Could be funded by transfers the server has no way to see
+        let secret_key = BlsSecretKey::new();
+        server.add_batch(&signed_batch(&secret_key)?)?;
+
+        assert!(matches!(
+            server.step_round().await?,
+            RoundStep::CollectingSignatures(_)
+        ));
+        server.inclusion_proof(&secret_key.public_key())?;
+
+        Ok(())
+    }
 }
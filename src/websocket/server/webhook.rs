@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    constants::{WEBHOOK_MAX_ATTEMPTS, WEBHOOK_RETRY_MILLISECONDS, WEBHOOK_TIMEOUT_SECONDS},
+    errors::CrateResult,
+    types::common::{TransferBlock, U8_32},
+};
+
+// Body posted to the webhook for every finalised block, the commitment is included so
+// services anchoring blocks don't have to recompute it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockWebhookPayload {
+    pub merkle_root: U8_32,
+    pub commitment: U8_32,
+    pub transfer_block: TransferBlock,
+}
+
+impl From<&TransferBlock> for BlockWebhookPayload {
+    fn from(transfer_block: &TransferBlock) -> Self {
+        Self {
+            merkle_root: transfer_block.merkle_root,
+            commitment: transfer_block.commitment(),
+            transfer_block: transfer_block.clone(),
+        }
+    }
+}
+
+// Posts the block on its own task so a slow or broken webhook never holds up finalisation, the
+// result is only logged
+pub fn spawn_block_webhook(url: String, transfer_block: &TransferBlock) -> JoinHandle<()> {
+    let payload = BlockWebhookPayload::from(transfer_block);
+
+    tokio::spawn(async move {
+        if let Err(e) = post_with_retries(&url, &payload).await {
+            error!(
+                "Failed to notify webhook of block {:?}: {}",
+                payload.merkle_root, e
+            );
+        }
+    })
+}
+
+async fn post_with_retries(url: &str, payload: &BlockWebhookPayload) -> CrateResult<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS))
+        .build()?;
+
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .post(url)
+            .json(payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < WEBHOOK_MAX_ATTEMPTS => {
+                warn!("Webhook attempt {} failed, retrying: {}", attempt, e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        attempt += 1;
+        tokio::time::sleep(Duration::from_millis(WEBHOOK_RETRY_MILLISECONDS)).await;
+    }
+}
@@ -1 +1,37 @@
 pub mod test_end_to_end;
+
+use anyhow::anyhow;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    errors::CrateResult,
+    types::{auth::sign_auth_challenge, signatures::BlsSecretKey},
+    websocket::ws_message::{parse_ws_message, WsMessage},
+};
+
+// Adds a raw connection for tests that drive the protocol by hand, answering the server's auth
+// challenge like Client does
+pub async fn add_raw_connection(
+    socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    secret_key: &BlsSecretKey,
+) -> CrateResult<()> {
+    socket
+        .send(WsMessage::CAddConnection(secret_key.public_key()).into())
+        .await?;
+
+    let msg = socket
+        .next()
+        .await
+        .ok_or(anyhow!("Connection closed before the auth challenge"))?;
+    let WsMessage::SAuthChallenge(nonce) = parse_ws_message(msg?)? else {
+        return Err(anyhow!("Expected SAuthChallenge"));
+    };
+
+    socket
+        .send(WsMessage::CAuthResponse(sign_auth_challenge(secret_key, &nonce)?).into())
+        .await?;
+
+    Ok(())
+}
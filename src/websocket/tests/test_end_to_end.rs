@@ -1,8 +1,9 @@
 #![allow(unused_imports)] // Weirdly needed for some reason
 use std::sync::Arc;
 
+use anyhow::anyhow;
 use log::info;
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, time::timeout};
 
 use crate::{
     errors::CrateResult,
@@ -22,13 +23,13 @@ async fn test_client_auto_syncs_transfers_and_contacts_receiver() -> CrateResult
     info!("Starting");
     let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
     let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
-    let _ = spawn_block_producer(server.clone(), Some(1));
+    let _ = spawn_block_producer(server.clone(), Some(1), None);
 
     // Delay 1s to allow the server to start
     tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
 
-    let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port).await?;
-    let (receiver, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port).await?;
+    let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port, 0).await?;
+    let (receiver, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port, 0).await?;
 
     let client_public_key = client.lock().await.wallet.public_key.clone();
 
@@ -53,3 +54,129 @@ async fn test_client_auto_syncs_transfers_and_contacts_receiver() -> CrateResult
 
     Ok(())
 }
+
+const CHAINED_NUM_ACCOUNTS: usize = 4;
+const CHAINED_AMOUNT_TO_INCREMENT: u64 = 100;
+const BALANCE_WAIT_TIMEOUT_SECONDS: u64 = 30;
+
+// Polls the clients until their balances match, rather than sleeping for a fixed duration
+async fn wait_for_balances(clients: &[Arc<Mutex<Client>>], expected: &[u64]) -> CrateResult<()> {
+    let mut balances = vec![];
+
+    let result = timeout(
+        tokio::time::Duration::from_secs(BALANCE_WAIT_TIMEOUT_SECONDS),
+        async {
+            loop {
+                balances.clear();
+                for client in clients {
+                    balances.push(client.lock().await.wallet.balance);
+                }
+
+                if balances == expected {
+                    return;
+                }
+
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        },
+    )
+    .await;
+
+    result.map_err(|_| {
+        anyhow!(
+            "Timed out waiting for balances, expected {:?} got {:?}",
+            expected,
+            balances
+        )
+    })
+}
+
+// Mirrors tests/test_aggregator_wallet_flow.rs, but drives every transfer through the websocket
+// server, the block producer and the clients' sync threads instead of calling the aggregator
+// directly. Each round every account that still holds funds passes its whole balance to the next
+// account, until the last account holds everything.
+#[tokio::test]
+async fn test_chained_transfers_over_websocket() -> CrateResult<()> {
+    let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+    let (server, _, port) = ServerState::new_with_ws_server(rollup_state.clone(), None).await?;
+    let _block_producer = spawn_block_producer(server.clone(), Some(1), Some(1));
+
+    let mut clients = vec![];
+    for _ in 0..CHAINED_NUM_ACCOUNTS {
+        let (client, _, _) = Client::new(Wallet::new(None), rollup_state.clone(), port, 0).await?;
+        clients.push(client);
+    }
+
+    let mut public_keys = vec![];
+    for client in clients.iter() {
+        public_keys.push(client.lock().await.wallet.public_key);
+    }
+
+    let mut expected_balances = vec![];
+    for (idx, public_key) in public_keys.iter().enumerate() {
+        let amount = (idx as u64 + 1) * CHAINED_AMOUNT_TO_INCREMENT;
+        rollup_state.add_deposit(public_key, amount).await?;
+        expected_balances.push(amount);
+    }
+
+    let total_balance = expected_balances.iter().sum::<u64>();
+
+    wait_for_balances(&clients, &expected_balances).await?;
+
+    for aggregator_loop in 0..CHAINED_NUM_ACCOUNTS - 1 {
+        let previous_balances = expected_balances.clone();
+
+        // Send from the end of the chain first, so if the block producer splits the batches
+        // across rounds a receiver's own batch is never in a later round than its incoming funds
+        for idx in (aggregator_loop..CHAINED_NUM_ACCOUNTS - 1).rev() {
+            let mut client = clients[idx].lock().await;
+            let balance = client.wallet.balance;
+
+            client
+                .wallet
+                .append_transaction_to_batch(public_keys[idx + 1], balance)?;
+            client.send_transaction_batch().await?;
+
+            expected_balances[idx] -= previous_balances[idx];
+            expected_balances[idx + 1] += previous_balances[idx];
+        }
+
+        wait_for_balances(&clients, &expected_balances).await?;
+    }
+
+    assert_eq!(expected_balances[CHAINED_NUM_ACCOUNTS - 1], total_balance);
+
+    Ok(())
+}
+
+// Same flow as the websocket test above, but the clients talk to the server state directly
+#[tokio::test]
+async fn test_transfer_in_process() -> CrateResult<()> {
+    let mut rollup_state = Arc::new(Mutex::new(MockRollupMemory::new()));
+    let server = Arc::new(Mutex::new(ServerState::new(rollup_state.clone())?));
+    let _block_producer = spawn_block_producer(server.clone(), Some(1), Some(1));
+
+    let (client, _, _) =
+        Client::new_in_process(Wallet::new(None), server.clone(), rollup_state.clone()).await?;
+    let (receiver, _, _) =
+        Client::new_in_process(Wallet::new(None), server.clone(), rollup_state.clone()).await?;
+    let clients = [client.clone(), receiver.clone()];
+
+    let client_public_key = client.lock().await.wallet.public_key;
+    let receiver_public_key = receiver.lock().await.wallet.public_key;
+
+    rollup_state.add_deposit(&client_public_key, 100).await?;
+    wait_for_balances(&clients, &[100, 0]).await?;
+
+    {
+        let mut client = client.lock().await;
+        client
+            .wallet
+            .append_transaction_to_batch(receiver_public_key, 50)?;
+        client.send_transaction_batch().await?;
+    }
+
+    wait_for_balances(&clients, &[50, 50]).await?;
+
+    Ok(())
+}
@@ -1,88 +1,495 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc, time::Duration};
 
+use anyhow::anyhow;
+use async_trait::async_trait;
 use futures_util::{stream::SplitSink, SinkExt};
-use tokio::net::TcpStream;
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
 use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
 use crate::{
     errors::CrateResult,
     types::{
-        balance::BalanceProof,
+        balance::{BalanceProof, BalanceProofKey},
+        common::U8_32,
         signatures::{BlsPublicKey, BlsSignature},
         transaction::{TransactionBatch, TransactionProof},
     },
 };
 
-use super::ws_message::WsMessage;
+use super::{
+    server::{
+        connection::handle_message,
+        server_state::{Connection, ServerState},
+    },
+    ws_message::{Codec, WsMessage},
+};
 
-pub trait ClientTransport: Debug {
+// Outbound side of a client's connection to the aggregator, lets the client be used with
+// something other than a websocket (e.g. a mock in tests)
+#[async_trait]
+pub trait ClientTransport: Debug + Send {
     async fn add_connection(&mut self, public_key: BlsPublicKey) -> CrateResult<()>;
 
+    // Whether the server answers add_connection with SAuthChallenge, which the client has to
+    // sign and send back through authenticate before anything else
+    fn requires_authentication(&self) -> bool;
+
+    async fn authenticate(&mut self, signature: BlsSignature) -> CrateResult<()>;
+
+    // Offers the codecs in order of preference, the server's reply comes back as a message
+    async fn negotiate_codec(&mut self, codecs: Vec<Codec>) -> CrateResult<()>;
+
+    // Encodes everything sent from then on with the codec, transports that don't serialise
+    // messages can ignore it
+    fn set_codec(&mut self, codec: Codec);
+
+    async fn resume_round(&mut self) -> CrateResult<()>;
+
     async fn send_transaction_batch(&mut self, batch: TransactionBatch) -> CrateResult<()>;
 
     async fn send_transaction_batch_signature(
         &mut self,
         public_key: BlsPublicKey,
+        root: U8_32,
         signature: BlsSignature,
     ) -> CrateResult<()>;
 
+    async fn send_batch_signatures(
+        &mut self,
+        signatures: Vec<(U8_32, BlsSignature)>,
+    ) -> CrateResult<()>;
+
     async fn send_batch_to_receivers(
         &mut self,
         proof: TransactionProof,
         balance_proof: BalanceProof,
     ) -> CrateResult<()>;
+
+    async fn request_proof_delta(&mut self, have_keys: Vec<BalanceProofKey>) -> CrateResult<()>;
+
+    async fn request_proof_for_root(&mut self, root: U8_32) -> CrateResult<()>;
+
+    async fn request_stored_balance_proof(&mut self) -> CrateResult<()>;
+
+    async fn query_balance(&mut self, public_key: BlsPublicKey) -> CrateResult<()>;
+
+    // Keepalive, transports that can't be left half open can ignore it
+    async fn ping(&mut self) -> CrateResult<()>;
+
+    async fn close(&mut self) -> CrateResult<()>;
 }
 
 #[derive(Debug)]
 pub struct WebSocketTransport {
     ws_send: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+    codec: Codec,
 }
 
 impl WebSocketTransport {
     pub fn new(ws_send: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>) -> Self {
-        Self { ws_send }
+        Self {
+            ws_send,
+            codec: Codec::default(),
+        }
+    }
+
+    async fn send(&mut self, message: WsMessage) -> CrateResult<()> {
+        self.ws_send.send(message.encode(self.codec)?).await?;
+
+        Ok(())
     }
 }
 
+#[async_trait]
 impl ClientTransport for WebSocketTransport {
     async fn add_connection(&mut self, public_key: BlsPublicKey) -> CrateResult<()> {
-        let message: Message = WsMessage::CAddConnection(public_key).into();
+        self.send(WsMessage::CAddConnection(public_key)).await
+    }
 
-        self.ws_send.send(message).await?;
+    fn requires_authentication(&self) -> bool {
+        true
+    }
 
-        Ok(())
+    async fn authenticate(&mut self, signature: BlsSignature) -> CrateResult<()> {
+        self.send(WsMessage::CAuthResponse(signature)).await
+    }
+
+    async fn negotiate_codec(&mut self, codecs: Vec<Codec>) -> CrateResult<()> {
+        self.send(WsMessage::CNegotiateCodec(codecs)).await
+    }
+
+    fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    async fn resume_round(&mut self) -> CrateResult<()> {
+        self.send(WsMessage::CResumeRound).await
     }
 
     async fn send_transaction_batch(&mut self, batch: TransactionBatch) -> CrateResult<()> {
-        let message: Message = WsMessage::CSendTransactionBatch(batch).into();
+        self.send(WsMessage::CSendTransactionBatch(batch)).await
+    }
+
+    async fn send_transaction_batch_signature(
+        &mut self,
+        public_key: BlsPublicKey,
+        root: U8_32,
+        signature: BlsSignature,
+    ) -> CrateResult<()> {
+        self.send(WsMessage::CSendTransactionBatchSignature(
+            public_key, root, signature,
+        ))
+        .await
+    }
+
+    async fn send_batch_signatures(
+        &mut self,
+        signatures: Vec<(U8_32, BlsSignature)>,
+    ) -> CrateResult<()> {
+        self.send(WsMessage::CSendBatchSignatures(signatures)).await
+    }
+
+    async fn send_batch_to_receivers(
+        &mut self,
+        proof: TransactionProof,
+        balance_proof: BalanceProof,
+    ) -> CrateResult<()> {
+        self.send(WsMessage::CSendBatchToReceivers(proof, balance_proof))
+            .await
+    }
+
+    async fn request_proof_delta(&mut self, have_keys: Vec<BalanceProofKey>) -> CrateResult<()> {
+        self.send(WsMessage::CRequestProofDelta { have_keys }).await
+    }
+
+    async fn request_proof_for_root(&mut self, root: U8_32) -> CrateResult<()> {
+        self.send(WsMessage::CRequestProofForRoot { root }).await
+    }
+
+    async fn request_stored_balance_proof(&mut self) -> CrateResult<()> {
+        self.send(WsMessage::CRequestStoredBalanceProof).await
+    }
+
+    async fn query_balance(&mut self, public_key: BlsPublicKey) -> CrateResult<()> {
+        self.send(WsMessage::CQueryBalance(public_key)).await
+    }
+
+    async fn ping(&mut self) -> CrateResult<()> {
+        self.ws_send.send(Message::Ping(vec![])).await?;
+
+        Ok(())
+    }
 
-        self.ws_send.send(message).await?;
+    async fn close(&mut self) -> CrateResult<()> {
+        let _ = timeout(Duration::from_secs(2), self.ws_send.close()).await;
 
         Ok(())
     }
+}
+
+// In-process transport, every message the client sends is forwarded to the receiver returned
+// from new. Useful for asserting what a client sent without running a server
+#[derive(Debug, Clone)]
+pub struct ChannelTransport {
+    sender: mpsc::UnboundedSender<WsMessage>,
+}
+
+impl ChannelTransport {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<WsMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        (Self { sender }, receiver)
+    }
+
+    fn send(&self, message: WsMessage) -> CrateResult<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| anyhow!("Channel transport receiver was dropped"))
+    }
+}
+
+#[async_trait]
+impl ClientTransport for ChannelTransport {
+    async fn add_connection(&mut self, public_key: BlsPublicKey) -> CrateResult<()> {
+        self.send(WsMessage::CAddConnection(public_key))
+    }
+
+    // Nothing on the other end sends a challenge
+    fn requires_authentication(&self) -> bool {
+        false
+    }
+
+    async fn authenticate(&mut self, signature: BlsSignature) -> CrateResult<()> {
+        self.send(WsMessage::CAuthResponse(signature))
+    }
+
+    async fn negotiate_codec(&mut self, codecs: Vec<Codec>) -> CrateResult<()> {
+        self.send(WsMessage::CNegotiateCodec(codecs))
+    }
+
+    fn set_codec(&mut self, _codec: Codec) {}
+
+    async fn resume_round(&mut self) -> CrateResult<()> {
+        self.send(WsMessage::CResumeRound)
+    }
+
+    async fn send_transaction_batch(&mut self, batch: TransactionBatch) -> CrateResult<()> {
+        self.send(WsMessage::CSendTransactionBatch(batch))
+    }
 
     async fn send_transaction_batch_signature(
         &mut self,
         public_key: BlsPublicKey,
+        root: U8_32,
         signature: BlsSignature,
     ) -> CrateResult<()> {
-        let message: Message =
-            WsMessage::CSendTransactionBatchSignature(public_key, signature).into();
+        self.send(WsMessage::CSendTransactionBatchSignature(
+            public_key, root, signature,
+        ))
+    }
 
-        self.ws_send.send(message).await?;
+    async fn send_batch_signatures(
+        &mut self,
+        signatures: Vec<(U8_32, BlsSignature)>,
+    ) -> CrateResult<()> {
+        self.send(WsMessage::CSendBatchSignatures(signatures))
+    }
 
+    async fn send_batch_to_receivers(
+        &mut self,
+        proof: TransactionProof,
+        balance_proof: BalanceProof,
+    ) -> CrateResult<()> {
+        self.send(WsMessage::CSendBatchToReceivers(proof, balance_proof))
+    }
+
+    async fn request_proof_delta(&mut self, have_keys: Vec<BalanceProofKey>) -> CrateResult<()> {
+        self.send(WsMessage::CRequestProofDelta { have_keys })
+    }
+
+    async fn request_proof_for_root(&mut self, root: U8_32) -> CrateResult<()> {
+        self.send(WsMessage::CRequestProofForRoot { root })
+    }
+
+    async fn request_stored_balance_proof(&mut self) -> CrateResult<()> {
+        self.send(WsMessage::CRequestStoredBalanceProof)
+    }
+
+    async fn query_balance(&mut self, public_key: BlsPublicKey) -> CrateResult<()> {
+        self.send(WsMessage::CQueryBalance(public_key))
+    }
+
+    async fn ping(&mut self) -> CrateResult<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> CrateResult<()> {
         Ok(())
     }
+}
+
+// Connects a client directly to a ServerState in the same process, messages skip the network and
+// serialisation entirely. Messages from the server arrive on the receiver returned from new
+pub struct InProcessTransport {
+    // The public key and id of the connection once it's been added
+    connection: Option<(BlsPublicKey, u64)>,
+    server_state: Arc<Mutex<ServerState>>,
+    to_client: mpsc::UnboundedSender<WsMessage>,
+}
+
+impl Debug for InProcessTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InProcessTransport")
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+impl InProcessTransport {
+    pub fn new(
+        server_state: Arc<Mutex<ServerState>>,
+    ) -> (Self, mpsc::UnboundedReceiver<WsMessage>) {
+        let (to_client, receiver) = mpsc::unbounded_channel();
+
+        (
+            Self {
+                connection: None,
+                server_state,
+                to_client,
+            },
+            receiver,
+        )
+    }
+
+    async fn handle_message(&self, message: WsMessage) -> CrateResult<()> {
+        let (public_key, _) = self
+            .connection
+            .ok_or(anyhow!("Must add the connection before sending messages"))?;
+
+        handle_message(&public_key, message, self.server_state.clone()).await
+    }
+}
+
+#[async_trait]
+impl ClientTransport for InProcessTransport {
+    // Mirrors the websocket handshake, the server sends to this connection through the channel
+    async fn add_connection(&mut self, public_key: BlsPublicKey) -> CrateResult<()> {
+        let id = self
+            .server_state
+            .lock()
+            .await
+            .add_connection(Connection::new(
+                public_key,
+                Box::new(self.to_client.clone()),
+            ))
+            .await;
+        self.connection = Some((public_key, id));
+
+        Ok(())
+    }
+
+    // The client and server share a process, there's no one in between to claim a key
+    fn requires_authentication(&self) -> bool {
+        false
+    }
+
+    async fn authenticate(&mut self, _signature: BlsSignature) -> CrateResult<()> {
+        Ok(())
+    }
+
+    async fn negotiate_codec(&mut self, codecs: Vec<Codec>) -> CrateResult<()> {
+        self.handle_message(WsMessage::CNegotiateCodec(codecs))
+            .await
+    }
+
+    // Messages are passed as they are, there's nothing to encode
+    fn set_codec(&mut self, _codec: Codec) {}
+
+    async fn resume_round(&mut self) -> CrateResult<()> {
+        self.handle_message(WsMessage::CResumeRound).await
+    }
+
+    async fn send_transaction_batch(&mut self, batch: TransactionBatch) -> CrateResult<()> {
+        self.handle_message(WsMessage::CSendTransactionBatch(batch))
+            .await
+    }
+
+    async fn send_transaction_batch_signature(
+        &mut self,
+        public_key: BlsPublicKey,
+        root: U8_32,
+        signature: BlsSignature,
+    ) -> CrateResult<()> {
+        self.handle_message(WsMessage::CSendTransactionBatchSignature(
+            public_key, root, signature,
+        ))
+        .await
+    }
+
+    async fn send_batch_signatures(
+        &mut self,
+        signatures: Vec<(U8_32, BlsSignature)>,
+    ) -> CrateResult<()> {
+        self.handle_message(WsMessage::CSendBatchSignatures(signatures))
+            .await
+    }
 
     async fn send_batch_to_receivers(
         &mut self,
         proof: TransactionProof,
         balance_proof: BalanceProof,
     ) -> CrateResult<()> {
-        let message: Message = WsMessage::CSendBatchToReceivers(proof, balance_proof).into();
+        self.handle_message(WsMessage::CSendBatchToReceivers(proof, balance_proof))
+            .await
+    }
+
+    async fn request_proof_delta(&mut self, have_keys: Vec<BalanceProofKey>) -> CrateResult<()> {
+        self.handle_message(WsMessage::CRequestProofDelta { have_keys })
+            .await
+    }
+
+    async fn request_proof_for_root(&mut self, root: U8_32) -> CrateResult<()> {
+        self.handle_message(WsMessage::CRequestProofForRoot { root })
+            .await
+    }
+
+    async fn request_stored_balance_proof(&mut self) -> CrateResult<()> {
+        self.handle_message(WsMessage::CRequestStoredBalanceProof)
+            .await
+    }
+
+    async fn query_balance(&mut self, public_key: BlsPublicKey) -> CrateResult<()> {
+        self.handle_message(WsMessage::CQueryBalance(public_key))
+            .await
+    }
+
+    async fn ping(&mut self) -> CrateResult<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> CrateResult<()> {
+        if let Some((public_key, id)) = self.connection.take() {
+            self.server_state
+                .lock()
+                .await
+                .remove_connection_with_id(&public_key, id)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Outbound side of the server's connection to a client
+#[async_trait]
+pub trait ServerTransport: Send {
+    async fn send(&mut self, message: WsMessage, codec: Codec) -> CrateResult<()>;
+
+    async fn ping(&mut self) -> CrateResult<()>;
+
+    async fn close(&mut self) -> CrateResult<()>;
+}
 
-        self.ws_send.send(message).await?;
+#[async_trait]
+impl ServerTransport for SplitSink<WebSocketStream<TcpStream>, Message> {
+    async fn send(&mut self, message: WsMessage, codec: Codec) -> CrateResult<()> {
+        SinkExt::send(self, message.encode(codec)?).await?;
+
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> CrateResult<()> {
+        SinkExt::send(self, Message::Ping(vec![])).await?;
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> CrateResult<()> {
+        SinkExt::close(self).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ServerTransport for mpsc::UnboundedSender<WsMessage> {
+    // Messages aren't serialised in process, so the codec doesn't matter
+    async fn send(&mut self, message: WsMessage, _codec: Codec) -> CrateResult<()> {
+        mpsc::UnboundedSender::send(self, message)
+            .map_err(|_| anyhow!("In process connection was dropped"))
+    }
+
+    // An in process connection can't be left half open, there's nothing to check
+    async fn ping(&mut self) -> CrateResult<()> {
+        Ok(())
+    }
 
+    // The client's receiver ends once the connection, and with it this sender, is dropped
+    async fn close(&mut self) -> CrateResult<()> {
         Ok(())
     }
 }
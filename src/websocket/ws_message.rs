@@ -5,15 +5,19 @@ use tokio_tungstenite::tungstenite::Message;
 use crate::errors::CrateResult;
 
 use crate::types::{
-    balance::BalanceProof,
-    common::TransferBlock,
+    balance::{BalanceProof, BalanceProofKey},
+    common::U8_32,
     signatures::{BlsPublicKey, BlsSignature},
     transaction::{TransactionBatch, TransactionProof},
 };
 
+// Text frames are JSON and binary frames are bincode, so either side can decode a message without
+// knowing which codec the connection agreed on
 pub fn parse_ws_message(msg: Message) -> CrateResult<WsMessage> {
     if msg.is_text() {
         Ok(msg.try_into()?)
+    } else if msg.is_binary() {
+        Ok(bincode::deserialize(&msg.into_data())?)
     } else if msg.is_close() {
         Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed.into())
     } else {
@@ -21,18 +25,80 @@ pub fn parse_ws_message(msg: Message) -> CrateResult<WsMessage> {
     }
 }
 
+// How messages are encoded on the wire. Connections use Json unless they negotiate something
+// else with CNegotiateCodec
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 2] = [Codec::Json, Codec::Bincode];
+}
+
 // The WsMessage enum is used to represent the different types of messages that can be sent over the WebSocket connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum WsMessage {
     // Messages prefixed with C are sent by the client
     CAddConnection(BlsPublicKey),
+    // The signature over the nonce from SAuthChallenge, see types/auth.rs. The connection isn't
+    // added until it's been verified against the public key from CAddConnection
+    CAuthResponse(BlsSignature),
     CSendTransactionBatch(TransactionBatch),
-    CSendTransactionBatchSignature(BlsPublicKey, BlsSignature),
+    CSendTransactionBatchSignature(BlsPublicKey, U8_32, BlsSignature),
+    // Signatures for several rounds at once, keyed by root. Signed by the connection's key
+    CSendBatchSignatures(Vec<(U8_32, BlsSignature)>),
     CSendBatchToReceivers(TransactionProof, BalanceProof),
+    // Sent after reconnecting with a pending batch, to get the inclusion proof re-sent
+    CResumeRound,
+    // The balance proof entries the client already holds, replacing any previously sent. Balance
+    // proofs forwarded to the client only include the entries that aren't in this set. Keys
+    // rather than roots, since holding one sender's entry for a root says nothing about another's
+    CRequestProofDelta { have_keys: Vec<BalanceProofKey> },
+    // Sent right after CAddConnection, the codecs the client supports in order of preference.
+    // The server replies with SCodecSelected in the chosen codec, or SCodecRejected
+    CNegotiateCodec(Vec<Codec>),
+    // Asks for the batches in the root that pay this client, for when the sender never forwarded
+    // them. Only served by servers with a proof store, arrives as SReceiveTransaction
+    CRequestProofForRoot { root: U8_32 },
+    // Asks for everything the server's proof store has for this client, answered with
+    // SStoredBalanceProof
+    CRequestStoredBalanceProof,
+    // Asks for the account's balance as the rollup sees it, answered with SBalanceResponse
+    CQueryBalance(BlsPublicKey),
 
     // Messages prefixed with S are sent by the server
-    SSendTransactionInclusionProof(TransactionProof),
+    // The reply to CAddConnection, a random nonce for the client to sign
+    SAuthChallenge(U8_32),
+    // The proof comes with the time the signature must be sent by, in unix milliseconds, after
+    // which the round may be finalised without it
+    SSendTransactionInclusionProof(TransactionProof, u64),
     SReceiveTransaction(TransactionProof, BalanceProof),
+    SRateLimited,
+    // Nobody signed the round with this root, so it was dropped without a transfer block
+    SRoundFailed(U8_32),
+    // The codec used for messages on this connection from now on
+    SCodecSelected(Codec),
+    // None of the client's codecs are supported, comes with the ones the server does support
+    SCodecRejected(Vec<Codec>),
+    // The client's proofs from the server's proof store, along with whoever paid them
+    SStoredBalanceProof(BalanceProof),
+    // Deposits less withdraws on the rollup for the account from CQueryBalance
+    SBalanceResponse(u64),
+    // Sent just before the connection is closed because a newer one for the same public key took
+    // over, the client shouldn't reconnect or the two would keep replacing each other
+    SConnectionReplaced,
+}
+
+impl WsMessage {
+    pub fn encode(&self, codec: Codec) -> CrateResult<Message> {
+        Ok(match codec {
+            Codec::Json => Message::Text(serde_json::to_string(self)?),
+            Codec::Bincode => Message::Binary(bincode::serialize(self)?),
+        })
+    }
 }
 
 impl From<WsMessage> for Message {
@@ -53,3 +119,49 @@ impl TryFrom<Message> for WsMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aggregator::Aggregator,
+        errors::CrateResult,
+        rollup::{mock_rollup_memory::MockRollupMemory, traits::MockRollupStateTrait},
+        wallet::wallet::Wallet,
+    };
+
+    use super::{parse_ws_message, Codec, WsMessage};
+
+    #[tokio::test]
+    async fn test_send_batch_round_trips_over_binary_and_json() -> CrateResult<()> {
+        let mut rollup_state = MockRollupMemory::new();
+        let mut sender = Wallet::new(None);
+        let receiver = Wallet::new(None);
+        rollup_state.add_deposit(&sender.public_key, 100).await?;
+        sender.sync_rollup_state(&rollup_state).await?;
+        sender.append_transaction_to_batch(receiver.public_key, 40)?;
+
+        let mut aggregator = Aggregator::new();
+        aggregator.add_batch(&sender.produce_batch()?)?;
+        aggregator.start_collecting_signatures()?;
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+        sender.validate_and_sign_proof(&proof)?;
+
+        let message = WsMessage::CSendBatchToReceivers(proof, sender.balance_proof.clone());
+        let json_frame = message.encode(Codec::Json)?;
+        let binary_frame = message.encode(Codec::Bincode)?;
+        assert!(json_frame.is_text());
+        assert!(binary_frame.is_binary());
+        // Proofs are mostly byte arrays, which JSON spells out as lists of numbers
+        assert!(binary_frame.len() < json_frame.len());
+
+        // WsMessage has no PartialEq, so compare through its JSON form
+        let expected = serde_json::to_value(&message)?;
+        let from_json = parse_ws_message(json_frame)?;
+        let from_binary = parse_ws_message(binary_frame)?;
+        assert!(matches!(from_binary, WsMessage::CSendBatchToReceivers(..)));
+        assert_eq!(serde_json::to_value(&from_json)?, expected);
+        assert_eq!(serde_json::to_value(&from_binary)?, expected);
+
+        Ok(())
+    }
+}
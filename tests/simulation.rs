@@ -0,0 +1,206 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use stateless_bitcoin_l2::{
+    aggregator::Aggregator,
+    errors::{CrateError, CrateResult},
+    rollup::{
+        mock_rollup_memory::MockRollupMemory,
+        traits::{MockRollupStateTrait, RollupStateTrait},
+    },
+    types::{common::seed_rng, transaction::TransactionProof},
+    wallet::wallet::Wallet,
+};
+
+const NUM_WALLETS: usize = 6;
+const INITIAL_DEPOSIT: u64 = 1_000;
+// Chance of a sender not signing, which leaves their batch in the block without a signature
+const SKIP_SIGNATURE_PROBABILITY: f64 = 0.15;
+const DEPOSIT_PROBABILITY: f64 = 0.1;
+
+// Drives wallets, an aggregator and the rollup through randomized rounds, checking after each
+// round that no funds were created or destroyed. Everything random (salts, keys, amounts,
+// recipients, who signs) comes from the seed, so a failing run can be reproduced with:
+//
+// SIMULATION_SEED=<seed> SIMULATION_ROUNDS=<rounds> cargo test --release --features simulation \
+//     --test simulation -- --nocapture
+//
+// Long runs get slow in debug builds since every balance proof is revalidated each round
+#[tokio::test]
+async fn test_simulation_conserves_balances() -> CrateResult<()> {
+    let seed = env_or("SIMULATION_SEED", 0);
+    let rounds = env_or("SIMULATION_ROUNDS", 8);
+
+    println!("Running {} simulation rounds with seed {}", rounds, seed);
+
+    // The library's salts and keys come from the thread's seeded RNG, the harness' decisions
+    // come from its own so they don't depend on how many salts the library generates
+    seed_rng(Some(seed));
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let result = run_simulation(&mut rng, rounds).await;
+
+    seed_rng(None);
+
+    result.map_err(|e| e.context(format!("Simulation failed with seed {}", seed)))
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+async fn run_simulation(rng: &mut StdRng, rounds: u64) -> CrateResult<()> {
+    let mut rollup_state = MockRollupMemory::new();
+    let mut wallets = vec![];
+    let mut total_deposited = 0;
+
+    for _ in 0..NUM_WALLETS {
+        let mut wallet = Wallet::new(None);
+        wallet.assert_cached_balance = true;
+        rollup_state
+            .add_deposit(&wallet.public_key, INITIAL_DEPOSIT)
+            .await?;
+        wallet.sync_rollup_state(&rollup_state).await?;
+        total_deposited += INITIAL_DEPOSIT;
+
+        wallets.push(wallet);
+    }
+
+    for round in 0..rounds {
+        if rng.gen_bool(DEPOSIT_PROBABILITY) {
+            let index = rng.gen_range(0..NUM_WALLETS);
+            let amount = rng.gen_range(1..=INITIAL_DEPOSIT);
+            rollup_state
+                .add_deposit(&wallets[index].public_key, amount)
+                .await?;
+            total_deposited += amount;
+        }
+
+        run_round(rng, &mut wallets, &mut rollup_state).await?;
+
+        for wallet in wallets.iter_mut() {
+            wallet.sync_rollup_state(&rollup_state).await?;
+        }
+
+        assert_conservation(&wallets, total_deposited)
+            .map_err(|e| e.context(format!("Round {}", round)))?;
+    }
+
+    Ok(())
+}
+
+async fn run_round(
+    rng: &mut StdRng,
+    wallets: &mut [Wallet],
+    rollup_state: &mut MockRollupMemory,
+) -> CrateResult<()> {
+    let mut aggregator = Aggregator::new();
+    let mut senders = vec![];
+
+    for index in 0..wallets.len() {
+        // Batches left over from a round the wallet didn't sign are sent again as they are
+        if wallets[index].transaction_batch.transactions.is_empty() {
+            if wallets[index].balance == 0 || rng.gen_bool(0.5) {
+                continue;
+            }
+
+            let mut recipients = (0..wallets.len())
+                .filter(|recipient| *recipient != index)
+                .collect::<Vec<_>>();
+            recipients.shuffle(rng);
+            let num_recipients = rng.gen_range(1..=3);
+
+            for recipient in recipients.into_iter().take(num_recipients) {
+                if wallets[index].balance == 0 {
+                    break;
+                }
+
+                let amount = rng.gen_range(1..=wallets[index].balance);
+                let to = wallets[recipient].public_key;
+                wallets[index].append_transaction_to_batch(to, amount)?;
+            }
+        }
+
+        let batch = wallets[index].produce_batch()?;
+        aggregator.add_batch(&batch)?;
+        senders.push(index);
+    }
+
+    if senders.is_empty() {
+        return Ok(());
+    }
+
+    aggregator.start_collecting_signatures()?;
+
+    let mut signed_proofs: Vec<(usize, TransactionProof)> = vec![];
+    for index in senders.iter().copied() {
+        let sender = &mut wallets[index];
+        let proof = aggregator.generate_proof_for_pubkey(&sender.public_key)?;
+
+        if rng.gen_bool(SKIP_SIGNATURE_PROBABILITY) {
+            continue;
+        }
+
+        let signature = sender.validate_and_sign_proof(&proof)?;
+        aggregator.add_signature(&sender.public_key, &signature)?;
+        signed_proofs.push((index, proof));
+    }
+
+    let transfer_block = match aggregator.finalise() {
+        Ok(transfer_block) => Some(transfer_block),
+        Err(e) if e.downcast_ref::<CrateError>() == Some(&CrateError::EmptyRound) => None,
+        Err(e) => return Err(e),
+    };
+
+    // Senders that didn't sign get their batch back to resend in a later round
+    for index in senders.iter() {
+        if wallets[*index].batch_is_pending() {
+            wallets[*index].abort_pending_batch()?;
+        }
+    }
+
+    let Some(transfer_block) = transfer_block else {
+        return Ok(());
+    };
+    rollup_state.add_transfer_block(transfer_block).await?;
+
+    for (sender_index, proof) in signed_proofs {
+        let senders_balance_proof = wallets[sender_index].balance_proof.clone();
+
+        for transaction in proof.batch.transactions.iter() {
+            let receiver = wallets
+                .iter_mut()
+                .find(|wallet| wallet.public_key == transaction.to)
+                .unwrap();
+
+            receiver
+                .add_receiving_transaction(&proof, &senders_balance_proof, rollup_state)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Funds in unsent or resent batches are already debited from the balance, so they're counted
+// separately
+fn assert_conservation(wallets: &[Wallet], total_deposited: u64) -> CrateResult<()> {
+    let balances = wallets.iter().map(|wallet| wallet.balance).sum::<u64>();
+    let in_batches = wallets
+        .iter()
+        .flat_map(|wallet| wallet.transaction_batch.transactions.iter())
+        .map(|transaction| transaction.amount)
+        .sum::<u64>();
+
+    if balances + in_batches != total_deposited {
+        return Err(anyhow::anyhow!(
+            "Balances ({}) plus unsent batches ({}) don't add up to the {} deposited",
+            balances,
+            in_batches,
+            total_deposited
+        ));
+    }
+
+    Ok(())
+}